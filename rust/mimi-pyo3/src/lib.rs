@@ -5,7 +5,7 @@
 use pyo3::prelude::*;
 
 use ::moshi as mm;
-use mm::{candle, candle_nn, conv, encodec, seanet, transformer};
+use mm::{candle, candle_nn, encodec};
 
 trait PyRes<R> {
     #[allow(unused)]
@@ -39,63 +39,9 @@ macro_rules! py_bail {
     };
 }
 
-fn encodec_cfg() -> encodec::Config {
-    let seanet_cfg = seanet::Config {
-        dimension: 512,
-        channels: 1,
-        causal: true,
-        n_filters: 64,
-        n_residual_layers: 1,
-        activation: candle_nn::Activation::Elu(1.),
-        compress: 2,
-        dilation_base: 2,
-        disable_norm_outer_blocks: 0,
-        final_activation: None,
-        kernel_size: 7,
-        residual_kernel_size: 3,
-        last_kernel_size: 3,
-        lstm: 0,
-        norm: conv::Norm::WeightNorm,
-        pad_mode: conv::PadMode::Constant,
-        ratios: vec![8, 6, 5, 4],
-        true_skip: true,
-    };
-    let transformer_cfg = transformer::Config {
-        d_model: seanet_cfg.dimension,
-        num_heads: 8,
-        num_layers: 8,
-        causal: true,
-        norm_first: true,
-        bias_ff: false,
-        bias_attn: false,
-        layer_scale: Some(0.01),
-        context: 250,
-        conv_kernel_size: 5,
-        use_conv_bias: true,
-        use_conv_block: false,
-        max_period: 10000,
-        positional_embedding: transformer::PositionalEmbedding::Rope,
-        gating: None,
-        norm: mm::NormType::LayerNorm,
-
-        dim_feedforward: 2048,
-        kv_repeat: 1,
-        conv_layout: true, // see builders.py
-        cross_attention: false,
-        max_seq_len: 4096,
-    };
-    encodec::Config {
-        channels: 1,
-        sample_rate: 24_000.,
-        frame_rate: 12.5,
-        renormalize: true,
-        resample_method: encodec::ResampleMethod::Conv,
-        seanet: seanet_cfg,
-        transformer: transformer_cfg,
-        quantizer_n_q: 8,
-        quantizer_bins: 2048,
-        quantizer_dim: 256,
-    }
+fn encodec_cfg(path: &std::path::Path) -> PyResult<encodec::Config> {
+    let cfg = encodec::Config::from_model_file(path).w_f(path)?;
+    Ok(cfg.unwrap_or_else(|| encodec::Config::v0_1(Some(8))))
 }
 
 #[pyclass]
@@ -117,9 +63,9 @@ impl Tokenizer {
             "bf16" => candle::DType::BF16,
             dtype => py_bail!("unsupported dtype '{dtype}'"),
         };
+        let cfg = encodec_cfg(&path)?;
         let vb =
             unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[path], dtype, &device).w()? };
-        let cfg = encodec_cfg();
         let encodec = encodec::Encodec::new(cfg, vb).w()?;
         Ok(Self { encodec, device, dtype })
     }
@@ -250,9 +196,9 @@ impl StreamTokenizer {
             "bf16" => candle::DType::BF16,
             dtype => py_bail!("unsupported dtype '{dtype}'"),
         };
+        let cfg = encodec_cfg(&path)?;
         let vb =
             unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[path], dtype, &device).w()? };
-        let cfg = encodec_cfg();
         let mut e_encodec = encodec::Encodec::new(cfg, vb).w()?;
         let mut d_encodec = e_encodec.clone();
         let (encoder_tx, e_rx) = std::sync::mpsc::channel::<Vec<f32>>();
@@ -342,19 +288,24 @@ impl StreamTokenizer {
 
 /// Writes an audio file using the wav format based on pcm data from a numpy array.
 ///
-/// This only supports a single channel at the moment so the input array data is expected to have a
-/// single dimension.
+/// The input array is expected to either have a single dimension for mono audio, or two
+/// dimensions (channels, time) for multi-channel audio.
 #[pyfunction]
 #[pyo3(signature = (filename, data, sample_rate))]
 fn write_wav(
     filename: std::path::PathBuf,
-    data: numpy::PyReadonlyArray1<f32>,
+    data: numpy::PyReadonlyArrayDyn<f32>,
     sample_rate: u32,
 ) -> PyResult<()> {
+    let data = data.as_array();
+    let channels: Vec<Vec<f32>> = match data.ndim() {
+        1 => vec![data.iter().copied().collect()],
+        2 => data.outer_iter().map(|c| c.iter().copied().collect()).collect(),
+        ndim => py_bail!("unexpected number of dimensions {ndim}, expected 1 or 2"),
+    };
     let w = std::fs::File::create(&filename).w_f(&filename)?;
     let mut w = std::io::BufWriter::new(w);
-    let data = data.as_array().to_vec();
-    mm::wav::write_pcm_as_wav(&mut w, &data, sample_rate).w_f(&filename)?;
+    mm::wav::write_pcm_channels_as_wav(&mut w, &channels, sample_rate).w_f(&filename)?;
     Ok(())
 }
 
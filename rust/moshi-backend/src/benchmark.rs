@@ -65,17 +65,10 @@ pub async fn run(args: &crate::BenchmarkArgs, config: &Config) -> Result<()> {
     );
     tracing::info!(?config, "starting benchmark");
     let session_config = SessionConfigReq {
-        text_temperature: None,
-        text_topk: None,
-        audio_temperature: None,
-        audio_topk: None,
         max_steps: Some(args.steps),
         audio_seed: Some(299792458),
         text_seed: Some(299792458),
-        email: None,
-        pad_mult: None,
-        repetition_penalty_context: None,
-        repetition_penalty: None,
+        ..Default::default()
     };
     if args.mimi_only {
         let device = crate::standalone::device(args.cpu)?;
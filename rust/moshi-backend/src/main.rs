@@ -8,6 +8,7 @@ use std::str::FromStr;
 
 mod audio;
 mod benchmark;
+mod session_log;
 mod standalone;
 mod stream_both;
 mod utils;
@@ -0,0 +1,104 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The token logs of the sessions. The generation state only keeps a window of the token
+//! history, so the tokens get written to disk before they leave this window. They are appended
+//! to temporary files and assembled into `{base_path}.safetensors` at the end of the session,
+//! with the same `text` and `audio` tensors as the full history would produce.
+
+use anyhow::Result;
+use byteorder::{LittleEndian, WriteBytesExt};
+use moshi::lm_generate_multistream::{State, UNGENERATED};
+use std::io::{BufWriter, Write};
+
+pub struct TokenLog {
+    base_path: String,
+    text: BufWriter<std::fs::File>,
+    audio: BufWriter<std::fs::File>,
+    codebooks: usize,
+    // The number of steps that have been written so far.
+    num_steps: usize,
+    // The text tokens used for the transcript, i.e. without the special tokens.
+    transcript_tokens: Vec<u32>,
+}
+
+impl TokenLog {
+    pub fn new(base_path: &str, codebooks: usize) -> Result<Self> {
+        let create = |ext: &str| -> Result<_> {
+            Ok(BufWriter::new(std::fs::File::create(format!("{base_path}.{ext}"))?))
+        };
+        Ok(Self {
+            base_path: base_path.to_string(),
+            text: create("text.tmp")?,
+            audio: create("audio.tmp")?,
+            codebooks,
+            num_steps: 0,
+            transcript_tokens: vec![],
+        })
+    }
+
+    // Writes the steps before `end` that have not been written yet.
+    fn write_until(&mut self, state: &State, end: usize) -> Result<()> {
+        let start = state.history_start();
+        if self.num_steps < start {
+            anyhow::bail!("steps {}..{start} left the history before being logged", self.num_steps)
+        }
+        let config = state.config();
+        let text_tokens = state.text_tokens(false);
+        let audio_tokens = state.audio_tokens(false);
+        for step in self.num_steps..end {
+            let text_token = text_tokens[step - start];
+            self.text.write_u32::<LittleEndian>(text_token)?;
+            for &audio_token in audio_tokens[step - start].iter() {
+                self.audio.write_u32::<LittleEndian>(audio_token)?;
+            }
+            if text_token != UNGENERATED
+                && text_token != config.text_pad_token
+                && text_token != config.text_eop_token
+                && text_token != config.text_start_token
+            {
+                self.transcript_tokens.push(text_token)
+            }
+        }
+        self.num_steps = usize::max(self.num_steps, end);
+        Ok(())
+    }
+
+    /// Writes the steps for which all the delayed codebooks have been generated. This has to be
+    /// called after each step so that these steps are still in the history window of `state`.
+    pub fn write_complete(&mut self, state: &State) -> Result<()> {
+        let max_delay = state.config().stream_layout.max_delay();
+        self.write_until(state, state.step_idx().saturating_sub(max_delay))
+    }
+
+    /// Writes the remaining steps, possibly with some ungenerated tokens, and assembles the
+    /// safetensors file. Returns the text tokens for the transcript.
+    pub fn finish(mut self, state: &State) -> Result<Vec<u32>> {
+        self.write_until(state, state.step_idx())?;
+        self.text.flush()?;
+        self.audio.flush()?;
+        let (n, codebooks) = (self.num_steps, self.codebooks);
+        // The audio data comes first, followed by the text data.
+        let audio_end = n * codebooks * 4;
+        let text_end = audio_end + n * 4;
+        let header = serde_json::json!({
+            "audio": {"dtype": "U32", "shape": [n, codebooks], "data_offsets": [0, audio_end]},
+            "text": {"dtype": "U32", "shape": [n], "data_offsets": [audio_end, text_end]},
+        });
+        // The header is padded with spaces so that the tensor data is 8 bytes aligned.
+        let mut header = header.to_string().into_bytes();
+        header.resize(header.len().next_multiple_of(8), b' ');
+        let st_filename = format!("{}.safetensors", self.base_path);
+        let mut file = BufWriter::new(std::fs::File::create(st_filename)?);
+        file.write_u64::<LittleEndian>(header.len() as u64)?;
+        file.write_all(&header)?;
+        for ext in ["audio.tmp", "text.tmp"] {
+            let path = format!("{}.{ext}", self.base_path);
+            std::io::copy(&mut std::fs::File::open(&path)?, &mut file)?;
+            std::fs::remove_file(&path)?;
+        }
+        file.flush()?;
+        Ok(self.transcript_tokens)
+    }
+}
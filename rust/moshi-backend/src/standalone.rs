@@ -73,8 +73,13 @@ impl stream_both::AppStateInner {
             tracing::info!(?dtype, ?device, "warming up the model");
             let mut lm_model = lm_model.clone();
             let (_v, ys) = lm_model.forward(None, vec![None; config.encodec_num_codebooks])?;
-            let mut lp = candle_transformers::generation::LogitsProcessor::new(123, None, None);
-            let _ = lm_model.depformer_sample(0, &ys, None, &mut lp)?;
+            let mut lps = vec![moshi::sampling::Sampler::greedy()];
+            let lm_config = config
+                .lm_config
+                .clone()
+                .unwrap_or_else(moshi::lm_generate_multistream::Config::v0_1);
+            let delays = lm_config.stream_layout.generated_delays();
+            let _ = lm_model.depformer_sample(0, &ys, None, &delays, &mut lps)?;
             let mut encodec_model = encodec_model.clone();
             let config = encodec_model.config();
             let frame_length = (config.sample_rate / config.frame_rate).ceil() as usize;
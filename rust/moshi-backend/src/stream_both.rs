@@ -22,6 +22,13 @@ pub struct Config {
     pub lm_config: Option<moshi::lm_generate_multistream::Config>,
     #[serde(default = "default_false")]
     pub use_cpu_for_encodec: bool,
+    /// The directory holding the audio files that sessions can use as voice prompts, voice
+    /// prompts are disabled when not set.
+    pub voice_prompt_dir: Option<String>,
+    /// Upper bound on the number of steps of a session, the value requested by clients gets
+    /// capped to it. Sessions are unbounded when not set.
+    #[serde(default)]
+    pub max_steps: Option<usize>,
 }
 
 fn default_false() -> bool {
@@ -36,6 +43,8 @@ impl Config {
         config.text_tokenizer_file = crate::utils::replace_env_vars(&config.text_tokenizer_file);
         config.encodec_model_file = crate::utils::replace_env_vars(&config.encodec_model_file);
         config.lm_model_file = crate::utils::replace_env_vars(&config.lm_model_file);
+        config.voice_prompt_dir =
+            config.voice_prompt_dir.map(|dir| crate::utils::replace_env_vars(&dir));
         Ok(config)
     }
 
@@ -88,12 +97,68 @@ impl AppStateInner {
     }
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+// Parses a comma separated list of `token:bias` pairs, e.g. `3:-1.5,42:2`.
+fn deserialize_logit_bias<'de, D>(deserializer: D) -> std::result::Result<Vec<(u32, f32)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    s.split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            let (token, bias) =
+                v.split_once(':').ok_or_else(|| D::Error::custom(format!("no bias in {v:?}")))?;
+            let token = token.trim().parse().map_err(D::Error::custom)?;
+            let bias = bias.trim().parse().map_err(D::Error::custom)?;
+            Ok((token, bias))
+        })
+        .collect()
+}
+
+// Parses a comma separated list of values, e.g. `1,2,3`.
+fn deserialize_list<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    use serde::de::Error;
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    s.split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| v.trim().parse().map_err(D::Error::custom))
+        .collect()
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
 pub struct SessionConfigReq {
     pub text_temperature: Option<f64>,
+    pub text_final_temperature: Option<f64>,
+    pub text_temperature_steps: Option<usize>,
     pub text_topk: Option<usize>,
+    pub text_top_p: Option<f64>,
+    pub text_min_p: Option<f64>,
+    pub text_typical_p: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_logit_bias")]
+    pub text_logit_bias: Vec<(u32, f32)>,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub text_banned_tokens: Vec<u32>,
     pub audio_temperature: Option<f64>,
+    pub audio_final_temperature: Option<f64>,
+    pub audio_temperature_steps: Option<usize>,
     pub audio_topk: Option<usize>,
+    pub audio_top_p: Option<f64>,
+    pub audio_min_p: Option<f64>,
+    pub audio_typical_p: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_logit_bias")]
+    pub audio_logit_bias: Vec<(u32, f32)>,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub audio_banned_tokens: Vec<u32>,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub audio_temperature_per_codebook: Vec<f64>,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub audio_topk_per_codebook: Vec<usize>,
     pub max_steps: Option<usize>,
     pub audio_seed: Option<u64>,
     pub text_seed: Option<u64>,
@@ -101,21 +166,32 @@ pub struct SessionConfigReq {
     pub pad_mult: Option<f32>,
     pub repetition_penalty_context: Option<usize>,
     pub repetition_penalty: Option<f32>,
+    pub text_prompt: Option<String>,
+    pub voice_prompt_file: Option<String>,
+    pub cfg_alpha: Option<f64>,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
 pub struct SessionConfig {
-    pub text_temperature: f64,
-    pub text_topk: usize,
-    pub audio_temperature: f64,
-    pub audio_topk: usize,
-    pub max_steps: usize,
+    pub text_sampling: moshi::sampling::SamplingConfig,
+    pub audio_sampling: moshi::sampling::SamplingConfig,
+    /// Per codebook overrides of the audio temperature and top-k, in sampling order. The
+    /// codebooks without an override use `audio_sampling`.
+    pub audio_temperature_per_codebook: Vec<f64>,
+    pub audio_topk_per_codebook: Vec<usize>,
+    pub max_steps: Option<usize>,
     pub audio_seed: u64,
     pub text_seed: u64,
+    /// Added to the text pad token logit after the temperature scaling, i.e. the pad probability
+    /// gets multiplied by `exp(pad_mult)`.
     pub pad_mult: Option<f32>,
-    pub repetition_penalty: Option<(usize, f32)>,
     pub email: Option<String>,
     pub user_feedback: Option<usize>,
+    pub text_prompt: Option<String>,
+    pub voice_prompt_file: Option<String>,
+    /// Classifier free guidance between the session with and without the text and voice
+    /// prompts, disabled when not set or when there is no prompt.
+    pub cfg_alpha: Option<f64>,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
@@ -132,23 +208,90 @@ struct SessionSummary<'a> {
 }
 
 impl SessionConfigReq {
-    fn into_session_config(self) -> SessionConfig {
+    fn into_session_config(self, server_max_steps: Option<usize>) -> SessionConfig {
         use rand::Rng;
 
         let repetition_penalty = self.repetition_penalty_context.zip(self.repetition_penalty);
+        // Without a prompt, both branches of classifier free guidance would be the same.
+        let has_prompt = self.text_prompt.is_some() || self.voice_prompt_file.is_some();
+        let cfg_alpha = self.cfg_alpha.filter(|_| has_prompt);
+        let text_sampling = moshi::sampling::SamplingConfig {
+            temperature: self.text_temperature.unwrap_or(0.8),
+            final_temperature: self.text_final_temperature,
+            temperature_steps: self.text_temperature_steps.unwrap_or(0),
+            top_k: Some(self.text_topk.unwrap_or(250)),
+            top_p: self.text_top_p,
+            min_p: self.text_min_p,
+            typical_p: self.text_typical_p,
+            repetition_penalty,
+            logit_bias: self.text_logit_bias,
+            post_temperature_logit_bias: vec![],
+            banned_tokens: self.text_banned_tokens,
+        };
+        let audio_sampling = moshi::sampling::SamplingConfig {
+            temperature: self.audio_temperature.unwrap_or(0.8),
+            final_temperature: self.audio_final_temperature,
+            temperature_steps: self.audio_temperature_steps.unwrap_or(0),
+            top_k: Some(self.audio_topk.unwrap_or(250)),
+            top_p: self.audio_top_p,
+            min_p: self.audio_min_p,
+            typical_p: self.audio_typical_p,
+            repetition_penalty: None,
+            logit_bias: self.audio_logit_bias,
+            post_temperature_logit_bias: vec![],
+            banned_tokens: self.audio_banned_tokens,
+        };
         SessionConfig {
-            text_temperature: self.text_temperature.unwrap_or(0.8),
-            text_topk: self.text_topk.unwrap_or(250),
+            text_sampling,
             text_seed: self.text_seed.unwrap_or_else(|| rand::thread_rng().gen()),
-            audio_temperature: self.audio_temperature.unwrap_or(0.8),
-            audio_topk: self.audio_topk.unwrap_or(250),
+            audio_sampling,
+            audio_temperature_per_codebook: self.audio_temperature_per_codebook,
+            audio_topk_per_codebook: self.audio_topk_per_codebook,
             audio_seed: self.audio_seed.unwrap_or_else(|| rand::thread_rng().gen()),
             email: self.email,
             user_feedback: None,
-            max_steps: self.max_steps.unwrap_or(4500).min(4500),
+            max_steps: match (self.max_steps, server_max_steps) {
+                (Some(max_steps), Some(server_max_steps)) => Some(max_steps.min(server_max_steps)),
+                (max_steps, server_max_steps) => max_steps.or(server_max_steps),
+            },
             pad_mult: self.pad_mult,
-            repetition_penalty,
+            text_prompt: self.text_prompt,
+            voice_prompt_file: self.voice_prompt_file,
+            cfg_alpha,
+        }
+    }
+}
+
+impl SessionConfig {
+    // The samplers for the generated audio codebooks. A single sampler is shared by all the
+    // codebooks unless some per codebook settings are provided, in which case each codebook
+    // gets its own rng stream.
+    fn audio_samplers(&self, num_codebooks: usize) -> Result<Vec<moshi::sampling::Sampler>> {
+        let temperatures = &self.audio_temperature_per_codebook;
+        let topks = &self.audio_topk_per_codebook;
+        if temperatures.is_empty() && topks.is_empty() {
+            return Ok(vec![self.audio_sampling.sampler(self.audio_seed)]);
+        }
+        if temperatures.len() > num_codebooks || topks.len() > num_codebooks {
+            anyhow::bail!(
+                "got {} temperatures and {} topks for {num_codebooks} audio codebooks",
+                temperatures.len(),
+                topks.len()
+            )
         }
+        let configs: Vec<_> = (0..num_codebooks)
+            .map(|idx| {
+                let mut config = self.audio_sampling.clone();
+                if let Some(&temperature) = temperatures.get(idx) {
+                    config.temperature = temperature
+                }
+                if let Some(&topk) = topks.get(idx) {
+                    config.top_k = Some(topk)
+                }
+                config
+            })
+            .collect();
+        Ok(moshi::sampling::SamplingConfig::samplers(&configs, self.audio_seed))
     }
 }
 
@@ -161,10 +304,16 @@ pub struct MetaData {
     pad_mult: f32,
     repetition_penalty_context: usize,
     repetition_penalty: f32,
+    text_sampling: moshi::sampling::SamplingConfig,
+    audio_sampling: moshi::sampling::SamplingConfig,
+    audio_temperature_per_codebook: Vec<f64>,
+    audio_topk_per_codebook: Vec<usize>,
+    cfg_alpha: Option<f64>,
     lm_model_file: String,
     encodec_model_file: String,
     build_info: crate::utils::BuildInfo,
     instance_name: String,
+    algorithmic_latency_ms: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -322,9 +471,99 @@ pub struct StreamingModel {
 }
 
 impl StreamingModel {
+    // The codebooks decoded by mimi, these are the first ones of the generated streams.
+    fn out_codebooks(&self, config: &moshi::lm_generate_multistream::Config) -> Vec<usize> {
+        let cb = self.state.config.encodec_num_codebooks;
+        config.stream_layout.generated_codebooks().into_iter().take(cb).collect()
+    }
+
+    // The audio tokens to decode after a step, the frames coming from the voice prompt are not
+    // played back.
+    fn out_audio_tokens(
+        state: &moshi::lm_generate_multistream::State,
+        out_codebooks: &[usize],
+        prompt_steps: usize,
+    ) -> Option<Vec<u32>> {
+        let max_delay = state.config().stream_layout.max_delay();
+        if state.step_idx() <= prompt_steps + max_delay {
+            return None;
+        }
+        let audio_tokens = state.last_audio_tokens()?;
+        Some(out_codebooks.iter().map(|&c| audio_tokens[c]).collect())
+    }
+
+    // Primes the state with a voice prompt read from the voice prompt directory.
+    fn prefill_voice(
+        &self,
+        state: &mut moshi::lm_generate_multistream::State,
+        file: &str,
+    ) -> Result<()> {
+        let dir = match self.state.config.voice_prompt_dir.as_ref() {
+            None => anyhow::bail!("voice prompts are not enabled"),
+            Some(dir) => std::path::Path::new(dir),
+        };
+        // Only plain file names are accepted so that sessions cannot read outside of dir.
+        if std::path::Path::new(file).file_name() != Some(std::ffi::OsStr::new(file)) {
+            anyhow::bail!("invalid voice prompt file {file}")
+        }
+        let (pcm, sample_rate) = crate::audio::pcm_decode(dir.join(file))?;
+        let mut encodec = self.state.encodec_model.clone();
+        let mimi_sample_rate = encodec.config().sample_rate as usize;
+        let pcm = crate::audio::resample(&pcm, sample_rate as usize, mimi_sample_rate)?;
+        let encodec_device =
+            if self.state.config.use_cpu_for_encodec { &candle::Device::Cpu } else { &self.device };
+        let pcm_len = pcm.len();
+        let pcm = candle::Tensor::from_vec(pcm, (1, 1, pcm_len), encodec_device)?;
+        let step_idx = state.step_idx();
+        state.prefill_voice(&mut encodec, &pcm)?;
+        tracing::info!(%file, steps = state.step_idx() - step_idx, "voice prompt");
+        Ok(())
+    }
+
+    // The number of steps of token history kept by the state, the older steps only live in the
+    // session log.
+    fn history_window(&self) -> usize {
+        let text_sampling = &self.session_config.text_sampling;
+        let context = text_sampling.repetition_penalty.map_or(0, |(context, _)| context);
+        usize::max(context, self.config.stream_layout.max_delay() + 1)
+    }
+
+    // Primes the state with the voice and text prompts of the session, returns the text token
+    // to use as input for the first step.
+    fn prefill(
+        &self,
+        state: &mut moshi::lm_generate_multistream::State,
+        log: &mut crate::session_log::TokenLog,
+    ) -> Result<u32> {
+        if let Some(file) = self.session_config.voice_prompt_file.as_ref() {
+            self.prefill_voice(state, file)?;
+        }
+        if let Some(text_prompt) = self.session_config.text_prompt.as_ref() {
+            let text_tokens = self
+                .state
+                .text_tokenizer
+                .encode(text_prompt)?
+                .into_iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>();
+            tracing::info!(steps = text_tokens.len(), "text prompt");
+            state.prefill_text(&text_tokens)?;
+        }
+        // From now on the state only keeps a window of the history, the steps have to be logged
+        // before leaving it.
+        log.write_complete(state)?;
+        state.set_max_history(Some(self.history_window()));
+        Ok(state.last_text_token())
+    }
+
+    fn max_steps_reached(&self, state: &moshi::lm_generate_multistream::State) -> bool {
+        self.session_config.max_steps.is_some_and(|max_steps| state.step_idx() >= max_steps)
+    }
+
     fn run_with_state(
         &self,
         state: &mut moshi::lm_generate_multistream::State,
+        log: &mut crate::session_log::TokenLog,
         receiver: std::sync::mpsc::Receiver<Vec<f32>>,
         sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
     ) -> Result<()> {
@@ -337,13 +576,15 @@ impl StreamingModel {
 
         encodec.reset_state();
         tracing::info!("processing loop");
-        let mut prev_text_token = config.text_start_token;
+        let mut prev_text_token = self.prefill(state, log)?;
+        let prompt_steps = state.step_idx();
         let mut tensor_tokens = vec![];
+        let out_codebooks = self.out_codebooks(&config);
         let encodec_device =
             if self.state.config.use_cpu_for_encodec { &candle::Device::Cpu } else { &self.device };
         encodec_device.synchronize()?;
         sender.send(StreamOut::Ready)?;
-        while let Ok(in_pcm) = receiver.recv() {
+        'outer: while let Ok(in_pcm) = receiver.recv() {
             if in_pcm.is_empty() {
                 continue;
             }
@@ -361,11 +602,14 @@ impl StreamingModel {
                 let codes = audio_tokens.i((0, .., step))?.to_vec1::<u32>()?;
                 sender.send(StreamOut::StepStart { step })?;
                 let text_token = state.step(prev_text_token, &codes, None)?;
+                log.write_complete(state)?;
                 sender.send(StreamOut::StepPostSampling { step })?;
-                if let Some(audio_tokens) = state.last_audio_tokens() {
+                if let Some(audio_tokens) =
+                    Self::out_audio_tokens(state, &out_codebooks, prompt_steps)
+                {
                     let audio_tokens = {
-                        let cb = app_state.config.encodec_num_codebooks;
-                        candle::Tensor::from_slice(&audio_tokens[..cb], (1, cb, 1), encodec_device)?
+                        let cb = audio_tokens.len();
+                        candle::Tensor::from_vec(audio_tokens, (1, cb, 1), encodec_device)?
                     };
                     tensor_tokens.push(audio_tokens.clone());
                     let pcm = encodec.decode_step(&audio_tokens.into())?;
@@ -379,6 +623,10 @@ impl StreamingModel {
                     sender.send(StreamOut::Text { text })?;
                 }
                 prev_text_token = text_token;
+                if self.max_steps_reached(state) {
+                    tracing::info!("max steps reached");
+                    break 'outer;
+                }
             }
         }
         tracing::info!("finished the processing loop");
@@ -388,6 +636,7 @@ impl StreamingModel {
     fn run_with_state_mt(
         &self,
         state: &mut moshi::lm_generate_multistream::State,
+        log: &mut crate::session_log::TokenLog,
         receiver: std::sync::mpsc::Receiver<Vec<f32>>,
         sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
     ) -> Result<()> {
@@ -400,8 +649,10 @@ impl StreamingModel {
 
         encodec.reset_state();
         tracing::info!("processing loop");
-        let mut prev_text_token = config.text_start_token;
+        let mut prev_text_token = self.prefill(state, log)?;
+        let prompt_steps = state.step_idx();
         let mut tensor_tokens = vec![];
+        let out_codebooks = self.out_codebooks(&config);
         let (tx_i, rx_i) = std::sync::mpsc::channel::<(Vec<u32>, usize)>();
         let (tx_o, rx_o) = std::sync::mpsc::channel::<Vec<u32>>();
         let sender = Arc::new(sender);
@@ -438,13 +689,13 @@ impl StreamingModel {
                 }
             });
             s.spawn({
-                let cb = app_state.config.encodec_num_codebooks;
                 let sender = sender.clone();
                 move || {
                     while let Ok(audio_tokens) = rx_o.recv() {
                         let audio_tokens = {
-                            candle::Tensor::from_slice(
-                                &audio_tokens[..cb],
+                            let cb = audio_tokens.len();
+                            candle::Tensor::from_vec(
+                                audio_tokens,
                                 (1, cb, 1),
                                 &candle::Device::Cpu,
                             )?
@@ -472,13 +723,22 @@ impl StreamingModel {
                     break;
                 }
                 let text_token = text_token?;
-                if let Some(audio_tokens) = state.last_audio_tokens() {
+                log.write_complete(state)?;
+                if let Some(audio_tokens) =
+                    Self::out_audio_tokens(state, &out_codebooks, prompt_steps)
+                {
                     tx_o.send(audio_tokens)?
                 }
                 if let Some(text) = app_state.text(prev_text_token, text_token, &config) {
                     sender.send(StreamOut::Text { text })?;
                 }
                 prev_text_token = text_token;
+                if self.max_steps_reached(state) {
+                    tracing::info!("max steps reached");
+                    drop(rx_i);
+                    drop(tx_o);
+                    break;
+                }
             }
             Ok::<_, anyhow::Error>(())
         });
@@ -494,7 +754,7 @@ impl StreamingModel {
             None => moshi::lm_generate_multistream::Config::v0_1(),
             Some(config) => config.clone(),
         };
-        let session_config = session_config.into_session_config();
+        let session_config = session_config.into_session_config(state.config.max_steps);
         Self { state: state.clone(), device: state.device.clone(), config, session_config }
     }
 
@@ -505,84 +765,76 @@ impl StreamingModel {
         addr: Option<String>,
     ) -> Result<()> {
         let app_state = &self.state;
+        let text_sampling = &self.session_config.text_sampling;
+        let audio_sampling = &self.session_config.audio_sampling;
         let (repetition_penalty_context, repetition_penalty) =
-            self.session_config.repetition_penalty.unwrap_or((32, 1.));
+            text_sampling.repetition_penalty.unwrap_or((32, 1.));
+        let pipeline_info = self.config.pipeline_stream_info(&app_state.encodec_model);
+        let sample_rate = app_state.encodec_model.config().sample_rate;
+        let algorithmic_latency_ms = pipeline_info.delay as f64 * 1000. / sample_rate;
+        tracing::info!(?pipeline_info, algorithmic_latency_ms, "pipeline latency");
         let metadata = MetaData {
-            text_temperature: self.session_config.text_temperature,
-            text_topk: self.session_config.text_topk,
-            audio_temperature: self.session_config.audio_temperature,
-            audio_topk: self.session_config.audio_topk,
+            text_temperature: text_sampling.temperature,
+            text_topk: text_sampling.top_k.unwrap_or(0),
+            audio_temperature: audio_sampling.temperature,
+            audio_topk: audio_sampling.top_k.unwrap_or(0),
             pad_mult: self.session_config.pad_mult.unwrap_or(0.),
             repetition_penalty,
             repetition_penalty_context,
+            text_sampling: text_sampling.clone(),
+            audio_sampling: audio_sampling.clone(),
+            audio_temperature_per_codebook: self
+                .session_config
+                .audio_temperature_per_codebook
+                .clone(),
+            audio_topk_per_codebook: self.session_config.audio_topk_per_codebook.clone(),
+            cfg_alpha: self.session_config.cfg_alpha,
             lm_model_file: self.state.config.lm_model_file.to_string(),
             encodec_model_file: self.state.config.encodec_model_file.to_string(),
             build_info: crate::utils::BuildInfo::new(),
             instance_name: self.state.config.instance_name.to_string(),
+            algorithmic_latency_ms,
         };
         sender.send(StreamOut::MetaData { metadata: Box::new(metadata) })?;
         let lm_model = app_state.lm_model.clone();
-        let audio_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
-            self.session_config.audio_seed,
-            candle_transformers::generation::Sampling::TopK {
-                k: self.session_config.audio_topk,
-                temperature: self.session_config.audio_temperature,
-            },
-        );
-        let text_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
-            self.session_config.text_seed,
-            candle_transformers::generation::Sampling::TopK {
-                k: self.session_config.text_topk,
-                temperature: self.session_config.text_temperature,
-            },
-        );
+        let num_codebooks = self.config.stream_layout.generated_codebooks().len();
+        let audio_lps = self.session_config.audio_samplers(num_codebooks)?;
+        let text_lp = {
+            let mut text_sampling = text_sampling.clone();
+            // Multiplies the pad probability by exp(pad_mult) whatever the temperature.
+            if let Some(pad_mult) = self.session_config.pad_mult {
+                let bias = (self.config.text_pad_token, pad_mult);
+                text_sampling.post_temperature_logit_bias.push(bias)
+            }
+            text_sampling.sampler(self.session_config.text_seed)
+        };
+        let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let (secs, us) = (since_epoch.as_secs(), since_epoch.subsec_micros());
+        let log_dir = &app_state.config.log_dir;
+        let base_path = format!("{log_dir}/{}-{secs}-{us}", app_state.config.instance_name);
+        let mut log =
+            crate::session_log::TokenLog::new(&base_path, self.config.total_audio_codebooks())?;
         let mut state = moshi::lm_generate_multistream::State::new(
             lm_model,
-            self.session_config.max_steps,
-            audio_lp,
+            audio_lps,
             text_lp,
-            self.session_config.pad_mult,
-            self.session_config.repetition_penalty,
             self.config.clone(),
-        );
+        )?;
+        state.set_cfg_alpha(self.session_config.cfg_alpha)?;
 
         // We want to log the output even if the run function returns an error.
         let run_result = if self.state.config.use_cpu_for_encodec {
-            self.run_with_state_mt(&mut state, receiver, sender)
+            self.run_with_state_mt(&mut state, &mut log, receiver, sender)
         } else {
-            self.run_with_state(&mut state, receiver, sender)
+            self.run_with_state(&mut state, &mut log, receiver, sender)
         };
         {
-            let text_tokens = state.text_tokens(false);
-            let transcript = {
-                let text_tokens = text_tokens
-                    .iter()
-                    .filter_map(|v| {
-                        let v = *v;
-                        if v != moshi::lm_generate_multistream::UNGENERATED
-                            && v != self.config.text_pad_token
-                            && v != self.config.text_eop_token
-                            && v != self.config.text_start_token
-                        {
-                            Some(v)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>();
-                self.state
-                    .text_tokenizer
-                    .decode_piece_ids(&text_tokens)
-                    .unwrap_or_else(|_| String::new())
-            };
-            let audio_tokens = state.audio_tokens(false);
-            let audio_tokens = audio_tokens.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
-            let text_tokens = candle::Tensor::new(text_tokens, &candle::Device::Cpu)?;
-            let audio_tokens = candle::Tensor::new(audio_tokens, &candle::Device::Cpu)?;
-            let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
-            let (secs, us) = (since_epoch.as_secs(), since_epoch.subsec_micros());
-            let log_dir = &app_state.config.log_dir;
-            let base_path = format!("{log_dir}/{}-{secs}-{us}", app_state.config.instance_name);
+            let transcript_tokens = log.finish(&state)?;
+            let transcript = self
+                .state
+                .text_tokenizer
+                .decode_piece_ids(&transcript_tokens)
+                .unwrap_or_else(|_| String::new());
             let json_filename = format!("{base_path}.json");
             let json_content = serde_json::to_string_pretty(&SessionSummary {
                 session_config: &self.session_config,
@@ -594,10 +846,6 @@ impl StreamingModel {
                 lm_config: &self.state.config.lm_config,
             })?;
             std::fs::write(json_filename, json_content)?;
-            let st_filename = format!("{base_path}.safetensors");
-            let st_content =
-                std::collections::HashMap::from([("text", text_tokens), ("audio", audio_tokens)]);
-            candle::safetensors::save(&st_content, st_filename)?;
         }
         run_result
     }
@@ -0,0 +1,77 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use anyhow::Result;
+use moshi::candle::{Device, Tensor};
+
+use crate::audio_io;
+
+// Audio gets encoded by chunks of one minute to bound memory usage, this is a multiple of the
+// 1920 samples mimi frame size.
+const CHUNK_LEN: usize = 1920 * 750;
+
+fn collect_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?
+        } else {
+            files.push(path)
+        }
+    }
+    Ok(())
+}
+
+pub fn run(mimi_model_file: &str, num_codebooks: usize, dir: &std::path::Path) -> Result<()> {
+    let dev = Device::Cpu;
+    let mut mimi = moshi::encodec::load(mimi_model_file, Some(num_codebooks), &dev)?;
+    let sample_rate = mimi.config().sample_rate as usize;
+    let mut files = vec![];
+    collect_files(dir, &mut files)?;
+    files.sort();
+
+    let mut stats = mimi.codebook_stats();
+    let mut n_files = 0;
+    for file in files.iter() {
+        let (pcm, sr) = match audio_io::pcm_decode(file) {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::warn!("skipping {file:?}: {err:?}");
+                continue;
+            }
+        };
+        let pcm = if sr as usize == sample_rate {
+            pcm
+        } else {
+            audio_io::resample(&pcm, sr as usize, sample_rate)?
+        };
+        let duration = pcm.len() as f64 / sample_rate as f64;
+        tracing::info!("encoding {file:?}, {duration:.1}s");
+        for chunk in pcm.chunks(CHUNK_LEN) {
+            let chunk = Tensor::new(chunk, &dev)?.reshape((1, 1, ()))?;
+            mimi.encode_with_stats(&chunk, &mut stats)?;
+        }
+        n_files += 1;
+    }
+    if stats.n_frames() == 0 {
+        anyhow::bail!("no audio could be decoded in {dir:?}")
+    }
+
+    let max_entropy = (stats.bins() as f64).log2();
+    println!("{n_files} files, {} frames, {} bins per codebook", stats.n_frames(), stats.bins());
+    println!("codebook  entropy  perplexity   used   dead  rel-err  snr-db");
+    for level in 0..stats.n_q() {
+        let dead = stats.dead_codes(level);
+        // The reconstruction error is for the first level + 1 codebooks combined.
+        let rel_err = stats.relative_error(level + 1);
+        println!(
+            "{level:8} {:5.2}/{max_entropy:.0} {:11.1} {:6} {dead:6} {rel_err:8.4} {:7.2}",
+            stats.entropy(level),
+            stats.perplexity(level),
+            stats.bins() - dead,
+            -10. * rel_err.log10(),
+        )
+    }
+    Ok(())
+}
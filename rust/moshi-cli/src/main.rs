@@ -6,7 +6,9 @@ use anyhow::Result;
 use clap::Parser;
 
 mod audio_io;
+mod codebook_stats;
 mod multistream;
+mod score;
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -34,6 +36,23 @@ enum Command {
         #[arg(long, default_value_t = 8998)]
         port: usize,
     },
+    /// Prints codebook utilisation statistics for the audio files in a directory.
+    CodebookStats {
+        #[arg(long)]
+        mimi_model_file: String,
+
+        #[arg(long, default_value_t = 8)]
+        num_codebooks: usize,
+
+        dir: std::path::PathBuf,
+    },
+    /// Prints the log-likelihood of the session logs written by the backend in a directory.
+    Score {
+        #[arg(long)]
+        lm_model_file: String,
+
+        dir: std::path::PathBuf,
+    },
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -58,6 +77,14 @@ async fn main() -> Result<()> {
             tracing_subscriber::fmt::init();
             multistream::client_tui::run(host, port).await?
         }
+        Command::CodebookStats { mimi_model_file, num_codebooks, dir } => {
+            tracing_subscriber::fmt::init();
+            codebook_stats::run(&mimi_model_file, num_codebooks, &dir)?
+        }
+        Command::Score { lm_model_file, dir } => {
+            tracing_subscriber::fmt::init();
+            score::run(&lm_model_file, &dir)?
+        }
     }
     Ok(())
 }
@@ -0,0 +1,123 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use anyhow::Result;
+use moshi::candle::{DType, Device};
+use moshi::lm_generate_multistream::Config;
+
+// Sum and count of negative log-likelihoods.
+#[derive(Debug, Default, Clone, Copy)]
+struct Nll {
+    sum: f64,
+    count: usize,
+}
+
+impl Nll {
+    fn add(&mut self, log_prob: f32) {
+        self.sum -= log_prob as f64;
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / usize::max(self.count, 1) as f64
+    }
+}
+
+// The session logs written by the backend include the multistream config in the json summary
+// next to the token file, older logs that do not have it use the v0.1 config.
+fn session_config(st_file: &std::path::Path) -> Config {
+    let json_file = st_file.with_extension("json");
+    let config = std::fs::read_to_string(&json_file)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(serde_json::from_str(&json)?));
+    match config {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::warn!("using the default config for {st_file:?}: {err:?}");
+            Config::v0_1()
+        }
+    }
+}
+
+fn score_file(
+    lm: &mut moshi::lm::LmModel,
+    file: &std::path::Path,
+    dev: &Device,
+) -> Result<(usize, Nll, Nll)> {
+    let tensors = moshi::candle::safetensors::load(file, dev)?;
+    let get = |name: &str| match tensors.get(name) {
+        None => anyhow::bail!("no {name} tensor"),
+        Some(t) => Ok(t),
+    };
+    let text_tokens = get("text")?.to_vec1::<u32>()?;
+    let audio_tokens = get("audio")?.to_vec2::<u32>()?;
+    let config = session_config(file);
+    let scores = lm.score(&text_tokens, &audio_tokens, &config)?;
+    let mut text = Nll::default();
+    let mut audio = Nll::default();
+    for &log_prob in scores.text.iter() {
+        text.add(log_prob)
+    }
+    for &log_prob in scores.audio.iter().flatten().flatten() {
+        audio.add(log_prob)
+    }
+    Ok((text_tokens.len(), text, audio))
+}
+
+pub fn run(lm_model_file: &str, dir: &std::path::Path) -> Result<()> {
+    let dev = Device::Cpu;
+    let mut lm = moshi::lm::load_streaming(lm_model_file, DType::F32, &dev)?;
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|v| v == "safetensors") {
+            files.push(path)
+        }
+    }
+    files.sort();
+
+    let mut total_text = Nll::default();
+    let mut total_audio = Nll::default();
+    let mut n_files = 0;
+    println!(
+        "{:>6} {:>9} {:>9} {:>9} {:>9}  file",
+        "steps", "text-nll", "text-ppl", "audio-nll", "audio-ppl"
+    );
+    for file in files.iter() {
+        let (steps, text, audio) = match score_file(&mut lm, file, &dev) {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::warn!("skipping {file:?}: {err:?}");
+                continue;
+            }
+        };
+        println!(
+            "{steps:6} {:9.3} {:9.2} {:9.3} {:9.2}  {}",
+            text.mean(),
+            text.mean().exp(),
+            audio.mean(),
+            audio.mean().exp(),
+            file.display()
+        );
+        total_text.merge(&text);
+        total_audio.merge(&audio);
+        n_files += 1;
+    }
+    if n_files == 0 {
+        anyhow::bail!("no session logs could be scored in {dir:?}")
+    }
+    println!(
+        "{n_files} files, text nll {:.3} ppl {:.2}, audio nll {:.3} ppl {:.2}",
+        total_text.mean(),
+        total_text.mean().exp(),
+        total_audio.mean(),
+        total_audio.mean().exp(),
+    );
+    Ok(())
+}
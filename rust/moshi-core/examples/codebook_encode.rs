@@ -0,0 +1,94 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Benchmarks `EuclideanCodebook::encode` for the codebook sizes used by mimi. The reference is
+// the previous scalar kernel which only supported f32, so half precision inputs have to be
+// upcast before using it. The matmul based `encode_slow` is also reported when available.
+//
+// cargo run --release --example codebook_encode
+
+use moshi::candle::{DType, Device, Result, Tensor};
+use moshi::quantization::EuclideanCodebook;
+
+const DIM: usize = 256;
+const CODEBOOK_SIZE: usize = 2048;
+
+fn scalar_encode(xs: &Tensor, embedding: &Tensor) -> Result<Tensor> {
+    use rayon::prelude::*;
+
+    let xs = xs.to_dtype(DType::F32)?;
+    let lhs = xs.flatten_all()?.to_vec1::<f32>()?;
+    let rhs = embedding.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+    let codes: Vec<u32> = lhs
+        .par_chunks(DIM)
+        .map(|lhs| {
+            let mut where_min = 0;
+            let mut min_dist = f32::INFINITY;
+            for (idx2, rhs) in rhs.chunks(DIM).enumerate() {
+                let mut dist = 0f32;
+                for (a, b) in lhs.iter().zip(rhs.iter()) {
+                    dist += (a - b) * (a - b)
+                }
+                if dist < min_dist {
+                    min_dist = dist;
+                    where_min = idx2;
+                }
+            }
+            where_min as u32
+        })
+        .collect();
+    let n = codes.len();
+    Tensor::from_vec(codes, n, xs.device())
+}
+
+fn time_ms<F: FnMut() -> Result<Tensor>>(iters: usize, mut f: F) -> Result<f64> {
+    // Warmup.
+    f()?;
+    let start = std::time::Instant::now();
+    for _ in 0..iters {
+        f()?;
+    }
+    Ok(start.elapsed().as_secs_f64() * 1000. / iters as f64)
+}
+
+fn main() -> Result<()> {
+    let dev = Device::Cpu;
+    println!(
+        "avx: {}, neon: {}, threads: {}",
+        moshi::candle::utils::with_avx(),
+        moshi::candle::utils::with_neon(),
+        rayon::current_num_threads(),
+    );
+    let embedding_sum = Tensor::randn(0f32, 1., (CODEBOOK_SIZE, DIM), &dev)?;
+    for dtype in [DType::F32, DType::F16, DType::BF16] {
+        let embedding = embedding_sum.to_dtype(dtype)?;
+        let ws = std::collections::HashMap::from([
+            ("_initialized".to_string(), Tensor::ones(1, dtype, &dev)?),
+            ("cluster_usage".to_string(), Tensor::ones(CODEBOOK_SIZE, dtype, &dev)?),
+            ("embedding_sum".to_string(), embedding.clone()),
+        ]);
+        let vb = moshi::candle_nn::VarBuilder::from_tensors(ws, dtype, &dev);
+        let cb = EuclideanCodebook::new(DIM, CODEBOOK_SIZE, vb)?;
+        // A single frame as used when streaming, a small batch, and a minute of audio at 25Hz.
+        for rows in [1, 8, 1500] {
+            let xs = Tensor::randn(0f32, 1., (rows, DIM), &dev)?.to_dtype(dtype)?;
+            let iters = usize::max(1, 3000 / rows);
+            let encode = time_ms(iters, || cb.encode(&xs))?;
+            let scalar = time_ms(iters, || scalar_encode(&xs, &embedding))?;
+            let codes = cb.encode(&xs)?.to_vec1::<u32>()?;
+            let ref_codes = scalar_encode(&xs, &embedding)?.to_vec1::<u32>()?;
+            let matching = codes.iter().zip(ref_codes.iter()).filter(|(a, b)| a == b).count();
+            // The matmul based version is not available for all dtypes on cpu, e.g. bf16.
+            let slow = match cb.encode_slow(&xs) {
+                Err(_) => "n/a".to_string(),
+                Ok(_) => format!("{:.3}ms", time_ms(iters, || cb.encode_slow(&xs))?),
+            };
+            println!(
+                "{dtype:?} rows {rows:4}: encode {encode:8.3}ms scalar {scalar:8.3}ms ({:.2}x) encode_slow {slow}, {matching}/{rows} matching codes",
+                scalar / encode,
+            )
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Reading model configurations from the weight files or from a `config.json` file.
+//!
+//! The configuration for a weight file is looked up in the following order:
+//! - a `config` entry in the file metadata holding the configuration as json, this uses the
+//!   `__metadata__` header for safetensors files and a string value for gguf files.
+//! - a `config.json` file in the same directory as the weights. As this file can be shared by
+//!   multiple models, the configuration is read from the section named after the model kind,
+//!   e.g. `lm` or `mimi`, if present. Otherwise the whole file is used when it deserializes to
+//!   the expected configuration.
+
+use candle::Result;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+pub const METADATA_KEY: &str = "config";
+pub const CONFIG_FILE: &str = "config.json";
+
+fn from_json<C: DeserializeOwned>(json: &str, path: &Path) -> Result<C> {
+    serde_json::from_str(json).map_err(|e| candle::Error::Msg(format!("{path:?}: {e}")))
+}
+
+// Returns the metadata of a safetensors file, only the header gets read.
+fn safetensors_metadata(path: &Path) -> Result<Option<serde_json::Value>> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+    // Headers are limited to 100MB by the safetensors format.
+    if len > 100_000_000 {
+        candle::bail!("{path:?}: invalid safetensors header length {len}")
+    }
+    let mut header = vec![0u8; len];
+    file.read_exact(&mut header)?;
+    let mut header: serde_json::Value = serde_json::from_slice(&header)
+        .map_err(|e| candle::Error::Msg(format!("{path:?}: {e}")))?;
+    Ok(header.get_mut("__metadata__").map(|v| v.take()))
+}
+
+fn metadata_config(path: &Path) -> Result<Option<String>> {
+    let is_gguf = path.extension().is_some_and(|v| v == "gguf");
+    if is_gguf {
+        let mut file = std::fs::File::open(path)?;
+        let content = candle::quantized::gguf_file::Content::read(&mut file)
+            .map_err(|e| e.with_path(path))?;
+        match content.metadata.get(METADATA_KEY) {
+            None => Ok(None),
+            Some(v) => Ok(Some(v.to_string()?.clone())),
+        }
+    } else {
+        let metadata = match safetensors_metadata(path)? {
+            None => return Ok(None),
+            Some(metadata) => metadata,
+        };
+        match metadata.get(METADATA_KEY) {
+            None => Ok(None),
+            Some(serde_json::Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => candle::bail!("{path:?}: the {METADATA_KEY} metadata is not a string"),
+        }
+    }
+}
+
+/// Reads the configuration associated with `model_file`, `section` is the name of the section
+/// to use in a shared `config.json` file. Returns `None` if no configuration is available.
+pub fn load_config<C: DeserializeOwned, P: AsRef<Path>>(
+    model_file: P,
+    section: &str,
+) -> Result<Option<C>> {
+    let model_file = model_file.as_ref();
+    if let Some(json) = metadata_config(model_file)? {
+        return Ok(Some(from_json(&json, model_file)?));
+    }
+    let config_file = match model_file.parent() {
+        None => return Ok(None),
+        Some(dir) => dir.join(CONFIG_FILE),
+    };
+    if !config_file.exists() {
+        return Ok(None);
+    }
+    let json = std::fs::read_to_string(&config_file)?;
+    let mut config: serde_json::Value = from_json(&json, &config_file)?;
+    match config.get_mut(section) {
+        Some(v) => {
+            let config = serde_json::from_value(v.take())
+                .map_err(|e| candle::Error::Msg(format!("{config_file:?} {section}: {e}")))?;
+            Ok(Some(config))
+        }
+        // The file may hold the configuration of another kind of model, in which case the
+        // presets are used.
+        None => match serde_json::from_value(config) {
+            Ok(config) => Ok(Some(config)),
+            Err(e) => {
+                tracing::warn!("ignoring {config_file:?} for {section}: {e}");
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// Serde mirror of `candle_nn::Activation` which only implements `Deserialize`, fields use it via
+/// `#[serde(with = "crate::config::Activation")]`.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(remote = "candle_nn::Activation", rename_all = "lowercase")]
+pub(crate) enum Activation {
+    #[serde(alias = "gelu")]
+    Gelu,
+    #[serde(alias = "gelu_new")]
+    NewGelu,
+    Relu,
+    Relu2,
+    Relu6,
+    Silu,
+    Sigmoid,
+    HardSigmoid,
+    Swiglu,
+    Swish,
+    HardSwish,
+    Elu(f64),
+    LeakyRelu(f64),
+    #[serde(alias = "gelu_pytorch_tanh")]
+    GeluPytorchTanh,
+}
+
+/// Same as `Activation` for optional fields.
+pub(crate) mod option_activation {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper(#[serde(with = "super::Activation")] candle_nn::Activation);
+
+    pub fn serialize<S: Serializer>(
+        v: &Option<candle_nn::Activation>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        v.map(Wrapper).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<candle_nn::Activation>, D::Error> {
+        let v = Option::<Wrapper>::deserialize(deserializer)?;
+        Ok(v.map(|Wrapper(v)| v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encodec, lm};
+
+    // A fresh directory for each test, the content from previous runs is removed.
+    fn test_dir(name: &str) -> Result<std::path::PathBuf> {
+        let dir = std::env::temp_dir().join(format!("moshi-config-{}-{name}", std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn write_safetensors(path: &Path, config: Option<String>) -> Result<()> {
+        let metadata = config.map(|c| [(METADATA_KEY.to_string(), c)].into_iter().collect());
+        let t = candle::Tensor::zeros(2, candle::DType::F32, &candle::Device::Cpu)?;
+        let data = safetensors::tensor::serialize([("w", &t)], &metadata)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    fn lm_config(text_in_vocab_size: usize) -> lm::Config {
+        lm::Config { text_in_vocab_size, ..lm::Config::v0_1_streaming(8) }
+    }
+
+    fn to_json<C: serde::Serialize>(c: &C) -> String {
+        serde_json::to_string(c).unwrap()
+    }
+
+    #[test]
+    fn presets_round_trip() -> Result<()> {
+        fn round_trip<C: serde::Serialize + DeserializeOwned>(c: &C) -> Result<()> {
+            let json = to_json(c);
+            let c: C = from_json(&json, Path::new("test"))?;
+            assert_eq!(to_json(&c), json);
+            Ok(())
+        }
+        round_trip(&lm::Config::v0_1())?;
+        round_trip(&lm::Config::v0_1_streaming(8))?;
+        round_trip(&lm::Config::tts_v0_1())?;
+        round_trip(&encodec::Config::v0_1(None))?;
+        round_trip(&encodec::Config::v0_1(Some(8)))?;
+        // Optional activations can be omitted.
+        let mut json: serde_json::Value =
+            from_json(&to_json(&lm::Config::v0_1()), Path::new("lm"))?;
+        json["transformer"].as_object_mut().unwrap().remove("gating");
+        let cfg: lm::Config = from_json(&json.to_string(), Path::new("lm"))?;
+        assert!(cfg.transformer.gating.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_takes_precedence() -> Result<()> {
+        let dir = test_dir("metadata")?;
+        let model_file = dir.join("model.safetensors");
+        let config_json = format!("{{\"lm\": {}}}", to_json(&lm_config(456)));
+        std::fs::write(dir.join(CONFIG_FILE), config_json)?;
+
+        write_safetensors(&model_file, Some(to_json(&lm_config(123))))?;
+        let cfg = lm::Config::from_model_file(&model_file)?.unwrap();
+        assert_eq!(cfg.text_in_vocab_size, 123);
+
+        write_safetensors(&model_file, None)?;
+        let cfg = lm::Config::from_model_file(&model_file)?.unwrap();
+        assert_eq!(cfg.text_in_vocab_size, 456);
+
+        std::fs::remove_file(dir.join(CONFIG_FILE))?;
+        assert!(lm::Config::from_model_file(&model_file)?.is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn config_file_sections() -> Result<()> {
+        let dir = test_dir("sections")?;
+        let model_file = dir.join("model.safetensors");
+        write_safetensors(&model_file, None)?;
+
+        let mimi = encodec::Config::v0_1(Some(8));
+        let config_json =
+            format!("{{\"lm\": {}, \"mimi\": {}}}", to_json(&lm_config(7)), to_json(&mimi));
+        std::fs::write(dir.join(CONFIG_FILE), config_json)?;
+        let cfg = lm::Config::from_model_file(&model_file)?.unwrap();
+        assert_eq!(cfg.text_in_vocab_size, 7);
+        let cfg = encodec::Config::from_model_file(&model_file)?.unwrap();
+        assert_eq!(cfg.quantizer_n_q, 8);
+
+        // Without sections, the whole file is used if it matches the expected configuration.
+        std::fs::write(dir.join(CONFIG_FILE), to_json(&lm_config(9)))?;
+        let cfg = lm::Config::from_model_file(&model_file)?.unwrap();
+        assert_eq!(cfg.text_in_vocab_size, 9);
+        assert!(encodec::Config::from_model_file(&model_file)?.is_none());
+
+        // An invalid section is an error rather than being ignored.
+        std::fs::write(dir.join(CONFIG_FILE), "{\"lm\": {\"d_model\": 1}}")?;
+        assert!(lm::Config::from_model_file(&model_file).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -2,19 +2,21 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::streaming::{StreamTensor, StreamingModule};
+use crate::streaming::{self, state_key, StateDict, StreamInfo, StreamTensor, StreamingModule};
 use candle::{Module, Result, Tensor, D};
 use candle_nn::{Conv1d, VarBuilder};
 
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Norm {
     WeightNorm,
     SpectralNorm,
     TimeGroupNorm,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PadMode {
     Constant,
     Reflect,
@@ -44,6 +46,31 @@ fn conv1d_weight_norm(
     Ok(Conv1d::new(weight, bias, config))
 }
 
+// Applies spectral norm for inference using the singular vectors estimated during training, no
+// power iteration is performed as this would only happen in training mode.
+// https://pytorch.org/docs/stable/generated/torch.nn.utils.spectral_norm.html
+// `dim` is the output channel dimension, i.e. 0 for convolutions and 1 for transposed ones.
+fn spectral_norm_weight<S: Into<candle::Shape>>(
+    shape: S,
+    dim: usize,
+    vb: &VarBuilder,
+) -> Result<Tensor> {
+    let shape = shape.into();
+    if vb.contains_tensor("weight") {
+        return vb.get(shape, "weight");
+    }
+    let weight = vb.get(shape.clone(), "weight_orig")?;
+    let h = shape.dims()[dim];
+    let w = shape.elem_count() / h;
+    let u = vb.get(h, "weight_u")?;
+    let v = vb.get(w, "weight_v")?;
+    let weight_mat = if dim == 0 { weight.clone() } else { weight.transpose(0, dim)? };
+    let weight_mat = weight_mat.reshape((h, w))?;
+    // sigma = u^T W v
+    let sigma = u.unsqueeze(0)?.matmul(&weight_mat.matmul(&v.unsqueeze(1)?)?)?.reshape(())?;
+    weight.broadcast_div(&sigma)
+}
+
 #[derive(Debug, Clone)]
 pub struct NormConv1d {
     conv: Conv1d,
@@ -74,7 +101,12 @@ impl NormConv1d {
             Some(Norm::WeightNorm) => {
                 conv1d_weight_norm(in_c, out_c, k_size, bias, cfg, vb.pp("conv"))?
             }
-            Some(Norm::SpectralNorm) => candle::bail!("SpectralNorm is not supported yet."),
+            Some(Norm::SpectralNorm) => {
+                let vb = vb.pp("conv");
+                let weight = spectral_norm_weight((out_c, in_c / cfg.groups, k_size), 0, &vb)?;
+                let bias = if bias { Some(vb.get(out_c, "bias")?) } else { None };
+                Conv1d::new(weight, bias, cfg)
+            }
         };
         let norm = match norm {
             None | Some(Norm::WeightNorm) | Some(Norm::SpectralNorm) => None,
@@ -139,7 +171,9 @@ impl NormConvTranspose1d {
                     weight_v.broadcast_mul(&weight_g)?.broadcast_div(&norm_v)?
                 }
             }
-            Some(Norm::SpectralNorm) => candle::bail!("SpectralNorm is not supported yet."),
+            Some(Norm::SpectralNorm) => {
+                spectral_norm_weight((in_c, out_c / groups, k_size), 1, &vb)?
+            }
         };
         let (ws, groups) = if groups == out_c && in_c == out_c {
             let eye = Tensor::eye(out_c, ws.dtype(), ws.device())?;
@@ -207,14 +241,38 @@ fn get_extra_padding_for_conv1d(
     Ok(ideal_len.saturating_sub(len))
 }
 
+// Reflect padding, similar to the pytorch version the input gets extended with zeros on the right
+// before being reflected when it is too short for the padding.
+fn pad1d_reflect(xs: &Tensor, pad_l: usize, pad_r: usize) -> Result<Tensor> {
+    let len = xs.dim(D::Minus1)?;
+    let max_pad = usize::max(pad_l, pad_r);
+    let extra_pad = if len <= max_pad { max_pad - len + 1 } else { 0 };
+    let len_ext = len + extra_pad;
+    let xs = xs.pad_with_zeros(D::Minus1, 0, extra_pad)?.contiguous()?;
+    let mut indexes: Vec<u32> = (1..=pad_l as u32).rev().collect();
+    indexes.extend(0..len_ext as u32);
+    indexes.extend((0..pad_r).map(|i| (len_ext - 2 - i) as u32));
+    let indexes = Tensor::new(indexes, xs.device())?;
+    // The extra padding is removed from the end of the padded tensor.
+    xs.index_select(&indexes, D::Minus1)?.narrow(D::Minus1, 0, pad_l + len + pad_r)
+}
+
 fn pad1d(xs: &Tensor, pad_l: usize, pad_r: usize, mode: PadMode) -> Result<Tensor> {
     match mode {
         PadMode::Constant => xs.pad_with_zeros(D::Minus1, pad_l, pad_r),
-        PadMode::Reflect => candle::bail!("pad-mode 'reflect' is not supported"),
+        PadMode::Reflect => pad1d_reflect(xs, pad_l, pad_r),
         PadMode::Replicate => xs.pad_with_same(D::Minus1, pad_l, pad_r),
     }
 }
 
+// Removes up to `left_trim` samples at the beginning of `xs`, `left_trim` is decreased by the
+// number of samples that have been removed.
+fn trim_left(xs: StreamTensor, left_trim: &mut usize) -> Result<StreamTensor> {
+    let trim = usize::min(*left_trim, xs.seq_len(D::Minus1)?);
+    *left_trim -= trim;
+    Ok(xs.split(D::Minus1, trim)?.1)
+}
+
 fn unpad1d(xs: &Tensor, unpad_l: usize, unpad_r: usize) -> Result<Tensor> {
     let len = xs.dim(D::Minus1)?;
     if len < unpad_l + unpad_r {
@@ -230,11 +288,26 @@ pub struct StreamableConv1d {
     pad_mode: PadMode,
     state_prev_xs: StreamTensor,
     left_pad_applied: bool,
+    // The last input samples, only used to compute the right padding with PadMode::Reflect.
+    state_tail: StreamTensor,
     kernel_size: usize,
     span: tracing::Span,
 }
 
 impl StreamableConv1d {
+    // Returns the effective kernel size with dilations and the left and right padding.
+    fn padding(&self) -> (usize, usize, usize) {
+        let conv_cfg = self.conv.conv.config();
+        let k_size = (self.kernel_size - 1) * conv_cfg.dilation + 1;
+        let padding_total = k_size - conv_cfg.stride;
+        if self.causal {
+            (k_size, padding_total, 0)
+        } else {
+            let padding_right = padding_total / 2;
+            (k_size, padding_total - padding_right, padding_right)
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         in_c: usize,
@@ -260,6 +333,7 @@ impl StreamableConv1d {
             pad_mode,
             state_prev_xs: StreamTensor::empty(),
             left_pad_applied: false,
+            state_tail: StreamTensor::empty(),
             kernel_size: k_size,
             span: tracing::span!(tracing::Level::TRACE, "streamable-conv1d"),
         })
@@ -291,6 +365,7 @@ impl Module for StreamableConv1d {
 impl StreamingModule for StreamableConv1d {
     fn reset_state(&mut self) {
         self.state_prev_xs.reset();
+        self.state_tail.reset();
         self.left_pad_applied = false;
     }
 
@@ -300,20 +375,36 @@ impl StreamingModule for StreamableConv1d {
             None => return Ok(().into()),
             Some(xs) => xs.clone(),
         };
+        if self.conv.norm.is_some() {
+            candle::bail!("GroupNorm normalizes over the whole sequence and cannot be streamed.")
+        }
+        let (kernel, padding_left, padding_right) = self.padding();
+        let stride = self.conv.conv.config().stride;
+        if self.pad_mode == PadMode::Reflect {
+            // Enough samples are kept to reflect the largest right padding used in flush.
+            let tail_len = usize::max(padding_left, padding_right + stride) + 1;
+            let tail = StreamTensor::cat2(&self.state_tail, &xs.clone().into(), D::Minus1)?;
+            let len = tail.seq_len(D::Minus1)?;
+            self.state_tail = tail.narrow(D::Minus1, len.saturating_sub(tail_len), tail_len)?;
+        }
         let xs = if self.left_pad_applied {
             xs
         } else {
+            // Reflect padding requires padding_left + 1 samples, these are buffered as is until
+            // enough of them have been received.
+            let xs = StreamTensor::cat2(&self.state_prev_xs, &xs.into(), D::Minus1)?;
+            let xs = match xs.as_option() {
+                Some(xs) => xs.clone(),
+                None => candle::bail!("internal error, empty input buffer"),
+            };
+            if self.pad_mode == PadMode::Reflect && xs.dim(D::Minus1)? <= padding_left {
+                self.state_prev_xs = xs.into();
+                return Ok(StreamTensor::empty());
+            }
+            self.state_prev_xs.reset();
             self.left_pad_applied = true;
-            let k_size = self.conv.conv.weight().dim(D::Minus1)?;
-            let conv_cfg = self.conv.conv.config();
-            let k_size = (k_size - 1) * conv_cfg.dilation + 1;
-            let padding_total = k_size - conv_cfg.stride;
-            pad1d(&xs, padding_total, 0, self.pad_mode)?
+            pad1d(&xs, padding_left, 0, self.pad_mode)?
         };
-        let cfg = self.conv.conv.config();
-        let stride = cfg.stride;
-        let dilation = cfg.dilation;
-        let kernel = (self.kernel_size - 1) * dilation + 1;
         let xs = StreamTensor::cat2(&self.state_prev_xs, &xs.into(), D::Minus1)?;
         let seq_len = xs.seq_len(D::Minus1)?;
         let num_frames = (seq_len + stride).saturating_sub(kernel) / stride;
@@ -330,6 +421,69 @@ impl StreamingModule for StreamableConv1d {
             Ok(StreamTensor::empty())
         }
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        let _enter = self.span.enter();
+        let ys = match self.state_prev_xs.as_option() {
+            None => StreamTensor::empty(),
+            // The left padding has not been applied yet so the buffer contains the whole input.
+            Some(xs) if !self.left_pad_applied => StreamTensor::from_tensor(self.forward(xs)?),
+            Some(xs) => {
+                let stride = self.conv.conv.config().stride;
+                let (kernel, padding_left, padding_right) = self.padding();
+                let seq_len = xs.dim(D::Minus1)?;
+                // The buffered samples start on a frame boundary, they get padded on the right
+                // to the smallest length that only contains full frames in the same way as
+                // get_extra_padding_for_conv1d does for the full sequence.
+                let len = seq_len + padding_right;
+                let target_len = len + (kernel % stride + stride - len % stride) % stride;
+                if target_len >= kernel {
+                    let pad_r = target_len - seq_len;
+                    let xs = match (self.pad_mode, self.state_tail.as_option()) {
+                        (PadMode::Reflect, Some(tail)) => {
+                            let tail_len = tail.dim(D::Minus1)?;
+                            let pad = pad1d_reflect(tail, padding_left, pad_r)?;
+                            let pad = pad.narrow(D::Minus1, padding_left + tail_len, pad_r)?;
+                            Tensor::cat(&[xs, &pad], D::Minus1)?
+                        }
+                        _ => pad1d(xs, 0, pad_r, self.pad_mode)?,
+                    };
+                    StreamTensor::from_tensor(xs.apply(&self.conv.conv)?)
+                } else {
+                    StreamTensor::empty()
+                }
+            }
+        };
+        self.state_prev_xs.reset();
+        self.state_tail.reset();
+        self.left_pad_applied = false;
+        Ok(ys)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.state_prev_xs.save_state(&state_key(prefix, "prev_xs"), state);
+        self.state_tail.save_state(&state_key(prefix, "tail"), state);
+        let left_pad_applied = self.left_pad_applied as usize;
+        streaming::save_usize(&state_key(prefix, "left_pad_applied"), left_pad_applied, state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.state_prev_xs = StreamTensor::load_state(&state_key(prefix, "prev_xs"), state);
+        self.state_tail = StreamTensor::load_state(&state_key(prefix, "tail"), state);
+        let left_pad_applied =
+            streaming::load_usize(&state_key(prefix, "left_pad_applied"), state)?;
+        self.left_pad_applied = left_pad_applied.unwrap_or(0) != 0;
+        Ok(())
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        // The left padding is applied on the first step so each output frame is produced as soon
+        // as the last `stride` samples that it depends on have been received, non-causal convs
+        // also have to wait for the samples used in the right padding.
+        let stride = self.conv.conv.config().stride;
+        let (_kernel, _padding_left, padding_right) = self.padding();
+        StreamInfo { stride_in: stride, stride_out: 1, delay: stride + padding_right }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -337,6 +491,8 @@ pub struct StreamableConvTranspose1d {
     convtr: NormConvTranspose1d,
     causal: bool,
     state_prev_ys: StreamTensor,
+    // Number of output samples that still have to be trimmed at the beginning of the stream.
+    state_left_trim: usize,
     kernel_size: usize,
     span: tracing::Span,
 }
@@ -365,37 +521,44 @@ impl StreamableConvTranspose1d {
             groups,
             vb.pp("convtr"),
         )?;
-        Ok(Self {
+        let mut s = Self {
             convtr,
             causal,
             kernel_size: k_size,
             state_prev_ys: StreamTensor::empty(),
+            state_left_trim: 0,
             span: tracing::span!(tracing::Level::TRACE, "streamable-conv-tr1d"),
-        })
+        };
+        s.state_left_trim = s.padding().0;
+        Ok(s)
+    }
+
+    // Returns the number of samples to trim on the left and on the right of the output.
+    fn padding(&self) -> (usize, usize) {
+        let padding_total = self.convtr.k_size.saturating_sub(self.convtr.stride);
+        if self.causal {
+            (0, padding_total)
+        } else {
+            let padding_right = padding_total / 2;
+            (padding_total - padding_right, padding_right)
+        }
     }
 }
 
 impl Module for StreamableConvTranspose1d {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let k_size = self.convtr.k_size;
-        let stride = self.convtr.stride;
-        let padding_total = k_size.saturating_sub(stride);
         let xs = xs.apply(&self.convtr)?;
-        if self.causal {
-            // This corresponds to trim_right_ratio = 1.
-            unpad1d(&xs, 0, padding_total)
-        } else {
-            let padding_right = padding_total / 2;
-            let padding_left = padding_total - padding_right;
-            unpad1d(&xs, padding_left, padding_right)
-        }
+        // For causal convs, this corresponds to trim_right_ratio = 1.
+        let (padding_left, padding_right) = self.padding();
+        unpad1d(&xs, padding_left, padding_right)
     }
 }
 
 impl StreamingModule for StreamableConvTranspose1d {
     fn reset_state(&mut self) {
-        self.state_prev_ys.reset()
+        self.state_prev_ys.reset();
+        self.state_left_trim = self.padding().0;
     }
 
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
@@ -404,6 +567,9 @@ impl StreamingModule for StreamableConvTranspose1d {
             Some(xs) => xs,
             None => return Ok(StreamTensor::empty()),
         };
+        if self.convtr.norm.is_some() {
+            candle::bail!("GroupNorm normalizes over the whole sequence and cannot be streamed.")
+        }
         let stride = self.convtr.stride;
         // We apply the underlying convtr directly rather than through forward so as
         // not to apply any padding here.
@@ -429,16 +595,80 @@ impl StreamingModule for StreamableConvTranspose1d {
         let invalid_steps = self.kernel_size - stride;
         let (ys, prev_ys) = StreamTensor::from(ys).split(D::Minus1, ot - invalid_steps)?;
         self.state_prev_ys = prev_ys;
+        trim_left(ys, &mut self.state_left_trim)
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        // The buffered outputs that are not part of the right padding trimmed in `forward` are
+        // final as no more input is coming. For causal convs, this is all of them.
+        let (_padding_left, padding_right) = self.padding();
+        let len = self.state_prev_ys.seq_len(D::Minus1)?;
+        let (ys, _) = self.state_prev_ys.split(D::Minus1, len.saturating_sub(padding_right))?;
+        let ys = trim_left(ys, &mut self.state_left_trim)?;
+        self.reset_state();
         Ok(ys)
     }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.state_prev_ys.save_state(&state_key(prefix, "prev_ys"), state);
+        streaming::save_usize(&state_key(prefix, "left_trim"), self.state_left_trim, state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.state_prev_ys = StreamTensor::load_state(&state_key(prefix, "prev_ys"), state);
+        let left_trim = streaming::load_usize(&state_key(prefix, "left_trim"), state)?;
+        self.state_left_trim = left_trim.unwrap_or_else(|| self.padding().0);
+        Ok(())
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        // The first output samples are trimmed for non-causal convs, this delays the output.
+        let stride = self.convtr.stride;
+        let (padding_left, _padding_right) = self.padding();
+        StreamInfo { stride_in: 1, stride_out: stride, delay: padding_left / stride + 1 }
+    }
+}
+
+// Builds a var-builder holding a single fixed weight, this is used by the non-learnt resampling
+// layers so that they can rely on the same convolutions as the learnt ones.
+fn fixed_weight_vb(name: &str, weight: Tensor) -> VarBuilder<'static> {
+    let (dtype, device) = (weight.dtype(), weight.device().clone());
+    let ws = std::collections::HashMap::from([(name.to_string(), weight)]);
+    VarBuilder::from_tensors(ws, dtype, &device)
+}
+
+// (b, c, t) -> (b * c, 1, t), the non-learnt resampling layers process each channel separately.
+fn fold_channels(xs: &StreamTensor) -> Result<StreamTensor> {
+    match xs.as_option() {
+        None => Ok(StreamTensor::empty()),
+        Some(xs) => {
+            let (b, c, t) = xs.dims3()?;
+            Ok(StreamTensor::from_tensor(xs.reshape((b * c, 1, t))?))
+        }
+    }
+}
+
+// (b * c, 1, t) -> (b, c, t)
+fn unfold_channels(xs: &StreamTensor, dim: usize) -> Result<StreamTensor> {
+    match xs.as_option() {
+        None => Ok(StreamTensor::empty()),
+        Some(xs) => {
+            let (bc, _, t) = xs.dims3()?;
+            Ok(StreamTensor::from_tensor(xs.reshape((bc / dim, dim, t))?))
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ConvDownsample1d {
     conv: StreamableConv1d,
+    learnt: bool,
+    dim: usize,
 }
 
 impl ConvDownsample1d {
+    /// When `learnt` is false, the weights are not loaded from `vb` and each channel is
+    /// downsampled by averaging over a window of `2 * stride` steps.
     pub fn new(
         stride: usize,
         dim: usize,
@@ -446,12 +676,16 @@ impl ConvDownsample1d {
         learnt: bool,
         vb: VarBuilder,
     ) -> Result<Self> {
-        if !learnt {
-            candle::bail!("only learnt=true is supported")
-        }
+        let (channels, vb) = if learnt {
+            (dim, vb.pp("conv"))
+        } else {
+            let weight =
+                (Tensor::ones((1, 1, 2 * stride), vb.dtype(), vb.device())? / (2 * stride) as f64)?;
+            (1, fixed_weight_vb("conv.conv.weight", weight))
+        };
         let conv = StreamableConv1d::new(
-            /* in_c */ dim,
-            /* out_c */ dim,
+            /* in_c */ channels,
+            /* out_c */ channels,
             /* k_size_c */ 2 * stride,
             /* stride */ stride,
             /* dilation */ 1,
@@ -460,15 +694,21 @@ impl ConvDownsample1d {
             /* causal */ causal,
             /* norm */ None,
             /* pad_mode */ PadMode::Replicate,
-            vb.pp("conv"),
+            vb,
         )?;
-        Ok(Self { conv })
+        Ok(Self { conv, learnt, dim })
     }
 }
 
 impl Module for ConvDownsample1d {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        xs.apply(&self.conv)
+        if self.learnt {
+            xs.apply(&self.conv)
+        } else {
+            let (b, c, t) = xs.dims3()?;
+            let ys = xs.reshape((b * c, 1, t))?.apply(&self.conv)?;
+            ys.reshape((b, c, ()))
+        }
     }
 }
 
@@ -478,16 +718,47 @@ impl StreamingModule for ConvDownsample1d {
     }
 
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
-        self.conv.step(xs)
+        if self.learnt {
+            self.conv.step(xs)
+        } else {
+            let ys = self.conv.step(&fold_channels(xs)?)?;
+            unfold_channels(&ys, self.dim)
+        }
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        if self.learnt {
+            self.conv.flush()
+        } else {
+            unfold_channels(&self.conv.flush()?, self.dim)
+        }
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.conv.save_state(&state_key(prefix, "conv"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.conv.load_state(&state_key(prefix, "conv"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        self.conv.stream_info()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ConvTrUpsample1d {
     convtr: StreamableConvTranspose1d,
+    // For the non-learnt version, this is applied to a sequence of ones so as to get the number
+    // of overlapping windows that contributed to each output step.
+    norm: Option<StreamableConvTranspose1d>,
+    dim: usize,
 }
 
 impl ConvTrUpsample1d {
+    /// When `learnt` is false, the weights are not loaded from `vb` and each output step is the
+    /// average of the input steps whose `2 * stride` windows overlap it.
     pub fn new(
         stride: usize,
         dim: usize,
@@ -495,37 +766,103 @@ impl ConvTrUpsample1d {
         learnt: bool,
         vb: VarBuilder,
     ) -> Result<Self> {
-        if !learnt {
-            candle::bail!("only learnt=true is supported")
-        }
+        let (channels, vb) = if learnt {
+            (dim, vb.pp("convtr"))
+        } else {
+            let weight = Tensor::ones((1, 1, 2 * stride), vb.dtype(), vb.device())?;
+            (1, fixed_weight_vb("convtr.convtr.weight", weight))
+        };
         let convtr = StreamableConvTranspose1d::new(
-            dim,
-            dim,
+            channels,
+            channels,
             /* k_size */ 2 * stride,
             /* stride */ stride,
-            /* groups */ dim,
+            /* groups */ channels,
             /* bias */ false,
             /* causal */ causal,
             /* norm */ None,
-            vb.pp("convtr"),
+            vb,
         )?;
-        Ok(Self { convtr })
+        let norm = if learnt { None } else { Some(convtr.clone()) };
+        Ok(Self { convtr, norm, dim })
     }
 }
 
 impl Module for ConvTrUpsample1d {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        xs.apply(&self.convtr)
+        match self.norm.as_ref() {
+            None => xs.apply(&self.convtr),
+            Some(norm) => {
+                let (b, c, t) = xs.dims3()?;
+                let ys = xs.reshape((b * c, 1, t))?.apply(&self.convtr)?;
+                let ones = Tensor::ones((1, 1, t), xs.dtype(), xs.device())?;
+                let ys = ys.broadcast_div(&ones.apply(norm)?)?;
+                ys.reshape((b, c, ()))
+            }
+        }
     }
 }
 
 impl StreamingModule for ConvTrUpsample1d {
     fn reset_state(&mut self) {
-        self.convtr.reset_state()
+        self.convtr.reset_state();
+        if let Some(norm) = self.norm.as_mut() {
+            norm.reset_state()
+        }
     }
 
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
-        self.convtr.step(xs)
+        let norm = match self.norm.as_mut() {
+            None => return self.convtr.step(xs),
+            Some(norm) => norm,
+        };
+        let ones = match xs.as_option() {
+            None => StreamTensor::empty(),
+            Some(xs) => {
+                let t = xs.dim(D::Minus1)?;
+                StreamTensor::from_tensor(Tensor::ones((1, 1, t), xs.dtype(), xs.device())?)
+            }
+        };
+        let ys = self.convtr.step(&fold_channels(xs)?)?;
+        let ys = normalize(&ys, &norm.step(&ones)?)?;
+        unfold_channels(&ys, self.dim)
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        let norm = match self.norm.as_mut() {
+            None => return self.convtr.flush(),
+            Some(norm) => norm,
+        };
+        let ys = normalize(&self.convtr.flush()?, &norm.flush()?)?;
+        unfold_channels(&ys, self.dim)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.convtr.save_state(&state_key(prefix, "convtr"), state)?;
+        if let Some(norm) = self.norm.as_ref() {
+            norm.save_state(&state_key(prefix, "norm"), state)?
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.convtr.load_state(&state_key(prefix, "convtr"), state)?;
+        if let Some(norm) = self.norm.as_mut() {
+            norm.load_state(&state_key(prefix, "norm"), state)?
+        }
+        Ok(())
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        self.convtr.stream_info()
+    }
+}
+
+fn normalize(ys: &StreamTensor, norm: &StreamTensor) -> Result<StreamTensor> {
+    match (ys.as_option(), norm.as_option()) {
+        (None, None) => Ok(StreamTensor::empty()),
+        (Some(ys), Some(norm)) => Ok(StreamTensor::from_tensor(ys.broadcast_div(norm)?)),
+        _ => candle::bail!("internal error, mismatched resampling normalization"),
     }
 }
 
@@ -534,6 +871,7 @@ mod tests {
     use super::*;
     use candle::IndexOp;
 
+    #[allow(clippy::too_many_arguments)]
     fn run_conv1d(
         k_size: usize,
         stride: usize,
@@ -541,23 +879,18 @@ mod tests {
         step_size: usize,
         len: usize,
         bias: bool,
+        causal: bool,
+        pad_mode: PadMode,
     ) -> Result<()> {
         // TODO: We should ensure for the seed to be constant when running these tests.
         let dev = &candle::Device::Cpu;
         let vm = candle_nn::VarMap::new();
         let vb = VarBuilder::from_varmap(&vm, candle::DType::F32, dev);
         let conv1d = StreamableConv1d::new(
-            /* in_c */ 2,
-            /* out_c */ 3,
-            /* k_size */ k_size,
-            /* stride */ stride,
-            /* dilation */ dilation,
-            /* groups */ 1,
-            /* bias */ bias,
-            /* causal */ true,
-            /* norm */ None,
-            /* pad_mode */ PadMode::Constant,
-            vb,
+            /* in_c */ 2, /* out_c */ 3, /* k_size */ k_size,
+            /* stride */ stride, /* dilation */ dilation, /* groups */ 1,
+            /* bias */ bias, /* causal */ causal, /* norm */ None,
+            /* pad_mode */ pad_mode, vb,
         )?;
         let xs = Tensor::randn(0f32, 1., (1, 2, step_size * len), dev)?;
         let ys = conv1d.forward(&xs)?;
@@ -570,6 +903,9 @@ mod tests {
                 ys_steps.push(ys.clone())
             }
         }
+        if let Some(ys) = conv1d.flush()?.as_option() {
+            ys_steps.push(ys.clone())
+        }
         let ys_steps = Tensor::cat(&ys_steps, D::Minus1)?;
         let diff = (&ys - &ys_steps)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
         if diff > 1e-5 {
@@ -587,6 +923,7 @@ mod tests {
         step_size: usize,
         len: usize,
         bias: bool,
+        causal: bool,
     ) -> Result<()> {
         // TODO: We should ensure for the seed to be constant when running these tests.
         let dev = &candle::Device::Cpu;
@@ -595,7 +932,7 @@ mod tests {
         let conv1d = StreamableConvTranspose1d::new(
             /* in_c */ 2, /* out_c */ 3, /* k_size */ k_size,
             /* stride */ stride, /* groups */ 1, /* bias */ bias,
-            /* causal */ true, /* norm */ None, vb,
+            /* causal */ causal, /* norm */ None, vb,
         )?;
         let xs = Tensor::randn(0f32, 1., (1, 2, step_size * len), dev)?;
         let ys = conv1d.forward(&xs)?;
@@ -608,6 +945,9 @@ mod tests {
                 ys_steps.push(ys.clone())
             }
         }
+        if let Some(ys) = conv1d.flush()?.as_option() {
+            ys_steps.push(ys.clone())
+        }
         let ys_steps = Tensor::cat(&ys_steps, D::Minus1)?;
         let diff = (&ys - &ys_steps)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
         if diff > 1e-5 {
@@ -623,24 +963,245 @@ mod tests {
     fn conv1d() -> Result<()> {
         for step_size in [1, 2, 3] {
             for bias in [false, true] {
-                run_conv1d(1, 1, 1, step_size, 5, bias)?;
-                run_conv1d(2, 1, 1, step_size, 5, bias)?;
-                run_conv1d(2, 2, 1, step_size, 6, bias)?;
-                run_conv1d(3, 2, 1, step_size, 8, bias)?;
-                run_conv1d(3, 2, 2, step_size, 8, bias)?;
+                for causal in [true, false] {
+                    for pad_mode in [PadMode::Constant, PadMode::Reflect, PadMode::Replicate] {
+                        run_conv1d(1, 1, 1, step_size, 5, bias, causal, pad_mode)?;
+                        run_conv1d(2, 1, 1, step_size, 5, bias, causal, pad_mode)?;
+                        run_conv1d(2, 2, 1, step_size, 6, bias, causal, pad_mode)?;
+                        run_conv1d(3, 2, 1, step_size, 8, bias, causal, pad_mode)?;
+                        run_conv1d(3, 2, 2, step_size, 8, bias, causal, pad_mode)?;
+                        // Lengths that are not a multiple of the stride, the tail has to be flushed.
+                        run_conv1d(3, 2, 1, step_size, 7, bias, causal, pad_mode)?;
+                        run_conv1d(4, 4, 1, step_size, 5, bias, causal, pad_mode)?;
+                        run_conv1d(5, 3, 2, step_size, 7, bias, causal, pad_mode)?;
+                    }
+                }
             }
         }
         Ok(())
     }
 
+    #[test]
+    fn reflect_pad() -> Result<()> {
+        let dev = &candle::Device::Cpu;
+        let xs = Tensor::arange(0f32, 5., dev)?.reshape((1, 1, 5))?;
+        let ys = pad1d(&xs, 2, 3, PadMode::Reflect)?;
+        assert_eq!(ys.flatten_all()?.to_vec1::<f32>()?, [2., 1., 0., 1., 2., 3., 4., 3., 2., 1.]);
+        // The input is extended with zeros when shorter than the padding.
+        let xs = Tensor::new(&[[[1f32, 2.]]], dev)?;
+        let ys = pad1d(&xs, 3, 1, PadMode::Reflect)?;
+        assert_eq!(ys.flatten_all()?.to_vec1::<f32>()?, [0., 0., 2., 1., 2., 0.]);
+        Ok(())
+    }
+
+    #[test]
+    fn conv1d_state() -> Result<()> {
+        let dev = &candle::Device::Cpu;
+        let vm = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&vm, candle::DType::F32, dev);
+        let mut conv1d = StreamableConv1d::new(
+            /* in_c */ 2,
+            /* out_c */ 3,
+            /* k_size */ 4,
+            /* stride */ 2,
+            /* dilation */ 1,
+            /* groups */ 1,
+            /* bias */ true,
+            /* causal */ true,
+            /* norm */ None,
+            /* pad_mode */ PadMode::Constant,
+            vb,
+        )?;
+        let xs = crate::streaming::test_utils::rand_tensor(0, 1., (1, 2, 12))?;
+        conv1d.step(&xs.i((.., .., ..5))?.into())?;
+        let mut state = StateDict::new();
+        conv1d.save_state("conv", &mut state)?;
+        let state = streaming::deserialize_state(&streaming::serialize_state(&state)?, dev)?;
+        let mut restored = conv1d.clone();
+        restored.reset_state();
+        restored.load_state("conv", &state)?;
+        let xs = xs.i((.., .., 5..))?.into();
+        let ys = conv1d.step(&xs)?.as_option().unwrap().to_vec3::<f32>()?;
+        let ys_restored = restored.step(&xs)?.as_option().unwrap().to_vec3::<f32>()?;
+        assert_eq!(ys, ys_restored);
+        Ok(())
+    }
+
+    // sigma = u^T W v where W is `weight` with the `dim` and 0 dimensions swapped and reshaped to
+    // a matrix, `weight` having three dimensions.
+    fn spectral_norm_sigma(weight: &[Vec<Vec<f32>>], dim: usize, u: &[f32], v: &[f32]) -> f32 {
+        let mut sigma = 0f32;
+        for (i, wi) in weight.iter().enumerate() {
+            for (j, wij) in wi.iter().enumerate() {
+                for (k, &w) in wij.iter().enumerate() {
+                    let (row, col) = if dim == 0 { (i, j) } else { (j, i) };
+                    sigma += u[row] * w * v[col * wij.len() + k]
+                }
+            }
+        }
+        sigma
+    }
+
+    #[test]
+    fn spectral_norm() -> Result<()> {
+        use crate::streaming::test_utils::{max_diff, rand_tensor};
+        use std::collections::HashMap;
+
+        let dev = &candle::Device::Cpu;
+        let check = |weight: Tensor, expected: &Tensor| -> Result<()> {
+            assert_eq!(weight.dims(), expected.dims());
+            let diff = max_diff(&weight, expected)?;
+            assert!(diff < 1e-5, "larger diff than expected {diff}");
+            Ok(())
+        };
+        // The spectral norm parametrization, together with a plain weight when set.
+        let conv_vb =
+            |prefix: &str, w: &Tensor, u: &Tensor, v: &Tensor, weight: Option<&Tensor>| {
+                let mut tensors = HashMap::from([
+                    (format!("{prefix}.weight_orig"), w.clone()),
+                    (format!("{prefix}.weight_u"), u.clone()),
+                    (format!("{prefix}.weight_v"), v.clone()),
+                ]);
+                if let Some(weight) = weight {
+                    tensors.insert(format!("{prefix}.weight"), weight.clone());
+                }
+                VarBuilder::from_tensors(tensors, candle::DType::F32, dev)
+            };
+
+        // A convolution with 3 output channels, 2 input channels and a kernel size of 4.
+        let weight_orig = rand_tensor(0, 1., (3, 2, 4))?;
+        let u = rand_tensor(1, 1., 3)?;
+        let v = rand_tensor(2, 1., 8)?;
+        let sigma = spectral_norm_sigma(
+            &weight_orig.to_vec3()?,
+            0,
+            &u.to_vec1::<f32>()?,
+            &v.to_vec1::<f32>()?,
+        );
+        let expected = (&weight_orig / sigma as f64)?;
+        let vb = conv_vb("conv", &weight_orig, &u, &v, None);
+        let norm = Some(Norm::SpectralNorm);
+        let cfg = candle_nn::Conv1dConfig::default();
+        let conv = NormConv1d::new(2, 3, 4, true, norm, false, cfg, vb)?;
+        check(conv.conv.weight().clone(), &expected)?;
+        // A plain weight takes precedence over the parametrization.
+        let weight = rand_tensor(5, 1., (3, 2, 4))?;
+        let vb = conv_vb("conv", &weight_orig, &u, &v, Some(&weight));
+        let conv = NormConv1d::new(2, 3, 4, true, norm, false, cfg, vb)?;
+        check(conv.conv.weight().clone(), &weight)?;
+
+        // A transposed convolution with 2 input channels, 3 output channels and a kernel size of
+        // 4, the output channels come second.
+        let weight_orig = rand_tensor(3, 1., (2, 3, 4))?;
+        let v = rand_tensor(4, 1., 8)?;
+        let sigma = spectral_norm_sigma(
+            &weight_orig.to_vec3()?,
+            1,
+            &u.to_vec1::<f32>()?,
+            &v.to_vec1::<f32>()?,
+        );
+        let expected = (&weight_orig / sigma as f64)?;
+        let vb = conv_vb("convtr", &weight_orig, &u, &v, None);
+        let convtr = NormConvTranspose1d::new(2, 3, 4, true, norm, false, 1, 1, vb)?;
+        check(convtr.ws.clone(), &expected)?;
+        let weight = rand_tensor(5, 1., (2, 3, 4))?;
+        let vb = conv_vb("convtr", &weight_orig, &u, &v, Some(&weight));
+        let convtr = NormConvTranspose1d::new(2, 3, 4, true, norm, false, 1, 1, vb)?;
+        check(convtr.ws.clone(), &weight)?;
+        Ok(())
+    }
+
     #[test]
     fn conv_tr1d() -> Result<()> {
         for step_size in [1, 2, 3] {
             for bias in [false, true] {
-                run_conv_tr1d(1, 1, step_size, 5, bias)?;
-                run_conv_tr1d(2, 1, step_size, 5, bias)?;
-                run_conv_tr1d(3, 1, step_size, 5, bias)?;
-                run_conv_tr1d(3, 2, step_size, 5, bias)?;
+                for causal in [true, false] {
+                    run_conv_tr1d(1, 1, step_size, 5, bias, causal)?;
+                    run_conv_tr1d(2, 1, step_size, 5, bias, causal)?;
+                    run_conv_tr1d(3, 1, step_size, 5, bias, causal)?;
+                    run_conv_tr1d(3, 2, step_size, 5, bias, causal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fixed_resampling() -> Result<()> {
+        let dev = &candle::Device::Cpu;
+        let vb = VarBuilder::zeros(candle::DType::F32, dev);
+        let mut down = ConvDownsample1d::new(2, 3, true, false, vb.clone())?;
+        let mut up = ConvTrUpsample1d::new(2, 3, true, false, vb)?;
+        // Averaging preserves constant signals.
+        let ones = Tensor::ones((2, 3, 8), candle::DType::F32, dev)?;
+        let ys = ones.apply(&down)?.apply(&up)?;
+        assert_eq!(ys.dims(), [2, 3, 8]);
+        assert_eq!(ys.flatten_all()?.min(0)?.to_vec0::<f32>()?, 1.);
+        assert_eq!(ys.flatten_all()?.max(0)?.to_vec0::<f32>()?, 1.);
+
+        let xs = crate::streaming::test_utils::rand_tensor(0, 1., (2, 3, 12))?;
+        let zs = xs.apply(&down)?;
+        let ys = zs.apply(&up)?;
+        let (mut zs_steps, mut ys_steps) = (vec![], vec![]);
+        for idx in 0..4 {
+            let zs = down.step(&xs.i((.., .., 3 * idx..3 * (idx + 1)))?.into())?;
+            if let Some(zs) = zs.as_option() {
+                zs_steps.push(zs.clone())
+            }
+            if let Some(ys) = up.step(&zs)?.as_option() {
+                ys_steps.push(ys.clone())
+            }
+        }
+        let zs = zs.to_vec3::<f32>()?;
+        let zs_steps = Tensor::cat(&zs_steps, D::Minus1)?.to_vec3::<f32>()?;
+        assert_eq!(zs, zs_steps);
+        let ys_steps = Tensor::cat(&ys_steps, D::Minus1)?;
+        let diff = (&ys - &ys_steps)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-5, "larger diff than expected {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn stream_info_delay() -> Result<()> {
+        use crate::streaming::test_utils::{first_output_step, rand_tensor};
+
+        let vb = VarBuilder::zeros(candle::DType::F32, &candle::Device::Cpu);
+        let xs = rand_tensor(0, 1., (1, 2, 32))?;
+        for (k_size, stride) in [(1, 1), (3, 1), (4, 2), (5, 2), (8, 4)] {
+            for causal in [true, false] {
+                let mut conv1d = StreamableConv1d::new(
+                    /* in_c */ 2,
+                    /* out_c */ 3,
+                    /* k_size */ k_size,
+                    /* stride */ stride,
+                    /* dilation */ 1,
+                    /* groups */ 1,
+                    /* bias */ true,
+                    /* causal */ causal,
+                    /* norm */ None,
+                    /* pad_mode */ PadMode::Constant,
+                    vb.clone(),
+                )?;
+                let info = conv1d.stream_info();
+                assert_eq!((info.stride_in, info.stride_out), (stride, 1));
+                let first = first_output_step(&mut conv1d, &xs)?;
+                assert_eq!(first, Some(info.delay), "conv {k_size} {stride} {causal}");
+
+                let mut convtr1d = StreamableConvTranspose1d::new(
+                    /* in_c */ 2,
+                    /* out_c */ 3,
+                    /* k_size */ k_size,
+                    /* stride */ stride,
+                    /* groups */ 1,
+                    /* bias */ true,
+                    /* causal */ causal,
+                    /* norm */ None,
+                    vb.clone(),
+                )?;
+                let info = convtr1d.stream_info();
+                assert_eq!((info.stride_in, info.stride_out), (1, stride));
+                let first = first_output_step(&mut convtr1d, &xs)?;
+                assert_eq!(first, Some(info.delay), "convtr {k_size} {stride} {causal}");
             }
         }
         Ok(())
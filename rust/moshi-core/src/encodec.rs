@@ -2,19 +2,24 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::streaming::{StreamTensor, StreamingModule};
+use crate::streaming::{
+    state_key, step_and_flush, StateDict, StreamInfo, StreamTensor, StreamingModule,
+};
 use crate::{conv, quantization, seanet, transformer};
-use candle::{DType, Device, Module, Result, Tensor};
+use candle::{Device, Module, Result, Tensor, D};
 use candle_nn::VarBuilder;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ResampleMethod {
     Conv,
     Interpolate,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
+    /// Number of audio channels. When the seanet model is mono, i.e. `seanet.channels` is 1, the
+    /// channels are encoded independently of each other.
     pub channels: usize,
     pub sample_rate: f64,
     pub frame_rate: f64,
@@ -72,7 +77,9 @@ impl Config {
             dim_feedforward: 2048,
             kv_repeat: 1,
             conv_layout: true, // see builders.py
-            max_seq_len: 8192, // the transformer works at 25hz so this is ~5 mins.
+            // The transformer works at 25hz so the precomputed rotary embeddings cover ~5 mins,
+            // later positions get computed on the fly.
+            max_seq_len: 8192,
         };
         Config {
             channels: 1,
@@ -87,6 +94,12 @@ impl Config {
             quantizer_dim: 256,
         }
     }
+
+    /// Reads the configuration associated with a weight file if any, see `crate::config` for
+    /// the supported locations.
+    pub fn from_model_file<P: AsRef<std::path::Path>>(model_file: P) -> Result<Option<Self>> {
+        crate::config::load_config(model_file, "mimi")
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -103,6 +116,13 @@ pub struct Encodec {
 
 impl Encodec {
     pub fn new(cfg: Config, vb: VarBuilder) -> Result<Self> {
+        if cfg.seanet.channels != cfg.channels && cfg.seanet.channels != 1 {
+            candle::bail!(
+                "unsupported channels {}, the seanet model has {} channels",
+                cfg.channels,
+                cfg.seanet.channels
+            )
+        }
         let dim = cfg.seanet.dimension;
         let encoder = seanet::SeaNetEncoder::new(&cfg.seanet, vb.pp("encoder"))?;
         let decoder = seanet::SeaNetDecoder::new(&cfg.seanet, vb.pp("decoder"))?;
@@ -131,18 +151,19 @@ impl Encodec {
 
         let downsample_stride = (encoder_frame_rate / cfg.frame_rate) as usize;
         // `upsample` and `downsample` only apply if frame_rate is different from encoder_frame_rate.
+        let learnt = cfg.resample_method == ResampleMethod::Conv;
         let downsample = conv::ConvDownsample1d::new(
             /* stride */ downsample_stride,
             /* dim */ dim,
             /* causal */ true,
-            /* learnt */ true,
+            /* learnt */ learnt,
             vb.pp("downsample"),
         )?;
         let upsample = conv::ConvTrUpsample1d::new(
             /* stride */ downsample_stride,
             /* dim */ dim,
             /* causal */ true,
-            /* learnt */ true,
+            /* learnt */ learnt,
             vb.pp("upsample"),
         )?;
 
@@ -162,69 +183,502 @@ impl Encodec {
         &self.config
     }
 
-    pub fn encode_pre_quantize(&mut self, xs: &Tensor) -> Result<Tensor> {
-        let xs = self.encoder.forward(xs)?;
+    // Number of channels that get processed independently by a mono model.
+    fn independent_channels(&self) -> usize {
+        if self.config.seanet.channels == self.config.channels {
+            1
+        } else {
+            self.config.channels
+        }
+    }
+
+    // (b, c * k, t) -> (b * c, k, t) where c is the number of independent channels.
+    fn fold_channels(&self, xs: &Tensor) -> Result<Tensor> {
+        let c = self.independent_channels();
+        if c == 1 {
+            return Ok(xs.clone());
+        }
+        let (b, ck, t) = xs.dims3()?;
+        if ck % c != 0 {
+            candle::bail!("unexpected shape {:?} for {c} channels", xs.shape())
+        }
+        xs.reshape((b * c, ck / c, t))
+    }
+
+    // (b * c, k, t) -> (b, c * k, t) where c is the number of independent channels.
+    fn unfold_channels(&self, xs: &Tensor) -> Result<Tensor> {
+        let c = self.independent_channels();
+        if c == 1 {
+            return Ok(xs.clone());
+        }
+        let (bc, k, t) = xs.dims3()?;
+        xs.reshape((bc / c, c * k, t))
+    }
+
+    fn fold_pcm(&self, xs: &Tensor) -> Result<Tensor> {
+        let channels = xs.dim(1)?;
+        if channels != self.config.channels {
+            candle::bail!("expected {} audio channels, got {channels}", self.config.channels)
+        }
+        self.fold_channels(xs)
+    }
+
+    // Latents before quantization with the channels folded in the batch dimension.
+    fn latents(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.encoder.forward(&self.fold_pcm(xs)?)?;
         self.encoder_transformer.reset_state();
         let xs = self.encoder_transformer.forward(&xs)?;
         let xs = &xs[0];
         xs.apply(&self.downsample)
     }
 
+    pub fn encode_pre_quantize(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.latents(xs)?;
+        self.unfold_channels(&xs)
+    }
+
+    /// Encodes pcm data of shape (b, channels, t). The resulting codes have a shape (b, k, t')
+    /// for mono models. When the channels are encoded independently, the codes for the different
+    /// channels are concatenated, resulting in a shape (b, channels * k, t') where the rows
+    /// `c * k..(c + 1) * k` hold the k codebooks of channel `c`.
     pub fn encode(&mut self, xs: &Tensor) -> Result<Tensor> {
-        let xs = self.encoder.forward(xs)?;
-        self.encoder_transformer.reset_state();
-        let xs = self.encoder_transformer.forward(&xs)?;
-        let xs = &xs[0];
-        let xs = xs.apply(&self.downsample)?;
-        let codes = self.quantizer.encode(&xs)?;
-        Ok(codes)
+        self.encode_with_n_q(xs, self.config.quantizer_n_q)
+    }
+
+    /// Same as `encode` but only uses the first `n_q` codebooks, `n_q` can be at most the number
+    /// of codebooks the model has been loaded with.
+    pub fn encode_with_n_q(&mut self, xs: &Tensor, n_q: usize) -> Result<Tensor> {
+        let xs = self.latents(xs)?;
+        let codes = self.quantizer.encode_with_n_q(&xs, n_q)?;
+        self.unfold_channels(&codes)
+    }
+
+    /// Empty codebook statistics for all the codebooks the model has been loaded with.
+    pub fn codebook_stats(&self) -> quantization::CodebookStats {
+        self.quantizer.stats()
     }
 
+    /// Same as `encode` but also accumulates codebook utilisation and reconstruction error
+    /// statistics in `stats`, the number of codebooks used is the one from `stats`.
+    pub fn encode_with_stats(
+        &mut self,
+        xs: &Tensor,
+        stats: &mut quantization::CodebookStats,
+    ) -> Result<Tensor> {
+        let xs = self.latents(xs)?;
+        let codes = self.quantizer.encode_with_stats(&xs, stats)?;
+        self.unfold_channels(&codes)
+    }
+
+    /// Streaming version of `encode`, the codes use the same layout.
     pub fn encode_step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
-        let xs = self.encoder.step(xs)?;
+        self.encode_step_with_n_q(xs, self.config.quantizer_n_q)
+    }
+
+    /// Streaming version of `encode_with_n_q`, the streaming state does not depend on `n_q` so
+    /// it can change from one step to the next.
+    pub fn encode_step_with_n_q(&mut self, xs: &StreamTensor, n_q: usize) -> Result<StreamTensor> {
+        let xs = match xs.as_option() {
+            None => StreamTensor::empty(),
+            Some(xs) => StreamTensor::from_tensor(self.fold_pcm(xs)?),
+        };
+        let xs = self.encoder.step(&xs)?;
         let xs = self.encoder_transformer.step(&xs)?;
         let xs = self.downsample.step(&xs)?;
+        self.quantize_step(&xs, n_q)
+    }
+
+    /// Flushes the encoder at the end of a stream, returning the codes for the last partial
+    /// frame if any. The encoder state is reset afterwards.
+    pub fn encode_flush(&mut self) -> Result<StreamTensor> {
+        self.encode_flush_with_n_q(self.config.quantizer_n_q)
+    }
+
+    pub fn encode_flush_with_n_q(&mut self, n_q: usize) -> Result<StreamTensor> {
+        let xs = self.encoder.flush()?;
+        let xs = step_and_flush(&mut self.encoder_transformer, &xs, D::Minus1)?;
+        let xs = step_and_flush(&mut self.downsample, &xs, D::Minus1)?;
+        self.quantize_step(&xs, n_q)
+    }
+
+    fn quantize_step(&self, xs: &StreamTensor, n_q: usize) -> Result<StreamTensor> {
         match xs.as_option() {
             None => Ok(().into()),
             Some(xs) => {
-                let codes = self.quantizer.encode(xs)?;
-                Ok(codes.into())
+                let codes = self.quantizer.encode_with_n_q(xs, n_q)?;
+                Ok(self.unfold_channels(&codes)?.into())
             }
         }
     }
 
+    // Restricts codes of shape (b * c, k, t) to their first n_q codebooks.
+    fn first_codebooks(codes: &Tensor, n_q: Option<usize>) -> Result<Tensor> {
+        match n_q {
+            None => Ok(codes.clone()),
+            Some(n_q) => {
+                let k = codes.dim(1)?;
+                if n_q == 0 || n_q > k {
+                    candle::bail!("n_q should be between 1 and {k}, got {n_q}")
+                }
+                codes.narrow(1, 0, n_q)
+            }
+        }
+    }
+
+    /// Decodes codes as returned by `encode` to pcm data of shape (b, channels, t). The codes
+    /// can use fewer codebooks than the model, e.g. when produced by `encode_with_n_q`. For
+    /// models that process the channels independently, the codes have a shape
+    /// (b, channels * k, t) with the codebooks of each channel being contiguous.
     pub fn decode(&mut self, codes: &Tensor) -> Result<Tensor> {
-        let emb = self.quantizer.decode(codes)?;
+        self.decode_(codes, None)
+    }
+
+    /// Decodes using only the first `n_q` codebooks of `codes` (per channel).
+    pub fn decode_with_n_q(&mut self, codes: &Tensor, n_q: usize) -> Result<Tensor> {
+        self.decode_(codes, Some(n_q))
+    }
+
+    fn decode_(&mut self, codes: &Tensor, n_q: Option<usize>) -> Result<Tensor> {
+        let codes = Self::first_codebooks(&self.fold_channels(codes)?, n_q)?;
+        let emb = self.quantizer.decode(&codes)?;
         let emb = emb.apply(&self.upsample)?;
         self.decoder_transformer.reset_state();
         let outs = self.decoder_transformer.forward(&emb)?;
         let out = &outs[0];
-        self.decoder.forward(out)
+        self.unfold_channels(&self.decoder.forward(out)?)
     }
 
+    /// Streaming version of `decode`, the codes use the same layout.
     pub fn decode_step(&mut self, codes: &StreamTensor) -> Result<StreamTensor> {
+        self.decode_step_(codes, None)
+    }
+
+    pub fn decode_step_with_n_q(
+        &mut self,
+        codes: &StreamTensor,
+        n_q: usize,
+    ) -> Result<StreamTensor> {
+        self.decode_step_(codes, Some(n_q))
+    }
+
+    fn decode_step_(&mut self, codes: &StreamTensor, n_q: Option<usize>) -> Result<StreamTensor> {
         let emb = match codes.as_option() {
-            Some(codes) => StreamTensor::from_tensor(self.quantizer.decode(codes)?),
+            Some(codes) => {
+                let codes = Self::first_codebooks(&self.fold_channels(codes)?, n_q)?;
+                StreamTensor::from_tensor(self.quantizer.decode(&codes)?)
+            }
             None => StreamTensor::empty(),
         };
         let emb = self.upsample.step(&emb)?;
         let out = self.decoder_transformer.step(&emb)?;
-        self.decoder.step(&out)
+        let pcm = self.decoder.step(&out)?;
+        self.unfold_channels_step(&pcm)
+    }
+
+    /// Flushes the decoder at the end of a stream, returning the remaining pcm data if any. The
+    /// decoder state is reset afterwards.
+    pub fn decode_flush(&mut self) -> Result<StreamTensor> {
+        let emb = self.upsample.flush()?;
+        let out = step_and_flush(&mut self.decoder_transformer, &emb, D::Minus1)?;
+        let pcm = step_and_flush(&mut self.decoder, &out, D::Minus1)?;
+        self.unfold_channels_step(&pcm)
+    }
+
+    fn unfold_channels_step(&self, pcm: &StreamTensor) -> Result<StreamTensor> {
+        match pcm.as_option() {
+            None => Ok(().into()),
+            Some(pcm) => Ok(self.unfold_channels(pcm)?.into()),
+        }
     }
 
     pub fn reset_state(&mut self) {
         self.encoder.reset_state();
         self.encoder_transformer.reset_state();
+        self.downsample.reset_state();
         self.decoder.reset_state();
         self.decoder_transformer.reset_state();
         self.upsample.reset_state();
     }
+
+    /// Timing of the streaming encoder, from pcm samples to code frames.
+    pub fn encode_stream_info(&self) -> StreamInfo {
+        self.encoder
+            .stream_info()
+            .then(&self.encoder_transformer.stream_info())
+            .then(&self.downsample.stream_info())
+    }
+
+    /// Timing of the streaming decoder, from code frames to pcm samples.
+    pub fn decode_stream_info(&self) -> StreamInfo {
+        self.upsample
+            .stream_info()
+            .then(&self.decoder_transformer.stream_info())
+            .then(&self.decoder.stream_info())
+    }
+
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.encoder.save_state(&state_key(prefix, "encoder"), state)?;
+        self.encoder_transformer.save_state(&state_key(prefix, "encoder_transformer"), state)?;
+        self.downsample.save_state(&state_key(prefix, "downsample"), state)?;
+        self.decoder.save_state(&state_key(prefix, "decoder"), state)?;
+        self.decoder_transformer.save_state(&state_key(prefix, "decoder_transformer"), state)?;
+        self.upsample.save_state(&state_key(prefix, "upsample"), state)
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.encoder.load_state(&state_key(prefix, "encoder"), state)?;
+        self.encoder_transformer.load_state(&state_key(prefix, "encoder_transformer"), state)?;
+        self.downsample.load_state(&state_key(prefix, "downsample"), state)?;
+        self.decoder.load_state(&state_key(prefix, "decoder"), state)?;
+        self.decoder_transformer.load_state(&state_key(prefix, "decoder_transformer"), state)?;
+        self.upsample.load_state(&state_key(prefix, "upsample"), state)
+    }
 }
 
 pub fn load(model_file: &str, num_codebooks: Option<usize>, dev: &Device) -> Result<Encodec> {
-    let vb =
-        unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[model_file], DType::F32, dev)? };
-    let cfg = Config::v0_1(num_codebooks);
-    let encodec = Encodec::new(cfg, vb)?;
-    Ok(encodec)
+    let loader = crate::loader::ModelLoader::new(model_file).mimi_device(dev);
+    let loader = match num_codebooks {
+        None => loader,
+        Some(num_codebooks) => loader.mimi_num_codebooks(num_codebooks),
+    };
+    loader.load_mimi()
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+    use candle::DType;
+
+    /// The v0_1 timings with tiny layers so that tests run quickly.
+    pub(crate) fn small_config(channels: usize, n_q: usize) -> Config {
+        let mut cfg = Config::v0_1(Some(n_q));
+        cfg.channels = channels;
+        cfg.seanet.n_filters = 4;
+        cfg.seanet.dimension = 16;
+        cfg.transformer.d_model = 16;
+        cfg.transformer.num_heads = 2;
+        cfg.transformer.num_layers = 1;
+        cfg.transformer.dim_feedforward = 32;
+        cfg.quantizer_dim = 8;
+        cfg.quantizer_bins = 32;
+        cfg
+    }
+
+    /// Creates the variables for `cfg` in `vm` with random values.
+    pub(crate) fn randomize(cfg: &Config, vm: &candle_nn::VarMap) -> Result<()> {
+        let vb = VarBuilder::from_varmap(vm, DType::F32, &Device::Cpu);
+        Encodec::new(cfg.clone(), vb)?;
+        crate::streaming::test_utils::randomize(vm, 0.3)?;
+        // Codebook entries are normalized by their usage which has to be positive.
+        for (name, var) in vm.data().lock().unwrap().iter() {
+            if name.ends_with("cluster_usage") {
+                var.set(&var.ones_like()?)?
+            }
+        }
+        Ok(())
+    }
+
+    /// A model with random weights, the weights are shared by all the models created from the
+    /// same `vm`.
+    pub(crate) fn small_model(cfg: &Config, vm: &candle_nn::VarMap) -> Result<Encodec> {
+        if vm.all_vars().is_empty() {
+            randomize(cfg, vm)?
+        }
+        let vb = VarBuilder::from_varmap(vm, DType::F32, &Device::Cpu);
+        Encodec::new(cfg.clone(), vb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::{small_config, small_model};
+    use super::*;
+    use crate::streaming::test_utils::{max_diff, rand_tensor};
+
+    type StepFn = fn(&mut Encodec, &StreamTensor) -> Result<StreamTensor>;
+    type FlushFn = fn(&mut Encodec) -> Result<StreamTensor>;
+
+    // Feeds `xs` by chunks of `chunk_len` on the last dimension then flushes the model.
+    fn run_chunks(
+        model: &mut Encodec,
+        xs: &Tensor,
+        chunk_len: usize,
+        step: StepFn,
+        flush: FlushFn,
+    ) -> Result<Tensor> {
+        let len = xs.dim(D::Minus1)?;
+        let mut ys = StreamTensor::empty();
+        for start in (0..len).step_by(chunk_len) {
+            let chunk = xs.narrow(D::Minus1, start, usize::min(chunk_len, len - start))?;
+            ys = StreamTensor::cat2(&ys, &step(model, &chunk.into())?, D::Minus1)?;
+        }
+        let ys = StreamTensor::cat2(&ys, &flush(model)?, D::Minus1)?;
+        match ys.as_option() {
+            None => candle::bail!("no output produced"),
+            Some(ys) => Ok(ys.clone()),
+        }
+    }
+
+    #[test]
+    fn encode_decode_flush() -> Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let mut model = small_model(&small_config(1, 4), &vm)?;
+        // The frame size is 1920, the last frame is only partially filled in most cases.
+        for len in [1920 * 5, 1920 * 5 + 700, 1920 * 3 + 1] {
+            let xs = rand_tensor(len as u64, 1., (1, 1, len))?;
+            let codes = model.encode(&xs)?;
+            assert_eq!(codes.dims(), [1, 4, len.div_ceil(1920)]);
+            for chunk_len in [1000, 1920] {
+                let codes_steps = run_chunks(
+                    &mut model,
+                    &xs,
+                    chunk_len,
+                    Encodec::encode_step,
+                    Encodec::encode_flush,
+                )?;
+                assert_eq!(codes.to_vec3::<u32>()?, codes_steps.to_vec3::<u32>()?, "len {len}");
+            }
+
+            let pcm = model.decode(&codes)?;
+            let pcm_steps =
+                run_chunks(&mut model, &codes, 1, Encodec::decode_step, Encodec::decode_flush)?;
+            let diff = max_diff(&pcm, &pcm_steps)?;
+            assert!(diff < 1e-4, "len {len} diff {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn state_round_trip() -> Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let cfg = small_config(1, 4);
+        let mut model = small_model(&cfg, &vm)?;
+        let len = 1920 * 6 + 500;
+        let xs = rand_tensor(1, 1., (1, 1, len))?;
+        let codes = model.encode(&xs)?;
+        let pcm = model.decode(&codes)?;
+
+        // Interrupt the stream in the middle of a frame.
+        let (xs_start, xs_end) =
+            (xs.narrow(D::Minus1, 0, 4000)?, xs.narrow(D::Minus1, 4000, len - 4000)?);
+        let codes_start = model.encode_step(&xs_start.into())?;
+        let (codes_prefix, codes_suffix) =
+            (codes.narrow(D::Minus1, 0, 3)?, codes.narrow(D::Minus1, 3, 4)?);
+        model.decode_step(&codes_prefix.into())?;
+        let mut state = StateDict::new();
+        model.save_state("mimi", &mut state)?;
+
+        let mut model = small_model(&cfg, &vm)?;
+        model.load_state("mimi", &state)?;
+        let codes_end =
+            run_chunks(&mut model, &xs_end, 1000, Encodec::encode_step, Encodec::encode_flush)?;
+        let codes_steps = StreamTensor::cat2(&codes_start, &codes_end.into(), D::Minus1)?;
+        assert_eq!(codes.to_vec3::<u32>()?, codes_steps.as_option().unwrap().to_vec3::<u32>()?);
+        let pcm_end =
+            run_chunks(&mut model, &codes_suffix, 1, Encodec::decode_step, Encodec::decode_flush)?;
+        let pcm_end_ref = pcm.narrow(D::Minus1, 1920 * 3, 1920 * 4)?;
+        let diff = max_diff(&pcm_end, &pcm_end_ref)?;
+        assert!(diff < 1e-4, "diff {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn stream_info() -> Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let mut model = small_model(&small_config(1, 4), &vm)?;
+        // One frame every 1920 samples at 24kHz, i.e. 12.5Hz, all the convs being causal.
+        let info = model.encode_stream_info();
+        assert_eq!(info, StreamInfo { stride_in: 1920, stride_out: 1, delay: 1920 });
+        let info = model.decode_stream_info();
+        assert_eq!(info, StreamInfo { stride_in: 1, stride_out: 1920, delay: 1 });
+
+        // The first frame is only produced once the delay has elapsed.
+        let xs = rand_tensor(2, 1., (1, 1, 1920))?;
+        let codes = model.encode_step(&xs.narrow(D::Minus1, 0, 1919)?.into())?;
+        assert!(codes.as_option().is_none());
+        let codes = model.encode_step(&xs.narrow(D::Minus1, 1919, 1)?.into())?;
+        let codes = codes.as_option().unwrap();
+        assert_eq!(codes.dims(), [1, 4, 1]);
+        let pcm = model.decode_step(&codes.clone().into())?;
+        assert_eq!(pcm.as_option().unwrap().dims(), [1, 1, 1920]);
+        Ok(())
+    }
+
+    #[test]
+    fn stereo_matches_mono() -> Result<()> {
+        let vm = candle_nn::VarMap::new();
+        // The seanet model is mono so both configs share the same weights.
+        let mut mono = small_model(&small_config(1, 4), &vm)?;
+        let mut stereo = small_model(&small_config(2, 4), &vm)?;
+        let len = 1920 * 3 + 200;
+        let xs = rand_tensor(3, 1., (1, 2, len))?;
+        let codes = stereo.encode(&xs)?;
+        assert_eq!(codes.dims(), [1, 8, 4]);
+        let pcm = stereo.decode(&codes)?;
+        assert_eq!(pcm.dims(), [1, 2, 4 * 1920]);
+        for c in 0..2 {
+            let mono_codes = mono.encode(&xs.narrow(1, c, 1)?)?;
+            let codes = codes.narrow(1, c * 4, 4)?;
+            assert_eq!(mono_codes.to_vec3::<u32>()?, codes.to_vec3::<u32>()?, "channel {c}");
+            let mono_pcm = mono.decode(&mono_codes)?;
+            let diff = max_diff(&mono_pcm, &pcm.narrow(1, c, 1)?)?;
+            assert!(diff < 1e-5, "channel {c} diff {diff}");
+        }
+
+        let codes_steps =
+            run_chunks(&mut stereo, &xs, 1000, Encodec::encode_step, Encodec::encode_flush)?;
+        assert_eq!(codes.to_vec3::<u32>()?, codes_steps.to_vec3::<u32>()?);
+        let pcm_steps =
+            run_chunks(&mut stereo, &codes, 1, Encodec::decode_step, Encodec::decode_flush)?;
+        let diff = max_diff(&pcm, &pcm_steps)?;
+        assert!(diff < 1e-4, "diff {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn n_q() -> Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let mut model = small_model(&small_config(1, 4), &vm)?;
+        let len = 1920 * 4 + 300;
+        let xs = rand_tensor(4, 1., (1, 1, len))?;
+        let codes = model.encode(&xs)?;
+        for n_q in 1..=4 {
+            // Residual quantization, the first levels do not depend on the following ones.
+            let codes_n_q = model.encode_with_n_q(&xs, n_q)?;
+            let first = codes.narrow(1, 0, n_q)?;
+            assert_eq!(codes_n_q.to_vec3::<u32>()?, first.to_vec3::<u32>()?, "n_q {n_q}");
+
+            let pcm = model.decode_with_n_q(&codes, n_q)?;
+            let diff = max_diff(&pcm, &model.decode(&first)?)?;
+            assert!(diff < 1e-5, "n_q {n_q} diff {diff}");
+        }
+
+        // Streaming with two codebooks.
+        let codes_steps = run_chunks(
+            &mut model,
+            &xs,
+            1000,
+            |m, xs| m.encode_step_with_n_q(xs, 2),
+            |m| m.encode_flush_with_n_q(2),
+        )?;
+        let first = codes.narrow(1, 0, 2)?;
+        assert_eq!(codes_steps.to_vec3::<u32>()?, first.to_vec3::<u32>()?);
+        let pcm = model.decode_with_n_q(&codes, 2)?;
+        let pcm_steps = run_chunks(
+            &mut model,
+            &codes,
+            1,
+            |m, codes| m.decode_step_with_n_q(codes, 2),
+            Encodec::decode_flush,
+        )?;
+        let diff = max_diff(&pcm, &pcm_steps)?;
+        assert!(diff < 1e-4, "diff {diff}");
+
+        for n_q in [0, 5] {
+            assert!(model.encode_with_n_q(&xs, n_q).is_err(), "n_q {n_q}");
+            assert!(model.decode_with_n_q(&codes, n_q).is_err(), "n_q {n_q}");
+        }
+        assert!(model.encode_step_with_n_q(&xs.into(), 5).is_err());
+        Ok(())
+    }
 }
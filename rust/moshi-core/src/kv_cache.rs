@@ -0,0 +1,147 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use candle::{Result, Tensor};
+
+/// A kv-cache for streaming attention that only keeps the keys and values for the last
+/// `context + 1` positions in a ring buffer, so that its memory usage does not depend on the
+/// number of processed steps.
+///
+/// Keys and values use the `(b, h, t, d)` layout. When appending a single step, the returned
+/// keys and values are the ring buffer itself so they are not in chronological order, this is
+/// fine as no attention mask is used in this case. When appending multiple steps, the returned
+/// keys and values are in chronological order: the last `context` cached positions followed by
+/// the new ones.
+///
+/// Clones share the underlying buffers, use [`RotatingKvCache::copy`] to get an independent
+/// cache.
+#[derive(Debug, Clone)]
+pub struct RotatingKvCache {
+    k: Option<Tensor>,
+    v: Option<Tensor>,
+    context: usize,
+    // Index of the next write in the ring buffer.
+    offset: usize,
+    // Number of positions held in the ring buffer, at most context + 1.
+    len: usize,
+}
+
+impl RotatingKvCache {
+    pub fn new(context: usize) -> Self {
+        Self { k: None, v: None, context, offset: 0, len: 0 }
+    }
+
+    pub fn context(&self) -> usize {
+        self.context
+    }
+
+    /// The number of positions held in the cache, at most `context + 1`.
+    pub fn current_seq_len(&self) -> usize {
+        self.len
+    }
+
+    fn capacity(&self) -> usize {
+        self.context + 1
+    }
+
+    pub fn reset(&mut self) {
+        self.k = None;
+        self.v = None;
+        self.offset = 0;
+        self.len = 0;
+    }
+
+    /// Returns a cache that does not share its buffers with `self`.
+    pub fn copy(&self) -> Result<Self> {
+        let k = self.k.as_ref().map(|k| k.copy()).transpose()?;
+        let v = self.v.as_ref().map(|v| v.copy()).transpose()?;
+        Ok(Self { k, v, context: self.context, offset: self.offset, len: self.len })
+    }
+
+    // The cached positions in chronological order.
+    fn ordered(&self, buf: &Tensor) -> Result<Tensor> {
+        if self.len < self.capacity() {
+            // The ring buffer has not wrapped yet so offset = len.
+            buf.narrow(2, 0, self.len)
+        } else if self.offset == 0 {
+            Ok(buf.clone())
+        } else {
+            let cap = self.capacity();
+            let head = buf.narrow(2, self.offset, cap - self.offset)?;
+            Tensor::cat(&[&head, &buf.narrow(2, 0, self.offset)?], 2)
+        }
+    }
+
+    /// A copy of the cached keys in chronological order.
+    pub fn k(&self) -> Result<Option<Tensor>> {
+        self.k.as_ref().map(|k| self.ordered(k)?.copy()).transpose()
+    }
+
+    /// A copy of the cached values in chronological order.
+    pub fn v(&self) -> Result<Option<Tensor>> {
+        self.v.as_ref().map(|v| self.ordered(v)?.copy()).transpose()
+    }
+
+    // Writes xs in the ring buffer starting at offset, xs has at most capacity positions.
+    fn write(buf: &Tensor, xs: &Tensor, offset: usize) -> Result<()> {
+        let t = xs.dim(2)?;
+        let cap = buf.dim(2)?;
+        let first = usize::min(t, cap - offset);
+        buf.slice_set(&xs.narrow(2, 0, first)?.contiguous()?, 2, offset)?;
+        if first < t {
+            buf.slice_set(&xs.narrow(2, first, t - first)?.contiguous()?, 2, 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let cap = self.capacity();
+        let (k_buf, v_buf) = match (&self.k, &self.v) {
+            (Some(k_buf), Some(v_buf)) => (k_buf.clone(), v_buf.clone()),
+            _ => {
+                let (b, h, _t, d) = k.dims4()?;
+                let k_buf = Tensor::zeros((b, h, cap, d), k.dtype(), k.device())?;
+                let (b, h, _t, d) = v.dims4()?;
+                let v_buf = Tensor::zeros((b, h, cap, d), v.dtype(), v.device())?;
+                self.k = Some(k_buf.clone());
+                self.v = Some(v_buf.clone());
+                (k_buf, v_buf)
+            }
+        };
+        let t = k.dim(2)?;
+        if t == 1 {
+            Self::write(&k_buf, k, self.offset)?;
+            Self::write(&v_buf, v, self.offset)?;
+            self.offset = (self.offset + 1) % cap;
+            self.len = usize::min(self.len + 1, cap);
+            let k = k_buf.narrow(2, 0, self.len)?;
+            let v = v_buf.narrow(2, 0, self.len)?;
+            return Ok((k, v));
+        }
+
+        let n_past = usize::min(self.len, self.context);
+        let (k_all, v_all) = if n_past == 0 {
+            (k.clone(), v.clone())
+        } else {
+            let past_k = self.ordered(&k_buf)?;
+            let past_v = self.ordered(&v_buf)?;
+            let past_k = past_k.narrow(2, self.len - n_past, n_past)?;
+            let past_v = past_v.narrow(2, self.len - n_past, n_past)?;
+            // The past steps are not contiguous so the concatenation is not either, this is not
+            // supported by the attention matmul when the batch size is larger than 1.
+            let k_all = Tensor::cat(&[&past_k, k], 2)?.contiguous()?;
+            let v_all = Tensor::cat(&[&past_v, v], 2)?.contiguous()?;
+            (k_all, v_all)
+        };
+        // Only the last capacity steps are kept.
+        let n_new = usize::min(t, cap);
+        let (offset, len) =
+            if n_new == cap { (0, cap) } else { (self.offset, usize::min(self.len + n_new, cap)) };
+        Self::write(&k_buf, &k.narrow(2, t - n_new, n_new)?, offset)?;
+        Self::write(&v_buf, &v.narrow(2, t - n_new, n_new)?, offset)?;
+        self.offset = (offset + n_new) % cap;
+        self.len = len;
+        Ok((k_all, v_all))
+    }
+}
@@ -5,21 +5,27 @@
 pub use candle;
 pub use candle_nn;
 
+pub mod config;
 pub mod conv;
 pub mod encodec;
+pub mod kv_cache;
 pub mod lm;
 pub mod lm_generate;
 pub mod lm_generate_multistream;
+pub mod loader;
+pub mod mimi_file;
 pub mod quantization;
 pub mod quantized_lm;
 pub mod quantized_transformer;
+pub mod sampling;
 pub mod seanet;
 pub mod streaming;
 pub mod transformer;
 pub mod tts;
 pub mod wav;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum NormType {
     RmsNorm,
     LayerNorm,
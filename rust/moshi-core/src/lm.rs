@@ -2,6 +2,8 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::sampling::{Context, Sampler};
+use crate::streaming::{self, state_key, StateDict};
 use crate::transformer;
 use candle::{DType, Device, IndexOp, Module, Result, Tensor};
 use candle_nn::VarBuilder;
@@ -17,13 +19,13 @@ thread_local! {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DepFormerConfig {
     pub transformer: transformer::Config,
     pub num_slices: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     pub transformer: transformer::Config,
     pub depformer: Option<DepFormerConfig>,
@@ -164,6 +166,98 @@ impl Config {
             audio_codebooks: 16,
         }
     }
+
+    /// Reads the configuration associated with a weight file if any, see `crate::config` for
+    /// the supported locations.
+    pub fn from_model_file<P: AsRef<std::path::Path>>(model_file: P) -> Result<Option<Self>> {
+        crate::config::load_config(model_file, "lm")
+    }
+}
+
+// The input of a depformer slice is the token sampled by the previous slice, i.e. the token for
+// the previous codebook. This token is replaced with the padding token until the delay of this
+// codebook has elapsed, the first slice takes the text token as input.
+pub(crate) fn slice_input_token(
+    slice_idx: usize,
+    step_idx: usize,
+    last_token: u32,
+    delays: &[usize],
+    audio_padding_token: u32,
+) -> u32 {
+    if slice_idx == 0 || step_idx >= delays[slice_idx - 1] {
+        last_token
+    } else {
+        audio_padding_token
+    }
+}
+
+// The input tokens of a depformer slice for consecutive teacher-forced steps starting at
+// `step_idx`, `audio_tokens` holding the target token of each slice for each step. Tokens that
+// have not been generated are replaced by padding.
+pub(crate) fn slice_input_ids(
+    slice_idx: usize,
+    step_idx: usize,
+    text_tokens: &[u32],
+    audio_tokens: &[Vec<u32>],
+    delays: &[usize],
+    audio_padding_token: u32,
+) -> Vec<u32> {
+    let pad = audio_padding_token;
+    text_tokens
+        .iter()
+        .zip(audio_tokens.iter())
+        .enumerate()
+        .map(|(i, (&text_token, audio_tokens))| {
+            if slice_idx == 0 {
+                text_token
+            } else {
+                let last_token = u32::min(audio_tokens[slice_idx - 1], pad);
+                slice_input_token(slice_idx, step_idx + i, last_token, delays, pad)
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn check_delays(delays: &[usize], num_slices: usize) -> Result<()> {
+    if delays.len() != num_slices {
+        candle::bail!(
+            "got {} codebook delays for a depformer with {num_slices} slices",
+            delays.len()
+        )
+    }
+    Ok(())
+}
+
+pub(crate) fn check_samplers(lps: &[Sampler], num_slices: usize) -> Result<()> {
+    if lps.len() != 1 && lps.len() != num_slices {
+        candle::bail!("got {} samplers for a depformer with {num_slices} slices", lps.len())
+    }
+    Ok(())
+}
+
+// The sampler of a depformer slice, a single sampler is shared by all the slices.
+pub(crate) fn slice_sampler(lps: &mut [Sampler], slice_idx: usize) -> &mut Sampler {
+    let idx = if lps.len() == 1 { 0 } else { slice_idx };
+    &mut lps[idx]
+}
+
+/// The `(b, t)` dimensions shared by the text and audio token ids, `None` if no ids are provided.
+pub(crate) fn ids_dims(
+    text_ids: Option<&Tensor>,
+    audio_ids: &[Option<Tensor>],
+) -> Result<Option<(usize, usize)>> {
+    let mut dims = None;
+    for ids in text_ids.into_iter().chain(audio_ids.iter().flatten()) {
+        let ids_dims = ids.dims2()?;
+        match dims {
+            None => dims = Some(ids_dims),
+            Some(dims) if dims != ids_dims => {
+                candle::bail!("token ids with different shapes {dims:?} and {ids_dims:?}")
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(dims)
 }
 
 #[derive(Debug, Clone)]
@@ -230,16 +324,53 @@ impl DepFormer {
         })
     }
 
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        // The slice transformers are reset on each sampling step so only the eos tracking has
+        // to be preserved.
+        if let Some(step_idx) = self.first_eos_step_idx {
+            streaming::save_usize(&state_key(prefix, "first_eos_step_idx"), step_idx, state)?
+        }
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.first_eos_step_idx =
+            streaming::load_usize(&state_key(prefix, "first_eos_step_idx"), state)?;
+        Ok(())
+    }
+
+    pub fn reset_state(&mut self) {
+        self.first_eos_step_idx = None
+    }
+
+    // The depformer can use a different device or dtype from the main transformer.
+    fn to_depformer_placement(&self, xs: &Tensor) -> Result<Tensor> {
+        match self.slices.first() {
+            None => Ok(xs.clone()),
+            Some(slice) => {
+                let w = slice.linear_in.weight();
+                xs.to_device(w.device())?.to_dtype(w.dtype())
+            }
+        }
+    }
+
     /// Run a transformer sampling step, getting a token id per codebook.
     /// - `xs` is the previous layer hidden state.
+    /// - `delays` is the delay in steps of each of the sampled codebooks.
+    /// - `lps` holds a sampler per slice, or a single sampler shared by all the slices.
     pub fn sample(
         &mut self,
         step_idx: usize,
         xs: &Tensor,
         text_token: Option<u32>,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        delays: &[usize],
+        lps: &mut [Sampler],
     ) -> Result<Vec<u32>> {
         use crate::streaming::StreamingModule;
+        check_delays(delays, self.slices.len())?;
+        check_samplers(lps, self.slices.len())?;
+        let xs = self.to_depformer_placement(xs)?;
+        let xs = &xs;
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
@@ -255,14 +386,13 @@ impl DepFormer {
             let xs = slice.linear_in.forward(xs)?;
             let xs = match last_token {
                 Some(last_token) => {
-                    // TODO(laurent): this seems a bit weird, does it mean that the acoustic delay
-                    // is somewhat hardcoded to ([1] + [0] * 7) * 2?
-                    // Maybe step_idx > 1 should be step_idx > 0 instead?
-                    let last_token = if slice_idx < 2 || slice_idx == 9 || step_idx > 1 {
-                        last_token
-                    } else {
-                        self.audio_padding_token
-                    };
+                    let last_token = slice_input_token(
+                        slice_idx,
+                        step_idx,
+                        last_token,
+                        delays,
+                        self.audio_padding_token,
+                    );
                     let token_id = Tensor::from_vec(vec![last_token], (1, 1), dev)?;
                     let token_emb = slice.emb.forward(&token_id)?;
                     xs.broadcast_add(&token_emb)?
@@ -275,6 +405,7 @@ impl DepFormer {
                 1 => logits.i((0, 0))?,
                 b_size => candle::bail!("unexpected batch size {b_size}"),
             };
+            let lp = slice_sampler(lps, slice_idx);
             let token = self.sample_maybe_postpone_eos(step_idx, &logits, lp)?;
             if VERBOSE.with(|v| *v) {
                 println!("sampled {token} logits {slice_idx}:\n{logits}");
@@ -289,9 +420,10 @@ impl DepFormer {
         &mut self,
         step_idx: usize,
         logits: &Tensor,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        lp: &mut Sampler,
     ) -> Result<u32> {
-        let token = lp.sample(logits)?;
+        let ctx = Context::new(step_idx);
+        let token = lp.sample(&ctx, logits)?;
         let token = if token == self.audio_eos_token {
             let first_eos_step_idx = match self.first_eos_step_idx {
                 Some(step_idx) => step_idx,
@@ -305,7 +437,7 @@ impl DepFormer {
             if step_idx >= first_eos_step_idx + 5 {
                 token
             } else {
-                lp.sample_f(logits, |pr| pr[self.audio_eos_token as usize] = 1e-9)?
+                lp.sample_f(&ctx, logits, |l| l[self.audio_eos_token as usize] = f32::NEG_INFINITY)?
             }
         } else {
             token
@@ -320,9 +452,14 @@ impl DepFormer {
         xs: &Tensor,
         cfg_alpha: f64,
         text_token: Option<u32>,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        delays: &[usize],
+        lps: &mut [Sampler],
     ) -> Result<Vec<u32>> {
         use crate::streaming::StreamingModule;
+        check_delays(delays, self.slices.len())?;
+        check_samplers(lps, self.slices.len())?;
+        let xs = self.to_depformer_placement(xs)?;
+        let xs = &xs;
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
@@ -338,13 +475,13 @@ impl DepFormer {
             let xs = slice.linear_in.forward(xs)?;
             let xs = match last_token {
                 Some(last_token) => {
-                    // TODO(laurent): same as above, this seems to hardcode some delays and it's not
-                    // obvious why it should be step_idx > 1 rather than step_idx > 0.
-                    let last_token = if slice_idx < 2 || step_idx > 1 {
-                        last_token
-                    } else {
-                        self.audio_padding_token
-                    };
+                    let last_token = slice_input_token(
+                        slice_idx,
+                        step_idx,
+                        last_token,
+                        delays,
+                        self.audio_padding_token,
+                    );
                     let token_id = Tensor::from_vec(vec![last_token], (1, 1), dev)?;
                     let token_emb = slice.emb.forward(&token_id)?;
                     xs.broadcast_add(&token_emb)?
@@ -357,16 +494,73 @@ impl DepFormer {
                 2 => ((logits.i((0, 0))? * cfg_alpha)? - (logits.i((1, 0))? * (cfg_alpha - 1.))?)?,
                 b_size => candle::bail!("unexpected batch size {b_size}"),
             };
+            let lp = slice_sampler(lps, slice_idx);
             let token = if slice_idx == 0 {
                 self.sample_maybe_postpone_eos(step_idx, &logits, lp)?
             } else {
-                lp.sample(&logits)?
+                lp.sample(&Context::new(step_idx), &logits)?
             };
             last_token = Some(token);
             tokens.push(token)
         }
         Ok(tokens)
     }
+
+    /// Teacher-forced log-probabilities of the audio tokens for `t` consecutive steps starting
+    /// at `step_idx`, one row per step with a value per slice.
+    /// - `xs` is the previous layer hidden state for these steps, `(1, t, d)`.
+    /// - `text_tokens` holds the text token for each step.
+    /// - `audio_tokens` holds the target token of each slice for each step.
+    /// - `delays` is the delay in steps of each of the sampled codebooks.
+    ///
+    /// The targets that the depformer cannot emit, e.g. padding, get a `None` log-probability.
+    pub fn log_probs(
+        &mut self,
+        step_idx: usize,
+        xs: &Tensor,
+        text_tokens: &[u32],
+        audio_tokens: &[Vec<u32>],
+        delays: &[usize],
+    ) -> Result<Vec<Vec<Option<f32>>>> {
+        use crate::streaming::StreamingModule;
+        check_delays(delays, self.slices.len())?;
+        let xs = self.to_depformer_placement(xs)?;
+        let (_b, t, d) = xs.dims3()?;
+        if text_tokens.len() != t || audio_tokens.len() != t {
+            candle::bail!(
+                "got {} text and {} audio steps for {t} hidden states",
+                text_tokens.len(),
+                audio_tokens.len()
+            )
+        }
+        // The steps are independent for the depformer so they are processed as a batch.
+        let xs = xs.reshape((t, 1, d))?;
+        let dev = xs.device();
+        let pad = self.audio_padding_token;
+        let mut log_probs = vec![Vec::with_capacity(self.slices.len()); t];
+        for slice_idx in 0..self.slices.len() {
+            if slice_idx == 0 {
+                self.slices[slice_idx].transformer.reset_state();
+            } else {
+                let (lhs, rhs) = self.slices.split_at_mut(slice_idx);
+                rhs[0].transformer.copy_state(&lhs[slice_idx - 1].transformer)?
+            }
+            let slice = &mut self.slices[slice_idx];
+            let token_ids =
+                slice_input_ids(slice_idx, step_idx, text_tokens, audio_tokens, delays, pad);
+            let token_ids = Tensor::from_vec(token_ids, (t, 1), dev)?;
+            let xs =
+                slice.linear_in.forward(&xs)?.broadcast_add(&slice.emb.forward(&token_ids)?)?;
+            let xs = slice.transformer.forward(&xs)?;
+            let logits = xs.apply(&slice.linear_out)?.i((.., 0))?.to_dtype(DType::F32)?;
+            let lps = candle_nn::ops::log_softmax(&logits, candle::D::Minus1)?.to_vec2::<f32>()?;
+            for (i, lps) in lps.iter().enumerate() {
+                let target = audio_tokens[i][slice_idx] as usize;
+                log_probs[i].push(lps.get(target).copied())
+            }
+        }
+        Ok(log_probs)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -382,6 +576,17 @@ pub struct Lm {
 
 impl Lm {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_depformer = vb.pp("depformer");
+        Self::new_with_depformer_vb(cfg, vb, vb_depformer)
+    }
+
+    /// Same as `new` but with a separate var-builder for the depformer weights, this makes it
+    /// possible to use a different device or dtype for the depformer.
+    pub fn new_with_depformer_vb(
+        cfg: &Config,
+        vb: VarBuilder,
+        vb_depformer: VarBuilder,
+    ) -> Result<Self> {
         let d_model = cfg.transformer.d_model;
         let depformer = match &cfg.depformer {
             None => None,
@@ -391,7 +596,7 @@ impl Lm {
                     cfg.audio_vocab_size,
                     d_model,
                     depformer_cfg,
-                    vb.pp("depformer"),
+                    vb_depformer,
                 )?;
                 Some(depformer)
             }
@@ -423,6 +628,36 @@ impl Lm {
         self.text_emb.embeddings().device()
     }
 
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        use crate::streaming::StreamingModule;
+        self.transformer.save_state(&state_key(prefix, "transformer"), state)?;
+        if let Some(depformer) = self.depformer.as_ref() {
+            depformer.save_state(&state_key(prefix, "depformer"), state)?
+        }
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        use crate::streaming::StreamingModule;
+        self.transformer.load_state(&state_key(prefix, "transformer"), state)?;
+        if let Some(depformer) = self.depformer.as_mut() {
+            depformer.load_state(&state_key(prefix, "depformer"), state)?
+        }
+        Ok(())
+    }
+
+    pub fn reset_state(&mut self) {
+        use crate::streaming::StreamingModule;
+        self.transformer.reset_state();
+        if let Some(depformer) = self.depformer.as_mut() {
+            depformer.reset_state()
+        }
+    }
+
+    /// Runs the model on `(b, t)` token ids for the text and for each audio codebook, a causal
+    /// mask is used when processing multiple time steps. Missing ids do not contribute to the
+    /// input embeddings. Returns the text logits and the hidden states, both for all the time
+    /// steps.
     pub fn forward(
         &mut self,
         text_ids: Option<Tensor>,
@@ -447,11 +682,12 @@ impl Lm {
             }
             println!();
         }
+        let (b, t) = ids_dims(text_ids.as_ref(), &audio_ids)?.unwrap_or((1, 1));
         let mut emb = match text_ids.as_ref() {
             Some(text_ids) => text_ids.apply(&self.text_emb)?,
             None => {
                 let device = self.text_emb.embeddings().device();
-                Tensor::zeros((1, 1, self.text_emb.hidden_size()), self.dtype, device)?
+                Tensor::zeros((b, t, self.text_emb.hidden_size()), self.dtype, device)?
             }
         };
 
@@ -477,11 +713,14 @@ impl Lm {
         ca_src: &Tensor,
     ) -> candle::Result<(Tensor, Tensor)> {
         let b_size = ca_src.dim(0)?;
+        // The token ids may have a batch size of 1 and get broadcasted to the batch size of ca_src.
+        let ids = text_ids.iter().chain(audio_ids.iter().flatten()).next();
+        let t = ids.map_or(Ok(1), |ids| ids.dim(1))?;
         let mut emb = match text_ids {
             Some(text_ids) => text_ids.apply(&self.text_emb)?,
             None => {
                 let device = self.text_emb.embeddings().device();
-                Tensor::zeros((b_size, 1, self.text_emb.hidden_size()), self.dtype, device)?
+                Tensor::zeros((b_size, t, self.text_emb.hidden_size()), self.dtype, device)?
             }
         };
         for (audio_emb, audio_ids) in self.audio_embs.iter().zip(audio_ids.iter()) {
@@ -497,6 +736,21 @@ impl Lm {
     }
 }
 
+// The maximum number of steps processed by a single forward pass of the model when scoring.
+const SCORE_CHUNK_SIZE: usize = 256;
+
+/// Teacher-forced log-probabilities of a token sequence, see [`LmModel::score`].
+#[derive(Debug, Clone)]
+pub struct Scores {
+    /// The log-probability of the text token for each step.
+    pub text: Vec<f32>,
+    /// The log-probability of the tokens sampled by the depformer for each step, one value per
+    /// generated codebook in sampling order. The tokens that cannot be sampled, e.g. padding
+    /// before the codebook delay or ungenerated tokens, get a `None` log-probability. The rows
+    /// are empty for models without a depformer.
+    pub audio: Vec<Vec<Option<f32>>>,
+}
+
 #[derive(Debug, Clone)]
 pub enum LmModel {
     Lm(Lm),
@@ -504,6 +758,7 @@ pub enum LmModel {
 }
 
 impl LmModel {
+    /// Runs the model on `(b, t)` token ids, see [`Lm::forward`].
     pub fn forward(
         &mut self,
         text_ids: Option<Tensor>,
@@ -515,25 +770,163 @@ impl LmModel {
         }
     }
 
+    pub fn reset_state(&mut self) {
+        match self {
+            Self::Lm(m) => m.reset_state(),
+            Self::QuantizedLm(m) => m.reset_state(),
+        }
+    }
+
+    /// Computes the teacher-forced log-probabilities of a text and audio token sequence, the
+    /// model state gets reset beforehand. The tokens use the layout of the token history of
+    /// [`crate::lm_generate_multistream::State`], e.g. as saved in the backend session logs:
+    /// `text_tokens[s]` is the text token for step `s` and `audio_tokens[s]` holds the tokens of
+    /// all the codebooks for frame `s`, the codebook delays from `config` being applied when
+    /// feeding them to the model.
+    pub fn score(
+        &mut self,
+        text_tokens: &[u32],
+        audio_tokens: &[Vec<u32>],
+        config: &crate::lm_generate_multistream::Config,
+    ) -> Result<Scores> {
+        let layout = &config.stream_layout;
+        layout.check()?;
+        let num_steps = text_tokens.len();
+        if audio_tokens.len() != num_steps {
+            candle::bail!("got {num_steps} text steps and {} audio steps", audio_tokens.len())
+        }
+        if let Some(tokens) = audio_tokens.iter().find(|v| v.len() != layout.total_codebooks()) {
+            candle::bail!(
+                "got {} audio tokens per step, expected {}",
+                tokens.len(),
+                layout.total_codebooks()
+            )
+        }
+        self.reset_state();
+        let dev = self.device().clone();
+        let audio_pad_token = config.audio_pad_token();
+        let generated_codebooks = layout.generated_codebooks();
+        let delays = layout.generated_delays();
+        let mut scores =
+            Scores { text: Vec::with_capacity(num_steps), audio: Vec::with_capacity(num_steps) };
+        for chunk_start in (0..num_steps).step_by(SCORE_CHUNK_SIZE) {
+            let chunk_len = usize::min(SCORE_CHUNK_SIZE, num_steps - chunk_start);
+            let steps = chunk_start..chunk_start + chunk_len;
+            let text_ids: Vec<u32> = steps
+                .clone()
+                .map(|s| if s == 0 { config.text_start_token } else { text_tokens[s - 1] })
+                .collect();
+            let text_ids = Tensor::from_vec(text_ids, (1, chunk_len), &dev)?;
+            let codes = (0..layout.total_codebooks())
+                .map(|codebook| {
+                    let delay = layout.delay(codebook);
+                    let ids: Vec<u32> = steps
+                        .clone()
+                        .map(|s| {
+                            if s <= delay {
+                                audio_pad_token
+                            } else {
+                                // Tokens that have not been generated are replaced by padding.
+                                u32::min(audio_tokens[s - delay - 1][codebook], audio_pad_token)
+                            }
+                        })
+                        .collect();
+                    Ok(Some(Tensor::from_vec(ids, (1, chunk_len), &dev)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let (text_logits, ys) = self.forward(Some(text_ids), codes)?;
+            let text_logits = text_logits.i(0)?.to_dtype(DType::F32)?;
+            let text_lps = candle_nn::ops::log_softmax(&text_logits, candle::D::Minus1)?;
+            for (s, lps) in steps.clone().zip(text_lps.to_vec2::<f32>()?) {
+                match lps.get(text_tokens[s] as usize) {
+                    None => candle::bail!("invalid text token {} at step {s}", text_tokens[s]),
+                    Some(&lp) => scores.text.push(lp),
+                }
+            }
+            // At step s, the depformer samples the tokens of frame s - delay for each of the
+            // generated codebooks.
+            let targets: Vec<Vec<u32>> = steps
+                .clone()
+                .map(|s| {
+                    generated_codebooks
+                        .iter()
+                        .zip(delays.iter())
+                        .map(
+                            |(&c, &d)| if s < d { audio_pad_token } else { audio_tokens[s - d][c] },
+                        )
+                        .collect()
+                })
+                .collect();
+            let text_tokens = &text_tokens[steps];
+            let audio_lps = match self {
+                Self::Lm(m) => match m.depformer.as_mut() {
+                    None => None,
+                    Some(m) => {
+                        Some(m.log_probs(chunk_start, &ys, text_tokens, &targets, &delays)?)
+                    }
+                },
+                Self::QuantizedLm(m) => match m.depformer.as_mut() {
+                    None => None,
+                    Some(m) => {
+                        Some(m.log_probs(chunk_start, &ys, text_tokens, &targets, &delays)?)
+                    }
+                },
+            };
+            scores.audio.extend(audio_lps.unwrap_or_else(|| vec![vec![]; chunk_len]))
+        }
+        Ok(scores)
+    }
+
     pub fn depformer_sample(
         &mut self,
         step_idx: usize,
         xs: &Tensor,
         text_token: Option<u32>,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        delays: &[usize],
+        lps: &mut [Sampler],
     ) -> Result<Option<Vec<u32>>> {
         let sample = match self {
             Self::Lm(m) => match &mut m.depformer {
                 None => None,
                 Some(m) => {
-                    let sample = m.sample(step_idx, xs, text_token, lp)?;
+                    let sample = m.sample(step_idx, xs, text_token, delays, lps)?;
                     Some(sample)
                 }
             },
             Self::QuantizedLm(m) => match &mut m.depformer {
                 None => None,
                 Some(m) => {
-                    let sample = m.sample(step_idx, xs, text_token, lp)?;
+                    let sample = m.sample(step_idx, xs, text_token, delays, lps)?;
+                    Some(sample)
+                }
+            },
+        };
+        Ok(sample)
+    }
+
+    /// Same as `depformer_sample` but with classifier free guidance, `xs` holds the hidden
+    /// states of the conditional and unconditional branches, `(2, 1, d)`.
+    pub fn depformer_sample_cfg(
+        &mut self,
+        step_idx: usize,
+        xs: &Tensor,
+        cfg_alpha: f64,
+        text_token: Option<u32>,
+        delays: &[usize],
+        lps: &mut [Sampler],
+    ) -> Result<Option<Vec<u32>>> {
+        let sample = match self {
+            Self::Lm(m) => match &mut m.depformer {
+                None => None,
+                Some(m) => {
+                    let sample = m.sample_cfg(step_idx, xs, cfg_alpha, text_token, delays, lps)?;
+                    Some(sample)
+                }
+            },
+            Self::QuantizedLm(m) => match &mut m.depformer {
+                None => None,
+                Some(m) => {
+                    let sample = m.sample_cfg(step_idx, xs, cfg_alpha, text_token, delays, lps)?;
                     Some(sample)
                 }
             },
@@ -547,6 +940,20 @@ impl LmModel {
             Self::QuantizedLm(m) => m.device(),
         }
     }
+
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        match self {
+            Self::Lm(m) => m.save_state(prefix, state),
+            Self::QuantizedLm(m) => m.save_state(prefix, state),
+        }
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        match self {
+            Self::Lm(m) => m.load_state(prefix, state),
+            Self::QuantizedLm(m) => m.load_state(prefix, state),
+        }
+    }
 }
 
 pub fn load<P: AsRef<std::path::Path>>(
@@ -555,19 +962,9 @@ pub fn load<P: AsRef<std::path::Path>>(
     quantized: bool,
     dev: &Device,
 ) -> Result<LmModel> {
-    let cfg = Config::v0_1();
-    let model = if quantized {
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(model_file, dev)?;
-        let lm = crate::quantized_lm::Lm::new(&cfg, vb)?;
-        LmModel::QuantizedLm(lm)
-    } else {
-        let vb =
-            unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[model_file], dtype, dev)? };
-        let lm = Lm::new(&cfg, vb)?;
-        LmModel::Lm(lm)
-    };
-    Ok(model)
+    let loader = crate::loader::ModelLoader::new(model_file).lm_device(dev).lm_dtype(dtype);
+    let loader = if quantized { loader.format(crate::loader::WeightFormat::Gguf) } else { loader };
+    loader.load_lm()
 }
 
 pub fn load_streaming<P: AsRef<std::path::Path>>(
@@ -575,20 +972,11 @@ pub fn load_streaming<P: AsRef<std::path::Path>>(
     dtype: DType,
     dev: &Device,
 ) -> Result<LmModel> {
-    let cfg = Config::v0_1_streaming(8);
-    let is_gguf = model_file.as_ref().extension().map_or(false, |v| v == "gguf");
-    let lm = if is_gguf {
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(model_file, dev)?;
-        let lm = crate::quantized_lm::Lm::new(&cfg, vb)?;
-        LmModel::QuantizedLm(lm)
-    } else {
-        let vb =
-            unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[model_file], dtype, dev)? };
-        let lm = Lm::new(&cfg, vb)?;
-        LmModel::Lm(lm)
-    };
-    Ok(lm)
+    crate::loader::ModelLoader::new(model_file)
+        .lm_device(dev)
+        .lm_dtype(dtype)
+        .default_lm_config(Config::v0_1_streaming(8))
+        .load_lm()
 }
 
 pub fn load_streaming_both_ways<P: AsRef<std::path::Path>>(
@@ -596,18 +984,358 @@ pub fn load_streaming_both_ways<P: AsRef<std::path::Path>>(
     dtype: DType,
     dev: &Device,
 ) -> Result<LmModel> {
-    let cfg = Config::v0_1_streaming(16);
-    let is_gguf = model_file.as_ref().extension().map_or(false, |v| v == "gguf");
-    let lm = if is_gguf {
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(model_file, dev)?;
-        let lm = crate::quantized_lm::Lm::new(&cfg, vb)?;
-        LmModel::QuantizedLm(lm)
-    } else {
-        let vb =
-            unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[model_file], dtype, dev)? };
-        let lm = Lm::new(&cfg, vb)?;
-        LmModel::Lm(lm)
-    };
-    Ok(lm)
+    crate::loader::ModelLoader::new(model_file)
+        .lm_device(dev)
+        .lm_dtype(dtype)
+        .default_lm_config(Config::v0_1_streaming(16))
+        .load_lm()
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+    use candle::quantized::{gguf_file, GgmlDType, QTensor};
+
+    /// A two layer model with tiny dimensions, a depformer with two slices and four audio
+    /// streams, e.g. two generated and two input codebooks. The audio eos token is 7 and the
+    /// audio padding token 8.
+    pub(crate) fn small_config() -> Config {
+        let mut cfg = Config::v0_1_streaming(2);
+        for t in [&mut cfg.transformer, &mut cfg.depformer.as_mut().unwrap().transformer] {
+            t.d_model = 16;
+            t.num_heads = 2;
+            t.num_layers = 1;
+            t.dim_feedforward = 32;
+        }
+        cfg.transformer.num_layers = 2;
+        cfg.text_in_vocab_size = 11;
+        cfg.text_out_vocab_size = 10;
+        cfg.audio_vocab_size = 9;
+        cfg.audio_codebooks = 4;
+        cfg
+    }
+
+    /// Creates the variables for `cfg` in `vm` with random values.
+    pub(crate) fn randomize(cfg: &Config, vm: &candle_nn::VarMap) -> Result<()> {
+        let vb = VarBuilder::from_varmap(vm, DType::F32, &Device::Cpu);
+        Lm::new(cfg, vb)?;
+        crate::streaming::test_utils::randomize(vm, 0.3)
+    }
+
+    pub(crate) fn small_lm(cfg: &Config, vm: &candle_nn::VarMap) -> Result<Lm> {
+        if vm.all_vars().is_empty() {
+            randomize(cfg, vm)?
+        }
+        Lm::new(cfg, VarBuilder::from_varmap(vm, DType::F32, &Device::Cpu))
+    }
+
+    /// The quantized version of `small_lm`, the weights are stored as f32.
+    pub(crate) fn small_quantized_lm(
+        cfg: &Config,
+        vm: &candle_nn::VarMap,
+    ) -> Result<crate::quantized_lm::Lm> {
+        if vm.all_vars().is_empty() {
+            randomize(cfg, vm)?
+        }
+        let bytes = gguf_bytes(vm)?;
+        let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf_buffer(
+            &bytes,
+            &Device::Cpu,
+        )?;
+        crate::quantized_lm::Lm::new(cfg, vb)
+    }
+
+    /// The weights from `vm` in the gguf format, the tensors are stored as f32 so that the
+    /// quantized model matches the float one up to rounding errors.
+    pub(crate) fn gguf_bytes(vm: &candle_nn::VarMap) -> Result<Vec<u8>> {
+        let data = vm.data().lock().unwrap();
+        let mut qtensors = Vec::with_capacity(data.len());
+        for (name, var) in data.iter() {
+            qtensors.push((name.as_str(), QTensor::quantize(var.as_tensor(), GgmlDType::F32)?))
+        }
+        let qtensors = qtensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>();
+        let mut buffer = std::io::Cursor::new(vec![]);
+        gguf_file::write(&mut buffer, &[], &qtensors)?;
+        Ok(buffer.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::{small_config, small_lm, small_quantized_lm};
+    use super::*;
+    use crate::streaming::test_utils::max_diff;
+
+    type Ids = (Tensor, Vec<Option<Tensor>>);
+
+    // Deterministic text and audio ids for `steps` single steps.
+    fn step_ids(cfg: &Config, steps: usize) -> Result<Vec<Ids>> {
+        let dev = &Device::Cpu;
+        let mut ids = Vec::with_capacity(steps);
+        for step in 0..steps {
+            let text_id = (step * 7 + 3) % cfg.text_in_vocab_size;
+            let text_ids = Tensor::new(&[[text_id as u32]], dev)?;
+            let audio_ids = (0..cfg.audio_codebooks)
+                .map(|c| {
+                    let id = (step * 5 + c * 3) % cfg.audio_vocab_size;
+                    Ok(Some(Tensor::new(&[[id as u32]], dev)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            ids.push((text_ids, audio_ids))
+        }
+        Ok(ids)
+    }
+
+    // Runs some steps, saves the state and restores it in a fresh model, the following steps
+    // should match an uninterrupted run.
+    fn check_state_round_trip(mk: impl Fn() -> Result<LmModel>) -> Result<()> {
+        let ids = step_ids(&small_config(), 8)?;
+        let mut model = mk()?;
+        let mut logits = vec![];
+        for (text_ids, audio_ids) in ids.iter() {
+            logits.push(model.forward(Some(text_ids.clone()), audio_ids.clone())?.0)
+        }
+
+        let mut model = mk()?;
+        for (text_ids, audio_ids) in ids[..5].iter() {
+            model.forward(Some(text_ids.clone()), audio_ids.clone())?;
+        }
+        let mut state = StateDict::new();
+        model.save_state("lm", &mut state)?;
+        let state = streaming::serialize_state(&state)?;
+        let state = streaming::deserialize_state(&state, &Device::Cpu)?;
+        let mut model = mk()?;
+        model.load_state("lm", &state)?;
+        for (step, (text_ids, audio_ids)) in ids.iter().enumerate().skip(5) {
+            let step_logits = model.forward(Some(text_ids.clone()), audio_ids.clone())?.0;
+            let diff = max_diff(&step_logits, &logits[step])?;
+            assert!(diff < 1e-5, "step {step} diff {diff}");
+        }
+        Ok(())
+    }
+
+    // Runs all the steps in a single forward call and compares the logits and hidden states with
+    // the ones from stepping through the same ids one at a time.
+    fn check_multi_step(mk: impl Fn() -> Result<LmModel>, with_text: bool) -> Result<()> {
+        let cfg = small_config();
+        let ids = step_ids(&cfg, 6)?;
+        let text_ids = |ids: &Tensor| if with_text { Some(ids.clone()) } else { None };
+        let mut model = mk()?;
+        let mut steps = vec![];
+        for (text_ids_, audio_ids) in ids.iter() {
+            steps.push(model.forward(text_ids(text_ids_), audio_ids.clone())?)
+        }
+
+        let all_text = ids.iter().map(|(t, _)| t).collect::<Vec<_>>();
+        let all_text = Tensor::cat(&all_text, 1)?;
+        let all_audio = (0..cfg.audio_codebooks)
+            .map(|c| {
+                let ids = ids.iter().map(|(_, a)| a[c].clone().unwrap()).collect::<Vec<_>>();
+                Ok(Some(Tensor::cat(&ids, 1)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut model = mk()?;
+        let (logits, ys) = model.forward(text_ids(&all_text), all_audio)?;
+        assert_eq!(logits.dims(), &[1, ids.len(), cfg.text_out_vocab_size]);
+        for (step, (step_logits, step_ys)) in steps.iter().enumerate() {
+            let diff = max_diff(step_logits, &logits.narrow(1, step, 1)?)?;
+            assert!(diff < 1e-5, "step {step} logits diff {diff}");
+            let diff = max_diff(step_ys, &ys.narrow(1, step, 1)?)?;
+            assert!(diff < 1e-5, "step {step} hidden states diff {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn multi_step_forward() -> Result<()> {
+        let cfg = small_config();
+        let vm = candle_nn::VarMap::new();
+        for with_text in [true, false] {
+            check_multi_step(|| Ok(LmModel::Lm(small_lm(&cfg, &vm)?)), with_text)?;
+            check_multi_step(
+                || Ok(LmModel::QuantizedLm(small_quantized_lm(&cfg, &vm)?)),
+                with_text,
+            )?;
+        }
+
+        // All the token ids have to share the same shape.
+        let mut model = small_lm(&cfg, &vm)?;
+        let dev = &Device::Cpu;
+        let audio_ids = vec![Some(Tensor::zeros((1, 3), DType::U32, dev)?); cfg.audio_codebooks];
+        let text_ids = Tensor::zeros((1, 2), DType::U32, dev)?;
+        assert!(model.forward(Some(text_ids), audio_ids).is_err());
+        Ok(())
+    }
+
+    // The text and depformer log-probabilities from stepping through the sequence one step at a
+    // time, the reference for `LmModel::score`.
+    fn step_scores(
+        lm: &mut Lm,
+        text_tokens: &[u32],
+        audio_tokens: &[Vec<u32>],
+        config: &crate::lm_generate_multistream::Config,
+    ) -> Result<Scores> {
+        use crate::streaming::StreamingModule;
+        let dev = &Device::Cpu;
+        let layout = &config.stream_layout;
+        let pad = config.audio_pad_token();
+        let delays = layout.generated_delays();
+        let log_softmax = |xs: &Tensor| -> Result<Vec<f32>> {
+            candle_nn::ops::log_softmax(&xs.flatten_all()?, 0)?.to_vec1::<f32>()
+        };
+        lm.reset_state();
+        let mut scores = Scores { text: vec![], audio: vec![] };
+        for (s, &text_token) in text_tokens.iter().enumerate() {
+            let text_id = if s == 0 { config.text_start_token } else { text_tokens[s - 1] };
+            let audio_ids = (0..layout.total_codebooks())
+                .map(|c| {
+                    let d = layout.delay(c);
+                    let id = if s <= d { pad } else { audio_tokens[s - d - 1][c].min(pad) };
+                    Ok(Some(Tensor::new(&[[id]], dev)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let (logits, ys) = lm.forward(Some(Tensor::new(&[[text_id]], dev)?), audio_ids)?;
+            scores.text.push(log_softmax(&logits)?[text_token as usize]);
+
+            let depformer = lm.depformer.as_mut().unwrap();
+            let mut lps = vec![];
+            let mut last_token = text_token;
+            for (slice_idx, (&c, &d)) in
+                layout.generated_codebooks().iter().zip(delays.iter()).enumerate()
+            {
+                if slice_idx == 0 {
+                    depformer.slices[0].transformer.reset_state();
+                } else {
+                    let (lhs, rhs) = depformer.slices.split_at_mut(slice_idx);
+                    rhs[0].transformer.copy_state(&lhs[slice_idx - 1].transformer)?
+                }
+                let slice = &mut depformer.slices[slice_idx];
+                let token = slice_input_token(slice_idx, s, last_token, &delays, pad);
+                let xs = slice.linear_in.forward(&ys)?;
+                let xs = xs.broadcast_add(&slice.emb.forward(&Tensor::new(&[[token]], dev)?)?)?;
+                let logits = slice.transformer.forward(&xs)?.apply(&slice.linear_out)?;
+                let target = if s < d { pad } else { audio_tokens[s - d][c] };
+                lps.push(log_softmax(&logits)?.get(target as usize).copied());
+                // Tokens that have not been generated are replaced by padding.
+                last_token = target.min(pad)
+            }
+            scores.audio.push(lps)
+        }
+        Ok(scores)
+    }
+
+    fn check_scores(lhs: &Scores, rhs: &Scores) {
+        assert_eq!(lhs.text.len(), rhs.text.len());
+        for (step, (l, r)) in lhs.text.iter().zip(rhs.text.iter()).enumerate() {
+            assert!((l - r).abs() < 1e-4, "text step {step}: {l} {r}")
+        }
+        assert_eq!(lhs.audio.len(), rhs.audio.len());
+        for (step, (l, r)) in lhs.audio.iter().zip(rhs.audio.iter()).enumerate() {
+            assert_eq!(l.len(), r.len());
+            for (slice_idx, (l, r)) in l.iter().zip(r.iter()).enumerate() {
+                match (l, r) {
+                    (Some(l), Some(r)) => {
+                        assert!((l - r).abs() < 1e-4, "step {step} slice {slice_idx}: {l} {r}")
+                    }
+                    (None, None) => {}
+                    _ => panic!("step {step} slice {slice_idx}: {l:?} {r:?}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn score() -> Result<()> {
+        use crate::lm_generate_multistream::{Config, StreamKind, StreamLayout};
+
+        let cfg = small_config();
+        let config = Config {
+            stream_layout: StreamLayout::new(vec![StreamKind::Generated, StreamKind::Input], 2, 1),
+            audio_vocab_size: cfg.audio_vocab_size,
+            text_pad_token: 3,
+            text_eop_token: 0,
+            text_start_token: 10,
+        };
+        // Spans multiple chunks, the audio tokens include some padding.
+        let num_steps = SCORE_CHUNK_SIZE + 20;
+        let text_tokens: Vec<u32> = (0..num_steps).map(|s| (s * 7 % 10) as u32).collect();
+        let audio_tokens: Vec<Vec<u32>> = (0..num_steps)
+            .map(|s| (0..4).map(|c| ((s * 5 + c * 3) % 9) as u32).collect())
+            .collect();
+
+        let vm = candle_nn::VarMap::new();
+        let mut lm = small_lm(&cfg, &vm)?;
+        let expected = step_scores(&mut lm, &text_tokens, &audio_tokens, &config)?;
+        assert!(expected.audio.iter().flatten().any(|lp| lp.is_none()));
+        let mut model = LmModel::Lm(lm);
+        check_scores(&model.score(&text_tokens, &audio_tokens, &config)?, &expected);
+        let mut model = LmModel::QuantizedLm(small_quantized_lm(&cfg, &vm)?);
+        check_scores(&model.score(&text_tokens, &audio_tokens, &config)?, &expected);
+        Ok(())
+    }
+
+    // A sampler that can only return `token`.
+    fn only(token: u32, vocab_size: u32) -> Sampler {
+        let banned = (0..vocab_size).filter(|&t| t != token).collect();
+        Sampler::new(0).with(crate::sampling::TokenBan(banned))
+    }
+
+    #[test]
+    fn depformer_samplers() -> Result<()> {
+        let cfg = small_config();
+        let vm = candle_nn::VarMap::new();
+        let vocab_size = cfg.audio_vocab_size as u32 - 1;
+        let text_ids = Tensor::new(&[[1u32]], &Device::Cpu)?;
+        for mut model in [
+            LmModel::Lm(small_lm(&cfg, &vm)?),
+            LmModel::QuantizedLm(small_quantized_lm(&cfg, &vm)?),
+        ] {
+            let (_, ys) = model.forward(Some(text_ids.clone()), vec![None; cfg.audio_codebooks])?;
+            let ys2 = Tensor::cat(&[&ys, &ys], 0)?;
+            let mut sample = |lps: &mut [Sampler]| -> Result<Vec<Option<Vec<u32>>>> {
+                let tokens = model.depformer_sample(0, &ys, Some(1), &[0, 1], lps)?;
+                let cfg_tokens = model.depformer_sample_cfg(0, &ys2, 1.5, Some(1), &[0, 1], lps)?;
+                Ok(vec![tokens, cfg_tokens])
+            };
+            // Each slice uses its own sampler.
+            let tokens = sample(&mut [only(2, vocab_size), only(5, vocab_size)])?;
+            assert_eq!(tokens, [Some(vec![2, 5]), Some(vec![2, 5])]);
+            // A single sampler is shared by all the slices.
+            let tokens = sample(&mut [only(4, vocab_size)])?;
+            assert_eq!(tokens, [Some(vec![4, 4]), Some(vec![4, 4])]);
+            // Otherwise there must be one sampler per slice.
+            for num_samplers in [0, 3] {
+                let mut lps = (0..num_samplers).map(Sampler::new).collect::<Vec<_>>();
+                assert!(model.depformer_sample(0, &ys, Some(1), &[0, 1], &mut lps).is_err());
+                let res = model.depformer_sample_cfg(0, &ys2, 1.5, Some(1), &[0, 1], &mut lps);
+                assert!(res.is_err());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn slice_input_tokens() {
+        use crate::lm_generate_multistream::Config;
+
+        // The depformer used to hardcode the delays of the v0_1 models: slices 1 and 9 take the
+        // semantic tokens, which are not delayed, as input.
+        for config in [Config::v0_1(), Config::v0_1_two_ways()] {
+            let delays = config.stream_layout.generated_delays();
+            for slice_idx in 0..delays.len() {
+                for step_idx in 0..4 {
+                    let token = slice_input_token(slice_idx, step_idx, 42, &delays, 7);
+                    let expected = slice_idx < 2 || slice_idx == 9 || step_idx > 1;
+                    assert_eq!(token, if expected { 42 } else { 7 }, "{slice_idx} {step_idx}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn state_round_trip() -> Result<()> {
+        let cfg = small_config();
+        let vm = candle_nn::VarMap::new();
+        check_state_round_trip(|| Ok(LmModel::Lm(small_lm(&cfg, &vm)?)))
+    }
 }
@@ -2,8 +2,8 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::sampling::{Context, Sampler};
 use candle::{IndexOp, Tensor};
-use candle_transformers::generation::LogitsProcessor;
 
 const UNGENERATED: u32 = u32::MAX;
 
@@ -38,13 +38,18 @@ impl Config {
     pub fn audio_codebooks(&self) -> usize {
         self.audio_codebooks
     }
+
+    /// The delay in steps of each audio codebook, only the first codebook is not delayed.
+    pub fn delays(&self) -> Vec<usize> {
+        (0..self.audio_codebooks).map(|c| if c == 0 { 0 } else { self.acoustic_delay }).collect()
+    }
 }
 
 pub struct State {
     model: crate::lm::LmModel,
     audio_tokens: Vec<Vec<u32>>,
-    audio_lp: LogitsProcessor,
-    text_lp: LogitsProcessor,
+    audio_lps: Vec<Sampler>,
+    text_lp: Sampler,
     step_idx: usize,
     config: Config,
     npads: i32,
@@ -54,13 +59,13 @@ impl State {
     pub fn new(
         model: crate::lm::LmModel,
         max_step_idx: usize,
-        audio_lp: LogitsProcessor,
-        text_lp: LogitsProcessor,
+        audio_lps: Vec<Sampler>,
+        text_lp: Sampler,
         config: Config,
     ) -> Self {
         let audio_tokens: Vec<Vec<u32>> =
             vec![vec![UNGENERATED; config.audio_codebooks]; max_step_idx + config.acoustic_delay];
-        Self { model, audio_tokens, audio_lp, text_lp, step_idx: 0, npads: 0, config }
+        Self { model, audio_tokens, audio_lps, text_lp, step_idx: 0, npads: 0, config }
     }
 
     pub fn audio_codebooks(&self) -> usize {
@@ -159,13 +164,23 @@ impl State {
         let (text_logits, ys) = self.model.forward(text_token, codes)?;
         let text_logits = text_logits.i((0, 0))?;
         let text_token = match force_text_token {
-            None => self.text_lp.sample_f(&text_logits, |prs| {
-                prs[self.config.text_bos_token as usize] = 1e-9;
-                if self.npads > 40 {
-                    let mul = 2f32.powi(self.npads - 40);
-                    prs[self.config.text_eos_token as usize] *= mul;
-                }
-            })?,
+            None => {
+                let ctx = Context::new(self.step_idx);
+                let bos_token = self.config.text_bos_token as usize;
+                let eos_token = self.config.text_eos_token as usize;
+                let npads = self.npads;
+                self.text_lp.sample_with(
+                    &ctx,
+                    &text_logits,
+                    |logits| logits[bos_token] = f32::NEG_INFINITY,
+                    |logits| {
+                        // Doubles the eos probability for each pad after the first 40 ones.
+                        if npads > 40 {
+                            logits[eos_token] += (npads - 40) as f32 * std::f32::consts::LN_2;
+                        }
+                    },
+                )?
+            }
             Some(t) => t,
         };
         if text_token == self.config.text_pad_token {
@@ -175,7 +190,13 @@ impl State {
         }
 
         let last_audio_tokens = if gen_audio {
-            self.model.depformer_sample(self.step_idx, &ys, Some(text_token), &mut self.audio_lp)?
+            self.model.depformer_sample(
+                self.step_idx,
+                &ys,
+                Some(text_token),
+                &self.config.delays(),
+                &mut self.audio_lps,
+            )?
         } else {
             None
         };
@@ -2,17 +2,102 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use candle::{IndexOp, Tensor};
-use candle_transformers::generation::LogitsProcessor;
+use crate::sampling::{Context, Rng, Sampler};
+use crate::streaming::{self, StateDict, StreamInfo};
+use candle::{Device, IndexOp, Tensor};
 
 pub const UNGENERATED: u32 = u32::MAX;
 
+// The maximum number of steps processed by a single forward pass of the model when prefilling.
+const PREFILL_CHUNK_SIZE: usize = 256;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamKind {
+    /// The tokens for this stream are sampled by the depformer.
+    Generated,
+    /// The tokens for this stream are provided when calling `State::step`, e.g. the user audio.
+    Input,
+}
+
+/// The layout of the audio streams handled by a multistream model. Each stream, e.g. one per
+/// speaker, uses the same number of codebooks and the same delay pattern. The audio tokens for
+/// a step are ordered by stream then by codebook.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamLayout {
+    pub streams: Vec<StreamKind>,
+    pub codebooks_per_stream: usize,
+    /// The delay in steps of each codebook within a stream, `codebooks_per_stream` values.
+    pub delays: Vec<usize>,
+}
+
+impl StreamLayout {
+    /// A layout where the first codebook of each stream, the semantic one, has no delay and the
+    /// acoustic codebooks are delayed by `acoustic_delay` steps.
+    pub fn new(
+        streams: Vec<StreamKind>,
+        codebooks_per_stream: usize,
+        acoustic_delay: usize,
+    ) -> Self {
+        let delays =
+            (0..codebooks_per_stream).map(|c| if c == 0 { 0 } else { acoustic_delay }).collect();
+        Self { streams, codebooks_per_stream, delays }
+    }
+
+    pub fn total_codebooks(&self) -> usize {
+        self.streams.len() * self.codebooks_per_stream
+    }
+
+    /// The delay of a codebook, `codebook` indexes the codebooks of all the streams.
+    pub fn delay(&self, codebook: usize) -> usize {
+        self.delays[codebook % self.codebooks_per_stream]
+    }
+
+    pub fn max_delay(&self) -> usize {
+        self.delays.iter().copied().max().unwrap_or(0)
+    }
+
+    fn codebooks(&self, kind: StreamKind) -> Vec<usize> {
+        let cps = self.codebooks_per_stream;
+        self.streams
+            .iter()
+            .enumerate()
+            .filter(|(_, &k)| k == kind)
+            .flat_map(|(stream_idx, _)| stream_idx * cps..(stream_idx + 1) * cps)
+            .collect()
+    }
+
+    /// The indexes of the codebooks sampled by the depformer, in sampling order.
+    pub fn generated_codebooks(&self) -> Vec<usize> {
+        self.codebooks(StreamKind::Generated)
+    }
+
+    /// The indexes of the codebooks provided as input.
+    pub fn input_codebooks(&self) -> Vec<usize> {
+        self.codebooks(StreamKind::Input)
+    }
+
+    /// The delays of the codebooks sampled by the depformer, in sampling order.
+    pub fn generated_delays(&self) -> Vec<usize> {
+        self.generated_codebooks().into_iter().map(|c| self.delay(c)).collect()
+    }
+
+    pub fn check(&self) -> candle::Result<()> {
+        if self.delays.len() != self.codebooks_per_stream {
+            candle::bail!(
+                "stream layout has {} delays for {} codebooks per stream",
+                self.delays.len(),
+                self.codebooks_per_stream
+            )
+        }
+        Ok(())
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
-    pub generated_audio_codebooks: usize,
-    pub input_audio_codebooks: usize,
+    pub stream_layout: StreamLayout,
     pub audio_vocab_size: usize,
-    pub acoustic_delay: usize,
     pub text_pad_token: u32,
     pub text_eop_token: u32,
     pub text_start_token: u32,
@@ -21,10 +106,8 @@ pub struct Config {
 impl Config {
     pub fn v0_1() -> Self {
         Self {
-            generated_audio_codebooks: 8,
-            input_audio_codebooks: 8,
+            stream_layout: StreamLayout::new(vec![StreamKind::Generated, StreamKind::Input], 8, 2),
             audio_vocab_size: 2049,
-            acoustic_delay: 2,
             text_eop_token: 0,
             text_pad_token: 3,
             text_start_token: 32000,
@@ -33,10 +116,12 @@ impl Config {
 
     pub fn v0_1_two_ways() -> Self {
         Self {
-            generated_audio_codebooks: 16,
-            input_audio_codebooks: 0,
+            stream_layout: StreamLayout::new(
+                vec![StreamKind::Generated, StreamKind::Generated],
+                8,
+                2,
+            ),
             audio_vocab_size: 2049,
-            acoustic_delay: 2,
             text_eop_token: 0,
             text_pad_token: 3,
             text_start_token: 32000,
@@ -48,104 +133,226 @@ impl Config {
     }
 
     pub fn total_audio_codebooks(&self) -> usize {
-        self.generated_audio_codebooks + self.input_audio_codebooks
+        self.stream_layout.total_codebooks()
+    }
+
+    pub fn generated_audio_codebooks(&self) -> usize {
+        self.stream_layout.generated_codebooks().len()
+    }
+
+    pub fn input_audio_codebooks(&self) -> usize {
+        self.stream_layout.input_codebooks().len()
+    }
+
+    /// Timing of the full speech-to-speech pipeline, from input pcm samples to output pcm
+    /// samples. The language model runs one step per mimi frame and all the tokens for a frame
+    /// are only available once the most delayed codebook has been generated.
+    pub fn pipeline_stream_info(&self, mimi: &crate::encodec::Encodec) -> StreamInfo {
+        let delay = 1 + self.stream_layout.max_delay();
+        let lm = StreamInfo { stride_in: 1, stride_out: 1, delay };
+        mimi.encode_stream_info().then(&lm).then(&mimi.decode_stream_info())
     }
 }
 
 pub struct State {
     model: crate::lm::LmModel,
+    // The token history, index 0 corresponds to step history_start. Steps before that have been
+    // dropped.
     audio_tokens: Vec<Vec<u32>>,
     text_tokens: Vec<u32>,
-    audio_lp: LogitsProcessor,
-    text_lp: LogitsProcessor,
+    // Whether each step of the history comes from a prompt, these steps are hidden from the
+    // unconditional branch when using classifier free guidance.
+    prompt_steps: Vec<bool>,
+    history_start: usize,
+    max_history: Option<usize>,
+    audio_lps: Vec<Sampler>,
+    text_lp: Sampler,
     step_idx: usize,
-    pad_mult: Option<f32>,
-    // For repetition penalty, we provide the context len (in text tokens) and the penalty.
-    repetition_penalty: Option<(usize, f32)>,
+    cfg_alpha: Option<f64>,
     config: Config,
 }
 
+fn save_rng(key: &str, rng: &Rng, state: &mut StateDict) -> candle::Result<()> {
+    state.insert(key.to_string(), Tensor::new(rng.state() as i64, &Device::Cpu)?);
+    Ok(())
+}
+
+fn load_rng(key: &str, state: &StateDict) -> candle::Result<Option<Rng>> {
+    match state.get(key) {
+        None => Ok(None),
+        Some(v) => Ok(Some(Rng::new(v.to_dtype(candle::DType::I64)?.to_scalar::<i64>()? as u64))),
+    }
+}
+
 impl State {
+    /// Creates a new generation state, the token history grows with the number of steps unless
+    /// a maximum history length is set with `set_max_history`. `audio_lps` holds a sampler per
+    /// generated codebook, or a single sampler shared by all of them. Fails if the stream layout
+    /// of `config` is invalid.
     pub fn new(
         model: crate::lm::LmModel,
-        max_step_idx: usize,
-        audio_lp: LogitsProcessor,
-        text_lp: LogitsProcessor,
-        pad_mult: Option<f32>,
-        repetition_penalty: Option<(usize, f32)>,
+        audio_lps: Vec<Sampler>,
+        text_lp: Sampler,
         config: Config,
-    ) -> Self {
-        let audio_tokens: Vec<Vec<u32>> = vec![
-            vec![UNGENERATED; config.total_audio_codebooks()];
-            max_step_idx + config.acoustic_delay
-        ];
-        let text_tokens = vec![UNGENERATED; max_step_idx + config.acoustic_delay];
-        Self {
+    ) -> candle::Result<Self> {
+        config.stream_layout.check()?;
+        Ok(Self {
             model,
-            audio_tokens,
-            text_tokens,
-            audio_lp,
+            audio_tokens: vec![],
+            text_tokens: vec![],
+            prompt_steps: vec![],
+            history_start: 0,
+            max_history: None,
+            audio_lps,
             text_lp,
             step_idx: 0,
-            pad_mult,
-            repetition_penalty,
+            cfg_alpha: None,
             config,
-        }
+        })
     }
 
     pub fn step_idx(&self) -> usize {
         self.step_idx
     }
 
-    fn audio_pad_token(&self) -> u32 {
-        self.config.audio_pad_token()
+    /// The step index of the first tokens returned by `audio_tokens` and `text_tokens`, this is
+    /// non-zero once some of the history has been dropped.
+    pub fn history_start(&self) -> usize {
+        self.history_start
     }
 
-    pub fn config(&self) -> &Config {
-        &self.config
+    /// Limits the token history to the last `max_history` steps, the older steps get dropped
+    /// as the generation goes on. The steps that are still required for sampling, i.e. the
+    /// acoustic delay and the text history used by the sampler, are always kept so the memory usage
+    /// is proportional to the largest of these windows rather than to the session length.
+    /// `None` keeps the whole history.
+    pub fn set_max_history(&mut self, max_history: Option<usize>) {
+        self.max_history = max_history;
+        if let Some(max_history) = max_history {
+            self.drop_history(self.step_idx.saturating_sub(max_history))
+        }
     }
 
-    fn apply_repetition_penalty(&self, logits: Tensor) -> candle::Result<Tensor> {
-        let logits = match self.repetition_penalty {
-            None => logits,
-            Some((_, penalty)) if penalty == 1. => logits,
-            Some((context_size, penalty)) => {
-                let device = logits.device();
-                let mut logits = logits.to_dtype(candle::DType::F32)?.to_vec1::<f32>()?;
-                let mut already_seen = std::collections::HashSet::new();
-                let mut non_pad_tokens = 0;
-                for &token_id in self.text_tokens(false).iter().rev() {
-                    if token_id == self.config.text_pad_token
-                        || token_id == self.config.text_eop_token
-                        || token_id == self.config.text_start_token
-                    {
-                        continue;
-                    }
-                    // Look at the last [context_size] tokens at most, count all tokens there even
-                    // if we already saw them.
-                    if non_pad_tokens >= context_size {
-                        break;
-                    }
-                    non_pad_tokens += 1;
+    /// Enables classifier free guidance: the model processes a batch of two sequences, the
+    /// conditional one sees the text and audio prompts while these are padded in the
+    /// unconditional one. The text and audio logits are combined as
+    /// `cfg_alpha * cond - (cfg_alpha - 1) * uncond` before sampling. This has to be set before
+    /// the first step as it changes the batch size of the model.
+    pub fn set_cfg_alpha(&mut self, cfg_alpha: Option<f64>) -> candle::Result<()> {
+        if self.step_idx > 0 && cfg_alpha.is_some() != self.cfg_alpha.is_some() {
+            candle::bail!("cfg has to be enabled or disabled before the first step")
+        }
+        self.cfg_alpha = cfg_alpha;
+        Ok(())
+    }
 
-                    if already_seen.contains(&token_id) {
-                        continue;
-                    }
+    fn batch_size(&self) -> usize {
+        if self.cfg_alpha.is_some() {
+            2
+        } else {
+            1
+        }
+    }
 
-                    already_seen.insert(token_id);
-                    if let Some(logit) = logits.get_mut(token_id as usize) {
-                        if *logit >= 0. {
-                            *logit /= penalty
-                        } else {
-                            *logit *= penalty
-                        }
-                    }
+    /// Drops the part of the token history that is not needed anymore to sample the next steps.
+    pub fn trim_history(&mut self) {
+        self.drop_history(self.step_idx)
+    }
+
+    // The first step that has to be kept for the generation to proceed: the audio tokens are
+    // read with the acoustic delay and the text sampler may look at the last text tokens.
+    fn required_history_start(&self) -> usize {
+        let mut start = self.step_idx.saturating_sub(self.config.stream_layout.max_delay() + 1);
+        let context_size = self.text_lp.history_len();
+        let mut non_pad_tokens = 0;
+        for (idx, &token_id) in self.text_tokens(false).iter().enumerate().rev() {
+            if non_pad_tokens >= context_size {
+                break;
+            }
+            start = usize::min(start, self.history_start + idx);
+            if !self.is_special_text_token(token_id) {
+                non_pad_tokens += 1
+            }
+        }
+        start
+    }
+
+    // The last non-special text tokens as used by the text sampler, most recent last.
+    fn text_history(&self) -> Vec<u32> {
+        let mut history: Vec<u32> = self
+            .text_tokens(false)
+            .iter()
+            .rev()
+            .filter(|&&t| t != UNGENERATED && !self.is_special_text_token(t))
+            .take(self.text_lp.history_len())
+            .copied()
+            .collect();
+        history.reverse();
+        history
+    }
+
+    // Drops the history before step `start`, or before the required history if it is earlier.
+    fn drop_history(&mut self, start: usize) {
+        let start = usize::min(start, self.required_history_start());
+        if start > self.history_start {
+            let n = usize::min(start - self.history_start, self.audio_tokens.len());
+            self.audio_tokens.drain(..n);
+            self.text_tokens.drain(..n);
+            self.prompt_steps.drain(..n);
+            self.history_start += n;
+        }
+    }
+
+    fn is_special_text_token(&self, token_id: u32) -> bool {
+        token_id == self.config.text_pad_token
+            || token_id == self.config.text_eop_token
+            || token_id == self.config.text_start_token
+    }
+
+    fn audio_pad_token(&self) -> u32 {
+        self.config.audio_pad_token()
+    }
+
+    // The model inputs for the batch, `ids` being the input of the conditional branch for each
+    // step and `src_steps` the steps these inputs come from. When using classifier free
+    // guidance, the inputs coming from a prompt step are replaced with `pad` in the
+    // unconditional branch.
+    fn batch_ids(
+        &self,
+        ids: Vec<u32>,
+        src_steps: impl Iterator<Item = Option<usize>>,
+        pad: u32,
+    ) -> candle::Result<Tensor> {
+        let dev = self.model.device();
+        let len = ids.len();
+        if self.cfg_alpha.is_none() {
+            return Tensor::from_vec(ids, (1, len), dev);
+        }
+        let uncond_ids: Vec<u32> = ids
+            .iter()
+            .zip(src_steps)
+            .map(|(&id, src_step)| match src_step {
+                Some(s) if s >= self.history_start && self.prompt_steps[s - self.history_start] => {
+                    pad
                 }
-                let logits_len = logits.len();
-                Tensor::from_vec(logits, logits_len, device)?
+                _ => id,
+            })
+            .collect();
+        Tensor::from_vec([ids, uncond_ids].concat(), (2, len), dev)
+    }
+
+    // Combines the conditional and unconditional logits of the batch, `(b, 1, v)`.
+    fn combine_logits(&self, logits: &Tensor) -> candle::Result<Tensor> {
+        match self.cfg_alpha {
+            None => logits.i((0, 0)),
+            Some(cfg_alpha) => {
+                (logits.i((0, 0))? * cfg_alpha)? - (logits.i((1, 0))? * (cfg_alpha - 1.))?
             }
-        };
-        Ok(logits)
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
     }
 
     // The acoustic tokens are written with a delay, so this can create "gaps" of UNGENERATED
@@ -156,56 +363,80 @@ impl State {
         input_audio_tokens: &[u32],
         force_text_token: Option<u32>,
     ) -> candle::Result<u32> {
-        let mut codes = Vec::with_capacity(self.config.total_audio_codebooks());
-        let dev = self.model.device();
-        for (c_idx, &t) in input_audio_tokens.iter().enumerate() {
-            self.audio_tokens[self.step_idx][c_idx + 8] = t
-        }
-        for codebook in 0..self.config.total_audio_codebooks() {
-            let t = if codebook == 0 || codebook == 8 {
-                if self.step_idx == 0 {
-                    self.audio_pad_token()
-                } else {
-                    self.audio_tokens[self.step_idx - 1][codebook]
-                }
-            } else if self.step_idx <= self.config.acoustic_delay {
-                self.audio_pad_token()
-            } else {
-                self.audio_tokens[self.step_idx - self.config.acoustic_delay - 1][codebook]
+        let layout = &self.config.stream_layout;
+        let input_codebooks = layout.input_codebooks();
+        if input_audio_tokens.len() > input_codebooks.len() {
+            candle::bail!(
+                "got {} input audio tokens, the model has {} input codebooks",
+                input_audio_tokens.len(),
+                input_codebooks.len()
+            )
+        }
+        let total_codebooks = layout.total_codebooks();
+        let mut codes = Vec::with_capacity(total_codebooks);
+        let start = self.history_start;
+        self.audio_tokens.push(vec![UNGENERATED; total_codebooks]);
+        self.text_tokens.push(UNGENERATED);
+        self.prompt_steps.push(false);
+        for (&codebook, &t) in input_codebooks.iter().zip(input_audio_tokens.iter()) {
+            self.audio_tokens[self.step_idx - start][codebook] = t
+        }
+        for codebook in 0..total_codebooks {
+            let delay = layout.delay(codebook);
+            let src_step = (self.step_idx > delay).then(|| self.step_idx - delay - 1);
+            let t = match src_step {
+                None => self.audio_pad_token(),
+                Some(s) => self.audio_tokens[s - start][codebook],
             };
             if t == UNGENERATED {
                 candle::bail!("internal error, ungenerated {}", self.step_idx)
             }
-            let t = Tensor::new(&[t], dev)?.unsqueeze(0)?;
-            codes.push(Some(t))
+            let ids = self.batch_ids(vec![t], std::iter::once(src_step), self.audio_pad_token())?;
+            codes.push(Some(ids))
         }
-        let text_token = Some(Tensor::from_vec(vec![text_token], (1, 1), dev)?);
-        let (text_logits, ys) = self.model.forward(text_token, codes)?;
-        let text_logits = text_logits.i((0, 0))?;
-        let text_logits = self.apply_repetition_penalty(text_logits)?;
+        let src_step = self.step_idx.checked_sub(1);
+        let text_ids = self.batch_ids(
+            vec![text_token],
+            std::iter::once(src_step),
+            self.config.text_pad_token,
+        )?;
+        let (text_logits, ys) = self.model.forward(Some(text_ids), codes)?;
+        let text_logits = self.combine_logits(&text_logits)?;
         let text_token = match force_text_token {
             Some(tt) => tt,
-            None => self.text_lp.sample_f(&text_logits, |prs| {
-                if let Some(pad_mult) = self.pad_mult.as_ref() {
-                    prs[self.config.text_pad_token as usize] *= f32::exp(*pad_mult);
-                }
-            })?,
+            None => {
+                let history = self.text_history();
+                let ctx = Context { step_idx: self.step_idx, history: &history };
+                self.text_lp.sample(&ctx, &text_logits)?
+            }
+        };
+        self.text_tokens[self.step_idx - start] = text_token;
+        let layout = &self.config.stream_layout;
+        let last_audio_tokens = match self.cfg_alpha {
+            None => self.model.depformer_sample(
+                self.step_idx,
+                &ys,
+                Some(text_token),
+                &layout.generated_delays(),
+                &mut self.audio_lps,
+            )?,
+            Some(cfg_alpha) => self.model.depformer_sample_cfg(
+                self.step_idx,
+                &ys,
+                cfg_alpha,
+                Some(text_token),
+                &layout.generated_delays(),
+                &mut self.audio_lps,
+            )?,
         };
-        self.text_tokens[self.step_idx] = text_token;
-        let last_audio_tokens = self.model.depformer_sample(
-            self.step_idx,
-            &ys,
-            Some(text_token),
-            &mut self.audio_lp,
-        )?;
         let audio_pad_token = self.audio_pad_token();
-        for c_idx in 0..self.config.generated_audio_codebooks {
-            let delay = if c_idx == 0 || c_idx == 8 { 0 } else { self.config.acoustic_delay };
-            let pos = &mut self.audio_tokens[self.step_idx.saturating_sub(delay)][c_idx];
+        for (g_idx, codebook) in layout.generated_codebooks().into_iter().enumerate() {
+            let delay = layout.delay(codebook);
+            let pos = &mut self.audio_tokens[self.step_idx.saturating_sub(delay) - start][codebook];
             match last_audio_tokens.as_ref() {
                 Some(lat) => {
                     if *pos == UNGENERATED {
-                        *pos = lat[c_idx]
+                        *pos = lat[g_idx]
                     }
                 }
                 None => {
@@ -216,38 +447,317 @@ impl State {
             }
         }
         self.step_idx += 1;
-        if self.step_idx >= self.audio_tokens.len() {
-            candle::bail!("max step-idx reached")
+        if let Some(max_history) = self.max_history {
+            self.drop_history(self.step_idx.saturating_sub(max_history))
         }
         Ok(text_token)
     }
 
-    /// If include_all is set, all the time steps are returned. Otherwise only the timesteps that
-    /// have been generated are handled.
+    /// The text token to use as input for the next step, i.e. the last text token or the start
+    /// token if no step has been processed yet.
+    pub fn last_text_token(&self) -> u32 {
+        match self.text_tokens(false).last() {
+            None => self.config.text_start_token,
+            Some(&text_token) => text_token,
+        }
+    }
+
+    /// Primes the session with a text prompt, e.g. a system prompt. The text tokens are forced
+    /// for one step each while the audio streams are padded. Returns the text token to use as
+    /// input for the next step.
+    pub fn prefill_text(&mut self, text_tokens: &[u32]) -> candle::Result<u32> {
+        let audio_pad_token = self.audio_pad_token();
+        let total_codebooks = self.config.total_audio_codebooks();
+        let audio_tokens = vec![vec![audio_pad_token; total_codebooks]; text_tokens.len()];
+        self.prefill(text_tokens, audio_tokens)
+    }
+
+    /// Primes the session with some audio, e.g. a reference voice. `moshi_codes` holds the
+    /// tokens of the generated codebooks for each step and `user_codes` the tokens of the input
+    /// codebooks. `user_codes` can be empty, in which case the input streams are padded. The
+    /// text stream is padded. Returns the text token to use as input for the next step.
+    pub fn prefill_audio(
+        &mut self,
+        moshi_codes: &[Vec<u32>],
+        user_codes: &[Vec<u32>],
+    ) -> candle::Result<u32> {
+        let layout = &self.config.stream_layout;
+        let generated_codebooks = layout.generated_codebooks();
+        let input_codebooks = layout.input_codebooks();
+        if !user_codes.is_empty() && user_codes.len() != moshi_codes.len() {
+            candle::bail!(
+                "got {} steps of user codes for {} steps of moshi codes",
+                user_codes.len(),
+                moshi_codes.len()
+            )
+        }
+        let audio_pad_token = self.audio_pad_token();
+        let mut audio_tokens = Vec::with_capacity(moshi_codes.len());
+        for (step, moshi_codes) in moshi_codes.iter().enumerate() {
+            let mut tokens = vec![audio_pad_token; layout.total_codebooks()];
+            if moshi_codes.len() != generated_codebooks.len() {
+                candle::bail!(
+                    "got {} moshi codes for step {step}, expected {}",
+                    moshi_codes.len(),
+                    generated_codebooks.len()
+                )
+            }
+            for (&codebook, &t) in generated_codebooks.iter().zip(moshi_codes.iter()) {
+                tokens[codebook] = t
+            }
+            if let Some(user_codes) = user_codes.get(step) {
+                if user_codes.len() != input_codebooks.len() {
+                    candle::bail!(
+                        "got {} user codes for step {step}, expected {}",
+                        user_codes.len(),
+                        input_codebooks.len()
+                    )
+                }
+                for (&codebook, &t) in input_codebooks.iter().zip(user_codes.iter()) {
+                    tokens[codebook] = t
+                }
+            }
+            audio_tokens.push(tokens)
+        }
+        let text_tokens = vec![self.config.text_pad_token; audio_tokens.len()];
+        self.prefill(&text_tokens, audio_tokens)
+    }
+
+    /// Primes the session with a voice prompt, `pcm` has shape (1, 1, t) and uses the sample
+    /// rate of `mimi`. The first generated stream gets the codes for `pcm` while the other
+    /// streams get the codes for silence. Returns the text token to use as input for the next
+    /// step.
+    pub fn prefill_voice(
+        &mut self,
+        mimi: &mut crate::encodec::Encodec,
+        pcm: &Tensor,
+    ) -> candle::Result<u32> {
+        let layout = self.config.stream_layout.clone();
+        let cps = layout.codebooks_per_stream;
+        let mut encode = |pcm: &Tensor| -> candle::Result<Vec<Vec<u32>>> {
+            mimi.reset_state();
+            let codes = mimi.encode_with_n_q(pcm, cps)?;
+            mimi.reset_state();
+            codes.i(0)?.t()?.to_vec2::<u32>()
+        };
+        let voice_codes = encode(pcm)?;
+        let silence_codes = encode(&pcm.zeros_like()?)?;
+        let first_generated = layout.streams.iter().position(|&k| k == StreamKind::Generated);
+        let mut moshi_codes = Vec::with_capacity(voice_codes.len());
+        let mut user_codes = Vec::with_capacity(voice_codes.len());
+        for (voice, silence) in voice_codes.iter().zip(silence_codes.iter()) {
+            let (mut moshi, mut user) = (vec![], vec![]);
+            for (stream_idx, &kind) in layout.streams.iter().enumerate() {
+                let codes = if Some(stream_idx) == first_generated { voice } else { silence };
+                match kind {
+                    StreamKind::Generated => moshi.extend_from_slice(codes),
+                    StreamKind::Input => user.extend_from_slice(codes),
+                }
+            }
+            moshi_codes.push(moshi);
+            user_codes.push(user);
+        }
+        self.prefill_audio(&moshi_codes, &user_codes)
+    }
+
+    // Runs teacher-forced steps: `text_tokens` and `audio_tokens` hold the tokens for each of
+    // the new steps, all the audio codebooks being provided. Nothing has to be sampled so the
+    // steps are processed in chunks of PREFILL_CHUNK_SIZE by the model.
+    fn prefill(&mut self, text_tokens: &[u32], audio_tokens: Vec<Vec<u32>>) -> candle::Result<u32> {
+        let layout = self.config.stream_layout.clone();
+        let audio_vocab_size = self.config.audio_vocab_size;
+        for tokens in audio_tokens.iter() {
+            if let Some(t) = tokens.iter().find(|&&t| t as usize >= audio_vocab_size) {
+                candle::bail!("invalid audio token {t} in prompt, vocab size {audio_vocab_size}")
+            }
+        }
+        // The delayed codebooks of the last frames only get written by the next steps. As the
+        // prompt overrides these steps, the missing tokens are padded.
+        let audio_pad_token = self.audio_pad_token();
+        for t in self.audio_tokens.iter_mut().flatten() {
+            if *t == UNGENERATED {
+                *t = audio_pad_token
+            }
+        }
+        let num_steps = text_tokens.len();
+        let first_step = self.step_idx;
+        self.audio_tokens.extend(audio_tokens);
+        self.text_tokens.extend_from_slice(text_tokens);
+        self.prompt_steps.resize(self.prompt_steps.len() + num_steps, true);
+        let start = self.history_start;
+        for chunk_start in (0..num_steps).step_by(PREFILL_CHUNK_SIZE) {
+            let chunk_len = usize::min(PREFILL_CHUNK_SIZE, num_steps - chunk_start);
+            let steps = first_step + chunk_start..first_step + chunk_start + chunk_len;
+            let text_ids: Vec<u32> = steps
+                .clone()
+                .map(|s| {
+                    if s == 0 {
+                        self.config.text_start_token
+                    } else {
+                        self.text_tokens[s - 1 - start]
+                    }
+                })
+                .collect();
+            let src_steps = steps.clone().map(|s| s.checked_sub(1));
+            let text_ids = self.batch_ids(text_ids, src_steps, self.config.text_pad_token)?;
+            let mut codes = Vec::with_capacity(layout.total_codebooks());
+            for codebook in 0..layout.total_codebooks() {
+                let delay = layout.delay(codebook);
+                let ids: Vec<u32> = steps
+                    .clone()
+                    .map(|s| {
+                        if s <= delay {
+                            audio_pad_token
+                        } else {
+                            self.audio_tokens[s - delay - 1 - start][codebook]
+                        }
+                    })
+                    .collect();
+                let src_steps = steps.clone().map(|s| (s > delay).then(|| s - delay - 1));
+                codes.push(Some(self.batch_ids(ids, src_steps, audio_pad_token)?))
+            }
+            self.model.forward(Some(text_ids), codes)?;
+            self.step_idx += chunk_len;
+        }
+        if let Some(max_history) = self.max_history {
+            self.drop_history(self.step_idx.saturating_sub(max_history))
+        }
+        Ok(self.last_text_token())
+    }
+
+    /// The audio tokens for the steps that are still in the history, starting at step
+    /// `history_start`. If include_all is set, all the time steps are returned. Otherwise only the
+    /// timesteps that have been generated are handled.
     pub fn audio_tokens(&self, include_all: bool) -> &[Vec<u32>] {
         if include_all {
             &self.audio_tokens
         } else {
-            let max_idx = usize::min(self.step_idx, self.audio_tokens.len());
+            let max_idx = usize::min(self.step_idx - self.history_start, self.audio_tokens.len());
             &self.audio_tokens[..max_idx]
         }
     }
 
+    /// The text tokens for the steps that are still in the history, starting at step
+    /// `history_start`.
     pub fn text_tokens(&self, include_all: bool) -> &[u32] {
         if include_all {
             &self.text_tokens
         } else {
-            let max_idx = usize::min(self.step_idx, self.text_tokens.len());
+            let max_idx = usize::min(self.step_idx - self.history_start, self.text_tokens.len());
             &self.text_tokens[..max_idx]
         }
     }
 
+    /// Serializes the streaming state of the session to a safetensors blob, this includes the
+    /// state of `mimi`, of the language model and the tokens generated so far. The logits
+    /// processors are not part of the snapshot so a restored session samples with the settings
+    /// of the state it has been restored into.
+    pub fn snapshot(&self, mimi: &crate::encodec::Encodec) -> candle::Result<Vec<u8>> {
+        let mut state = StateDict::new();
+        mimi.save_state("mimi", &mut state)?;
+        self.model.save_state("lm", &mut state)?;
+        let shape = (self.audio_tokens.len(), self.config.total_audio_codebooks());
+        let audio_tokens = Tensor::from_vec(self.audio_tokens.concat(), shape, &Device::Cpu)?;
+        state.insert("audio_tokens".to_string(), audio_tokens);
+        let text_tokens = Tensor::new(self.text_tokens.as_slice(), &Device::Cpu)?;
+        state.insert("text_tokens".to_string(), text_tokens);
+        let prompt_steps: Vec<u8> = self.prompt_steps.iter().map(|&p| p as u8).collect();
+        state.insert("prompt_steps".to_string(), Tensor::new(prompt_steps, &Device::Cpu)?);
+        streaming::save_usize("step_idx", self.step_idx, &mut state)?;
+        streaming::save_usize("batch_size", self.batch_size(), &mut state)?;
+        streaming::save_usize("history_start", self.history_start, &mut state)?;
+        // The rngs are part of the snapshot so that a restored session samples the same tokens
+        // as the original one would have.
+        save_rng("text_rng", self.text_lp.rng(), &mut state)?;
+        for (i, lp) in self.audio_lps.iter().enumerate() {
+            save_rng(&format!("audio_rng.{i}"), lp.rng(), &mut state)?;
+        }
+        streaming::serialize_state(&state)
+    }
+
+    /// Restores a session previously serialized with `snapshot`, `mimi` should be on the same
+    /// device as the language model.
+    pub fn restore(
+        &mut self,
+        mimi: &mut crate::encodec::Encodec,
+        data: &[u8],
+    ) -> candle::Result<()> {
+        let state = streaming::deserialize_state(data, self.model.device())?;
+        let get = |key: &str| match state.get(key) {
+            None => candle::bail!("missing {key} in snapshot"),
+            Some(v) => Ok(v),
+        };
+        let audio_tokens = get("audio_tokens")?;
+        let (_, codebooks) = audio_tokens.dims2()?;
+        if codebooks != self.config.total_audio_codebooks() {
+            candle::bail!(
+                "snapshot has {codebooks} codebooks, expected {}",
+                self.config.total_audio_codebooks()
+            )
+        }
+        let mut audio_tokens = audio_tokens.to_vec2::<u32>()?;
+        let mut text_tokens = get("text_tokens")?.to_vec1::<u32>()?;
+        let mut prompt_steps = match state.get("prompt_steps") {
+            None => vec![false; text_tokens.len()],
+            Some(p) => p.to_vec1::<u8>()?.into_iter().map(|p| p != 0).collect(),
+        };
+        let batch_size = streaming::load_usize("batch_size", &state)?.unwrap_or(1);
+        if batch_size != self.batch_size() {
+            candle::bail!(
+                "snapshot has a batch size of {batch_size}, expected {}, cfg has to match",
+                self.batch_size()
+            )
+        }
+        let step_idx = match streaming::load_usize("step_idx", &state)? {
+            None => candle::bail!("missing step_idx in snapshot"),
+            Some(step_idx) => step_idx,
+        };
+        let history_start = streaming::load_usize("history_start", &state)?.unwrap_or(0);
+        if history_start > step_idx
+            || history_start + audio_tokens.len() < step_idx
+            || audio_tokens.len() != text_tokens.len()
+            || prompt_steps.len() != text_tokens.len()
+        {
+            candle::bail!("inconsistent snapshot, step-idx {step_idx}")
+        }
+        // Snapshots may include some ungenerated steps after step_idx.
+        audio_tokens.truncate(step_idx - history_start);
+        text_tokens.truncate(step_idx - history_start);
+        prompt_steps.truncate(step_idx - history_start);
+        // Snapshots from older versions do not include the rngs, the current ones are kept.
+        let text_rng = load_rng("text_rng", &state)?;
+        let audio_rngs = (0..self.audio_lps.len())
+            .map(|i| load_rng(&format!("audio_rng.{i}"), &state))
+            .collect::<candle::Result<Vec<_>>>()?;
+        mimi.load_state("mimi", &state)?;
+        self.model.load_state("lm", &state)?;
+        if let Some(rng) = text_rng {
+            *self.text_lp.rng_mut() = rng
+        }
+        for (lp, rng) in self.audio_lps.iter_mut().zip(audio_rngs) {
+            if let Some(rng) = rng {
+                *lp.rng_mut() = rng
+            }
+        }
+        self.audio_tokens = audio_tokens;
+        self.text_tokens = text_tokens;
+        self.prompt_steps = prompt_steps;
+        self.history_start = history_start;
+        self.step_idx = step_idx;
+        if let Some(max_history) = self.max_history {
+            self.drop_history(self.step_idx.saturating_sub(max_history))
+        }
+        Ok(())
+    }
+
     pub fn last_audio_tokens(&self) -> Option<Vec<u32>> {
-        if self.step_idx <= self.config.acoustic_delay {
+        let max_delay = self.config.stream_layout.max_delay();
+        if self.step_idx <= max_delay {
             None
         } else {
-            // step_idx is in advance by 1 + there is a 2 token delay on audio tokens.
-            let audio_tokens = &self.audio_tokens[self.step_idx - self.config.acoustic_delay - 1];
+            // step_idx is in advance by 1 + the delay of the most delayed codebook.
+            let audio_tokens =
+                &self.audio_tokens[self.step_idx - max_delay - 1 - self.history_start];
             if audio_tokens.iter().any(|v| *v as usize >= self.config.audio_vocab_size - 1) {
                 None
             } else {
@@ -256,3 +766,358 @@ impl State {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lm::{test_utils, LmModel};
+
+    // Two generated and two input codebooks, matching `crate::lm::test_utils::small_config`.
+    fn small_config() -> Config {
+        Config {
+            stream_layout: StreamLayout::new(vec![StreamKind::Generated, StreamKind::Input], 2, 1),
+            audio_vocab_size: 9,
+            text_pad_token: 3,
+            text_eop_token: 0,
+            text_start_token: 10,
+        }
+    }
+
+    fn sampling_state(vm: &candle_nn::VarMap, seed: u64) -> candle::Result<State> {
+        let model = LmModel::Lm(test_utils::small_lm(&test_utils::small_config(), vm)?);
+        State::new(model, vec![Sampler::new(seed)], Sampler::new(seed + 1), small_config())
+    }
+
+    // Runs generation steps with deterministic input audio tokens, returns the text tokens.
+    fn run(state: &mut State, steps: std::ops::Range<usize>) -> candle::Result<Vec<u32>> {
+        let mut text_tokens = vec![];
+        for step in steps {
+            let input_audio_tokens = [(step * 3 % 7) as u32, (step * 5 % 7) as u32];
+            let text_token = state.step(state.last_text_token(), &input_audio_tokens, None)?;
+            text_tokens.push(text_token)
+        }
+        Ok(text_tokens)
+    }
+
+    #[test]
+    fn snapshot_restores_rngs() -> candle::Result<()> {
+        use crate::encodec::test_utils::{small_config, small_model};
+
+        let vm = candle_nn::VarMap::new();
+        let mut mimi = small_model(&small_config(1, 4), &candle_nn::VarMap::new())?;
+        let mut state = sampling_state(&vm, 42)?;
+        let text_tokens = run(&mut state, 0..12)?;
+        let audio_tokens = state.audio_tokens(true).to_vec();
+        let mut state = sampling_state(&vm, 1337)?;
+        assert_ne!(run(&mut state, 0..12)?, text_tokens);
+
+        let mut state = sampling_state(&vm, 42)?;
+        let mut restored_tokens = run(&mut state, 0..6)?;
+        let snapshot = state.snapshot(&mimi)?;
+        // The restored session uses the rngs from the snapshot rather than its own seed.
+        let mut state = sampling_state(&vm, 1337)?;
+        state.restore(&mut mimi, &snapshot)?;
+        restored_tokens.extend(run(&mut state, 6..12)?);
+        assert_eq!(restored_tokens, text_tokens);
+        assert_eq!(state.audio_tokens(true), audio_tokens);
+        Ok(())
+    }
+
+    // A state whose text sampler looks at the last text tokens, pad tokens are favored so that
+    // the sampler history spans more steps than its context.
+    fn penalized_state(
+        vm: &candle_nn::VarMap,
+        max_history: Option<usize>,
+    ) -> candle::Result<State> {
+        use crate::sampling::{LogitBias, RepetitionPenalty};
+
+        let model = LmModel::Lm(test_utils::small_lm(&test_utils::small_config(), vm)?);
+        let text_lp = Sampler::new(1)
+            .with(LogitBias(vec![(3, 2.)]))
+            .with(RepetitionPenalty { context: 3, penalty: 4. });
+        let mut state = State::new(model, vec![Sampler::new(0)], text_lp, small_config())?;
+        state.set_max_history(max_history);
+        Ok(state)
+    }
+
+    #[test]
+    fn trimmed_history() -> candle::Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let mut state = penalized_state(&vm, None)?;
+        let text_tokens = run(&mut state, 0..40)?;
+        let audio_tokens = state.audio_tokens(false).to_vec();
+        assert_eq!(state.history_start(), 0);
+        assert!(text_tokens.iter().filter(|&&t| t == 3).count() > 5);
+
+        for max_history in [Some(0), Some(1), Some(5)] {
+            let mut trimmed = penalized_state(&vm, max_history)?;
+            let mut trimmed_tokens = vec![];
+            for step in 0..40 {
+                trimmed_tokens.extend(run(&mut trimmed, step..step + 1)?);
+                if max_history == Some(0) {
+                    trimmed.trim_history()
+                }
+                let start = trimmed.history_start();
+                // The steps read with the acoustic delay are always kept.
+                assert!(start + 2 <= trimmed.step_idx() || start == 0);
+                assert_eq!(trimmed.text_tokens(false), &text_tokens[start..step + 1]);
+                // The last step is not complete yet because of the acoustic delay.
+                let trimmed_audio = trimmed.audio_tokens(false);
+                let complete = &trimmed_audio[..trimmed_audio.len() - 1];
+                assert_eq!(complete, &audio_tokens[start..step]);
+            }
+            assert_eq!(trimmed_tokens, text_tokens, "{max_history:?}");
+            let start = trimmed.history_start();
+            assert_eq!(trimmed.audio_tokens(false), &audio_tokens[start..]);
+            assert!(trimmed.history_start() > 20, "{max_history:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_trimmed_history() -> candle::Result<()> {
+        use crate::encodec::test_utils::{small_config, small_model};
+
+        let vm = candle_nn::VarMap::new();
+        let mut mimi = small_model(&small_config(1, 4), &candle_nn::VarMap::new())?;
+        let mut state = penalized_state(&vm, None)?;
+        let text_tokens = run(&mut state, 0..30)?;
+        let audio_tokens = state.audio_tokens(false).to_vec();
+
+        let mut state = penalized_state(&vm, Some(2))?;
+        let mut restored_tokens = run(&mut state, 0..15)?;
+        assert!(state.history_start() > 0);
+        let snapshot = state.snapshot(&mimi)?;
+        for max_history in [None, Some(2)] {
+            let mut restored = penalized_state(&vm, max_history)?;
+            restored.restore(&mut mimi, &snapshot)?;
+            assert_eq!(restored.history_start(), state.history_start());
+            assert_eq!(restored.last_text_token(), state.last_text_token());
+            let mut tokens = restored_tokens.clone();
+            tokens.extend(run(&mut restored, 15..30)?);
+            assert_eq!(tokens, text_tokens, "{max_history:?}");
+            let start = restored.history_start();
+            assert_eq!(restored.audio_tokens(false), &audio_tokens[start..]);
+        }
+        restored_tokens.extend(run(&mut state, 15..30)?);
+        assert_eq!(restored_tokens, text_tokens);
+        Ok(())
+    }
+
+    #[test]
+    fn stream_layout() -> candle::Result<()> {
+        let layout = Config::v0_1().stream_layout;
+        assert_eq!(layout.generated_codebooks(), (0..8).collect::<Vec<_>>());
+        assert_eq!(layout.input_codebooks(), (8..16).collect::<Vec<_>>());
+        assert_eq!(layout.generated_delays(), [0, 2, 2, 2, 2, 2, 2, 2]);
+        assert_eq!((layout.delay(8), layout.delay(9), layout.max_delay()), (0, 2, 2));
+        let layout = Config::v0_1_two_ways().stream_layout;
+        assert_eq!(layout.generated_codebooks(), (0..16).collect::<Vec<_>>());
+        assert!(layout.input_codebooks().is_empty());
+
+        // Invalid layouts are rejected when creating the state rather than on each step.
+        let mut config = small_config();
+        config.stream_layout.delays.push(1);
+        let vm = candle_nn::VarMap::new();
+        let model = LmModel::Lm(test_utils::small_lm(&test_utils::small_config(), &vm)?);
+        assert!(State::new(model, vec![Sampler::new(0)], Sampler::new(1), config).is_err());
+        Ok(())
+    }
+
+    fn greedy_state(vm: &candle_nn::VarMap, quantized: bool) -> candle::Result<State> {
+        let cfg = test_utils::small_config();
+        let model = if quantized {
+            LmModel::QuantizedLm(test_utils::small_quantized_lm(&cfg, vm)?)
+        } else {
+            LmModel::Lm(test_utils::small_lm(&cfg, vm)?)
+        };
+        State::new(model, vec![Sampler::greedy()], Sampler::greedy(), small_config())
+    }
+
+    // The text logits of the model for a fixed input, this advances the model state.
+    fn next_logits(state: &mut State) -> candle::Result<Tensor> {
+        let dev = &Device::Cpu;
+        let text_ids = Tensor::new(&[[state.last_text_token()]], dev)?;
+        let audio_ids = (0..4u32)
+            .map(|c| Ok(Some(Tensor::new(&[[c]], dev)?)))
+            .collect::<candle::Result<Vec<_>>>()?;
+        let (text_logits, _) = state.model.forward(Some(text_ids), audio_ids)?;
+        Ok(text_logits)
+    }
+
+    #[test]
+    fn chunked_prefill() -> candle::Result<()> {
+        use crate::streaming::test_utils::max_diff;
+
+        let vm = candle_nn::VarMap::new();
+        // More steps than a single chunk, the codes avoid the audio padding token.
+        let steps = PREFILL_CHUNK_SIZE + 30;
+        let moshi_codes: Vec<Vec<u32>> =
+            (0..steps).map(|s| vec![(s * 3 % 8) as u32, (s * 5 % 8) as u32]).collect();
+        let user_codes: Vec<Vec<u32>> =
+            (0..steps).map(|s| vec![(s * 7 % 8) as u32, (s % 8) as u32]).collect();
+        let text_tokens: Vec<u32> = (0..20).map(|s| (s * 7 % 10) as u32).collect();
+        for quantized in [false, true] {
+            // Some steps get generated before the prompts so that they override ungenerated
+            // delayed tokens.
+            let mut chunked = greedy_state(&vm, quantized)?;
+            run(&mut chunked, 0..3)?;
+            chunked.prefill_audio(&moshi_codes, &user_codes)?;
+            chunked.prefill_text(&text_tokens)?;
+
+            let mut single = greedy_state(&vm, quantized)?;
+            run(&mut single, 0..3)?;
+            for step in 0..steps {
+                single.prefill_audio(&moshi_codes[step..step + 1], &user_codes[step..step + 1])?;
+            }
+            for &text_token in text_tokens.iter() {
+                single.prefill_text(&[text_token])?;
+            }
+
+            let n = chunked.step_idx();
+            assert_eq!((n, single.step_idx()), (3 + steps + 20, 3 + steps + 20));
+            assert_eq!(chunked.text_tokens(true), single.text_tokens(true));
+            assert_eq!(chunked.audio_tokens(true), single.audio_tokens(true));
+            assert_eq!(chunked.prompt_steps, single.prompt_steps);
+            assert_eq!(run(&mut chunked, n..n + 5)?, run(&mut single, n..n + 5)?);
+            assert_eq!(chunked.audio_tokens(false), single.audio_tokens(false));
+            let diff = max_diff(&next_logits(&mut chunked)?, &next_logits(&mut single)?)?;
+            assert!(diff < 1e-4, "quantized {quantized} diff {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn voice_prompt() -> candle::Result<()> {
+        use crate::encodec::test_utils::{small_config, small_model};
+        use crate::streaming::test_utils::rand_tensor;
+
+        let vm = candle_nn::VarMap::new();
+        // The codes have to fit in the audio vocabulary of the language model.
+        let mimi_config = crate::encodec::Config { quantizer_bins: 8, ..small_config(1, 4) };
+        let mut mimi = small_model(&mimi_config, &candle_nn::VarMap::new())?;
+        let pcm = rand_tensor(0, 0.5, (1, 1, 1920 * 6))?;
+        let voice_codes = mimi.encode_with_n_q(&pcm, 2)?.i(0)?.t()?.to_vec2::<u32>()?;
+        let silence_codes = mimi.encode_with_n_q(&pcm.zeros_like()?, 2)?.i(0)?.t()?;
+        let silence_codes = silence_codes.to_vec2::<u32>()?;
+
+        let mut state = sampling_state(&vm, 0)?;
+        state.prefill_voice(&mut mimi, &pcm)?;
+        let mut expected = sampling_state(&vm, 0)?;
+        expected.prefill_audio(&voice_codes, &silence_codes)?;
+        assert_eq!(state.step_idx(), 6);
+        assert_eq!(state.audio_tokens(false), expected.audio_tokens(false));
+        assert_eq!(state.text_tokens(false), [3; 6]);
+        assert_eq!(run(&mut state, 6..10)?, run(&mut expected, 6..10)?);
+        Ok(())
+    }
+
+    // A greedy state, the audio eos token is banned as the depformer postpones it on all the
+    // slices without classifier free guidance but only on the first one with it.
+    fn cfg_state(
+        vm: &candle_nn::VarMap,
+        quantized: bool,
+        cfg_alpha: Option<f64>,
+    ) -> candle::Result<State> {
+        use crate::sampling::SamplingConfig;
+
+        let cfg = test_utils::small_config();
+        let model = if quantized {
+            LmModel::QuantizedLm(test_utils::small_quantized_lm(&cfg, vm)?)
+        } else {
+            LmModel::Lm(test_utils::small_lm(&cfg, vm)?)
+        };
+        let audio_lp = SamplingConfig { banned_tokens: vec![7], ..SamplingConfig::greedy() };
+        let lps = vec![audio_lp.sampler(0)];
+        let mut state = State::new(model, lps, Sampler::greedy(), small_config())?;
+        state.set_cfg_alpha(cfg_alpha)?;
+        Ok(state)
+    }
+
+    // Runs a couple steps, a text prompt and then some more steps.
+    fn run_with_prompt(state: &mut State, prompt: &[u32], n: usize) -> candle::Result<Vec<u32>> {
+        let mut text_tokens = run(state, 0..2)?;
+        state.prefill_text(prompt)?;
+        let start = state.step_idx();
+        text_tokens.extend(run(state, start..start + n)?);
+        Ok(text_tokens)
+    }
+
+    #[test]
+    fn cfg_alpha_one() -> candle::Result<()> {
+        let vm = candle_nn::VarMap::new();
+        for quantized in [false, true] {
+            // With an alpha of 1, only the conditional branch contributes to the logits.
+            let mut plain = cfg_state(&vm, quantized, None)?;
+            let mut guided = cfg_state(&vm, quantized, Some(1.))?;
+            let tokens = run_with_prompt(&mut plain, &[5, 6, 1], 20)?;
+            assert_eq!(run_with_prompt(&mut guided, &[5, 6, 1], 20)?, tokens, "{quantized}");
+            assert_eq!(guided.audio_tokens(false), plain.audio_tokens(false));
+            assert_eq!(guided.batch_size(), 2);
+            assert!(guided.set_cfg_alpha(None).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_unconditional_branch() -> candle::Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let mut state = cfg_state(&vm, false, Some(2.))?;
+        run_with_prompt(&mut state, &[5, 6], 0)?;
+        assert_eq!(state.prompt_steps, [false, false, true, true]);
+        // The inputs coming from prompt steps are padded in the unconditional branch.
+        let src_steps = [Some(0), Some(2), Some(3), None];
+        let ids = state.batch_ids(vec![1, 2, 3, 4], src_steps.into_iter(), 9)?;
+        assert_eq!(ids.to_vec2::<u32>()?, [[1, 2, 3, 4], [1, 9, 9, 4]]);
+        state.cfg_alpha = None;
+        let ids = state.batch_ids(vec![1, 2, 3, 4], src_steps.into_iter(), 9)?;
+        assert_eq!(ids.to_vec2::<u32>()?, [[1, 2, 3, 4]]);
+
+        // With an alpha of 0, only the unconditional branch contributes to the logits so the
+        // generation matches a session where the prompt is made of text pad tokens.
+        for quantized in [false, true] {
+            let mut plain = cfg_state(&vm, quantized, None)?;
+            let mut guided = cfg_state(&vm, quantized, Some(0.))?;
+            let tokens = run_with_prompt(&mut plain, &[3, 3, 3], 20)?;
+            let guided_tokens = run_with_prompt(&mut guided, &[5, 6, 1], 20)?;
+            assert_eq!(guided_tokens[5..], tokens[5..], "{quantized}");
+            assert_eq!(guided.audio_tokens(false), plain.audio_tokens(false));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_quantized() -> candle::Result<()> {
+        // The quantized model goes through its own depformer_sample_cfg.
+        let vm = candle_nn::VarMap::new();
+        let mut float = cfg_state(&vm, false, Some(3.))?;
+        let mut quantized = cfg_state(&vm, true, Some(3.))?;
+        let tokens = run_with_prompt(&mut float, &[5, 6, 1], 20)?;
+        assert_eq!(run_with_prompt(&mut quantized, &[5, 6, 1], 20)?, tokens);
+        assert_eq!(quantized.audio_tokens(false), float.audio_tokens(false));
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_snapshot() -> candle::Result<()> {
+        use crate::encodec::test_utils::{small_config, small_model};
+
+        let vm = candle_nn::VarMap::new();
+        let mut mimi = small_model(&small_config(1, 4), &candle_nn::VarMap::new())?;
+        let mut state = cfg_state(&vm, false, Some(2.))?;
+        run_with_prompt(&mut state, &[5, 6], 4)?;
+        let snapshot = state.snapshot(&mimi)?;
+
+        let mut restored = cfg_state(&vm, false, Some(2.))?;
+        restored.restore(&mut mimi, &snapshot)?;
+        assert_eq!(restored.prompt_steps, state.prompt_steps);
+        assert_eq!(restored.prompt_steps, [false, false, true, true, false, false, false, false]);
+        assert_eq!(run(&mut restored, 8..16)?, run(&mut state, 8..16)?);
+        assert_eq!(restored.audio_tokens(false), state.audio_tokens(false));
+
+        // The batch size of the snapshot has to match.
+        let mut restored = cfg_state(&vm, false, None)?;
+        assert!(restored.restore(&mut mimi, &snapshot).is_err());
+        Ok(())
+    }
+}
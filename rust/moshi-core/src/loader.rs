@@ -0,0 +1,640 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Loading of the language model and of mimi from safetensors, sharded safetensors or gguf
+//! files with a single builder.
+//!
+//! ```ignore
+//! let loader = ModelLoader::new("path/to/model/dir")
+//!     .device(&Device::new_cuda(0)?)
+//!     .dtype(DType::BF16)
+//!     .mimi_device(&Device::Cpu)
+//!     .mimi_dtype(DType::F32);
+//! let lm = loader.load_lm()?;
+//! let mimi = loader.load_mimi()?;
+//! ```
+//!
+//! All the tensors are checked against the configuration before returning, missing tensors and
+//! shape mismatches are reported together rather than one at a time. Tensors from the files
+//! that are not used by the model are only reported as an error in strict mode. Checkpoints
+//! routinely contain more codebooks than get loaded so the unused quantizer layers are not
+//! reported at all.
+
+use crate::{encodec, lm};
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Result, Shape, Tensor};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Maximum number of tensor names listed per category in error messages.
+const MAX_LISTED: usize = 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WeightFormat {
+    Safetensors,
+    /// Multiple safetensors files listed in a `*.safetensors.index.json` file.
+    ShardedSafetensors,
+    Gguf,
+}
+
+impl WeightFormat {
+    /// Detects the format of a weight file from its name, falling back to the file magic.
+    pub fn detect<P: AsRef<Path>>(p: P) -> Result<Self> {
+        use std::io::Read;
+
+        let p = p.as_ref();
+        let name = p.file_name().map(|v| v.to_string_lossy()).unwrap_or_default();
+        if name.ends_with(".safetensors.index.json") {
+            return Ok(Self::ShardedSafetensors);
+        }
+        match p.extension().and_then(|v| v.to_str()) {
+            Some("gguf") => Ok(Self::Gguf),
+            Some("safetensors") => Ok(Self::Safetensors),
+            _ => {
+                let mut magic = [0u8; 4];
+                std::fs::File::open(p)?.read_exact(&mut magic).map_err(|e| {
+                    candle::Error::Msg(format!("{p:?}: cannot read weight file, {e}"))
+                })?;
+                if &magic == b"GGUF" {
+                    Ok(Self::Gguf)
+                } else {
+                    Ok(Self::Safetensors)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Placement {
+    dtype: DType,
+    device: Device,
+}
+
+#[derive(Debug, Default)]
+struct WeightReport {
+    used: HashSet<String>,
+    missing: Vec<String>,
+    mismatched: Vec<String>,
+}
+
+// A backend that records the tensors being used, missing tensors and shape mismatches are
+// replaced by zeros so that all the errors can be reported at once. Without safetensors, only
+// the shapes are checked and all the returned tensors are placeholders.
+#[derive(Clone)]
+struct CheckedBackend {
+    st: Option<Arc<MmapedSafetensors>>,
+    shapes: Arc<HashMap<String, Vec<usize>>>,
+    report: Arc<Mutex<WeightReport>>,
+}
+
+impl CheckedBackend {
+    fn new(st: Option<Arc<MmapedSafetensors>>, shapes: HashMap<String, Vec<usize>>) -> Self {
+        Self { st, shapes: Arc::new(shapes), report: Arc::new(Mutex::new(WeightReport::default())) }
+    }
+
+    fn safetensors(files: &[PathBuf]) -> Result<Self> {
+        let st = unsafe { MmapedSafetensors::multi(files)? };
+        let shapes = st.tensors().into_iter().map(|(n, v)| (n, v.shape().to_vec())).collect();
+        Ok(Self::new(Some(Arc::new(st)), shapes))
+    }
+
+    fn gguf(path: &Path) -> Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let content = candle::quantized::gguf_file::Content::read(&mut file)
+            .map_err(|e| candle::Error::Msg(format!("{path:?}: {e}")))?;
+        let shapes =
+            content.tensor_infos.into_iter().map(|(n, v)| (n, v.shape.dims().to_vec())).collect();
+        Ok(Self::new(None, shapes))
+    }
+
+    fn vb(&self, dtype: DType, device: &Device) -> candle_nn::VarBuilder<'static> {
+        candle_nn::VarBuilder::from_backend(Box::new(self.clone()), dtype, device.clone())
+    }
+
+    fn placeholder(s: Shape, dtype: DType, dev: &Device) -> Result<Tensor> {
+        // Broadcasting avoids allocating memory for tensors that would be unused anyway.
+        Tensor::zeros((), dtype, dev)?.broadcast_as(s)
+    }
+}
+
+impl candle_nn::var_builder::SimpleBackend for CheckedBackend {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: candle_nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let mut report = self.report.lock().unwrap();
+        report.used.insert(name.to_string());
+        match (self.shapes.get(name), self.st.as_ref()) {
+            (None, _) => {
+                report.missing.push(format!("{name} {:?}", s.dims()));
+                Self::placeholder(s, dtype, dev)
+            }
+            (Some(shape), _) if shape != s.dims() => {
+                report.mismatched.push(format!("{name} expected {:?} got {shape:?}", s.dims()));
+                Self::placeholder(s, dtype, dev)
+            }
+            (Some(_), None) => Self::placeholder(s, dtype, dev),
+            (Some(_), Some(st)) => st.load(name, dev)?.to_dtype(dtype),
+        }
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.shapes.contains_key(name)
+    }
+}
+
+// Whether `name` belongs to a quantizer layer past the ones used by the model, e.g.
+// `quantizer.rvq_rest.vq.layers.12._codebook.embedding_sum` when only 8 codebooks are loaded.
+fn is_unused_codebook(name: &str, used: &HashSet<String>) -> bool {
+    if !name.starts_with("quantizer.") {
+        return false;
+    }
+    let pos = match name.find(".layers.") {
+        None => return false,
+        Some(pos) => pos + ".layers.".len(),
+    };
+    let (group, rest) = name.split_at(pos);
+    let layer_idx = match rest.split('.').next().and_then(|v| v.parse::<usize>().ok()) {
+        None => return false,
+        Some(layer_idx) => layer_idx,
+    };
+    let is_used = |idx: usize| {
+        let prefix = format!("{group}{idx}.");
+        used.iter().any(|u| u.starts_with(&prefix))
+    };
+    let num_used = (0..).take_while(|&idx| is_used(idx)).count();
+    num_used > 0 && layer_idx >= num_used
+}
+
+fn list(names: &[String]) -> String {
+    let mut s = names.iter().take(MAX_LISTED).cloned().collect::<Vec<_>>().join(", ");
+    if names.len() > MAX_LISTED {
+        s.push_str(&format!(" and {} more", names.len() - MAX_LISTED))
+    }
+    s
+}
+
+/// Builder used to load the language model and mimi, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct ModelLoader {
+    path: PathBuf,
+    mimi_path: Option<PathBuf>,
+    format: Option<WeightFormat>,
+    lm: Placement,
+    // Unset values default to the ones of the main transformer.
+    depformer_dtype: Option<DType>,
+    depformer_device: Option<Device>,
+    mimi: Placement,
+    default_lm_config: Option<lm::Config>,
+    default_mimi_config: Option<encodec::Config>,
+    mimi_num_codebooks: Option<usize>,
+    strict: bool,
+}
+
+impl ModelLoader {
+    /// `path` is either a weight file or a directory containing the weights. In a directory,
+    /// the language model is read from `model.safetensors.index.json`, `model.safetensors` or a
+    /// single `.gguf` file, and mimi from `mimi.safetensors` or `tokenizer-*.safetensors`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let cpu = Placement { dtype: DType::F32, device: Device::Cpu };
+        Self {
+            path: path.as_ref().to_path_buf(),
+            mimi_path: None,
+            format: None,
+            lm: cpu.clone(),
+            depformer_dtype: None,
+            depformer_device: None,
+            mimi: cpu,
+            default_lm_config: None,
+            default_mimi_config: None,
+            mimi_num_codebooks: None,
+            strict: false,
+        }
+    }
+
+    /// Sets the device for all the components.
+    pub fn device(mut self, device: &Device) -> Self {
+        self.lm.device = device.clone();
+        self.depformer_device = None;
+        self.mimi.device = device.clone();
+        self
+    }
+
+    /// Sets the dtype for all the components, the dtype of gguf weights is set by the file.
+    pub fn dtype(mut self, dtype: DType) -> Self {
+        self.lm.dtype = dtype;
+        self.depformer_dtype = None;
+        self.mimi.dtype = dtype;
+        self
+    }
+
+    pub fn lm_device(mut self, device: &Device) -> Self {
+        self.lm.device = device.clone();
+        self
+    }
+
+    pub fn lm_dtype(mut self, dtype: DType) -> Self {
+        self.lm.dtype = dtype;
+        self
+    }
+
+    /// By default the depformer uses the same device as the main transformer. A separate
+    /// placement is not supported for gguf weights.
+    pub fn depformer_device(mut self, device: &Device) -> Self {
+        self.depformer_device = Some(device.clone());
+        self
+    }
+
+    pub fn depformer_dtype(mut self, dtype: DType) -> Self {
+        self.depformer_dtype = Some(dtype);
+        self
+    }
+
+    pub fn mimi_device(mut self, device: &Device) -> Self {
+        self.mimi.device = device.clone();
+        self
+    }
+
+    pub fn mimi_dtype(mut self, dtype: DType) -> Self {
+        self.mimi.dtype = dtype;
+        self
+    }
+
+    /// Uses a separate mimi weight file rather than looking it up in the model directory.
+    pub fn mimi_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.mimi_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Overrides the format detection for the language model weights.
+    pub fn format(mut self, format: WeightFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Configuration used for the language model when none is provided alongside the weights,
+    /// `lm::Config::v0_1` is used if not set.
+    pub fn default_lm_config(mut self, cfg: lm::Config) -> Self {
+        self.default_lm_config = Some(cfg);
+        self
+    }
+
+    /// Configuration used for mimi when none is provided alongside the weights,
+    /// `encodec::Config::v0_1` is used if not set.
+    pub fn default_mimi_config(mut self, cfg: encodec::Config) -> Self {
+        self.default_mimi_config = Some(cfg);
+        self
+    }
+
+    /// Number of codebooks used by mimi, this overrides the value from the configuration.
+    pub fn mimi_num_codebooks(mut self, num_codebooks: usize) -> Self {
+        self.mimi_num_codebooks = Some(num_codebooks);
+        self
+    }
+
+    /// When set, tensors from the weight files that are not used by the model result in an
+    /// error rather than a warning.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    fn lm_path(&self) -> Result<PathBuf> {
+        if !self.path.is_dir() {
+            return Ok(self.path.clone());
+        }
+        for name in ["model.safetensors.index.json", "model.safetensors"] {
+            let p = self.path.join(name);
+            if p.exists() {
+                return Ok(p);
+            }
+        }
+        let ggufs = files_matching(&self.path, |n| n.ends_with(".gguf"))?;
+        match ggufs.as_slice() {
+            [p] => Ok(p.clone()),
+            [] => candle::bail!(
+                "{:?}: no model.safetensors.index.json, model.safetensors or .gguf file",
+                self.path
+            ),
+            _ => candle::bail!("{:?}: multiple gguf files {ggufs:?}", self.path),
+        }
+    }
+
+    fn mimi_path_(&self) -> Result<PathBuf> {
+        if let Some(p) = self.mimi_path.as_ref() {
+            return Ok(p.clone());
+        }
+        if !self.path.is_dir() {
+            return Ok(self.path.clone());
+        }
+        let p = self.path.join("mimi.safetensors");
+        if p.exists() {
+            return Ok(p);
+        }
+        let files = files_matching(&self.path, |n| {
+            n.starts_with("tokenizer-") && n.ends_with(".safetensors")
+        })?;
+        match files.as_slice() {
+            [p] => Ok(p.clone()),
+            [] => candle::bail!(
+                "{:?}: no mimi.safetensors or tokenizer-*.safetensors file",
+                self.path
+            ),
+            _ => candle::bail!("{:?}: multiple mimi weight files {files:?}", self.path),
+        }
+    }
+
+    // Returns the safetensors files for a single or sharded checkpoint.
+    fn safetensors_files(path: &Path, format: WeightFormat) -> Result<Vec<PathBuf>> {
+        if format != WeightFormat::ShardedSafetensors {
+            return Ok(vec![path.to_path_buf()]);
+        }
+        let index = std::fs::read_to_string(path)?;
+        let index: serde_json::Value = serde_json::from_str(&index)
+            .map_err(|e| candle::Error::Msg(format!("{path:?}: {e}")))?;
+        let weight_map = match index.get("weight_map").and_then(|v| v.as_object()) {
+            None => candle::bail!("{path:?}: no weight_map in index"),
+            Some(weight_map) => weight_map,
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut files = vec![];
+        for file in weight_map.values() {
+            match file.as_str() {
+                None => candle::bail!("{path:?}: unexpected weight_map value {file}"),
+                Some(file) => {
+                    let file = dir.join(file);
+                    if !files.contains(&file) {
+                        files.push(file)
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    // Builds a model checking that all the tensors are available with the expected shapes,
+    // `f` gets a function returning a var builder for a given dtype and device so that
+    // components can be placed differently.
+    fn build_checked<M, F>(
+        &self,
+        what: &str,
+        source: &str,
+        backend: CheckedBackend,
+        f: F,
+    ) -> Result<M>
+    where
+        F: FnOnce(&dyn Fn(DType, &Device) -> candle_nn::VarBuilder<'static>) -> Result<M>,
+    {
+        let model = f(&|dtype, device| backend.vb(dtype, device))?;
+        let report = backend.report.lock().unwrap();
+        let mut unexpected = backend
+            .shapes
+            .keys()
+            .filter(|n| !report.used.contains(*n) && !is_unused_codebook(n, &report.used))
+            .cloned()
+            .collect::<Vec<_>>();
+        unexpected.sort();
+        let mut errors = vec![];
+        if !report.missing.is_empty() {
+            errors.push(format!("missing tensors: {}", list(&report.missing)))
+        }
+        if !report.mismatched.is_empty() {
+            errors.push(format!("shape mismatches: {}", list(&report.mismatched)))
+        }
+        if !unexpected.is_empty() {
+            let msg = format!("unexpected tensors: {}", list(&unexpected));
+            if self.strict {
+                errors.push(msg)
+            } else {
+                tracing::warn!("{what} {source}: {msg}")
+            }
+        }
+        if !errors.is_empty() {
+            candle::bail!("invalid {what} weights {source}\n{}", errors.join("\n"))
+        }
+        Ok(model)
+    }
+
+    /// Loads the language model, the configuration is read alongside the weights if available.
+    pub fn load_lm(&self) -> Result<lm::LmModel> {
+        let path = self.lm_path()?;
+        let format = match self.format {
+            Some(format) => format,
+            None => WeightFormat::detect(&path)?,
+        };
+        let files = Self::safetensors_files(&path, format)?;
+        let cfg = match lm::Config::from_model_file(&files[0])? {
+            Some(cfg) => cfg,
+            None => self.default_lm_config.clone().unwrap_or_else(lm::Config::v0_1),
+        };
+        let model = match format {
+            WeightFormat::Gguf => {
+                // The quantized model uses the same tensor names and shapes as the float one, a
+                // dry run of the latter on placeholder tensors validates the whole file at once.
+                let backend = CheckedBackend::gguf(&path)?;
+                self.build_checked("lm", &format!("{path:?}"), backend, |vb| {
+                    lm::Lm::new(&cfg, vb(DType::F32, &Device::Cpu))
+                })?;
+                let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
+                    &path,
+                    &self.lm.device,
+                )?;
+                let lm = crate::quantized_lm::Lm::new(&cfg, vb)
+                    .map_err(|e| candle::Error::Msg(format!("invalid lm weights {path:?}: {e}")))?;
+                lm::LmModel::QuantizedLm(lm)
+            }
+            WeightFormat::Safetensors | WeightFormat::ShardedSafetensors => {
+                let depformer_dtype = self.depformer_dtype.unwrap_or(self.lm.dtype);
+                let depformer_device = self.depformer_device.as_ref().unwrap_or(&self.lm.device);
+                let backend = CheckedBackend::safetensors(&files)?;
+                let lm = self.build_checked("lm", &format!("{files:?}"), backend, |vb| {
+                    let vb_depformer = vb(depformer_dtype, depformer_device).pp("depformer");
+                    lm::Lm::new_with_depformer_vb(
+                        &cfg,
+                        vb(self.lm.dtype, &self.lm.device),
+                        vb_depformer,
+                    )
+                })?;
+                lm::LmModel::Lm(lm)
+            }
+        };
+        Ok(model)
+    }
+
+    /// Loads mimi, the configuration is read alongside the weights if available.
+    pub fn load_mimi(&self) -> Result<encodec::Encodec> {
+        let path = self.mimi_path_()?;
+        let format = WeightFormat::detect(&path)?;
+        if format == WeightFormat::Gguf {
+            candle::bail!("{path:?}: gguf weights are not supported for mimi")
+        }
+        let files = Self::safetensors_files(&path, format)?;
+        let mut cfg = match encodec::Config::from_model_file(&files[0])? {
+            Some(cfg) => cfg,
+            None => self.default_mimi_config.clone().unwrap_or_else(|| encodec::Config::v0_1(None)),
+        };
+        if let Some(num_codebooks) = self.mimi_num_codebooks {
+            cfg.quantizer_n_q = num_codebooks
+        }
+        let backend = CheckedBackend::safetensors(&files)?;
+        self.build_checked("mimi", &format!("{files:?}"), backend, |vb| {
+            encodec::Encodec::new(cfg, vb(self.mimi.dtype, &self.mimi.device))
+        })
+    }
+}
+
+fn files_matching<F: Fn(&str) -> bool>(dir: &Path, f: F) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let matches = path.file_name().and_then(|n| n.to_str()).is_some_and(&f);
+        if matches && path.is_file() {
+            files.push(path)
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encodec::test_utils::{randomize, small_config};
+
+    // A fresh directory for each test, the content from previous runs is removed.
+    fn test_dir(name: &str) -> Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("moshi-loader-{}-{name}", std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn format_detection() -> Result<()> {
+        let dir = test_dir("detect")?;
+        let detect = |name: &str| WeightFormat::detect(dir.join(name));
+        assert_eq!(detect("model.safetensors")?, WeightFormat::Safetensors);
+        assert_eq!(detect("model.q8.gguf")?, WeightFormat::Gguf);
+        assert_eq!(detect("model.safetensors.index.json")?, WeightFormat::ShardedSafetensors);
+        // Other extensions fall back on the file magic.
+        std::fs::write(dir.join("model.bin"), b"GGUF\x03\x00\x00\x00")?;
+        assert_eq!(detect("model.bin")?, WeightFormat::Gguf);
+        std::fs::write(dir.join("weights"), 2u64.to_le_bytes())?;
+        assert_eq!(detect("weights")?, WeightFormat::Safetensors);
+        assert!(detect("missing").is_err());
+
+        // In a directory, the sharded index comes first, then the single file, then gguf.
+        let loader = ModelLoader::new(&dir);
+        assert!(loader.lm_path().is_err());
+        std::fs::write(dir.join("model.q8.gguf"), b"GGUF")?;
+        assert_eq!(loader.lm_path()?, dir.join("model.q8.gguf"));
+        std::fs::write(dir.join("model.q4.gguf"), b"GGUF")?;
+        assert!(loader.lm_path().is_err());
+        std::fs::write(dir.join("model.safetensors"), b"")?;
+        assert_eq!(loader.lm_path()?, dir.join("model.safetensors"));
+        let index = dir.join("model.safetensors.index.json");
+        let weight_map = r#"{"weight_map": {"a": "model-1.safetensors", "b": "model-2.safetensors", "c": "model-1.safetensors"}}"#;
+        std::fs::write(&index, weight_map)?;
+        assert_eq!(loader.lm_path()?, index);
+        let files = ModelLoader::safetensors_files(&index, WeightFormat::ShardedSafetensors)?;
+        assert_eq!(files, [dir.join("model-1.safetensors"), dir.join("model-2.safetensors")]);
+
+        assert!(loader.mimi_path_().is_err());
+        std::fs::write(dir.join("tokenizer-e351c8d8.safetensors"), b"")?;
+        assert_eq!(loader.mimi_path_()?, dir.join("tokenizer-e351c8d8.safetensors"));
+        std::fs::write(dir.join("mimi.safetensors"), b"")?;
+        assert_eq!(loader.mimi_path_()?, dir.join("mimi.safetensors"));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn safetensors_report() -> Result<()> {
+        let dir = test_dir("safetensors")?;
+        let path = dir.join("mimi.safetensors");
+        // The checkpoint holds more codebooks than the ones being loaded, these are not reported
+        // even in strict mode.
+        let vm = candle_nn::VarMap::new();
+        randomize(&small_config(1, 6), &vm)?;
+        vm.save(&path)?;
+        let loader = ModelLoader::new(&dir).default_mimi_config(small_config(1, 4)).strict(true);
+        loader.load_mimi()?;
+
+        let mut tensors = candle::safetensors::load(&path, &Device::Cpu)?;
+        let first = "quantizer.rvq_first.vq.layers.0._codebook.embedding_sum";
+        let rest = "quantizer.rvq_rest.vq.layers.1._codebook.embedding_sum";
+        tensors.remove(first);
+        tensors.insert(rest.to_string(), Tensor::zeros((32, 4), DType::F32, &Device::Cpu)?);
+        tensors.insert("foo.weight".to_string(), Tensor::zeros(2, DType::F32, &Device::Cpu)?);
+        candle::safetensors::save(&tensors, &path)?;
+        let err = loader.load_mimi().unwrap_err().to_string();
+        assert!(err.contains(&format!("missing tensors: {first} [32, 8]")), "{err}");
+        assert!(err.contains(&format!("shape mismatches: {rest} expected [32, 8] got [32, 4]")));
+        assert!(err.contains("unexpected tensors: foo.weight"), "{err}");
+        // Outside of strict mode, unexpected tensors only result in a warning.
+        let err = loader.clone().strict(false).load_mimi().unwrap_err().to_string();
+        assert!(err.contains("missing tensors") && !err.contains("unexpected"), "{err}");
+
+        // Sharded checkpoints are checked as a whole.
+        tensors.remove(rest);
+        tensors.remove("foo.weight");
+        let (lhs, rhs): (Vec<_>, Vec<_>) = tensors.into_iter().partition(|(n, _)| n.as_str() < "e");
+        let mut weight_map = serde_json::Map::new();
+        for (i, shard) in [lhs, rhs].into_iter().enumerate() {
+            let file = format!("mimi-{i}.safetensors");
+            for (name, _) in shard.iter() {
+                weight_map.insert(name.clone(), file.clone().into());
+            }
+            let shard = shard.into_iter().collect::<HashMap<_, _>>();
+            candle::safetensors::save(&shard, dir.join(file))?;
+        }
+        let index = dir.join("mimi.safetensors.index.json");
+        std::fs::write(&index, serde_json::json!({ "weight_map": weight_map }).to_string())?;
+        let err = loader.clone().mimi_path(&index).load_mimi().unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("missing tensors: {first} [32, 8], {rest} [32, 8]")),
+            "{err}"
+        );
+        assert!(!err.contains("shape mismatches") && !err.contains("unexpected"), "{err}");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn gguf_report() -> Result<()> {
+        use crate::lm::test_utils;
+
+        let dir = test_dir("gguf")?;
+        let cfg = test_utils::small_config();
+        let vm = candle_nn::VarMap::new();
+        test_utils::randomize(&cfg, &vm)?;
+        std::fs::write(dir.join("model.gguf"), test_utils::gguf_bytes(&vm)?)?;
+        let loader = ModelLoader::new(&dir).default_lm_config(cfg).strict(true);
+        assert!(matches!(loader.load_lm()?, lm::LmModel::QuantizedLm(_)));
+
+        {
+            let mut data = vm.data().lock().unwrap();
+            data.remove("text_emb.weight");
+            let var = candle::Var::zeros((3, 16), DType::F32, &Device::Cpu)?;
+            data.insert("text_linear.weight".to_string(), var);
+            let var = candle::Var::zeros(2, DType::F32, &Device::Cpu)?;
+            data.insert("foo.weight".to_string(), var);
+        }
+        std::fs::write(dir.join("model.gguf"), test_utils::gguf_bytes(&vm)?)?;
+        let err = loader.load_lm().unwrap_err().to_string();
+        assert!(err.contains("missing tensors: text_emb.weight [11, 16]"), "{err}");
+        assert!(err.contains("shape mismatches: text_linear.weight expected [10, 16] got [3, 16]"));
+        assert!(err.contains("unexpected tensors: foo.weight"), "{err}");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
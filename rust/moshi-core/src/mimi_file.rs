@@ -0,0 +1,792 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The `.mimi` file format, a compact container for mimi codes.
+//!
+//! A file starts with a fixed size header followed by a range coded payload. Each codebook uses
+//! its own adaptive frequency model, these models can be initialized with priors, e.g. estimated
+//! from the codebook histograms of a representative dataset. The priors are not stored in the
+//! file, only their hash is, so the same priors have to be provided when decoding.
+//!
+//! Each frame is preceded by a continuation flag so that files can be written in a streaming way
+//! without knowing the number of frames in advance.
+
+use candle::{Device, Result, Tensor};
+
+const MAGIC: &[u8; 4] = b"MIMI";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 37;
+
+// Range coder constants, the range is kept above TOP so that dividing it by a total frequency
+// of at most MAX_TOTAL retains enough precision.
+const TOP: u32 = 1 << 24;
+const MAX_TOTAL: u32 = 1 << 16;
+const INCREMENT: u32 = 32;
+
+/// 64 bits FNV-1a hash, used to identify the codec weights and the priors.
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub sample_rate: u32,
+    pub frame_rate: f64,
+    pub n_q: usize,
+    pub bins: usize,
+    /// Identifies the codec that produced the codes, e.g. `hash_bytes` on the weights file.
+    pub codec_hash: u64,
+    /// Hash of the priors used for the frequency models, 0 when no priors are used.
+    pub priors_hash: u64,
+}
+
+impl Header {
+    pub fn new(cfg: &crate::encodec::Config, n_q: usize, codec_hash: u64) -> Self {
+        Self {
+            sample_rate: cfg.sample_rate as u32,
+            frame_rate: cfg.frame_rate,
+            n_q,
+            bins: cfg.quantizer_bins,
+            codec_hash,
+            priors_hash: 0,
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.n_q == 0 || self.n_q > u16::MAX as usize {
+            candle::bail!("unsupported number of codebooks {}", self.n_q)
+        }
+        if self.bins < 2 || self.bins > (MAX_TOTAL / 2) as usize {
+            candle::bail!("unsupported number of bins {}", self.bins)
+        }
+        let mut data = Vec::with_capacity(HEADER_LEN);
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&self.sample_rate.to_le_bytes());
+        data.extend_from_slice(&self.frame_rate.to_le_bytes());
+        data.extend_from_slice(&(self.n_q as u16).to_le_bytes());
+        data.extend_from_slice(&(self.bins as u16).to_le_bytes());
+        data.extend_from_slice(&self.codec_hash.to_le_bytes());
+        data.extend_from_slice(&self.priors_hash.to_le_bytes());
+        Ok(data)
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+            candle::bail!("not a mimi file")
+        }
+        if data[4] != VERSION {
+            candle::bail!("unsupported mimi file version {}", data[4])
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]) as usize;
+        let u64_at = |i: usize| {
+            let mut v = [0u8; 8];
+            v.copy_from_slice(&data[i..i + 8]);
+            v
+        };
+        let header = Self {
+            sample_rate: u32::from_le_bytes([data[5], data[6], data[7], data[8]]),
+            frame_rate: f64::from_le_bytes(u64_at(9)),
+            n_q: u16_at(17),
+            bins: u16_at(19),
+            codec_hash: u64::from_le_bytes(u64_at(21)),
+            priors_hash: u64::from_le_bytes(u64_at(29)),
+        };
+        if header.n_q == 0 || header.bins < 2 || header.bins > (MAX_TOTAL / 2) as usize {
+            candle::bail!("invalid mimi file header {header:?}")
+        }
+        Ok(header)
+    }
+}
+
+/// Initial code distributions for each codebook.
+#[derive(Debug, Clone)]
+pub struct Priors {
+    // Initial frequencies, all of them are positive and sum to at most MAX_TOTAL / 2.
+    freqs: Vec<Vec<u32>>,
+}
+
+impl Priors {
+    /// Builds priors from per-codebook code counts.
+    pub fn from_histograms(histograms: &[Vec<u64>]) -> Result<Self> {
+        let bins = match histograms.first() {
+            None => candle::bail!("empty histograms"),
+            Some(h) => h.len(),
+        };
+        if bins < 2 || bins > (MAX_TOTAL / 4) as usize {
+            candle::bail!("unsupported number of bins {bins}")
+        }
+        let mut freqs = Vec::with_capacity(histograms.len());
+        for h in histograms.iter() {
+            if h.len() != bins {
+                candle::bail!("inconsistent histogram sizes {} {bins}", h.len())
+            }
+            // Every code gets a frequency of at least one and the observed counts share what
+            // remains of half the maximum total, leaving room for adaptation.
+            let budget = (MAX_TOTAL / 2) as u64 - bins as u64;
+            let sum = h.iter().sum::<u64>().max(1);
+            freqs.push(
+                h.iter().map(|&c| 1 + (c as u128 * budget as u128 / sum as u128) as u32).collect(),
+            )
+        }
+        Ok(Self { freqs })
+    }
+
+    pub fn from_stats(stats: &crate::quantization::CodebookStats) -> Result<Self> {
+        let histograms = (0..stats.n_q()).map(|l| stats.histogram(l).to_vec()).collect::<Vec<_>>();
+        Self::from_histograms(&histograms)
+    }
+
+    pub fn n_q(&self) -> usize {
+        self.freqs.len()
+    }
+
+    pub fn bins(&self) -> usize {
+        self.freqs[0].len()
+    }
+
+    pub fn hash(&self) -> u64 {
+        let data = self.freqs.iter().flatten().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        hash_bytes(&data)
+    }
+
+    pub fn to_tensor(&self) -> Result<Tensor> {
+        let shape = (self.n_q(), self.bins());
+        Tensor::from_vec(self.freqs.concat(), shape, &Device::Cpu)
+    }
+
+    /// Builds priors from a (n_q, bins) tensor as returned by `to_tensor`.
+    pub fn from_tensor(freqs: &Tensor) -> Result<Self> {
+        let freqs = freqs.to_dtype(candle::DType::U32)?.to_vec2::<u32>()?;
+        let bins = match freqs.first() {
+            Some(f) if f.len() >= 2 => f.len(),
+            _ => candle::bail!("empty priors"),
+        };
+        for f in freqs.iter() {
+            if f.len() != bins || f.contains(&0) || f.iter().sum::<u32>() > MAX_TOTAL / 2 {
+                candle::bail!("invalid prior frequencies")
+            }
+        }
+        Ok(Self { freqs })
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, p: P) -> Result<()> {
+        let tensors = std::collections::HashMap::from([("priors", self.to_tensor()?)]);
+        candle::safetensors::save(&tensors, p)
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let tensors = candle::safetensors::load(p, &Device::Cpu)?;
+        match tensors.get("priors") {
+            None => candle::bail!("no priors tensor"),
+            Some(t) => Self::from_tensor(t),
+        }
+    }
+}
+
+// Adaptive frequency model, the cumulative frequencies are maintained in a Fenwick tree so that
+// both encoding and decoding are logarithmic in the number of symbols.
+#[derive(Debug, Clone)]
+struct FrequencyModel {
+    freqs: Vec<u32>,
+    tree: Vec<u32>,
+    total: u32,
+}
+
+impl FrequencyModel {
+    fn new(freqs: Vec<u32>) -> Self {
+        let mut s = Self { tree: vec![0; freqs.len() + 1], freqs, total: 0 };
+        s.rebuild();
+        s
+    }
+
+    fn rebuild(&mut self) {
+        self.tree.iter_mut().for_each(|v| *v = 0);
+        for i in 0..self.freqs.len() {
+            let mut j = i + 1;
+            while j < self.tree.len() {
+                self.tree[j] += self.freqs[i];
+                j += j & j.wrapping_neg();
+            }
+        }
+        self.total = self.freqs.iter().sum();
+    }
+
+    // Sum of the frequencies of the symbols strictly below `symbol`.
+    fn cumulative(&self, symbol: usize) -> u32 {
+        let mut sum = 0;
+        let mut j = symbol;
+        while j > 0 {
+            sum += self.tree[j];
+            j &= j - 1;
+        }
+        sum
+    }
+
+    // Returns the symbol whose cumulative range contains `target` together with the cumulative
+    // frequency of that symbol.
+    fn find(&self, target: u32) -> (usize, u32) {
+        let mut pos = 0;
+        let mut cum = 0;
+        let mut step = (self.tree.len() - 1).next_power_of_two();
+        while step > 0 {
+            let next = pos + step;
+            if next < self.tree.len() && cum + self.tree[next] <= target {
+                pos = next;
+                cum += self.tree[next];
+            }
+            step >>= 1;
+        }
+        (pos, cum)
+    }
+
+    fn update(&mut self, symbol: usize) {
+        if self.total + INCREMENT > MAX_TOTAL {
+            self.freqs.iter_mut().for_each(|f| *f = f.div_ceil(2));
+            self.rebuild();
+        }
+        self.freqs[symbol] += INCREMENT;
+        self.total += INCREMENT;
+        let mut j = symbol + 1;
+        while j < self.tree.len() {
+            self.tree[j] += INCREMENT;
+            j += j & j.wrapping_neg();
+        }
+    }
+}
+
+// The continuation flag model followed by one model per codebook.
+fn models(header: &Header, priors: Option<&Priors>) -> Result<Vec<FrequencyModel>> {
+    let mut models = vec![FrequencyModel::new(vec![1, 1])];
+    match priors {
+        None => {
+            for _ in 0..header.n_q {
+                models.push(FrequencyModel::new(vec![1; header.bins]))
+            }
+        }
+        Some(priors) => {
+            if priors.n_q() < header.n_q || priors.bins() != header.bins {
+                candle::bail!(
+                    "priors for {}x{} codes cannot be used for {}x{}",
+                    priors.n_q(),
+                    priors.bins(),
+                    header.n_q,
+                    header.bins
+                )
+            }
+            for freqs in priors.freqs.iter().take(header.n_q) {
+                models.push(FrequencyModel::new(freqs.clone()))
+            }
+        }
+    }
+    Ok(models)
+}
+
+/// Writes `.mimi` files, either in one go with `encode` or frame by frame with `encode_step`
+/// followed by `finish`. The returned bytes should be concatenated.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    header: Header,
+    models: Vec<FrequencyModel>,
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+    finished: bool,
+}
+
+impl Encoder {
+    /// The `priors_hash` field of the header is set from `priors`.
+    pub fn new(header: &Header, priors: Option<&Priors>) -> Result<Self> {
+        let mut header = header.clone();
+        header.priors_hash = priors.map_or(0, |p| p.hash());
+        let models = models(&header, priors)?;
+        let out = header.to_bytes()?;
+        Ok(Self {
+            header,
+            models,
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out,
+            finished: false,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xFF000000 || self.low > u32::MAX as u64 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FFFFFF) << 8;
+    }
+
+    fn encode_symbol(&mut self, model_idx: usize, symbol: usize) {
+        let model = &self.models[model_idx];
+        let r = self.range / model.total;
+        self.low += r as u64 * model.cumulative(symbol) as u64;
+        self.range = r * model.freqs[symbol];
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low()
+        }
+        self.models[model_idx].update(symbol)
+    }
+
+    /// Encodes the codes for a single frame.
+    pub fn encode_frame(&mut self, codes: &[u32]) -> Result<()> {
+        if self.finished {
+            candle::bail!("encode_frame called on a finished mimi encoder")
+        }
+        if codes.len() != self.header.n_q {
+            candle::bail!("expected {} codes, got {}", self.header.n_q, codes.len())
+        }
+        if let Some(c) = codes.iter().find(|&&c| c as usize >= self.header.bins) {
+            candle::bail!("code {c} is out of range for {} bins", self.header.bins)
+        }
+        self.encode_symbol(0, 1);
+        for (i, &c) in codes.iter().enumerate() {
+            self.encode_symbol(i + 1, c as usize)
+        }
+        Ok(())
+    }
+
+    /// Encodes codes of shape (1, n_q, t) and returns the bytes that are ready to be written.
+    /// The range coder holds back a few bytes so the last frames only become decodable once more
+    /// data has been written or the stream has been finished.
+    pub fn encode_step(&mut self, codes: &Tensor) -> Result<Vec<u8>> {
+        let codes = codes.squeeze(0)?.t()?.to_vec2::<u32>()?;
+        for codes in codes.iter() {
+            self.encode_frame(codes)?
+        }
+        Ok(std::mem::take(&mut self.out))
+    }
+
+    /// Marks the end of the stream and returns the remaining bytes.
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        if !self.finished {
+            self.encode_symbol(0, 0);
+            for _ in 0..5 {
+                self.shift_low()
+            }
+            self.finished = true;
+        }
+        Ok(std::mem::take(&mut self.out))
+    }
+}
+
+/// Reads `.mimi` files, the data can be provided incrementally with `push_bytes`, frames are
+/// returned by `decode_step` as soon as they can be decoded.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    priors: Option<Priors>,
+    codec_hash: Option<u64>,
+    header: Option<Header>,
+    models: Vec<FrequencyModel>,
+    data: Vec<u8>,
+    pos: usize,
+    code: u32,
+    range: u32,
+    // Set when the range decoder has been initialized.
+    started: bool,
+    // Set once all the input data has been provided.
+    input_done: bool,
+    // Set when the end of stream flag has been decoded.
+    done: bool,
+}
+
+impl Decoder {
+    pub fn new(priors: Option<Priors>) -> Self {
+        Self {
+            priors,
+            codec_hash: None,
+            header: None,
+            models: vec![],
+            data: vec![],
+            pos: 0,
+            code: 0,
+            range: u32::MAX,
+            started: false,
+            input_done: false,
+            done: false,
+        }
+    }
+
+    /// Rejects files that have not been produced by the codec identified by `codec_hash`.
+    pub fn with_codec_hash(mut self, codec_hash: u64) -> Self {
+        self.codec_hash = Some(codec_hash);
+        self
+    }
+
+    /// The header is available once enough bytes have been pushed.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    pub fn push_bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data)
+    }
+
+    /// Signals that no more data will be pushed.
+    pub fn finish_input(&mut self) {
+        self.input_done = true
+    }
+
+    /// Returns true once the end of stream marker has been decoded.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        let byte = match self.data.get(self.pos) {
+            Some(&b) => b,
+            None if !self.input_done => return Ok(None),
+            // The encoder flushes all the bytes that the decoder reads up to the end of stream
+            // marker, reading past them only happens for truncated files.
+            None => candle::bail!("truncated mimi file"),
+        };
+        self.pos += 1;
+        Ok(Some(byte))
+    }
+
+    // Decodes a symbol without updating the model, returns None if more data is required.
+    fn decode_symbol(&mut self, model_idx: usize) -> Result<Option<usize>> {
+        let model = &self.models[model_idx];
+        let r = self.range / model.total;
+        let target = u32::min(self.code / r, model.total - 1);
+        let (symbol, cum) = model.find(target);
+        self.code -= r * cum;
+        self.range = r * model.freqs[symbol];
+        while self.range < TOP {
+            match self.next_byte()? {
+                None => return Ok(None),
+                Some(b) => {
+                    self.code = (self.code << 8) | b as u32;
+                    self.range <<= 8;
+                }
+            }
+        }
+        Ok(Some(symbol))
+    }
+
+    fn start(&mut self) -> Result<bool> {
+        if self.header.is_none() {
+            if self.data.len() < HEADER_LEN {
+                if self.input_done {
+                    candle::bail!("truncated mimi file header")
+                }
+                return Ok(false);
+            }
+            let header = Header::from_bytes(&self.data[..HEADER_LEN])?;
+            if self.codec_hash.is_some_and(|h| h != header.codec_hash) {
+                candle::bail!("the mimi file has been encoded with a different codec")
+            }
+            let priors_hash = self.priors.as_ref().map_or(0, |p| p.hash());
+            if priors_hash != header.priors_hash {
+                candle::bail!("the mimi file has been encoded with different priors")
+            }
+            self.models = models(&header, self.priors.as_ref())?;
+            self.header = Some(header);
+            self.pos = HEADER_LEN;
+        }
+        if !self.started {
+            if self.data.len() < self.pos + 5 && !self.input_done {
+                return Ok(false);
+            }
+            // The first byte output by the range encoder is always 0.
+            for _ in 0..5 {
+                let b = self.next_byte()?.unwrap_or(0);
+                self.code = (self.code << 8) | b as u32;
+            }
+            self.started = true;
+        }
+        Ok(true)
+    }
+
+    // Decodes a single frame, the decoder state is left unchanged if there is not enough data.
+    fn decode_frame(&mut self) -> Result<Option<Vec<u32>>> {
+        let (pos, code, range) = (self.pos, self.code, self.range);
+        let mut symbols = Vec::with_capacity(self.models.len());
+        for model_idx in 0..self.models.len() {
+            match self.decode_symbol(model_idx)? {
+                None => {
+                    (self.pos, self.code, self.range) = (pos, code, range);
+                    return Ok(None);
+                }
+                Some(0) if model_idx == 0 => {
+                    self.done = true;
+                    return Ok(None);
+                }
+                Some(s) => symbols.push(s),
+            }
+        }
+        // Each model is used once per frame so the updates can be delayed until the frame has
+        // been fully decoded.
+        for (model, &s) in self.models.iter_mut().zip(symbols.iter()) {
+            model.update(s)
+        }
+        Ok(Some(symbols[1..].iter().map(|&s| s as u32).collect()))
+    }
+
+    /// Decodes all the frames available so far, returning codes of shape (1, n_q, t) or None
+    /// if no new frame could be decoded.
+    pub fn decode_step(&mut self, dev: &Device) -> Result<Option<Tensor>> {
+        if self.done || !self.start()? {
+            return Ok(None);
+        }
+        let mut frames = vec![];
+        while let Some(frame) = self.decode_frame()? {
+            frames.extend(frame)
+        }
+        if self.input_done && !self.done {
+            candle::bail!("truncated mimi file")
+        }
+        // Drop the consumed data, the range decoder only looks at the bytes after pos.
+        if self.pos > 4096 {
+            self.data.drain(..self.pos);
+            self.pos = 0;
+        }
+        if frames.is_empty() {
+            return Ok(None);
+        }
+        let n_q = self.models.len() - 1;
+        let t = frames.len() / n_q;
+        let codes = Tensor::from_vec(frames, (t, n_q), dev)?;
+        Ok(Some(codes.t()?.unsqueeze(0)?))
+    }
+}
+
+/// Encodes codes of shape (1, n_q, t) to the content of a `.mimi` file.
+pub fn encode(header: &Header, priors: Option<&Priors>, codes: &Tensor) -> Result<Vec<u8>> {
+    let mut encoder = Encoder::new(header, priors)?;
+    let mut data = encoder.encode_step(codes)?;
+    data.extend(encoder.finish()?);
+    Ok(data)
+}
+
+/// Decodes the content of a `.mimi` file, returning the header and codes of shape (1, n_q, t).
+pub fn decode(data: &[u8], priors: Option<Priors>, dev: &Device) -> Result<(Header, Tensor)> {
+    let mut decoder = Decoder::new(priors);
+    decoder.push_bytes(data);
+    decoder.finish_input();
+    let codes = decoder.decode_step(dev)?;
+    let header = match decoder.header() {
+        None => candle::bail!("truncated mimi file header"),
+        Some(header) => header.clone(),
+    };
+    let codes = match codes {
+        Some(codes) => codes,
+        None => Tensor::zeros((1, header.n_q, 0), candle::DType::U32, dev)?,
+    };
+    Ok((header, codes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODEC_HASH: u64 = 0x0102_0304_0506_0708;
+
+    fn header(n_q: usize, bins: usize) -> Header {
+        Header {
+            sample_rate: 24000,
+            frame_rate: 12.5,
+            n_q,
+            bins,
+            codec_hash: CODEC_HASH,
+            priors_hash: 0,
+        }
+    }
+
+    // Random codes of shape (1, n_q, t), the lower codes are more likely so that priors help.
+    fn random_codes(seed: u64, n_q: usize, bins: usize, t: usize) -> Result<Tensor> {
+        let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        let codes = (0..n_q * t)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                let (a, b) = ((x >> 11) as usize % bins, (x >> 37) as usize % bins);
+                (a * b / bins) as u32
+            })
+            .collect::<Vec<_>>();
+        Tensor::from_vec(codes, (1, n_q, t), &Device::Cpu)
+    }
+
+    fn estimate_priors(codes: &Tensor, bins: usize) -> Result<Priors> {
+        let histograms = codes
+            .squeeze(0)?
+            .to_vec2::<u32>()?
+            .iter()
+            .map(|codes| {
+                let mut h = vec![0u64; bins];
+                codes.iter().for_each(|&c| h[c as usize] += 1);
+                h
+            })
+            .collect::<Vec<_>>();
+        Priors::from_histograms(&histograms)
+    }
+
+    #[test]
+    fn header_layout() -> Result<()> {
+        let h = Header { priors_hash: 0x1112_1314_1516_1718, ..header(8, 2048) };
+        let bytes = h.to_bytes()?;
+        assert_eq!(bytes.len(), HEADER_LEN);
+        assert_eq!(&bytes[..5], b"MIMI\x01");
+        assert_eq!(bytes[5..9], 24000u32.to_le_bytes());
+        assert_eq!(bytes[9..17], 12.5f64.to_le_bytes());
+        assert_eq!(bytes[17..21], [8, 0, 0, 8]);
+        assert_eq!(bytes[21..29], [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(bytes[29..37], [0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11]);
+        assert_eq!(Header::from_bytes(&bytes)?, h);
+
+        assert!(Header::from_bytes(&bytes[..HEADER_LEN - 1]).is_err());
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(Header::from_bytes(&bad).is_err());
+        let mut bad = bytes.clone();
+        bad[4] = VERSION + 1;
+        assert!(Header::from_bytes(&bad).is_err());
+        let mut bad = bytes;
+        bad[17..19].copy_from_slice(&[0, 0]);
+        assert!(Header::from_bytes(&bad).is_err());
+        assert!(header(0, 2048).to_bytes().is_err());
+        assert!(header(8, 1).to_bytes().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_round_trip() -> Result<()> {
+        let dev = &Device::Cpu;
+        for (n_q, bins) in [(1, 2048), (4, 2048), (8, 2048), (32, 16), (3, 2)] {
+            let h = header(n_q, bins);
+            let codes = random_codes(n_q as u64, n_q, bins, 100)?;
+            let priors = estimate_priors(&random_codes(n_q as u64 + 1, n_q, bins, 100)?, bins)?;
+            for priors in [None, Some(priors)] {
+                let data = encode(&h, priors.as_ref(), &codes)?;
+                let (header, decoded) = decode(&data, priors.clone(), dev)?;
+                assert_eq!(header.priors_hash, priors.as_ref().map_or(0, |p| p.hash()));
+                assert_eq!(header, Header { priors_hash: header.priors_hash, ..h.clone() });
+                assert_eq!(decoded.to_vec3::<u32>()?, codes.to_vec3::<u32>()?, "{n_q} {bins}");
+            }
+            // Files without any frame.
+            let empty = codes.narrow(2, 0, 0)?;
+            let (_, decoded) = decode(&encode(&h, None, &empty)?, None, dev)?;
+            assert_eq!(decoded.dims(), [1, n_q, 0]);
+        }
+        // Priors estimated on the same distribution result in smaller files.
+        let codes = random_codes(0, 8, 2048, 200)?;
+        let priors = estimate_priors(&random_codes(1, 8, 2048, 2000)?, 2048)?;
+        let with_priors = encode(&header(8, 2048), Some(&priors), &codes)?;
+        let without_priors = encode(&header(8, 2048), None, &codes)?;
+        assert!(with_priors.len() < without_priors.len());
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_round_trip() -> Result<()> {
+        let dev = &Device::Cpu;
+        let (n_q, bins) = (8, 2048);
+        let codes = random_codes(2, n_q, bins, 60)?;
+        let priors = estimate_priors(&random_codes(3, n_q, bins, 60)?, bins)?;
+        let h = header(n_q, bins);
+        let mut encoder = Encoder::new(&h, Some(&priors))?;
+        let mut data = vec![];
+        for t in 0..60 {
+            data.extend(encoder.encode_step(&codes.narrow(2, t, 1)?)?)
+        }
+        data.extend(encoder.finish()?);
+        assert!(encoder.encode_step(&codes.narrow(2, 0, 1)?).is_err());
+        // Encoding frame by frame results in the same file.
+        assert_eq!(data, encode(&h, Some(&priors), &codes)?);
+
+        let mut decoder = Decoder::new(Some(priors)).with_codec_hash(CODEC_HASH);
+        let mut frames = vec![];
+        for (i, b) in data.iter().enumerate() {
+            decoder.push_bytes(&[*b]);
+            if i + 1 == data.len() {
+                decoder.finish_input()
+            }
+            if let Some(codes) = decoder.decode_step(dev)? {
+                frames.push(codes)
+            }
+            assert_eq!(decoder.header().is_some(), i + 1 >= HEADER_LEN);
+        }
+        assert!(decoder.is_done());
+        // Frames get decoded as the data arrives rather than all at the end.
+        assert!(frames.len() > 10);
+        let decoded = Tensor::cat(&frames, 2)?;
+        assert_eq!(decoded.to_vec3::<u32>()?, codes.to_vec3::<u32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_priors_or_codec() -> Result<()> {
+        let dev = &Device::Cpu;
+        let codes = random_codes(4, 4, 16, 20)?;
+        let priors = estimate_priors(&codes, 16)?;
+        let other_priors = estimate_priors(&random_codes(5, 4, 16, 20)?, 16)?;
+        let data = encode(&header(4, 16), Some(&priors), &codes)?;
+        assert!(decode(&data, None, dev).is_err());
+        assert!(decode(&data, Some(other_priors.clone()), dev).is_err());
+        let data = encode(&header(4, 16), None, &codes)?;
+        assert!(decode(&data, Some(other_priors), dev).is_err());
+        // Priors for fewer codebooks or a different number of bins cannot be used.
+        assert!(encode(&header(5, 16), Some(&priors), &random_codes(4, 5, 16, 2)?).is_err());
+        assert!(encode(&header(4, 32), Some(&priors), &codes).is_err());
+
+        let mut decoder = Decoder::new(None).with_codec_hash(CODEC_HASH + 1);
+        decoder.push_bytes(&data);
+        decoder.finish_input();
+        assert!(decoder.decode_step(dev).is_err());
+        let mut decoder = Decoder::new(None).with_codec_hash(CODEC_HASH);
+        decoder.push_bytes(&data);
+        decoder.finish_input();
+        assert!(decoder.decode_step(dev)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_or_corrupt() -> Result<()> {
+        let dev = &Device::Cpu;
+        let codes = random_codes(6, 4, 16, 30)?;
+        let priors = estimate_priors(&codes, 16)?;
+        let data = encode(&header(4, 16), Some(&priors), &codes)?;
+        for len in 0..data.len() {
+            assert!(decode(&data[..len], Some(priors.clone()), dev).is_err(), "truncated {len}");
+        }
+        // Corrupt payloads either fail or decode to some codes, they should never panic.
+        for pos in HEADER_LEN..data.len() {
+            for flip in [0x01, 0x80, 0xFF] {
+                let mut data = data.clone();
+                data[pos] ^= flip;
+                if let Ok((_, decoded)) = decode(&data, Some(priors.clone()), dev) {
+                    assert_eq!(decoded.dim(1)?, 4);
+                }
+            }
+        }
+        let mut data = data;
+        data.extend_from_slice(&[0xFF; 16]);
+        // Garbage after the end of stream marker is ignored.
+        let (_, decoded) = decode(&data, Some(priors), dev)?;
+        assert_eq!(decoded.to_vec3::<u32>()?, codes.to_vec3::<u32>()?);
+        Ok(())
+    }
+}
@@ -7,6 +7,77 @@ use candle_nn::{linear, Linear, VarBuilder};
 
 struct CodebookEncode;
 
+// Number of accumulators used when computing distances, this lets the compiler vectorize the
+// inner loop with SIMD instructions.
+const LANES: usize = 8;
+// Number of input rows that are compared to each codebook entry in a single pass so that the
+// codebook entries are loaded from memory once per block rather than once per row.
+const ROWS_PER_BLOCK: usize = 4;
+
+#[inline(always)]
+fn squared_dist(lhs: &[f32], rhs: &[f32]) -> f32 {
+    let mut acc = [0f32; LANES];
+    let lhs_chunks = lhs.chunks_exact(LANES);
+    let rhs_chunks = rhs.chunks_exact(LANES);
+    let (lhs_rem, rhs_rem) = (lhs_chunks.remainder(), rhs_chunks.remainder());
+    for (l, r) in lhs_chunks.zip(rhs_chunks) {
+        for i in 0..LANES {
+            let d = l[i] - r[i];
+            acc[i] += d * d
+        }
+    }
+    let mut dist = acc.iter().sum::<f32>();
+    for (l, r) in lhs_rem.iter().zip(rhs_rem.iter()) {
+        dist += (l - r) * (l - r)
+    }
+    dist
+}
+
+// Returns the index of the closest codebook entry for each of the rows in `lhs`.
+fn codebook_encode(lhs: &[f32], codebook: &[f32], dim: usize) -> Vec<u32> {
+    use rayon::prelude::*;
+
+    let mut dst = vec![0u32; lhs.len() / dim];
+    dst.par_chunks_mut(ROWS_PER_BLOCK).zip(lhs.par_chunks(dim * ROWS_PER_BLOCK)).for_each(
+        |(dst, lhs)| {
+            let mut min_dist = [f32::INFINITY; ROWS_PER_BLOCK];
+            for (idx2, rhs) in codebook.chunks_exact(dim).enumerate() {
+                for (row_idx, lhs) in lhs.chunks_exact(dim).enumerate() {
+                    let dist = squared_dist(lhs, rhs);
+                    if dist < min_dist[row_idx] {
+                        min_dist[row_idx] = dist;
+                        dst[row_idx] = idx2 as u32;
+                    }
+                }
+            }
+        },
+    );
+    dst
+}
+
+// Half precision storages get converted to f32 so that the distances are accumulated with a
+// reasonable precision.
+fn f32_slice<'a>(
+    storage: &'a candle::CpuStorage,
+    layout: &Layout,
+    name: &str,
+) -> Result<std::borrow::Cow<'a, [f32]>> {
+    use candle::CpuStorage;
+    use std::borrow::Cow;
+
+    let (o1, o2) = match layout.contiguous_offsets() {
+        None => candle::bail!("CodebookEncode, {name} has to be contiguous, got {layout:?}"),
+        Some(offsets) => offsets,
+    };
+    let slice = match storage {
+        CpuStorage::F32(vs) => Cow::Borrowed(&vs[o1..o2]),
+        CpuStorage::F16(vs) => Cow::Owned(vs[o1..o2].iter().map(|v| v.to_f32()).collect()),
+        CpuStorage::BF16(vs) => Cow::Owned(vs[o1..o2].iter().map(|v| v.to_f32()).collect()),
+        _ => candle::bail!("CodebookEncode, {name} has to be f32, f16 or bf16"),
+    };
+    Ok(slice)
+}
+
 impl candle::CustomOp2 for CodebookEncode {
     fn name(&self) -> &'static str {
         "cb"
@@ -19,50 +90,17 @@ impl candle::CustomOp2 for CodebookEncode {
         rhs_storage: &candle::CpuStorage,
         rhs_layout: &Layout,
     ) -> Result<(candle::CpuStorage, Shape)> {
-        use rayon::prelude::*;
-
         let (lhs_dim1, lhs_dim2) = lhs_layout.shape().dims2()?;
-        let (rhs_dim1, rhs_dim2) = rhs_layout.shape().dims2()?;
+        let (_rhs_dim1, rhs_dim2) = rhs_layout.shape().dims2()?;
         if lhs_dim2 != rhs_dim2 {
             candle::bail!("CodebookEncode, mismatch on last dim, {lhs_layout:?} {rhs_layout:?}");
         }
         if lhs_dim2 == 0 {
             candle::bail!("CodebookEncode, empty last dim {lhs_layout:?}")
         }
-        let lhs = match lhs_layout.contiguous_offsets() {
-            None => candle::bail!("CodebookEncode, lhs has to be contiguous, got {lhs_layout:?}"),
-            Some((o1, o2)) => {
-                let slice = lhs_storage.as_slice::<f32>()?;
-                &slice[o1..o2]
-            }
-        };
-        let rhs = match rhs_layout.contiguous_offsets() {
-            None => candle::bail!("CodebookEncode, rhs has to be contiguous, got {rhs_layout:?}"),
-            Some((o1, o2)) => {
-                let slice = rhs_storage.as_slice::<f32>()?;
-                &slice[o1..o2]
-            }
-        };
-        let dst = (0..lhs_dim1)
-            .into_par_iter()
-            .map(|idx1| {
-                let mut where_min = 0;
-                let mut min_dist = f32::INFINITY;
-                let lhs = &lhs[idx1 * lhs_dim2..(idx1 + 1) * lhs_dim2];
-                for idx2 in 0..rhs_dim1 {
-                    let rhs = &rhs[idx2 * rhs_dim2..(idx2 + 1) * rhs_dim2];
-                    let mut dist = 0f32;
-                    for (a, b) in lhs.iter().zip(rhs.iter()) {
-                        dist += (a - b) * (a - b)
-                    }
-                    if dist < min_dist {
-                        min_dist = dist;
-                        where_min = idx2;
-                    }
-                }
-                where_min as u32
-            })
-            .collect();
+        let lhs = f32_slice(lhs_storage, lhs_layout, "lhs")?;
+        let rhs = f32_slice(rhs_storage, rhs_layout, "rhs")?;
+        let dst = codebook_encode(&lhs, &rhs, lhs_dim2);
         let storage = candle::WithDType::to_cpu_storage_owned(dst);
         Ok((storage, (lhs_dim1,).into()))
     }
@@ -75,6 +113,9 @@ pub struct EuclideanCodebook {
     cluster_usage: Tensor,
     embedding_sum: Tensor,
     embedding: Tensor,
+    // f32 version of the embedding used by the cpu kernel, this avoids converting the whole
+    // codebook on each call for half precision models.
+    embedding_f32: Tensor,
     c2: Tensor,
     epsilon: f64,
     dim: usize,
@@ -93,11 +134,13 @@ impl EuclideanCodebook {
             embedding_sum.broadcast_div(&cluster_usage)?
         };
         let c2 = ((&embedding * &embedding)?.sum(D::Minus1)? / 2.0)?;
+        let embedding_f32 = embedding.to_dtype(candle::DType::F32)?.contiguous()?;
         Ok(Self {
             initialized,
             cluster_usage,
             embedding_sum,
             embedding,
+            embedding_f32,
             c2,
             epsilon,
             dim,
@@ -139,7 +182,7 @@ impl EuclideanCodebook {
         target_shape.pop();
         let xs = xs.flatten_to(D::Minus2)?;
         let _ = xs.dims2()?;
-        let codes = Tensor::apply_op2(&xs, &self.embedding, CodebookEncode)?;
+        let codes = Tensor::apply_op2(&xs, &self.embedding_f32, CodebookEncode)?;
         codes.reshape(target_shape)
     }
 
@@ -220,22 +263,44 @@ impl ResidualVectorQuantization {
     }
 
     pub fn encode(&self, xs: &Tensor) -> Result<Tensor> {
-        let mut codes = Vec::with_capacity(self.layers.len());
+        self.encode_with_n_q(xs, self.layers.len())
+    }
+
+    /// Encodes `xs` using only the first `n_q` levels.
+    pub fn encode_with_n_q(&self, xs: &Tensor, n_q: usize) -> Result<Tensor> {
+        self.encode_levels(xs, n_q, |_| Ok(()))
+    }
+
+    /// Same as `encode_with_n_q`, `f` gets called after each level with the remaining residual.
+    pub fn encode_levels<F: FnMut(&Tensor) -> Result<()>>(
+        &self,
+        xs: &Tensor,
+        n_q: usize,
+        mut f: F,
+    ) -> Result<Tensor> {
+        if n_q > self.layers.len() {
+            candle::bail!("n_q {n_q} is larger than the number of layers {}", self.layers.len())
+        }
+        let mut codes = Vec::with_capacity(n_q);
         let mut residual = xs.clone();
-        for layer in self.layers.iter() {
+        for layer in self.layers.iter().take(n_q) {
             let indices = layer.encode(&residual)?;
             let quantized = layer.decode(&indices)?;
             residual = (residual - quantized)?;
+            f(&residual)?;
             codes.push(indices)
         }
         Tensor::stack(&codes, 0)
     }
 
+    /// Decodes codes of shape (k, b, t), when k is smaller than the number of layers only the
+    /// first k levels are used.
     pub fn decode(&self, xs: &Tensor) -> Result<Tensor> {
         if self.layers.is_empty() {
             candle::bail!("empty layers in ResidualVectorQuantization")
         }
-        if self.layers.len() != xs.dim(0)? {
+        let n_q = xs.dim(0)?;
+        if n_q == 0 || n_q > self.layers.len() {
             candle::bail!(
                 "mismatch between the number of layers {} and the code shape {:?}",
                 self.layers.len(),
@@ -243,7 +308,7 @@ impl ResidualVectorQuantization {
             )
         }
         let mut quantized = self.layers[0].decode(&xs.i(0)?)?;
-        for (i, layer) in self.layers.iter().enumerate().skip(1) {
+        for (i, layer) in self.layers.iter().enumerate().take(n_q).skip(1) {
             let xs = xs.i(i)?;
             quantized = (quantized + layer.decode(&xs))?
         }
@@ -312,6 +377,27 @@ impl ResidualVectorQuantizer {
         codes.transpose(0, 1)
     }
 
+    pub fn encode_with_n_q(&self, xs: &Tensor, n_q: usize) -> Result<Tensor> {
+        let codes = self.vq.encode_with_n_q(&xs.apply(&self.input_proj.as_ref())?, n_q)?;
+        codes.transpose(0, 1)
+    }
+
+    /// Same as `encode_with_n_q`, `f` gets called after each level with the value that `decode`
+    /// would return for the codes of this level and of all the previous ones.
+    pub fn encode_levels<F: FnMut(&Tensor) -> Result<()>>(
+        &self,
+        xs: &Tensor,
+        n_q: usize,
+        mut f: F,
+    ) -> Result<Tensor> {
+        let xs = xs.apply(&self.input_proj.as_ref())?;
+        let codes = self.vq.encode_levels(&xs, n_q, |residual| {
+            let quantized = (&xs - residual)?;
+            f(&quantized.apply(&self.output_proj.as_ref())?)
+        })?;
+        codes.transpose(0, 1)
+    }
+
     pub fn decode(&self, codes: &Tensor) -> Result<Tensor> {
         // codes is [B, K, T], with T frames, K nb of codebooks, vq.decode expects [K, B, T].
         let codes = codes.transpose(0, 1)?;
@@ -330,6 +416,7 @@ pub struct SplitResidualVectorQuantizer {
     rvq_first: ResidualVectorQuantizer,
     rvq_rest: ResidualVectorQuantizer,
     n_q: usize,
+    bins: usize,
     span_encode: tracing::Span,
     span_decode: tracing::Span,
 }
@@ -363,28 +450,101 @@ impl SplitResidualVectorQuantizer {
         )?;
         let span_encode = tracing::span!(tracing::Level::TRACE, "split-rvq-encode");
         let span_decode = tracing::span!(tracing::Level::TRACE, "split-rvq-decode");
-        Ok(Self { rvq_first, rvq_rest, n_q, span_encode, span_decode })
+        Ok(Self { rvq_first, rvq_rest, n_q, bins, span_encode, span_decode })
+    }
+
+    pub fn n_q(&self) -> usize {
+        self.n_q
+    }
+
+    /// Empty statistics to be used with `encode_with_stats`.
+    pub fn stats(&self) -> CodebookStats {
+        CodebookStats::new(self.n_q, self.bins)
+    }
+
+    /// Same as `encode_with_n_q` using the number of codebooks from `stats`, the code histograms
+    /// and the reconstruction errors for each number of levels get accumulated in `stats`.
+    pub fn encode_with_stats(&self, xs: &Tensor, stats: &mut CodebookStats) -> Result<Tensor> {
+        let _enter = self.span_encode.enter();
+        let n_q = stats.n_q();
+        if n_q == 0 || n_q > self.n_q {
+            candle::bail!("n_q should be between 1 and {}, got {n_q}", self.n_q)
+        }
+        if stats.bins() != self.bins {
+            candle::bail!("stats are for {} bins, the quantizer has {}", stats.bins(), self.bins)
+        }
+        let sq_sum = |xs: &Tensor| -> Result<f64> {
+            let v = xs.to_dtype(candle::DType::F32)?.sqr()?.sum_all()?.to_scalar::<f32>()?;
+            Ok(v as f64)
+        };
+        // The reconstruction errors are computed while encoding from the partial sums of the
+        // quantized values rather than by decoding the codes for each number of levels.
+        let mut first_quantized = None;
+        let first_codes = self.rvq_first.encode_levels(xs, 1, |quantized| {
+            stats.sq_err[0] += sq_sum(&(xs - quantized)?)?;
+            first_quantized = Some(quantized.clone());
+            Ok(())
+        })?;
+        let codes = match first_quantized {
+            Some(first_quantized) if n_q > 1 => {
+                let mut sq_err = stats.sq_err.iter_mut().skip(1);
+                let rest_codes = self.rvq_rest.encode_levels(xs, n_q - 1, |quantized| {
+                    if let Some(sq_err) = sq_err.next() {
+                        *sq_err += sq_sum(&((xs - &first_quantized)? - quantized)?)?
+                    }
+                    Ok(())
+                })?;
+                Tensor::cat(&[first_codes, rest_codes], 1)?
+            }
+            _ => first_codes,
+        };
+        for codes in codes.to_vec3::<u32>()? {
+            for (counts, codes) in stats.counts.iter_mut().zip(codes.iter()) {
+                for &c in codes.iter() {
+                    counts[c as usize] += 1
+                }
+            }
+        }
+        stats.sq_norm += sq_sum(xs)?;
+        stats.n_values += xs.elem_count() as u64;
+        stats.n_frames += (codes.dim(0)? * codes.dim(2)?) as u64;
+        Ok(codes)
     }
 
     pub fn encode(&self, xs: &Tensor) -> Result<Tensor> {
+        self.encode_with_n_q(xs, self.n_q)
+    }
+
+    /// Encodes `xs` using only the first `n_q` codebooks, this results in codes of shape
+    /// (b, n_q, t).
+    pub fn encode_with_n_q(&self, xs: &Tensor, n_q: usize) -> Result<Tensor> {
         let _enter = self.span_encode.enter();
+        if n_q == 0 || n_q > self.n_q {
+            candle::bail!("n_q should be between 1 and {}, got {n_q}", self.n_q)
+        }
         let codes = self.rvq_first.encode(xs)?;
-        if self.n_q > 1 {
+        if n_q > 1 {
             // We encode xs again here rather than the residual. The decomposition is not
             // hierarchical but rather having semantic tokens for rvq_first and the acoustic tokens
             // for rvq_rest.
-            let rest_codes = self.rvq_rest.encode(xs)?;
+            let rest_codes = self.rvq_rest.encode_with_n_q(xs, n_q - 1)?;
             Tensor::cat(&[codes, rest_codes], 1)
         } else {
             Ok(codes)
         }
     }
 
+    /// Decodes codes of shape (b, k, t), k can be smaller than the number of codebooks in which
+    /// case only the first k codebooks are used.
     pub fn decode(&self, codes: &Tensor) -> Result<Tensor> {
         // codes is [B, K, T], with T frames, K nb of codebooks.
         let _enter = self.span_decode.enter();
+        let n_q = codes.dim(1)?;
+        if n_q == 0 || n_q > self.n_q {
+            candle::bail!("expected between 1 and {} codebooks, got {n_q}", self.n_q)
+        }
         let quantized = self.rvq_first.decode(&codes.i((.., ..1))?)?;
-        let quantized = if self.n_q > 1 {
+        let quantized = if n_q > 1 {
             (quantized + self.rvq_rest.decode(&codes.i((.., 1..))?))?
         } else {
             quantized
@@ -392,3 +552,244 @@ impl SplitResidualVectorQuantizer {
         Ok(quantized)
     }
 }
+
+/// Codebook utilisation statistics accumulated over calls to
+/// `SplitResidualVectorQuantizer::encode_with_stats`.
+#[derive(Debug, Clone)]
+pub struct CodebookStats {
+    bins: usize,
+    // Histogram of the codes for each codebook.
+    counts: Vec<Vec<u64>>,
+    // Squared reconstruction error when using the first k + 1 codebooks.
+    sq_err: Vec<f64>,
+    // Squared norm of the latents being quantized, used to normalize the errors.
+    sq_norm: f64,
+    // Number of latent coefficients that have been quantized.
+    n_values: u64,
+    n_frames: u64,
+}
+
+impl CodebookStats {
+    pub fn new(n_q: usize, bins: usize) -> Self {
+        Self {
+            bins,
+            counts: vec![vec![0; bins]; n_q],
+            sq_err: vec![0.; n_q],
+            sq_norm: 0.,
+            n_values: 0,
+            n_frames: 0,
+        }
+    }
+
+    pub fn n_q(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn bins(&self) -> usize {
+        self.bins
+    }
+
+    /// Number of frames that have been encoded, for batched inputs each batch element counts.
+    pub fn n_frames(&self) -> u64 {
+        self.n_frames
+    }
+
+    pub fn histogram(&self, level: usize) -> &[u64] {
+        &self.counts[level]
+    }
+
+    /// Entropy in bits of the empirical code distribution for the given codebook.
+    pub fn entropy(&self, level: usize) -> f64 {
+        let total = self.counts[level].iter().sum::<u64>();
+        if total == 0 {
+            return 0.;
+        }
+        let total = total as f64;
+        self.counts[level]
+            .iter()
+            .filter(|&&c| c > 0)
+            .map(|&c| {
+                let p = c as f64 / total;
+                p * (1. / p).log2()
+            })
+            .sum()
+    }
+
+    /// Effective number of codes used, i.e. two to the power of the entropy.
+    pub fn perplexity(&self, level: usize) -> f64 {
+        self.entropy(level).exp2()
+    }
+
+    /// Number of codes that have never been selected.
+    pub fn dead_codes(&self, level: usize) -> usize {
+        self.counts[level].iter().filter(|&&c| c == 0).count()
+    }
+
+    /// Mean squared error per latent coefficient when decoding with the first `n_levels`
+    /// codebooks.
+    pub fn mse(&self, n_levels: usize) -> f64 {
+        if self.n_values == 0 {
+            return 0.;
+        }
+        self.sq_err[n_levels - 1] / self.n_values as f64
+    }
+
+    /// Squared reconstruction error when decoding with the first `n_levels` codebooks,
+    /// relative to the squared norm of the latents.
+    pub fn relative_error(&self, n_levels: usize) -> f64 {
+        if self.sq_norm == 0. {
+            return 0.;
+        }
+        self.sq_err[n_levels - 1] / self.sq_norm
+    }
+
+    /// Merges the statistics accumulated in `other`, e.g. by another thread.
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        if self.n_q() != other.n_q() || self.bins != other.bins {
+            candle::bail!(
+                "cannot merge stats for {}x{} codes with {}x{}",
+                self.n_q(),
+                self.bins,
+                other.n_q(),
+                other.bins
+            )
+        }
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            c.iter_mut().zip(o.iter()).for_each(|(c, o)| *c += o)
+        }
+        self.sq_err.iter_mut().zip(other.sq_err.iter()).for_each(|(e, o)| *e += o);
+        self.sq_norm += other.sq_norm;
+        self.n_values += other.n_values;
+        self.n_frames += other.n_frames;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.n_q(), self.bins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::test_utils::rand_tensor;
+    use candle::DType;
+
+    fn codebook(embedding: &Tensor) -> Result<EuclideanCodebook> {
+        let (codebook_size, dim) = embedding.dims2()?;
+        let dev = embedding.device();
+        let ws = std::collections::HashMap::from([
+            ("_initialized".to_string(), Tensor::ones(1, embedding.dtype(), dev)?),
+            ("cluster_usage".to_string(), Tensor::ones(codebook_size, embedding.dtype(), dev)?),
+            ("embedding_sum".to_string(), embedding.clone()),
+        ]);
+        let vb = VarBuilder::from_tensors(ws, embedding.dtype(), dev);
+        EuclideanCodebook::new(dim, codebook_size, vb)
+    }
+
+    #[test]
+    fn codebook_encode() -> Result<()> {
+        // The number of rows and the dimension are mostly not multiples of the block sizes.
+        for (rows, dim) in [(13, 12), (ROWS_PER_BLOCK, LANES), (1, 3), (31, 20)] {
+            let xs = rand_tensor(rows as u64, 1., (rows, dim))?;
+            let embedding = rand_tensor(dim as u64 + 100, 1., (32, dim))?;
+            for dtype in [DType::F32, DType::F16, DType::BF16] {
+                let xs = xs.to_dtype(dtype)?;
+                let cb = codebook(&embedding.to_dtype(dtype)?)?;
+                let codes = cb.encode(&xs)?.to_vec1::<u32>()?;
+                // The kernel computes the distances in f32, the reference does the same with the
+                // values rounded to the tested dtype.
+                let cb_f32 = codebook(&embedding.to_dtype(dtype)?.to_dtype(DType::F32)?)?;
+                let expected = cb_f32.encode_slow(&xs.to_dtype(DType::F32)?)?;
+                assert_eq!(codes, expected.to_vec1::<u32>()?, "{rows} {dim} {dtype:?}");
+                let expected = cb_f32.encode_very_slow(&xs.to_dtype(DType::F32)?)?;
+                assert_eq!(codes, expected.to_vec1::<u32>()?, "{rows} {dim} {dtype:?}");
+            }
+        }
+        // Batched inputs keep their leading dimensions.
+        let xs = rand_tensor(0, 1., (2, 5, 12))?;
+        let cb = codebook(&rand_tensor(1, 1., (32, 12))?)?;
+        assert_eq!(cb.encode(&xs)?.dims(), [2, 5]);
+        assert_eq!(cb.encode(&xs)?.to_vec2::<u32>()?, cb.encode_slow(&xs)?.to_vec2::<u32>()?);
+        Ok(())
+    }
+
+    fn stats(counts: Vec<Vec<u64>>, sq_err: Vec<f64>, sq_norm: f64) -> CodebookStats {
+        let bins = counts[0].len();
+        let n_frames = counts[0].iter().sum();
+        CodebookStats { bins, counts, sq_err, sq_norm, n_values: 8, n_frames }
+    }
+
+    #[test]
+    fn codebook_stats() -> Result<()> {
+        let mut s = stats(
+            vec![vec![2, 2, 0, 0], vec![1, 1, 1, 1], vec![3, 1, 0, 0]],
+            vec![2., 1., 0.5],
+            4.,
+        );
+        assert_eq!((s.n_q(), s.bins(), s.n_frames()), (3, 4, 4));
+        assert_eq!(s.histogram(2), [3, 1, 0, 0]);
+        assert_eq!((s.entropy(0), s.entropy(1)), (1., 2.));
+        // -(3/4 log2(3/4) + 1/4 log2(1/4)) = 2 - 3/4 log2(3)
+        assert!((s.entropy(2) - (2. - 0.75 * 3f64.log2())).abs() < 1e-12);
+        assert_eq!((s.perplexity(0), s.perplexity(1)), (2., 4.));
+        assert_eq!((s.dead_codes(0), s.dead_codes(1), s.dead_codes(2)), (2, 0, 2));
+        assert_eq!((s.mse(1), s.mse(2), s.mse(3)), (0.25, 0.125, 0.0625));
+        assert_eq!((s.relative_error(1), s.relative_error(3)), (0.5, 0.125));
+
+        let other =
+            stats(vec![vec![0, 0, 4, 4], vec![0; 4], vec![0, 0, 0, 4]], vec![2., 1., 0.5], 4.);
+        s.merge(&other)?;
+        assert_eq!(s.histogram(0), [2, 2, 4, 4]);
+        assert_eq!(s.histogram(1), [1, 1, 1, 1]);
+        assert_eq!(s.n_frames(), 12);
+        assert!((s.entropy(0) - (1. / 3. + 3f64.log2())).abs() < 1e-12);
+        assert_eq!((s.dead_codes(0), s.dead_codes(2)), (0, 1));
+        assert_eq!((s.mse(1), s.relative_error(1)), (0.25, 0.5));
+        assert!(s.merge(&CodebookStats::new(2, 4)).is_err());
+        assert!(s.merge(&CodebookStats::new(3, 8)).is_err());
+
+        // Empty statistics do not result in NaNs.
+        s.reset();
+        assert_eq!((s.n_frames(), s.dead_codes(0)), (0, 4));
+        assert_eq!((s.entropy(0), s.mse(1), s.relative_error(1)), (0., 0., 0.));
+        Ok(())
+    }
+
+    #[test]
+    fn encode_with_stats() -> Result<()> {
+        let vm = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&vm, DType::F32, &candle::Device::Cpu);
+        let build = || SplitResidualVectorQuantizer::new(8, Some(16), Some(16), 4, 32, vb.clone());
+        build()?;
+        crate::streaming::test_utils::randomize(&vm, 1.)?;
+        for (name, var) in vm.data().lock().unwrap().iter() {
+            if name.ends_with("cluster_usage") {
+                var.set(&var.ones_like()?)?
+            }
+        }
+        let q = build()?;
+        let xs = rand_tensor(0, 1., (2, 16, 5))?;
+        for n_q in 1..=4 {
+            let mut stats = CodebookStats::new(n_q, 32);
+            let codes = q.encode_with_stats(&xs, &mut stats)?;
+            assert_eq!(codes.to_vec3::<u32>()?, q.encode_with_n_q(&xs, n_q)?.to_vec3::<u32>()?);
+            assert_eq!(stats.n_frames(), 10);
+            for level in 0..n_q {
+                let level_codes = codes.i((.., level))?.flatten_all()?.to_vec1::<u32>()?;
+                let mut counts = vec![0u64; 32];
+                level_codes.iter().for_each(|&c| counts[c as usize] += 1);
+                assert_eq!(stats.histogram(level), counts);
+
+                // The errors match the ones obtained by decoding the codes.
+                let quantized = q.decode(&codes.narrow(1, 0, level + 1)?)?;
+                let mse = (&xs - quantized)?.sqr()?.mean_all()?.to_scalar::<f32>()? as f64;
+                let diff = (stats.mse(level + 1) - mse).abs();
+                assert!(diff < 1e-5 * mse.max(1.), "n_q {n_q} level {level} {diff}");
+            }
+        }
+        assert!(q.encode_with_stats(&xs, &mut CodebookStats::new(5, 32)).is_err());
+        assert!(q.encode_with_stats(&xs, &mut CodebookStats::new(2, 16)).is_err());
+        Ok(())
+    }
+}
@@ -2,8 +2,13 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::lm::{Config, DepFormerConfig, VERBOSE};
+use crate::lm::{
+    check_delays, check_samplers, ids_dims, slice_input_ids, slice_input_token, slice_sampler,
+    Config, DepFormerConfig, VERBOSE,
+};
 use crate::quantized_transformer as transformer;
+use crate::sampling::{Context, Sampler};
+use crate::streaming::{self, state_key, StateDict};
 use candle::{DType, Device, IndexOp, Module, Result, Tensor};
 use candle_transformers::quantized_nn::{linear_b, Embedding, Linear};
 use candle_transformers::quantized_var_builder::VarBuilder;
@@ -72,16 +77,40 @@ impl DepFormer {
         })
     }
 
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        // The slice transformers are reset on each sampling step so only the eos tracking has
+        // to be preserved.
+        if let Some(step_idx) = self.first_eos_step_idx {
+            streaming::save_usize(&state_key(prefix, "first_eos_step_idx"), step_idx, state)?
+        }
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.first_eos_step_idx =
+            streaming::load_usize(&state_key(prefix, "first_eos_step_idx"), state)?;
+        Ok(())
+    }
+
+    pub fn reset_state(&mut self) {
+        self.first_eos_step_idx = None
+    }
+
     /// Run a transformer sampling step, getting a token id per codebook.
     /// - `xs` is the previous layer hidden state.
+    /// - `delays` is the delay in steps of each of the sampled codebooks.
+    /// - `lps` holds a sampler per slice, or a single sampler shared by all the slices.
     pub fn sample(
         &mut self,
         step_idx: usize,
         xs: &Tensor,
         text_token: Option<u32>,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        delays: &[usize],
+        lps: &mut [Sampler],
     ) -> Result<Vec<u32>> {
         use crate::streaming::StreamingModule;
+        check_delays(delays, self.slices.len())?;
+        check_samplers(lps, self.slices.len())?;
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
@@ -97,11 +126,13 @@ impl DepFormer {
             let xs = slice.linear_in.forward(xs)?;
             let xs = match last_token {
                 Some(last_token) => {
-                    let last_token = if slice_idx < 2 || step_idx > 1 {
-                        last_token
-                    } else {
-                        self.audio_padding_token
-                    };
+                    let last_token = slice_input_token(
+                        slice_idx,
+                        step_idx,
+                        last_token,
+                        delays,
+                        self.audio_padding_token,
+                    );
                     let token_id = Tensor::from_vec(vec![last_token], (1, 1), dev)?;
                     let token_emb = slice.emb.forward(&token_id)?;
                     xs.broadcast_add(&token_emb)?
@@ -117,6 +148,7 @@ impl DepFormer {
                 1 => logits.i((0, 0))?,
                 b_size => candle::bail!("unexpected batch size {b_size}"),
             };
+            let lp = slice_sampler(lps, slice_idx);
             let token = self.sample_maybe_postpone_eos(step_idx, &logits, lp)?;
             last_token = Some(token);
             tokens.push(token)
@@ -128,9 +160,10 @@ impl DepFormer {
         &mut self,
         step_idx: usize,
         logits: &Tensor,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        lp: &mut Sampler,
     ) -> Result<u32> {
-        let token = lp.sample(logits)?;
+        let ctx = Context::new(step_idx);
+        let token = lp.sample(&ctx, logits)?;
         let token = if token == self.audio_eos_token {
             let first_eos_step_idx = match self.first_eos_step_idx {
                 Some(step_idx) => step_idx,
@@ -144,7 +177,7 @@ impl DepFormer {
             if step_idx >= first_eos_step_idx + 5 {
                 token
             } else {
-                lp.sample_f(logits, |pr| pr[self.audio_eos_token as usize] = 1e-9)?
+                lp.sample_f(&ctx, logits, |l| l[self.audio_eos_token as usize] = f32::NEG_INFINITY)?
             }
         } else {
             token
@@ -159,9 +192,12 @@ impl DepFormer {
         xs: &Tensor,
         cfg_alpha: f64,
         text_token: Option<u32>,
-        lp: &mut candle_transformers::generation::LogitsProcessor,
+        delays: &[usize],
+        lps: &mut [Sampler],
     ) -> Result<Vec<u32>> {
         use crate::streaming::StreamingModule;
+        check_delays(delays, self.slices.len())?;
+        check_samplers(lps, self.slices.len())?;
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
@@ -177,11 +213,13 @@ impl DepFormer {
             let xs = slice.linear_in.forward(xs)?;
             let xs = match last_token {
                 Some(last_token) => {
-                    let last_token = if slice_idx < 2 || step_idx > 1 {
-                        last_token
-                    } else {
-                        self.audio_padding_token
-                    };
+                    let last_token = slice_input_token(
+                        slice_idx,
+                        step_idx,
+                        last_token,
+                        delays,
+                        self.audio_padding_token,
+                    );
                     let token_id = Tensor::from_vec(vec![last_token], (1, 1), dev)?;
                     let token_emb = slice.emb.forward(&token_id)?;
                     xs.broadcast_add(&token_emb)?
@@ -194,16 +232,72 @@ impl DepFormer {
                 2 => ((logits.i((0, 0))? * cfg_alpha)? - (logits.i((1, 0))? * (cfg_alpha - 1.))?)?,
                 b_size => candle::bail!("unexpected batch size {b_size}"),
             };
+            let lp = slice_sampler(lps, slice_idx);
             let token = if slice_idx == 0 {
                 self.sample_maybe_postpone_eos(step_idx, &logits, lp)?
             } else {
-                lp.sample(&logits)?
+                lp.sample(&Context::new(step_idx), &logits)?
             };
             last_token = Some(token);
             tokens.push(token)
         }
         Ok(tokens)
     }
+
+    /// Teacher-forced log-probabilities of the audio tokens for `t` consecutive steps starting
+    /// at `step_idx`, one row per step with a value per slice.
+    /// - `xs` is the previous layer hidden state for these steps, `(1, t, d)`.
+    /// - `text_tokens` holds the text token for each step.
+    /// - `audio_tokens` holds the target token of each slice for each step.
+    /// - `delays` is the delay in steps of each of the sampled codebooks.
+    ///
+    /// The targets that the depformer cannot emit, e.g. padding, get a `None` log-probability.
+    pub fn log_probs(
+        &mut self,
+        step_idx: usize,
+        xs: &Tensor,
+        text_tokens: &[u32],
+        audio_tokens: &[Vec<u32>],
+        delays: &[usize],
+    ) -> Result<Vec<Vec<Option<f32>>>> {
+        use crate::streaming::StreamingModule;
+        check_delays(delays, self.slices.len())?;
+        let (_b, t, d) = xs.dims3()?;
+        if text_tokens.len() != t || audio_tokens.len() != t {
+            candle::bail!(
+                "got {} text and {} audio steps for {t} hidden states",
+                text_tokens.len(),
+                audio_tokens.len()
+            )
+        }
+        // The steps are independent for the depformer so they are processed as a batch.
+        let xs = xs.reshape((t, 1, d))?;
+        let dev = xs.device();
+        let pad = self.audio_padding_token;
+        let mut log_probs = vec![Vec::with_capacity(self.slices.len()); t];
+        for slice_idx in 0..self.slices.len() {
+            if slice_idx == 0 {
+                self.slices[slice_idx].transformer.reset_state();
+            } else {
+                let (lhs, rhs) = self.slices.split_at_mut(slice_idx);
+                rhs[0].transformer.copy_state(&lhs[slice_idx - 1].transformer)?
+            }
+            let slice = &mut self.slices[slice_idx];
+            let token_ids =
+                slice_input_ids(slice_idx, step_idx, text_tokens, audio_tokens, delays, pad);
+            let token_ids = Tensor::from_vec(token_ids, (t, 1), dev)?;
+            let xs =
+                slice.linear_in.forward(&xs)?.broadcast_add(&slice.emb.forward(&token_ids)?)?;
+            let xs = slice.transformer.forward(&xs)?;
+            let logits = xs.apply(&slice.linear_out)?.i((.., 0))?.to_dtype(DType::F32)?;
+            let lps = candle_nn::ops::log_softmax(&logits, candle::D::Minus1)?.to_vec2::<f32>()?;
+            for (i, lps) in lps.iter().enumerate() {
+                let target = audio_tokens[i][slice_idx] as usize;
+                log_probs[i].push(lps.get(target).copied())
+            }
+        }
+        Ok(log_probs)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -259,6 +353,33 @@ impl Lm {
         self.text_emb.embeddings().device()
     }
 
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        use crate::streaming::StreamingModule;
+        self.transformer.save_state(&state_key(prefix, "transformer"), state)?;
+        if let Some(depformer) = self.depformer.as_ref() {
+            depformer.save_state(&state_key(prefix, "depformer"), state)?
+        }
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        use crate::streaming::StreamingModule;
+        self.transformer.load_state(&state_key(prefix, "transformer"), state)?;
+        if let Some(depformer) = self.depformer.as_mut() {
+            depformer.load_state(&state_key(prefix, "depformer"), state)?
+        }
+        Ok(())
+    }
+
+    pub fn reset_state(&mut self) {
+        use crate::streaming::StreamingModule;
+        self.transformer.reset_state();
+        if let Some(depformer) = self.depformer.as_mut() {
+            depformer.reset_state()
+        }
+    }
+
+    /// Runs the model on `(b, t)` token ids, see [`crate::lm::Lm::forward`].
     pub fn forward(
         &mut self,
         text_ids: Option<Tensor>,
@@ -283,12 +404,13 @@ impl Lm {
             }
             println!();
         }
+        let (b, t) = ids_dims(text_ids.as_ref(), &audio_ids)?.unwrap_or((1, 1));
         let mut emb = match text_ids.as_ref() {
             Some(text_ids) => text_ids.apply(&self.text_emb)?,
             None => {
                 let text_emb = self.text_emb.embeddings();
                 let device = text_emb.device();
-                Tensor::zeros((1, 1, text_emb.dim(1)?), self.dtype, device)?
+                Tensor::zeros((b, t, text_emb.dim(1)?), self.dtype, device)?
             }
         };
 
@@ -314,12 +436,15 @@ impl Lm {
         ca_src: &Tensor,
     ) -> candle::Result<(Tensor, Tensor)> {
         let b_size = ca_src.dim(0)?;
+        // The token ids may have a batch size of 1 and get broadcasted to the batch size of ca_src.
+        let ids = text_ids.iter().chain(audio_ids.iter().flatten()).next();
+        let t = ids.map_or(Ok(1), |ids| ids.dim(1))?;
         let mut emb = match text_ids {
             Some(text_ids) => text_ids.apply(&self.text_emb)?,
             None => {
                 let text_emb = self.text_emb.embeddings();
                 let device = text_emb.device();
-                Tensor::zeros((b_size, 1, text_emb.dim(1)?), self.dtype, device)?
+                Tensor::zeros((b_size, t, text_emb.dim(1)?), self.dtype, device)?
             }
         };
         for (audio_emb, audio_ids) in self.audio_embs.iter().zip(audio_ids.iter()) {
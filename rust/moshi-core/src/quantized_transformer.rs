@@ -2,12 +2,14 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::streaming::{StreamTensor, StreamingModule};
+use crate::kv_cache::RotatingKvCache;
+use crate::streaming::{self, state_key, StateDict, StreamInfo, StreamTensor, StreamingModule};
 use crate::transformer::{get_mask, PositionalEmbedding, RotaryEmbedding};
 
 use candle::{DType, IndexOp, Module, Result, Tensor, D};
 use candle_transformers::quantized_nn::{layer_norm, linear_b, Linear};
 use candle_transformers::quantized_var_builder::VarBuilder;
+use candle_transformers::utils::repeat_kv;
 use std::sync::Arc;
 
 pub use crate::transformer::Config;
@@ -36,10 +38,9 @@ pub struct StreamingMultiheadAttention {
     out_proj: Linear,
     kv_repeat: usize,
     num_heads: usize,
-    context: usize,
     neg_inf: Tensor,
     rope: Option<Arc<RotaryEmbedding>>,
-    kv_cache: candle_nn::kv_cache::KvCache,
+    kv_cache: RotatingKvCache,
     use_kv_cache: bool,
     pos: usize,
     span: tracing::Span,
@@ -56,7 +57,7 @@ fn matmul_dtype(device: &candle::Device) -> DType {
 impl StreamingMultiheadAttention {
     pub fn new(rope: &Option<Arc<RotaryEmbedding>>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let embed_dim = cfg.d_model;
-        let num_kv = cfg.num_heads / cfg.kv_repeat;
+        let num_kv = cfg.num_kv_heads()?;
         let out_dim = embed_dim + 2 * num_kv * (embed_dim / cfg.num_heads);
         let in_proj_weight = vb.get((out_dim, embed_dim), "in_proj_weight")?;
         let in_proj_bias = if cfg.bias_attn {
@@ -73,9 +74,8 @@ impl StreamingMultiheadAttention {
             rope: rope.clone(),
             kv_repeat: cfg.kv_repeat,
             num_heads: cfg.num_heads,
-            context: cfg.context,
             neg_inf,
-            kv_cache: candle_nn::kv_cache::KvCache::new(2, cfg.max_seq_len),
+            kv_cache: RotatingKvCache::new(cfg.context),
             use_kv_cache: true,
             pos: 0,
             span: tracing::span!(tracing::Level::TRACE, "mha"),
@@ -84,46 +84,38 @@ impl StreamingMultiheadAttention {
 
     pub fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        if self.kv_repeat != 1 {
-            candle::bail!("only kv-repeat = 1 is supported")
-        }
         let (b, t, hd) = xs.dims3()?;
         let head_dim = hd / self.num_heads;
+        let num_kv = self.num_heads / self.kv_repeat;
+        let kv_dim = num_kv * head_dim;
         // time_dim = 1, layout: b,t,h,d
-        let qkv = xs.apply(&self.in_proj)?.reshape((b, t, 3, self.num_heads, head_dim))?;
+        let qkv = xs.apply(&self.in_proj)?;
         let original_dtype = qkv.dtype();
         let qkv = qkv.to_dtype(matmul_dtype(xs.device()))?;
-        let q = qkv.i((.., .., 0))?;
-        let k = qkv.i((.., .., 1))?;
-        let v = qkv.i((.., .., 2))?;
+        let q = qkv.narrow(D::Minus1, 0, hd)?.reshape((b, t, self.num_heads, head_dim))?;
+        let k = qkv.narrow(D::Minus1, hd, kv_dim)?.reshape((b, t, num_kv, head_dim))?;
+        let v = qkv.narrow(D::Minus1, hd + kv_dim, kv_dim)?.reshape((b, t, num_kv, head_dim))?;
         // qk_layer_norm = None
-        // kv_repeat = 1, otherwise we would need repeat_kv
         let mut q = q.transpose(1, 2)?.contiguous()?; // b,h,t,d
-        let mut k = k.transpose(1, 2)?.contiguous()?; // b,h,k,d
-        let v = v.transpose(1, 2)?.contiguous()?; // b,h,k,d
+        let mut k = k.transpose(1, 2)?.contiguous()?; // b,h_kv,k,d
+        let v = v.transpose(1, 2)?.contiguous()?; // b,h_kv,k,d
         if let Some(rope) = &self.rope {
             q = rope.apply_rotary_emb(&q, self.pos)?;
             k = rope.apply_rotary_emb(&k, self.pos)?;
         }
 
+        // The kv-cache returns at most context past steps followed by the new ones, this is
+        // consistent with the mask shape we provide.
         let (k, v) = if self.use_kv_cache {
             self.pos += k.dim(2)?;
             self.kv_cache.append(&k.contiguous()?, &v.contiguous()?)?
         } else {
             (k, v)
         };
-        // The KV cache keeps all the data at the moment, we want to trim
-        // down the part that comes from the cache to at most context to
-        // be coherent with the mask shape we provide.
-        let k_len = k.dim(2)?;
-        let k_target_len = t + usize::min(self.context, k_len - t);
-        let (k, v) = if k_target_len < k_len {
-            let k = k.narrow(2, k_len - k_target_len, k_target_len)?;
-            let v = v.narrow(2, k_len - k_target_len, k_target_len)?;
-            (k, v)
-        } else {
-            (k.clone(), v.clone())
-        };
+        // The kv-cache only holds the num_kv heads, these get shared by groups of kv_repeat
+        // query heads.
+        let k = repeat_kv(k, self.kv_repeat)?; // b,h,k,d
+        let v = repeat_kv(v, self.kv_repeat)?; // b,h,k,d
 
         let pre_ws = q.matmul(&k.t()?)?; // b,h,t,k
         let pre_ws = (pre_ws * (head_dim as f64).powf(-0.5))?;
@@ -148,11 +140,37 @@ impl StreamingMultiheadAttention {
     }
 
     pub fn reset_kv_cache(&mut self) {
-        self.kv_cache.reset()
+        self.kv_cache.reset();
+        self.pos = 0;
     }
 
-    pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
-        self.kv_cache = kv_cache
+    pub fn copy_state(&mut self, from: &Self) -> Result<()> {
+        self.kv_cache = from.kv_cache.copy()?;
+        self.pos = from.pos;
+        Ok(())
+    }
+
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        if let (Some(k), Some(v)) = (self.kv_cache.k()?, self.kv_cache.v()?) {
+            state.insert(state_key(prefix, "k_cache"), k);
+            state.insert(state_key(prefix, "v_cache"), v);
+        }
+        streaming::save_usize(&state_key(prefix, "pos"), self.pos, state)
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.kv_cache.reset();
+        let k = state.get(&state_key(prefix, "k_cache"));
+        let v = state.get(&state_key(prefix, "v_cache"));
+        match (k, v) {
+            (Some(k), Some(v)) => {
+                self.kv_cache.append(k, v)?;
+            }
+            (None, None) => {}
+            _ => candle::bail!("inconsistent kv-cache state for {prefix}"),
+        }
+        self.pos = streaming::load_usize(&state_key(prefix, "pos"), state)?.unwrap_or(0);
+        Ok(())
     }
 }
 
@@ -175,9 +193,6 @@ impl StreamingMultiheadCrossAttention {
 
     pub fn forward(&self, xs: &Tensor, ca_src: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        if self.kv_repeat != 1 {
-            candle::bail!("only kv-repeat = 1 is supported")
-        }
         let (b, t, hd) = xs.dims3()?;
         let head_dim = hd / self.num_heads;
         // time_dim = 1, layout: b,t,h,d
@@ -189,10 +204,9 @@ impl StreamingMultiheadCrossAttention {
         let k = k.reshape((ca_b, ca_t, ca_dim / head_dim, head_dim))?;
         let v = v.reshape((ca_b, ca_t, ca_dim / head_dim, head_dim))?;
         // qk_layer_norm = None
-        // kv_repeat = 1, otherwise we would need repeat_kv
         let q = q.transpose(1, 2)?.contiguous()?; // b,h,t,d
-        let k = k.transpose(1, 2)?.contiguous()?; // b,h,k,d
-        let v = v.transpose(1, 2)?.contiguous()?; // b,h,k,d
+        let k = repeat_kv(k.transpose(1, 2)?.contiguous()?, self.kv_repeat)?; // b,h,k,d
+        let v = repeat_kv(v.transpose(1, 2)?.contiguous()?, self.kv_repeat)?; // b,h,k,d
 
         let pre_ws = q.matmul(&k.t()?)?; // b,h,t,k
         let pre_ws = (pre_ws * (head_dim as f64).powf(-0.5))?;
@@ -400,8 +414,16 @@ impl StreamingTransformerLayer {
         self.self_attn.reset_kv_cache()
     }
 
-    pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
-        self.self_attn.set_kv_cache(kv_cache)
+    pub fn copy_state(&mut self, from: &Self) -> Result<()> {
+        self.self_attn.copy_state(&from.self_attn)
+    }
+
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.self_attn.save_state(&state_key(prefix, "self_attn"), state)
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.self_attn.load_state(&state_key(prefix, "self_attn"), state)
     }
 }
 
@@ -449,7 +471,7 @@ impl StreamingTransformer {
         let (_b, t, c) = xs.dims3()?;
         // We will extract at most "context" from the kv_cache.
         // Note that the mask will discard the values that are before context.
-        let pos = self.layers[0].self_attn.kv_cache.k_cache().current_seq_len().min(self.context);
+        let pos = self.layers[0].self_attn.kv_cache.current_seq_len().min(self.context);
         let mask =
             if t == 1 { None } else { Some(get_mask(t, pos + t, self.context, xs.device())?) };
         let mut xs = match self.positional_embedding {
@@ -481,10 +503,9 @@ impl StreamingTransformer {
         if self.layers.len() != from.layers.len() {
             candle::bail!("cannot copy kv-caches as the transformers have different depths")
         }
-        self.layers
-            .iter_mut()
-            .zip(from.layers.iter())
-            .for_each(|(v, w)| v.set_kv_cache(w.self_attn.kv_cache.clone()));
+        for (v, w) in self.layers.iter_mut().zip(from.layers.iter()) {
+            v.copy_state(w)?
+        }
         Ok(())
     }
 }
@@ -500,6 +521,25 @@ impl StreamingModule for StreamingTransformer {
             Some(xs) => Ok(StreamTensor::from_tensor(self.forward(xs)?)),
         }
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        self.reset_state();
+        Ok(StreamTensor::empty())
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer.save_state(&state_key(prefix, &format!("layers.{i}")), state)?
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer.load_state(&state_key(prefix, &format!("layers.{i}")), state)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -584,4 +624,77 @@ impl StreamingModule for ProjectedTransformer {
             }
         })
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        self.transformer.flush()
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.transformer.save_state(&state_key(prefix, "transformer"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.transformer.load_state(&state_key(prefix, "transformer"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        self.transformer.stream_info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::test_utils::{max_diff, rand_tensor};
+    use crate::transformer::test_utils::{gqa_config, random_weights, repeat_kv_weights};
+    use candle::quantized::{gguf_file, GgmlDType, QTensor};
+    use candle::Device;
+    use std::collections::HashMap;
+
+    fn transformer(ws: &HashMap<String, Tensor>, cfg: &Config) -> Result<StreamingTransformer> {
+        let mut qtensors = vec![];
+        for (name, w) in ws.iter() {
+            qtensors.push((name.as_str(), QTensor::quantize(w, GgmlDType::F32)?))
+        }
+        let qtensors: Vec<_> = qtensors.iter().map(|(n, w)| (*n, w)).collect();
+        let mut buffer = std::io::Cursor::new(vec![]);
+        gguf_file::write(&mut buffer, &[], &qtensors)?;
+        let vb = VarBuilder::from_gguf_buffer(buffer.get_ref(), &Device::Cpu)?;
+        StreamingTransformer::new(cfg, vb)
+    }
+
+    #[test]
+    fn gqa_matches_repeated_heads() -> Result<()> {
+        let dev = &Device::Cpu;
+        let cfg = gqa_config(2);
+        let ws = random_weights(&cfg, dev)?;
+        let mut gqa = transformer(&ws, &cfg)?;
+        let mut mha = transformer(&repeat_kv_weights(&ws, &cfg)?, &gqa_config(1))?;
+        let mut float = crate::transformer::StreamingTransformer::new(
+            &cfg,
+            candle_nn::VarBuilder::from_tensors(ws, DType::F32, dev),
+        )?;
+        let xs = rand_tensor(0, 1., (2, 10, cfg.d_model))?;
+        let ys_gqa = gqa.forward(&xs)?;
+        assert!(max_diff(&ys_gqa, &mha.forward(&xs)?)? < 1e-4);
+        assert!(max_diff(&ys_gqa, &float.forward(&xs)?)? < 1e-4);
+
+        gqa.reset_state();
+        mha.reset_state();
+        for t in 0..xs.dim(1)? {
+            let xs = xs.narrow(1, t, 1)?.contiguous()?;
+            assert!(max_diff(&gqa.forward(&xs)?, &mha.forward(&xs)?)? < 1e-4);
+        }
+
+        // The kv-cache copied over to another transformer only holds the kv heads for the last
+        // context steps.
+        let mut copy = transformer(&random_weights(&cfg, dev)?, &cfg)?;
+        copy.copy_state(&gqa)?;
+        let k = copy.layers[0].self_attn.kv_cache.k()?.unwrap();
+        assert_eq!(
+            k.dims(),
+            &[2, cfg.num_kv_heads()?, cfg.context + 1, cfg.d_model / cfg.num_heads]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,680 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Composable logits processing for the text and audio sampling.
+//!
+//! A [`Sampler`] runs a chain of [`LogitsProcessor`] stages on the logits and then samples from
+//! the resulting distribution. The stages use `-inf` logits for the tokens that cannot be
+//! sampled anymore. [`SamplingConfig`] describes the usual chains in a serializable way.
+
+use candle::{DType, Result, Tensor};
+
+/// The information available to the processing stages when sampling a token.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    /// The generation step.
+    pub step_idx: usize,
+    /// The previous tokens of the stream being sampled, most recent last. Only the last
+    /// [`Sampler::history_len`] tokens have to be provided.
+    pub history: &'a [u32],
+}
+
+impl Context<'static> {
+    pub fn new(step_idx: usize) -> Self {
+        Self { step_idx, history: &[] }
+    }
+}
+
+pub trait LogitsProcessor: std::fmt::Debug + Send {
+    /// Modifies the logits in place.
+    fn process(&self, ctx: &Context, logits: &mut [f32]);
+
+    /// The number of past tokens used by this stage.
+    fn history_len(&self) -> usize {
+        0
+    }
+}
+
+// The probabilities for the logits, the `-inf` logits get a probability of 0.
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut prs: Vec<f32> = logits.iter().map(|&l| (l - max).exp()).collect();
+    let sum: f32 = prs.iter().sum();
+    prs.iter_mut().for_each(|p| *p /= sum);
+    prs
+}
+
+// Only keeps the smallest set of tokens whose probability mass reaches `p`, the tokens are
+// considered by increasing `score`.
+fn keep_mass(logits: &mut [f32], prs: &[f32], p: f64, score: impl Fn(usize) -> f32) {
+    let mut indices: Vec<usize> = (0..logits.len()).filter(|&i| prs[i] > 0.).collect();
+    indices.sort_by(|&i, &j| score(i).total_cmp(&score(j)));
+    let mut mass = 0f64;
+    let mut kept = indices.len();
+    for (n, &i) in indices.iter().enumerate() {
+        mass += prs[i] as f64;
+        if mass >= p {
+            kept = n + 1;
+            break;
+        }
+    }
+    for &i in indices[kept..].iter() {
+        logits[i] = f32::NEG_INFINITY
+    }
+}
+
+/// Divides the logits by a temperature that moves linearly from `start` to `end` over the first
+/// `steps` generation steps. A temperature of 0 results in greedy sampling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Temperature {
+    pub start: f64,
+    pub end: f64,
+    pub steps: usize,
+}
+
+impl Temperature {
+    pub fn constant(temperature: f64) -> Self {
+        Self { start: temperature, end: temperature, steps: 0 }
+    }
+
+    pub fn linear(start: f64, end: f64, steps: usize) -> Self {
+        Self { start, end, steps }
+    }
+
+    pub fn at(&self, step_idx: usize) -> f64 {
+        if step_idx >= self.steps {
+            self.end
+        } else {
+            self.start + (self.end - self.start) * step_idx as f64 / self.steps as f64
+        }
+    }
+}
+
+impl LogitsProcessor for Temperature {
+    fn process(&self, ctx: &Context, logits: &mut [f32]) {
+        let temperature = self.at(ctx.step_idx);
+        if temperature <= 0. {
+            let argmax =
+                logits.iter().enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b)).map(|(i, _)| i);
+            for (i, l) in logits.iter_mut().enumerate() {
+                if Some(i) != argmax {
+                    *l = f32::NEG_INFINITY
+                }
+            }
+        } else {
+            let temperature = temperature as f32;
+            logits.iter_mut().for_each(|l| *l /= temperature)
+        }
+    }
+}
+
+/// Only keeps the `k` most likely tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopK(pub usize);
+
+impl LogitsProcessor for TopK {
+    fn process(&self, _ctx: &Context, logits: &mut [f32]) {
+        if self.0 == 0 || self.0 >= logits.len() {
+            return;
+        }
+        let mut indices: Vec<usize> = (0..logits.len()).collect();
+        indices.select_nth_unstable_by(self.0 - 1, |&i, &j| logits[j].total_cmp(&logits[i]));
+        for &i in indices[self.0..].iter() {
+            logits[i] = f32::NEG_INFINITY
+        }
+    }
+}
+
+/// Nucleus sampling, only keeps the most likely tokens that make up a probability mass of `p`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopP(pub f64);
+
+impl LogitsProcessor for TopP {
+    fn process(&self, _ctx: &Context, logits: &mut [f32]) {
+        let prs = softmax(logits);
+        keep_mass(logits, &prs, self.0, |i| -prs[i])
+    }
+}
+
+/// Only keeps the tokens with a probability of at least `p` times the one of the most likely
+/// token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MinP(pub f64);
+
+impl LogitsProcessor for MinP {
+    fn process(&self, _ctx: &Context, logits: &mut [f32]) {
+        let prs = softmax(logits);
+        let max = prs.iter().copied().fold(0f32, f32::max);
+        let threshold = (max as f64 * self.0) as f32;
+        for (l, &p) in logits.iter_mut().zip(prs.iter()) {
+            if p < threshold {
+                *l = f32::NEG_INFINITY
+            }
+        }
+    }
+}
+
+/// Locally typical sampling, only keeps the tokens whose information content is the closest to
+/// the entropy of the distribution, up to a probability mass of `p`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Typical(pub f64);
+
+impl LogitsProcessor for Typical {
+    fn process(&self, _ctx: &Context, logits: &mut [f32]) {
+        let prs = softmax(logits);
+        let entropy: f32 = prs.iter().filter(|&&p| p > 0.).map(|&p| -p * p.ln()).sum();
+        keep_mass(logits, &prs, self.0, |i| (-prs[i].ln() - entropy).abs())
+    }
+}
+
+/// Adds a bias to the logits of some tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogitBias(pub Vec<(u32, f32)>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, _ctx: &Context, logits: &mut [f32]) {
+        for &(token, bias) in self.0.iter() {
+            if let Some(l) = logits.get_mut(token as usize) {
+                *l += bias
+            }
+        }
+    }
+}
+
+/// Prevents some tokens from being sampled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenBan(pub Vec<u32>);
+
+impl LogitsProcessor for TokenBan {
+    fn process(&self, _ctx: &Context, logits: &mut [f32]) {
+        for &token in self.0.iter() {
+            if let Some(l) = logits.get_mut(token as usize) {
+                *l = f32::NEG_INFINITY
+            }
+        }
+    }
+}
+
+/// Penalizes the tokens that appear in the last `context` tokens of the history, positive
+/// logits are divided by `penalty` and negative ones multiplied by it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepetitionPenalty {
+    pub context: usize,
+    pub penalty: f32,
+}
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&self, ctx: &Context, logits: &mut [f32]) {
+        let mut already_seen = std::collections::HashSet::new();
+        for &token in ctx.history.iter().rev().take(self.context) {
+            if !already_seen.insert(token) {
+                continue;
+            }
+            if let Some(l) = logits.get_mut(token as usize) {
+                if *l >= 0. {
+                    *l /= self.penalty
+                } else {
+                    *l *= self.penalty
+                }
+            }
+        }
+    }
+
+    fn history_len(&self) -> usize {
+        self.context
+    }
+}
+
+/// A splitmix64 random number generator. Unlike the `rand` generators, its state is a single
+/// integer that can be saved and restored when snapshotting a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn state(&self) -> u64 {
+        self.0
+    }
+
+    pub fn set_state(&mut self, state: u64) {
+        self.0 = state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform sample in `[0, 1)`.
+    pub fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Samples tokens from logits after running them through a chain of processing stages.
+pub struct Sampler {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    // The number of stages up to the last temperature stage.
+    temperature_end: usize,
+    rng: Rng,
+}
+
+impl Sampler {
+    /// A sampler without any processing stage, i.e. sampling from the model distribution.
+    pub fn new(seed: u64) -> Self {
+        Self { processors: vec![], temperature_end: 0, rng: Rng::new(seed) }
+    }
+
+    /// A greedy sampler, always returning the most likely token.
+    pub fn greedy() -> Self {
+        Self::new(0).with(Temperature::constant(0.))
+    }
+
+    /// Appends a stage to the processing chain.
+    pub fn with<P: LogitsProcessor + 'static>(mut self, processor: P) -> Self {
+        self.processors.push(Box::new(processor));
+        if std::any::TypeId::of::<P>() == std::any::TypeId::of::<Temperature>() {
+            self.temperature_end = self.processors.len()
+        }
+        self
+    }
+
+    /// The number of past tokens that the stages look at.
+    pub fn history_len(&self) -> usize {
+        self.processors.iter().map(|p| p.history_len()).max().unwrap_or(0)
+    }
+
+    /// The random number generator, its state is part of session snapshots.
+    pub fn rng(&self) -> &Rng {
+        &self.rng
+    }
+
+    pub fn rng_mut(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn sample(&mut self, ctx: &Context, logits: &Tensor) -> Result<u32> {
+        self.sample_f(ctx, logits, |_| {})
+    }
+
+    /// Same as `sample` but `f` gets applied to the logits before the processing stages, e.g. to
+    /// ban some tokens.
+    pub fn sample_f(
+        &mut self,
+        ctx: &Context,
+        logits: &Tensor,
+        f: impl FnOnce(&mut [f32]),
+    ) -> Result<u32> {
+        self.sample_with(ctx, logits, f, |_| {})
+    }
+
+    /// Same as `sample_f` but `post_f` gets applied to the logits right after the temperature
+    /// stage. Adding `b` to a logit at this point multiplies the probability of the token by
+    /// `exp(b)` whatever the temperature.
+    pub fn sample_with(
+        &mut self,
+        ctx: &Context,
+        logits: &Tensor,
+        f: impl FnOnce(&mut [f32]),
+        post_f: impl FnOnce(&mut [f32]),
+    ) -> Result<u32> {
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        f(&mut logits);
+        let (pre, post) = self.processors.split_at(self.temperature_end);
+        for processor in pre.iter() {
+            processor.process(ctx, &mut logits)
+        }
+        post_f(&mut logits);
+        for processor in post.iter() {
+            processor.process(ctx, &mut logits)
+        }
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if max == f32::NEG_INFINITY {
+            candle::bail!("all the tokens have been filtered out at step {}", ctx.step_idx)
+        }
+        let probs = logits.iter().map(|&l| ((l - max) as f64).exp()).collect::<Vec<_>>();
+        let mut threshold = self.rng.uniform() * probs.iter().sum::<f64>();
+        // Rounding errors could make the threshold exceed the total, the last token with a
+        // non-zero probability is used in that case.
+        let mut token = 0;
+        for (idx, &p) in probs.iter().enumerate() {
+            if p > 0. {
+                token = idx;
+                if threshold < p {
+                    break;
+                }
+                threshold -= p
+            }
+        }
+        Ok(token as u32)
+    }
+}
+
+/// The settings of a [`Sampler`], each field enables the matching stage. The stages are applied
+/// in the following order: repetition penalty, logit bias, token bans, temperature,
+/// post-temperature logit bias, top-k, top-p, min-p and typical sampling.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SamplingConfig {
+    /// The sampling temperature, 0 for greedy sampling.
+    pub temperature: f64,
+    /// When set, the temperature moves linearly from `temperature` to this value over the first
+    /// `temperature_steps` steps.
+    #[serde(default)]
+    pub final_temperature: Option<f64>,
+    #[serde(default)]
+    pub temperature_steps: usize,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub min_p: Option<f64>,
+    #[serde(default)]
+    pub typical_p: Option<f64>,
+    /// The number of past tokens to look at and the penalty to apply to them.
+    #[serde(default)]
+    pub repetition_penalty: Option<(usize, f32)>,
+    /// Added to the logits before the temperature scaling, so it also applies to greedy sampling.
+    #[serde(default)]
+    pub logit_bias: Vec<(u32, f32)>,
+    /// Added to the logits after the temperature scaling, i.e. multiplies the probability of the
+    /// token by `exp(bias)`.
+    #[serde(default)]
+    pub post_temperature_logit_bias: Vec<(u32, f32)>,
+    #[serde(default)]
+    pub banned_tokens: Vec<u32>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            final_temperature: None,
+            temperature_steps: 0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+            repetition_penalty: None,
+            logit_bias: vec![],
+            post_temperature_logit_bias: vec![],
+            banned_tokens: vec![],
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn top_k(temperature: f64, k: usize) -> Self {
+        Self { temperature, top_k: Some(k), ..Default::default() }
+    }
+
+    pub fn greedy() -> Self {
+        Self { temperature: 0., ..Default::default() }
+    }
+
+    /// A sampler per config, e.g. one per audio codebook. Each sampler uses its own rng stream
+    /// derived from `seed` so that the codebooks are sampled independently.
+    pub fn samplers(configs: &[Self], seed: u64) -> Vec<Sampler> {
+        configs.iter().enumerate().map(|(i, c)| c.sampler(seed.wrapping_add(i as u64))).collect()
+    }
+
+    pub fn sampler(&self, seed: u64) -> Sampler {
+        let mut sampler = Sampler::new(seed);
+        if let Some((context, penalty)) = self.repetition_penalty {
+            if context > 0 && penalty != 1. {
+                sampler = sampler.with(RepetitionPenalty { context, penalty })
+            }
+        }
+        if !self.logit_bias.is_empty() {
+            sampler = sampler.with(LogitBias(self.logit_bias.clone()))
+        }
+        if !self.banned_tokens.is_empty() {
+            sampler = sampler.with(TokenBan(self.banned_tokens.clone()))
+        }
+        let end = self.final_temperature.unwrap_or(self.temperature);
+        sampler = sampler.with(Temperature::linear(self.temperature, end, self.temperature_steps));
+        if !self.post_temperature_logit_bias.is_empty() {
+            sampler = sampler.with(LogitBias(self.post_temperature_logit_bias.clone()))
+        }
+        if let Some(k) = self.top_k {
+            sampler = sampler.with(TopK(k))
+        }
+        if let Some(p) = self.top_p {
+            sampler = sampler.with(TopP(p))
+        }
+        if let Some(p) = self.min_p {
+            sampler = sampler.with(MinP(p))
+        }
+        if let Some(p) = self.typical_p {
+            sampler = sampler.with(Typical(p))
+        }
+        sampler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEG_INF: f32 = f32::NEG_INFINITY;
+
+    fn process(p: &impl LogitsProcessor, ctx: &Context, logits: &[f32]) -> Vec<f32> {
+        let mut logits = logits.to_vec();
+        p.process(ctx, &mut logits);
+        logits
+    }
+
+    // The indexes of the tokens that can still be sampled.
+    fn kept(p: &impl LogitsProcessor, logits: &[f32]) -> Vec<usize> {
+        let logits = process(p, &Context::new(0), logits);
+        (0..logits.len()).filter(|&i| logits[i] > NEG_INF).collect()
+    }
+
+    // The logits for the probabilities 0.5, 0.3, 0.15 and 0.05.
+    fn logits() -> Vec<f32> {
+        [0.5f32, 0.3, 0.15, 0.05].iter().map(|p| p.ln()).collect()
+    }
+
+    #[test]
+    fn temperature() {
+        let t = Temperature::linear(1., 0.5, 10);
+        assert_eq!([t.at(0), t.at(5), t.at(10), t.at(100)], [1., 0.75, 0.5, 0.5]);
+        assert_eq!(Temperature::constant(0.7).at(0), 0.7);
+        assert_eq!(process(&t, &Context::new(5), &[1.5, -3.]), [2., -4.]);
+        assert_eq!(process(&t, &Context::new(20), &[1.5, -3.]), [3., -6.]);
+
+        let greedy = Temperature::constant(0.);
+        assert_eq!(process(&greedy, &Context::new(0), &[1., 3., 2.]), [NEG_INF, 3., NEG_INF]);
+        // A single token is kept on ties.
+        let kept = kept(&greedy, &[1., 3., 3., 2.]);
+        assert!(kept == [1] || kept == [2], "{kept:?}");
+    }
+
+    #[test]
+    fn top_k() {
+        assert_eq!(kept(&TopK(2), &[1., 3., 2., 0.]), [1, 2]);
+        assert_eq!(kept(&TopK(1), &[1., 3., 2., 0.]), [1]);
+        // 0 or a k larger than the vocabulary disable the stage.
+        assert_eq!(kept(&TopK(0), &[1., 3., 2., 0.]), [0, 1, 2, 3]);
+        assert_eq!(kept(&TopK(5), &[1., 3., 2., 0.]), [0, 1, 2, 3]);
+        // Exactly k tokens are kept on ties.
+        let kept = kept(&TopK(2), &[1., 1., 1., 0.]);
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|&i| i < 3), "{kept:?}");
+    }
+
+    #[test]
+    fn top_p() {
+        assert_eq!(kept(&TopP(0.3), &logits()), [0]);
+        assert_eq!(kept(&TopP(0.7), &logits()), [0, 1]);
+        assert_eq!(kept(&TopP(0.85), &logits()), [0, 1, 2]);
+        assert_eq!(kept(&TopP(1.), &logits()), [0, 1, 2, 3]);
+        // The filtered tokens do not count in the probability mass.
+        let mut l = logits();
+        l[0] = NEG_INF;
+        assert_eq!(kept(&TopP(0.55), &l), [1]);
+        assert_eq!(kept(&TopP(0.65), &l), [1, 2]);
+    }
+
+    #[test]
+    fn min_p() {
+        assert_eq!(kept(&MinP(0.5), &logits()), [0, 1]);
+        assert_eq!(kept(&MinP(0.2), &logits()), [0, 1, 2]);
+        assert_eq!(kept(&MinP(0.), &logits()), [0, 1, 2, 3]);
+        // The most likely tokens are always kept.
+        assert_eq!(kept(&MinP(1.), &[2., 2., 1.]), [0, 1]);
+    }
+
+    #[test]
+    fn typical() {
+        // The entropy is 1.142 nats, the tokens by increasing distance between their
+        // information content and the entropy are 1, 0, 2 and 3.
+        assert_eq!(kept(&Typical(0.2), &logits()), [1]);
+        assert_eq!(kept(&Typical(0.5), &logits()), [0, 1]);
+        assert_eq!(kept(&Typical(0.9), &logits()), [0, 1, 2]);
+        assert_eq!(kept(&Typical(1.), &logits()), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn logit_bias_and_token_ban() {
+        let ctx = Context::new(0);
+        // Out of vocabulary tokens are ignored.
+        let bias = LogitBias(vec![(0, 1.), (2, -2.), (7, 3.)]);
+        assert_eq!(process(&bias, &ctx, &[1., 2., 3.]), [2., 2., 1.]);
+        let ban = TokenBan(vec![2, 0, 7]);
+        assert_eq!(process(&ban, &ctx, &[1., 2., 3.]), [NEG_INF, 2., NEG_INF]);
+    }
+
+    #[test]
+    fn repetition_penalty() {
+        let p = RepetitionPenalty { context: 3, penalty: 2. };
+        assert_eq!(p.history_len(), 3);
+        // Token 0 is out of the context and token 1 is only penalized once.
+        let ctx = Context { step_idx: 0, history: &[0, 1, 1, 3] };
+        assert_eq!(process(&p, &ctx, &[2., 2., -2., -2.]), [2., 1., -2., -4.]);
+        assert_eq!(process(&p, &Context::new(0), &[2., 2., -2., -2.]), [2., 2., -2., -2.]);
+    }
+
+    #[test]
+    fn sampler() -> Result<()> {
+        let logits = Tensor::new(&[0.5f32, 3., -1., 2.], &candle::Device::Cpu)?;
+        let ctx = Context::new(0);
+        assert_eq!(Sampler::greedy().sample(&ctx, &logits)?, 1);
+
+        // The same seed gives the same tokens and the filtered tokens are never sampled.
+        let sample = |seed| -> Result<Vec<u32>> {
+            let mut sampler = Sampler::new(seed).with(TokenBan(vec![1]));
+            (0..50).map(|_| sampler.sample(&ctx, &logits)).collect()
+        };
+        let tokens = sample(42)?;
+        assert_eq!(tokens, sample(42)?);
+        assert_ne!(tokens, sample(43)?);
+        assert!(tokens.iter().all(|&t| t != 1 && t < 4), "{tokens:?}");
+        assert!(tokens.contains(&3), "{tokens:?}");
+
+        let mut sampler = Sampler::new(0).with(TokenBan(vec![0, 1, 2, 3]));
+        assert!(sampler.sample(&ctx, &logits).is_err());
+        let mut sampler = Sampler::greedy();
+        assert!(sampler.sample_f(&ctx, &logits, |l| l.fill(NEG_INF)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sample_with() -> Result<()> {
+        let logits = Tensor::new(&[1f32, 3., 2.], &candle::Device::Cpu)?;
+        let ctx = Context::new(0);
+        let mut sampler = Sampler::new(0).with(Temperature::constant(0.5)).with(TopK(1));
+        let mut seen = (vec![], vec![]);
+        let token = sampler.sample_with(
+            &ctx,
+            &logits,
+            |l| seen.0 = l.to_vec(),
+            |l| {
+                seen.1 = l.to_vec();
+                l[2] += 3.
+            },
+        )?;
+        // The post hook runs after the temperature stage and before the top-k one.
+        assert_eq!(seen, (vec![1., 3., 2.], vec![2., 6., 4.]));
+        assert_eq!(token, 2);
+
+        // Without a temperature stage, the post hook runs before all the stages.
+        let mut sampler = Sampler::new(0).with(TopK(1));
+        assert_eq!(sampler.sample_with(&ctx, &logits, |_| {}, |l| l[0] += 5.)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn samplers() -> Result<()> {
+        let logits = Tensor::new(&[0f32; 16], &candle::Device::Cpu)?;
+        let ctx = Context::new(0);
+        let sample = |sampler: &mut Sampler| -> Result<Vec<u32>> {
+            (0..20).map(|_| sampler.sample(&ctx, &logits)).collect()
+        };
+        let config = SamplingConfig::default();
+        let mut samplers = SamplingConfig::samplers(&[config.clone(), config.clone()], 42);
+        assert_eq!(samplers.len(), 2);
+        // Each sampler uses the stream for seed + i.
+        let tokens0 = sample(&mut samplers[0])?;
+        let tokens1 = sample(&mut samplers[1])?;
+        assert_ne!(tokens0, tokens1);
+        assert_eq!(tokens0, sample(&mut config.sampler(42))?);
+        assert_eq!(tokens1, sample(&mut config.sampler(43))?);
+
+        // The configs are applied in order.
+        let banned = SamplingConfig { banned_tokens: vec![1], ..SamplingConfig::greedy() };
+        let configs = [banned, SamplingConfig::greedy()];
+        let logits = Tensor::new(&[0f32, 1., 0.5], &candle::Device::Cpu)?;
+        let tokens = SamplingConfig::samplers(&configs, 0)
+            .iter_mut()
+            .map(|s| s.sample(&ctx, &logits))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens, [2, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn sampling_config() -> Result<()> {
+        let dev = &candle::Device::Cpu;
+        let ctx = Context::new(0);
+        let logits = Tensor::new(&[1f32, 0., 0.], dev)?;
+        let greedy = SamplingConfig::greedy;
+
+        // The logit bias is applied before the greedy stage and the bans after the bias.
+        let config = SamplingConfig { logit_bias: vec![(2, 5.)], ..greedy() };
+        assert_eq!(config.sampler(0).sample(&ctx, &logits)?, 2);
+        let config = SamplingConfig { banned_tokens: vec![2], ..config };
+        assert_eq!(config.sampler(0).sample(&ctx, &logits)?, 0);
+        // The post-temperature bias cannot change a greedy choice.
+        let config = SamplingConfig { post_temperature_logit_bias: vec![(2, 5.)], ..greedy() };
+        assert_eq!(config.sampler(0).sample(&ctx, &logits)?, 0);
+
+        // The repetition penalty comes before the temperature.
+        let config = SamplingConfig { repetition_penalty: Some((2, 4.)), ..greedy() };
+        let sampler = config.sampler(0);
+        assert_eq!(sampler.history_len(), 2);
+        let logits = Tensor::new(&[2f32, 1., 0.], dev)?;
+        let ctx = Context { step_idx: 0, history: &[0] };
+        assert_eq!(config.sampler(0).sample(&ctx, &logits)?, 1);
+
+        // The post-temperature bias is applied before the top-k stage, the temperature being
+        // used to scale the logits from 2, 1, 0 to 4, 2, 0.
+        let config = SamplingConfig {
+            post_temperature_logit_bias: vec![(2, 4.5)],
+            ..SamplingConfig::top_k(0.5, 1)
+        };
+        assert_eq!(config.sampler(0).sample(&Context::new(0), &logits)?, 2);
+        let config = SamplingConfig { logit_bias: vec![(2, 2.5)], ..SamplingConfig::top_k(0.5, 1) };
+        assert_eq!(config.sampler(0).sample(&Context::new(0), &logits)?, 2);
+        let config = SamplingConfig { logit_bias: vec![(2, 1.5)], ..SamplingConfig::top_k(0.5, 1) };
+        assert_eq!(config.sampler(0).sample(&Context::new(0), &logits)?, 0);
+        Ok(())
+    }
+}
@@ -2,13 +2,15 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::streaming::{self, StreamTensor, StreamingModule};
-use candle::{Module, Result, Tensor};
+use crate::streaming::{
+    self, state_key, step_and_flush, StateDict, StreamInfo, StreamTensor, StreamingModule,
+};
+use candle::{Module, Result, Tensor, D};
 use candle_nn::VarBuilder;
 
 use crate::conv::{StreamableConv1d, StreamableConvTranspose1d};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     pub dimension: usize,
     pub channels: usize,
@@ -16,6 +18,7 @@ pub struct Config {
     pub n_filters: usize,
     pub n_residual_layers: usize,
     pub ratios: Vec<usize>,
+    #[serde(with = "crate::config::Activation")]
     pub activation: candle_nn::Activation,
     pub norm: crate::conv::Norm,
     pub kernel_size: usize,
@@ -27,6 +30,7 @@ pub struct Config {
     pub compress: usize,
     pub lstm: usize,
     pub disable_norm_outer_blocks: usize,
+    #[serde(default, with = "crate::config::option_activation")]
     pub final_activation: Option<candle_nn::Activation>,
 }
 
@@ -123,6 +127,7 @@ impl StreamingModule for SeaNetResnetBlock {
         if let Some(shortcut) = self.shortcut.as_mut() {
             shortcut.reset_state()
         }
+        self.skip_op.reset_state()
     }
 
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
@@ -136,6 +141,209 @@ impl StreamingModule for SeaNetResnetBlock {
             Some(shortcut) => self.skip_op.step(&ys, &xs.apply(shortcut)?),
         }
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        let _enter = self.span.enter();
+        let mut ys = StreamTensor::empty();
+        for block in self.block.iter_mut() {
+            ys = step_and_flush(block, &ys.apply(&self.activation)?, D::Minus1)?;
+        }
+        // Any remaining input for the skip connection is already buffered in skip_op.
+        let ys = self.skip_op.step(&ys, &StreamTensor::empty())?;
+        self.skip_op.reset_state();
+        Ok(ys)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        for (i, block) in self.block.iter().enumerate() {
+            block.save_state(&state_key(prefix, &format!("block.{i}")), state)?
+        }
+        if let Some(shortcut) = self.shortcut.as_ref() {
+            shortcut.save_state(&state_key(prefix, "shortcut"), state)?
+        }
+        self.skip_op.save_state(&state_key(prefix, "skip_op"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        for (i, block) in self.block.iter_mut().enumerate() {
+            block.load_state(&state_key(prefix, &format!("block.{i}")), state)?
+        }
+        if let Some(shortcut) = self.shortcut.as_mut() {
+            shortcut.load_state(&state_key(prefix, "shortcut"), state)?
+        }
+        self.skip_op.load_state(&state_key(prefix, "skip_op"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        // The skip connection is applied as soon as both branches are available, and the
+        // shortcut branch is never slower than the main one.
+        self.block
+            .iter()
+            .fold(StreamInfo::identity(), |info, block| info.then(&block.stream_info()))
+    }
+}
+
+/// A single LSTM layer using the PyTorch weight layout, the cell is stepped manually so that the
+/// hidden and cell states can be carried over, saved and restored.
+#[derive(Debug, Clone)]
+struct LstmLayer {
+    w_ih: Tensor,
+    w_hh: Tensor,
+    b_ih: Tensor,
+    b_hh: Tensor,
+    hidden_dim: usize,
+}
+
+impl LstmLayer {
+    fn new(in_dim: usize, hidden_dim: usize, layer_idx: usize, vb: VarBuilder) -> Result<Self> {
+        // Same initialization hints as candle_nn::lstm.
+        let w_init = candle_nn::init::DEFAULT_KAIMING_UNIFORM;
+        let w_ih = vb.get_with_hints(
+            (4 * hidden_dim, in_dim),
+            &format!("weight_ih_l{layer_idx}"),
+            w_init,
+        )?;
+        let w_hh = vb.get_with_hints(
+            (4 * hidden_dim, hidden_dim),
+            &format!("weight_hh_l{layer_idx}"),
+            w_init,
+        )?;
+        let b_ih = vb.get(4 * hidden_dim, &format!("bias_ih_l{layer_idx}"))?;
+        let b_hh = vb.get(4 * hidden_dim, &format!("bias_hh_l{layer_idx}"))?;
+        Ok(Self { w_ih, w_hh, b_ih, b_hh, hidden_dim })
+    }
+
+    fn zero_state(&self, b_size: usize, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let zeros = Tensor::zeros((b_size, self.hidden_dim), xs.dtype(), xs.device())?;
+        Ok((zeros.clone(), zeros))
+    }
+
+    /// Runs the layer on a (b, t, c) tensor starting from the `(h, c)` state, returns the
+    /// outputs for all the time steps together with the final state.
+    fn run(&self, xs: &Tensor, (h, c): (Tensor, Tensor)) -> Result<(Tensor, (Tensor, Tensor))> {
+        let seq_len = xs.dim(1)?;
+        // The input projection does not depend on the state so it is computed for all the
+        // time steps at once.
+        let xs_proj = xs.broadcast_matmul(&self.w_ih.t()?)?.broadcast_add(&self.b_ih)?;
+        let w_hh = self.w_hh.t()?;
+        let (mut h, mut c) = (h, c);
+        let mut outs = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let gates = (xs_proj.narrow(1, t, 1)?.squeeze(1)?
+                + h.matmul(&w_hh)?.broadcast_add(&self.b_hh)?)?;
+            let gates = gates.chunk(4, 1)?;
+            let in_gate = candle_nn::ops::sigmoid(&gates[0])?;
+            let forget_gate = candle_nn::ops::sigmoid(&gates[1])?;
+            let cell_gate = gates[2].tanh()?;
+            let out_gate = candle_nn::ops::sigmoid(&gates[3])?;
+            c = ((forget_gate * &c)? + (in_gate * cell_gate)?)?;
+            h = (out_gate * c.tanh()?)?;
+            outs.push(h.clone())
+        }
+        let ys = Tensor::stack(&outs, 1)?;
+        Ok((ys, (h, c)))
+    }
+}
+
+/// A stack of LSTM layers operating on (b, c, t) tensors, the hidden and cell states are carried
+/// over between calls to `step`. When `skip` is set, the input is added to the output.
+#[derive(Debug, Clone)]
+pub struct StreamableLstm {
+    layers: Vec<LstmLayer>,
+    skip: bool,
+    state: Vec<Option<(Tensor, Tensor)>>,
+    span: tracing::Span,
+}
+
+impl StreamableLstm {
+    pub fn new(dim: usize, num_layers: usize, skip: bool, vb: VarBuilder) -> Result<Self> {
+        let mut layers = Vec::with_capacity(num_layers);
+        for layer_idx in 0..num_layers {
+            layers.push(LstmLayer::new(dim, dim, layer_idx, vb.pp("lstm"))?)
+        }
+        Ok(Self {
+            layers,
+            skip,
+            state: vec![None; num_layers],
+            span: tracing::span!(tracing::Level::TRACE, "lstm"),
+        })
+    }
+
+    fn run(
+        layers: &[LstmLayer],
+        skip: bool,
+        xs: &Tensor,
+        state: &mut [Option<(Tensor, Tensor)>],
+    ) -> Result<Tensor> {
+        let b_size = xs.dim(0)?;
+        let xs = xs.transpose(1, 2)?;
+        let mut ys = xs.clone();
+        for (layer, state) in layers.iter().zip(state.iter_mut()) {
+            let s = match state.take() {
+                None => layer.zero_state(b_size, &xs)?,
+                Some(s) => s,
+            };
+            let (layer_ys, s) = layer.run(&ys, s)?;
+            *state = Some(s);
+            ys = layer_ys;
+        }
+        let ys = if skip { (ys + xs)? } else { ys };
+        ys.transpose(1, 2)
+    }
+}
+
+impl Module for StreamableLstm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let mut state = vec![None; self.layers.len()];
+        Self::run(&self.layers, self.skip, xs, &mut state)
+    }
+}
+
+impl StreamingModule for StreamableLstm {
+    fn reset_state(&mut self) {
+        self.state.iter_mut().for_each(|s| *s = None)
+    }
+
+    fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
+        let _enter = self.span.enter();
+        match xs.as_option() {
+            None => Ok(StreamTensor::empty()),
+            Some(xs) if xs.dim(D::Minus1)? == 0 => Ok(StreamTensor::empty()),
+            Some(xs) => {
+                let ys = Self::run(&self.layers, self.skip, xs, &mut self.state)?;
+                Ok(StreamTensor::from_tensor(ys))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        self.reset_state();
+        Ok(StreamTensor::empty())
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        for (i, s) in self.state.iter().enumerate() {
+            if let Some((h, c)) = s {
+                state.insert(state_key(prefix, &format!("layers.{i}.h")), h.clone());
+                state.insert(state_key(prefix, &format!("layers.{i}.c")), c.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        for (i, s) in self.state.iter_mut().enumerate() {
+            let h = state.get(&state_key(prefix, &format!("layers.{i}.h")));
+            let c = state.get(&state_key(prefix, &format!("layers.{i}.c")));
+            *s = match (h, c) {
+                (Some(h), Some(c)) => Some((h.clone(), c.clone())),
+                (None, None) => None,
+                _ => candle::bail!("incomplete lstm state for layer {i} in {prefix}"),
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -149,15 +357,13 @@ pub struct SeaNetEncoder {
     init_conv1d: StreamableConv1d,
     activation: candle_nn::Activation,
     layers: Vec<EncoderLayer>,
+    lstm: Option<StreamableLstm>,
     final_conv1d: StreamableConv1d,
     span: tracing::Span,
 }
 
 impl SeaNetEncoder {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        if cfg.lstm > 0 {
-            candle::bail!("seanet lstm is not supported")
-        }
         let n_blocks = 2 + cfg.ratios.len();
         let mut mult = 1usize;
         let init_norm = if cfg.disable_norm_outer_blocks >= 1 { None } else { Some(cfg.norm) };
@@ -205,7 +411,7 @@ impl SeaNetEncoder {
                 /* dilation */ 1,
                 /* groups */ 1,
                 /* bias */ true,
-                /* causal */ true,
+                /* causal */ cfg.causal,
                 /* norm */ norm,
                 /* pad_mode */ cfg.pad_mode,
                 vb.pp(layer_idx + 1),
@@ -215,6 +421,13 @@ impl SeaNetEncoder {
             layers.push(layer);
             mult *= 2
         }
+        let lstm = if cfg.lstm > 0 {
+            let lstm = StreamableLstm::new(mult * cfg.n_filters, cfg.lstm, true, vb.pp(layer_idx))?;
+            layer_idx += 1;
+            Some(lstm)
+        } else {
+            None
+        };
 
         let final_norm =
             if cfg.disable_norm_outer_blocks >= n_blocks { None } else { Some(cfg.norm) };
@@ -235,6 +448,7 @@ impl SeaNetEncoder {
             init_conv1d,
             activation: cfg.activation,
             layers,
+            lstm,
             final_conv1d,
             span: tracing::span!(tracing::Level::TRACE, "sea-encoder"),
         })
@@ -251,6 +465,9 @@ impl Module for SeaNetEncoder {
             }
             xs = xs.apply(&self.activation)?.apply(&layer.downsample)?;
         }
+        if let Some(lstm) = self.lstm.as_ref() {
+            xs = xs.apply(lstm)?
+        }
         xs.apply(&self.activation)?.apply(&self.final_conv1d)
     }
 }
@@ -262,6 +479,9 @@ impl StreamingModule for SeaNetEncoder {
             v.residuals.iter_mut().for_each(|v| v.reset_state());
             v.downsample.reset_state()
         });
+        if let Some(lstm) = self.lstm.as_mut() {
+            lstm.reset_state()
+        }
         self.final_conv1d.reset_state();
     }
 
@@ -274,8 +494,70 @@ impl StreamingModule for SeaNetEncoder {
             }
             xs = layer.downsample.step(&xs.apply(&self.activation)?)?;
         }
+        if let Some(lstm) = self.lstm.as_mut() {
+            xs = lstm.step(&xs)?
+        }
         self.final_conv1d.step(&xs.apply(&self.activation)?)
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        let _enter = self.span.enter();
+        let mut xs = self.init_conv1d.flush()?;
+        for layer in self.layers.iter_mut() {
+            for residual in layer.residuals.iter_mut() {
+                xs = step_and_flush(residual, &xs, D::Minus1)?;
+            }
+            xs = step_and_flush(&mut layer.downsample, &xs.apply(&self.activation)?, D::Minus1)?;
+        }
+        if let Some(lstm) = self.lstm.as_mut() {
+            xs = step_and_flush(lstm, &xs, D::Minus1)?
+        }
+        step_and_flush(&mut self.final_conv1d, &xs.apply(&self.activation)?, D::Minus1)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.init_conv1d.save_state(&state_key(prefix, "init_conv1d"), state)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let prefix = state_key(prefix, &format!("layers.{i}"));
+            for (j, residual) in layer.residuals.iter().enumerate() {
+                residual.save_state(&state_key(&prefix, &format!("residuals.{j}")), state)?
+            }
+            layer.downsample.save_state(&state_key(&prefix, "downsample"), state)?
+        }
+        if let Some(lstm) = self.lstm.as_ref() {
+            lstm.save_state(&state_key(prefix, "lstm"), state)?
+        }
+        self.final_conv1d.save_state(&state_key(prefix, "final_conv1d"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.init_conv1d.load_state(&state_key(prefix, "init_conv1d"), state)?;
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = state_key(prefix, &format!("layers.{i}"));
+            for (j, residual) in layer.residuals.iter_mut().enumerate() {
+                residual.load_state(&state_key(&prefix, &format!("residuals.{j}")), state)?
+            }
+            layer.downsample.load_state(&state_key(&prefix, "downsample"), state)?
+        }
+        if let Some(lstm) = self.lstm.as_mut() {
+            lstm.load_state(&state_key(prefix, "lstm"), state)?
+        }
+        self.final_conv1d.load_state(&state_key(prefix, "final_conv1d"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        let mut info = self.init_conv1d.stream_info();
+        for layer in self.layers.iter() {
+            for residual in layer.residuals.iter() {
+                info = info.then(&residual.stream_info())
+            }
+            info = info.then(&layer.downsample.stream_info())
+        }
+        if let Some(lstm) = self.lstm.as_ref() {
+            info = info.then(&lstm.stream_info())
+        }
+        info.then(&self.final_conv1d.stream_info())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -287,6 +569,7 @@ struct DecoderLayer {
 #[derive(Debug, Clone)]
 pub struct SeaNetDecoder {
     init_conv1d: StreamableConv1d,
+    lstm: Option<StreamableLstm>,
     activation: candle_nn::Activation,
     layers: Vec<DecoderLayer>,
     final_conv1d: StreamableConv1d,
@@ -296,9 +579,6 @@ pub struct SeaNetDecoder {
 
 impl SeaNetDecoder {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        if cfg.lstm > 0 {
-            candle::bail!("seanet lstm is not supported")
-        }
         let n_blocks = 2 + cfg.ratios.len();
         let mut mult = 1 << cfg.ratios.len();
         let init_norm =
@@ -319,6 +599,13 @@ impl SeaNetDecoder {
             vb.pp(layer_idx),
         )?;
         layer_idx += 1;
+        let lstm = if cfg.lstm > 0 {
+            let lstm = StreamableLstm::new(mult * cfg.n_filters, cfg.lstm, true, vb.pp(layer_idx))?;
+            layer_idx += 1;
+            Some(lstm)
+        } else {
+            None
+        };
         let mut layers = Vec::with_capacity(cfg.ratios.len());
         for (i, &ratio) in cfg.ratios.iter().enumerate() {
             let norm = if cfg.disable_norm_outer_blocks + i + 1 >= n_blocks {
@@ -333,7 +620,7 @@ impl SeaNetDecoder {
                 /* stride */ ratio,
                 /* groups */ 1,
                 /* bias */ true,
-                /* causal */ true,
+                /* causal */ cfg.causal,
                 /* norm */ norm,
                 vb.pp(layer_idx + 1),
             )?;
@@ -375,6 +662,7 @@ impl SeaNetDecoder {
         )?;
         Ok(Self {
             init_conv1d,
+            lstm,
             activation: cfg.activation,
             layers,
             final_conv1d,
@@ -388,6 +676,9 @@ impl Module for SeaNetDecoder {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
         let mut xs = xs.apply(&self.init_conv1d)?;
+        if let Some(lstm) = self.lstm.as_ref() {
+            xs = xs.apply(lstm)?
+        }
         for layer in self.layers.iter() {
             xs = xs.apply(&self.activation)?.apply(&layer.upsample)?;
             for residual in layer.residuals.iter() {
@@ -406,6 +697,9 @@ impl Module for SeaNetDecoder {
 impl StreamingModule for SeaNetDecoder {
     fn reset_state(&mut self) {
         self.init_conv1d.reset_state();
+        if let Some(lstm) = self.lstm.as_mut() {
+            lstm.reset_state()
+        }
         self.layers.iter_mut().for_each(|v| {
             v.residuals.iter_mut().for_each(|v| v.reset_state());
             v.upsample.reset_state()
@@ -416,6 +710,9 @@ impl StreamingModule for SeaNetDecoder {
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
         let _enter = self.span.enter();
         let mut xs = self.init_conv1d.step(xs)?;
+        if let Some(lstm) = self.lstm.as_mut() {
+            xs = lstm.step(&xs)?
+        }
         for layer in self.layers.iter_mut() {
             xs = layer.upsample.step(&xs.apply(&self.activation)?)?;
             for residual in layer.residuals.iter_mut() {
@@ -429,4 +726,168 @@ impl StreamingModule for SeaNetDecoder {
         };
         Ok(xs)
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        let _enter = self.span.enter();
+        let mut xs = self.init_conv1d.flush()?;
+        if let Some(lstm) = self.lstm.as_mut() {
+            xs = step_and_flush(lstm, &xs, D::Minus1)?
+        }
+        for layer in self.layers.iter_mut() {
+            xs = step_and_flush(&mut layer.upsample, &xs.apply(&self.activation)?, D::Minus1)?;
+            for residual in layer.residuals.iter_mut() {
+                xs = step_and_flush(residual, &xs, D::Minus1)?;
+            }
+        }
+        let xs = step_and_flush(&mut self.final_conv1d, &xs.apply(&self.activation)?, D::Minus1)?;
+        let xs = match self.final_activation.as_ref() {
+            None => xs,
+            Some(act) => xs.apply(act)?,
+        };
+        Ok(xs)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.init_conv1d.save_state(&state_key(prefix, "init_conv1d"), state)?;
+        if let Some(lstm) = self.lstm.as_ref() {
+            lstm.save_state(&state_key(prefix, "lstm"), state)?
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            let prefix = state_key(prefix, &format!("layers.{i}"));
+            layer.upsample.save_state(&state_key(&prefix, "upsample"), state)?;
+            for (j, residual) in layer.residuals.iter().enumerate() {
+                residual.save_state(&state_key(&prefix, &format!("residuals.{j}")), state)?
+            }
+        }
+        self.final_conv1d.save_state(&state_key(prefix, "final_conv1d"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.init_conv1d.load_state(&state_key(prefix, "init_conv1d"), state)?;
+        if let Some(lstm) = self.lstm.as_mut() {
+            lstm.load_state(&state_key(prefix, "lstm"), state)?
+        }
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = state_key(prefix, &format!("layers.{i}"));
+            layer.upsample.load_state(&state_key(&prefix, "upsample"), state)?;
+            for (j, residual) in layer.residuals.iter_mut().enumerate() {
+                residual.load_state(&state_key(&prefix, &format!("residuals.{j}")), state)?
+            }
+        }
+        self.final_conv1d.load_state(&state_key(prefix, "final_conv1d"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        let mut info = self.init_conv1d.stream_info();
+        if let Some(lstm) = self.lstm.as_ref() {
+            info = info.then(&lstm.stream_info())
+        }
+        for layer in self.layers.iter() {
+            info = info.then(&layer.upsample.stream_info());
+            for residual in layer.residuals.iter() {
+                info = info.then(&residual.stream_info())
+            }
+        }
+        info.then(&self.final_conv1d.stream_info())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::test_utils::{max_diff, rand_tensor, randomize, step_chunks};
+
+    fn small_config(lstm: usize) -> Config {
+        Config {
+            dimension: 8,
+            channels: 1,
+            causal: true,
+            n_filters: 4,
+            n_residual_layers: 1,
+            ratios: vec![4, 2],
+            activation: candle_nn::Activation::Elu(1.),
+            norm: crate::conv::Norm::WeightNorm,
+            kernel_size: 5,
+            residual_kernel_size: 3,
+            last_kernel_size: 3,
+            dilation_base: 2,
+            pad_mode: crate::conv::PadMode::Constant,
+            true_skip: true,
+            compress: 2,
+            lstm,
+            disable_norm_outer_blocks: 0,
+            final_activation: None,
+        }
+    }
+
+    #[test]
+    fn lstm_streaming() -> Result<()> {
+        let dev = &candle::Device::Cpu;
+        let vm = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&vm, candle::DType::F32, dev);
+        let mut lstm = StreamableLstm::new(6, 2, true, vb)?;
+        randomize(&vm, 0.5)?;
+        let xs = rand_tensor(1, 1., (2, 6, 11))?;
+        let ys = lstm.forward(&xs)?;
+        for chunk_len in [1, 3, 11] {
+            let ys_steps = step_chunks(&mut lstm, &xs, chunk_len)?;
+            let diff = max_diff(&ys, &ys_steps)?;
+            assert!(diff < 1e-5, "chunk {chunk_len} diff {diff}");
+        }
+
+        // Restoring a saved state resumes the stream.
+        let mut state = StateDict::new();
+        lstm.step(&xs.narrow(2, 0, 4)?.into())?;
+        lstm.save_state("lstm", &mut state)?;
+        lstm.reset_state();
+        lstm.load_state("lstm", &state)?;
+        let ys_end = lstm.step(&xs.narrow(2, 4, 7)?.into())?;
+        let diff = max_diff(&ys.narrow(2, 4, 7)?, ys_end.as_option().unwrap())?;
+        assert!(diff < 1e-5, "restored diff {diff}");
+        Ok(())
+    }
+
+    fn check_streaming(cfg: &Config, len: usize, chunk_len: usize) -> Result<()> {
+        let dev = &candle::Device::Cpu;
+        let vm = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&vm, candle::DType::F32, dev);
+        // The first pass creates the variables, weight norm is applied at construction time so
+        // the modules have to be created again once the weights are randomized.
+        SeaNetEncoder::new(cfg, vb.pp("encoder"))?;
+        SeaNetDecoder::new(cfg, vb.pp("decoder"))?;
+        randomize(&vm, 0.3)?;
+        let mut encoder = SeaNetEncoder::new(cfg, vb.pp("encoder"))?;
+        let mut decoder = SeaNetDecoder::new(cfg, vb.pp("decoder"))?;
+
+        let xs = rand_tensor(len as u64, 1., (1, cfg.channels, len))?;
+        let ys = encoder.forward(&xs)?;
+        let ys_steps = step_chunks(&mut encoder, &xs, chunk_len)?;
+        let diff = max_diff(&ys, &ys_steps)?;
+        assert!(diff < 1e-4, "encoder len {len} chunk {chunk_len} diff {diff}");
+
+        let zs = decoder.forward(&ys)?;
+        for chunk_len in [1, 2] {
+            let zs_steps = step_chunks(&mut decoder, &ys, chunk_len)?;
+            let diff = max_diff(&zs, &zs_steps)?;
+            assert!(diff < 1e-4, "decoder len {len} chunk {chunk_len} diff {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn seanet_streaming() -> Result<()> {
+        // The frame size is 8, use lengths and chunk sizes that are not multiples of it.
+        let cfg = small_config(0);
+        for (len, chunk_len) in [(64, 8), (43, 5), (43, 8), (9, 3), (21, 21)] {
+            check_streaming(&cfg, len, chunk_len)?
+        }
+        let cfg = Config { channels: 2, causal: false, ..small_config(0) };
+        check_streaming(&cfg, 43, 5)
+    }
+
+    #[test]
+    fn seanet_lstm_streaming() -> Result<()> {
+        check_streaming(&small_config(2), 64, 8)?;
+        check_streaming(&small_config(2), 43, 5)
+    }
 }
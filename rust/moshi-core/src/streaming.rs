@@ -2,7 +2,42 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use candle::{Result, Tensor};
+use candle::{Device, Result, Tensor};
+
+/// The streaming state of a model, tensors are indexed by their dotted path in the model in the
+/// same way as the model weights.
+pub type StateDict = std::collections::HashMap<String, Tensor>;
+
+pub fn state_key(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{prefix}.{name}")
+    }
+}
+
+pub fn save_usize(key: &str, v: usize, state: &mut StateDict) -> Result<()> {
+    state.insert(key.to_string(), Tensor::new(v as i64, &Device::Cpu)?);
+    Ok(())
+}
+
+pub fn load_usize(key: &str, state: &StateDict) -> Result<Option<usize>> {
+    match state.get(key) {
+        None => Ok(None),
+        Some(v) => Ok(Some(v.to_dtype(candle::DType::I64)?.to_scalar::<i64>()? as usize)),
+    }
+}
+
+/// Serializes a state dict to the safetensors format.
+pub fn serialize_state(state: &StateDict) -> Result<Vec<u8>> {
+    Ok(safetensors::tensor::serialize(state, &None)?)
+}
+
+/// Deserializes a state dict from the safetensors format, `device` should be the device on which
+/// the model that the state gets restored into has been loaded.
+pub fn deserialize_state(data: &[u8], device: &Device) -> Result<StateDict> {
+    candle::safetensors::load_buffer(data, device)
+}
 
 pub trait Dim: candle::shape::Dim + Copy {}
 impl<T: candle::shape::Dim + Copy> Dim for T {}
@@ -123,12 +158,91 @@ impl StreamTensor {
             Some(t) => Ok(Self::from_tensor(t.apply(m)?)),
         }
     }
+
+    /// Stores the tensor in `state`, nothing gets stored for an empty tensor.
+    pub fn save_state(&self, key: &str, state: &mut StateDict) {
+        if let Some(t) = self.0.as_ref() {
+            state.insert(key.to_string(), t.clone());
+        }
+    }
+
+    pub fn load_state(key: &str, state: &StateDict) -> Self {
+        Self(state.get(key).cloned())
+    }
+}
+
+/// Timing properties of a streaming module: each `stride_in` input steps result in `stride_out`
+/// output steps, and the first output only gets produced once `delay` input steps have been
+/// received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub stride_in: usize,
+    pub stride_out: usize,
+    pub delay: usize,
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl StreamInfo {
+    /// A module that produces one output step per input step without any buffering.
+    pub fn identity() -> Self {
+        Self { stride_in: 1, stride_out: 1, delay: 1 }
+    }
+
+    /// The timing of running `self` followed by `next`, the delay is expressed in input steps
+    /// of `self`.
+    pub fn then(&self, next: &Self) -> Self {
+        let g = gcd(self.stride_out, next.stride_in);
+        // The first chunk of self.stride_out steps is available after self.delay input steps,
+        // the following ones every self.stride_in input steps.
+        let chunks = usize::max(1, next.delay.div_ceil(self.stride_out));
+        Self {
+            stride_in: self.stride_in * next.stride_in / g,
+            stride_out: self.stride_out * next.stride_out / g,
+            delay: self.delay + (chunks - 1) * self.stride_in,
+        }
+    }
 }
 
 pub trait StreamingModule {
-    // TODO: Should we also have a flush method?
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor>;
     fn reset_state(&mut self);
+    /// Signals the end of the stream: pads and processes whatever input is still buffered and
+    /// returns the corresponding output. The state is reset afterwards so that the module can be
+    /// used for a new stream. Modules without any lookahead only have to reset their state.
+    fn flush(&mut self) -> Result<StreamTensor>;
+    /// Stores the streaming state of the module in `state` using keys that start with `prefix`.
+    /// Stateless modules have nothing to store.
+    fn save_state(&self, _prefix: &str, _state: &mut StateDict) -> Result<()> {
+        Ok(())
+    }
+    /// Restores a streaming state previously stored with `save_state`, missing entries are
+    /// considered as empty.
+    fn load_state(&mut self, _prefix: &str, _state: &StateDict) -> Result<()> {
+        Ok(())
+    }
+    /// The input and output strides of the module together with its algorithmic delay. The
+    /// default is for modules that map each input step to an output step right away.
+    fn stream_info(&self) -> StreamInfo {
+        StreamInfo::identity()
+    }
+}
+
+/// Runs a last step on `xs` then flushes the module, the outputs are concatenated on `dim`.
+pub fn step_and_flush<M: StreamingModule + ?Sized, D: Dim>(
+    m: &mut M,
+    xs: &StreamTensor,
+    dim: D,
+) -> Result<StreamTensor> {
+    let ys = m.step(xs)?;
+    let ys_flush = m.flush()?;
+    StreamTensor::cat2(&ys, &ys_flush, dim)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -157,6 +271,18 @@ impl StreamingBinOp {
         self.prev_rhs.reset();
     }
 
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.prev_lhs.save_state(&state_key(prefix, "prev_lhs"), state);
+        self.prev_rhs.save_state(&state_key(prefix, "prev_rhs"), state);
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.prev_lhs = StreamTensor::load_state(&state_key(prefix, "prev_lhs"), state);
+        self.prev_rhs = StreamTensor::load_state(&state_key(prefix, "prev_rhs"), state);
+        Ok(())
+    }
+
     pub fn forward(&self, lhs: &Tensor, rhs: &Tensor) -> Result<Tensor> {
         match self.op {
             BinOp::Add => Tensor::add(lhs, rhs),
@@ -188,13 +314,416 @@ impl StreamingBinOp {
     }
 }
 
+impl<M: StreamingModule + ?Sized> StreamingModule for Box<M> {
+    fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
+        (**self).step(xs)
+    }
+
+    fn reset_state(&mut self) {
+        (**self).reset_state()
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        (**self).flush()
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        (**self).save_state(prefix, state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        (**self).load_state(prefix, state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        (**self).stream_info()
+    }
+}
+
 /// Simple wrapper that doesn't do any buffering.
 pub struct Map<T: candle::Module>(T);
 
+impl<T: candle::Module> Map<T> {
+    pub fn new(m: T) -> Self {
+        Self(m)
+    }
+}
+
 impl<T: candle::Module> StreamingModule for Map<T> {
     fn reset_state(&mut self) {}
 
     fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
         xs.apply(&self.0)
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        Ok(StreamTensor::empty())
+    }
+}
+
+/// Applies a list of streaming modules one after the other, `dim` is the time dimension.
+pub struct StreamingSequential {
+    layers: Vec<Box<dyn StreamingModule + Send>>,
+    pub dim: candle::D,
+}
+
+impl StreamingSequential {
+    pub fn new(dim: candle::D) -> Self {
+        Self { layers: vec![], dim }
+    }
+
+    /// Appends a layer at the end of the pipeline, builder style.
+    pub fn with<M: StreamingModule + Send + 'static>(mut self, layer: M) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn push(&mut self, layer: Box<dyn StreamingModule + Send>) {
+        self.layers.push(layer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+}
+
+impl StreamingModule for StreamingSequential {
+    fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
+        let mut xs = xs.clone();
+        for layer in self.layers.iter_mut() {
+            xs = layer.step(&xs)?
+        }
+        Ok(xs)
+    }
+
+    fn reset_state(&mut self) {
+        self.layers.iter_mut().for_each(|v| v.reset_state())
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        // The output of flushing a layer has to go through the remaining layers before these
+        // get flushed.
+        let mut xs = StreamTensor::empty();
+        for layer in self.layers.iter_mut() {
+            xs = step_and_flush(layer, &xs, self.dim)?
+        }
+        Ok(xs)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer.save_state(&state_key(prefix, &format!("layers.{i}")), state)?
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer.load_state(&state_key(prefix, &format!("layers.{i}")), state)?
+        }
+        Ok(())
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        self.layers
+            .iter()
+            .fold(StreamInfo::identity(), |info, layer| info.then(&layer.stream_info()))
+    }
+}
+
+/// Combines the output of `inner` with its input, or with the output of `shortcut` when
+/// provided, using a `StreamingBinOp`. Both branches should have the same strides.
+pub struct StreamingResidual {
+    inner: Box<dyn StreamingModule + Send>,
+    shortcut: Option<Box<dyn StreamingModule + Send>>,
+    skip_op: StreamingBinOp,
+}
+
+impl StreamingResidual {
+    /// The usual residual connection, adding the input of `inner` to its output.
+    pub fn new<M: StreamingModule + Send + 'static>(inner: M, dim: candle::D) -> Self {
+        Self::new_with_op(Box::new(inner), None, BinOp::Add, dim)
+    }
+
+    pub fn new_with_op(
+        inner: Box<dyn StreamingModule + Send>,
+        shortcut: Option<Box<dyn StreamingModule + Send>>,
+        op: BinOp,
+        dim: candle::D,
+    ) -> Self {
+        Self { inner, shortcut, skip_op: StreamingBinOp::new(op, dim) }
+    }
+}
+
+impl StreamingModule for StreamingResidual {
+    fn step(&mut self, xs: &StreamTensor) -> Result<StreamTensor> {
+        let ys = self.inner.step(xs)?;
+        match self.shortcut.as_mut() {
+            None => self.skip_op.step(&ys, xs),
+            Some(shortcut) => {
+                let xs = shortcut.step(xs)?;
+                self.skip_op.step(&ys, &xs)
+            }
+        }
+    }
+
+    fn reset_state(&mut self) {
+        self.inner.reset_state();
+        if let Some(shortcut) = self.shortcut.as_mut() {
+            shortcut.reset_state()
+        }
+        self.skip_op.reset_state()
+    }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        let ys = self.inner.flush()?;
+        let xs = match self.shortcut.as_mut() {
+            None => StreamTensor::empty(),
+            Some(shortcut) => shortcut.flush()?,
+        };
+        let ys = self.skip_op.step(&ys, &xs)?;
+        self.skip_op.reset_state();
+        Ok(ys)
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.inner.save_state(&state_key(prefix, "inner"), state)?;
+        if let Some(shortcut) = self.shortcut.as_ref() {
+            shortcut.save_state(&state_key(prefix, "shortcut"), state)?
+        }
+        self.skip_op.save_state(&state_key(prefix, "skip_op"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.inner.load_state(&state_key(prefix, "inner"), state)?;
+        if let Some(shortcut) = self.shortcut.as_mut() {
+            shortcut.load_state(&state_key(prefix, "shortcut"), state)?
+        }
+        self.skip_op.load_state(&state_key(prefix, "skip_op"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        let inner = self.inner.stream_info();
+        let shortcut = match self.shortcut.as_ref() {
+            None => StreamInfo::identity(),
+            Some(shortcut) => shortcut.stream_info(),
+        };
+        StreamInfo { delay: usize::max(inner.delay, shortcut.delay), ..inner }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+
+    /// Deterministic pseudo-random values uniformly distributed in `[-scale, scale)`, tests use
+    /// these rather than `Tensor::randn` so that failures can be reproduced.
+    pub(crate) fn rand_tensor<S: Into<candle::Shape>>(
+        seed: u64,
+        scale: f32,
+        shape: S,
+    ) -> Result<Tensor> {
+        let shape = shape.into();
+        let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        let vs = (0..shape.elem_count())
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                ((x >> 40) as f32 / (1u64 << 23) as f32 - 1.) * scale
+            })
+            .collect::<Vec<_>>();
+        Tensor::from_vec(vs, shape, &Device::Cpu)
+    }
+
+    /// Fills all the variables of `vm` with random values, `VarMap` initializes most weights to
+    /// zero which would make the streaming comparisons trivial. Modules that derive their weights
+    /// at construction time, e.g. with weight norm, have to be created again afterwards.
+    pub(crate) fn randomize(vm: &candle_nn::VarMap, scale: f32) -> Result<()> {
+        for (name, var) in vm.data().lock().unwrap().iter() {
+            let seed =
+                name.bytes().fold(7u64, |acc, b| acc.wrapping_mul(31).wrapping_add(b as u64));
+            let v = rand_tensor(seed, scale, var.shape())?;
+            var.set(&v.to_dtype(var.dtype())?.to_device(var.device())?)?
+        }
+        Ok(())
+    }
+
+    /// Feeds `xs` to `m` by chunks of `chunk_len` on the last dimension, flushes the module and
+    /// concatenates all the outputs.
+    pub(crate) fn step_chunks<M: StreamingModule + ?Sized>(
+        m: &mut M,
+        xs: &Tensor,
+        chunk_len: usize,
+    ) -> Result<Tensor> {
+        let len = xs.dim(candle::D::Minus1)?;
+        let mut ys = StreamTensor::empty();
+        for start in (0..len).step_by(chunk_len) {
+            let chunk = xs.narrow(candle::D::Minus1, start, usize::min(chunk_len, len - start))?;
+            let chunk_ys = m.step(&chunk.into())?;
+            ys = StreamTensor::cat2(&ys, &chunk_ys, candle::D::Minus1)?;
+        }
+        let ys = StreamTensor::cat2(&ys, &m.flush()?, candle::D::Minus1)?;
+        match ys.as_option() {
+            None => candle::bail!("no output produced"),
+            Some(ys) => Ok(ys.clone()),
+        }
+    }
+
+    /// Feeds `xs` to `m` one step at a time and returns the number of steps that were required
+    /// before the first output got produced, `None` if there was no output.
+    pub(crate) fn first_output_step<M: StreamingModule + ?Sized>(
+        m: &mut M,
+        xs: &Tensor,
+    ) -> Result<Option<usize>> {
+        for idx in 0..xs.dim(candle::D::Minus1)? {
+            let ys = m.step(&xs.narrow(candle::D::Minus1, idx, 1)?.into())?;
+            if ys.as_option().is_some() {
+                return Ok(Some(idx + 1));
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn max_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f32> {
+        if lhs.dims() != rhs.dims() {
+            candle::bail!("shape mismatch {:?} {:?}", lhs.shape(), rhs.shape())
+        }
+        let diff = (lhs.to_dtype(candle::DType::F32)? - rhs.to_dtype(candle::DType::F32)?)?;
+        diff.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::{max_diff, rand_tensor, randomize, step_chunks};
+    use super::*;
+    use crate::conv::{PadMode, StreamableConv1d, StreamableConvTranspose1d};
+    use candle_nn::VarBuilder;
+
+    fn conv1d(
+        in_c: usize,
+        out_c: usize,
+        k_size: usize,
+        stride: usize,
+        causal: bool,
+        vb: VarBuilder,
+    ) -> Result<StreamableConv1d> {
+        StreamableConv1d::new(
+            in_c,
+            out_c,
+            k_size,
+            stride,
+            /* dilation */ 1,
+            /* groups */ 1,
+            /* bias */ true,
+            causal,
+            /* norm */ None,
+            PadMode::Constant,
+            vb,
+        )
+    }
+
+    fn convtr1d(
+        in_c: usize,
+        out_c: usize,
+        k_size: usize,
+        stride: usize,
+        vb: VarBuilder,
+    ) -> Result<StreamableConvTranspose1d> {
+        StreamableConvTranspose1d::new(
+            in_c, out_c, k_size, stride, /* groups */ 1, /* bias */ true,
+            /* causal */ true, /* norm */ None, vb,
+        )
+    }
+
+    // Creates the variables with random values, the modules have to be built again afterwards.
+    fn random_vb<F: Fn(VarBuilder) -> Result<()>>(f: F) -> Result<VarBuilder<'static>> {
+        let vm = candle_nn::VarMap::new();
+        f(VarBuilder::from_varmap(&vm, candle::DType::F32, &Device::Cpu))?;
+        randomize(&vm, 0.5)?;
+        Ok(VarBuilder::from_varmap(&vm, candle::DType::F32, &Device::Cpu))
+    }
+
+    #[test]
+    fn sequential() -> Result<()> {
+        let build = |vb: VarBuilder| -> Result<_> {
+            Ok((
+                conv1d(2, 3, 4, 2, true, vb.pp("0"))?,
+                convtr1d(3, 2, 4, 2, vb.pp("1"))?,
+                conv1d(2, 2, 3, 1, false, vb.pp("2"))?,
+            ))
+        };
+        let vb = random_vb(|vb| build(vb).map(|_| ()))?;
+        let (l0, l1, l2) = build(vb.clone())?;
+        for len in [24, 23] {
+            let xs = rand_tensor(len as u64, 1., (1, 2, len))?;
+            let ys = xs.apply(&l0)?.apply(&l1)?.apply(&l2)?;
+            let (l0, l1, l2) = build(vb.clone())?;
+            let mut seq = StreamingSequential::new(candle::D::Minus1).with(l0).with(l1).with(l2);
+            assert_eq!(seq.len(), 3);
+            for chunk_len in [1, 3, 7, len] {
+                let ys_steps = step_chunks(&mut seq, &xs, chunk_len)?;
+                let diff = max_diff(&ys, &ys_steps)?;
+                assert!(diff < 1e-5, "len {len} chunk {chunk_len} diff {diff}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn residual() -> Result<()> {
+        let vb = random_vb(|vb| conv1d(2, 2, 3, 1, false, vb).map(|_| ()))?;
+        let conv = conv1d(2, 2, 3, 1, false, vb.clone())?;
+        let xs = rand_tensor(0, 1., (1, 2, 11))?;
+        let ys = (xs.apply(&conv)? + &xs)?;
+        // The non-causal conv delays its output, the input has to be buffered by the residual.
+        let mut residual = StreamingResidual::new(conv, candle::D::Minus1);
+        assert_eq!(residual.stream_info().delay, 2);
+        for chunk_len in [1, 2, 5, 11] {
+            let ys_steps = step_chunks(&mut residual, &xs, chunk_len)?;
+            let diff = max_diff(&ys, &ys_steps)?;
+            assert!(diff < 1e-5, "chunk {chunk_len} diff {diff}");
+        }
+
+        // A shortcut branch and another binary op.
+        let shortcut = conv1d(2, 2, 3, 1, false, vb.clone())?;
+        let ys = (xs.apply(&shortcut)? * xs.apply(&shortcut)?)?;
+        let mut residual = StreamingResidual::new_with_op(
+            Box::new(conv1d(2, 2, 3, 1, false, vb.clone())?),
+            Some(Box::new(shortcut)),
+            BinOp::Mul,
+            candle::D::Minus1,
+        );
+        for chunk_len in [1, 4, 11] {
+            let ys_steps = step_chunks(&mut residual, &xs, chunk_len)?;
+            let diff = max_diff(&ys, &ys_steps)?;
+            assert!(diff < 1e-5, "chunk {chunk_len} diff {diff}");
+        }
+        Ok(())
+    }
+
+    fn info(stride_in: usize, stride_out: usize, delay: usize) -> StreamInfo {
+        StreamInfo { stride_in, stride_out, delay }
+    }
+
+    #[test]
+    fn stream_info_then() {
+        let conv = info(4, 1, 6);
+        let id = StreamInfo::identity();
+        assert_eq!(id.then(&conv), conv);
+        assert_eq!(conv.then(&id), conv);
+        // Two downsampling convs, the second one needs 3 outputs of the first one which are
+        // available after 6 + 2 * 4 input steps.
+        assert_eq!(conv.then(&info(2, 1, 3)), info(8, 1, 14));
+        // A downsampling followed by an upsampling.
+        assert_eq!(conv.then(&info(1, 4, 1)), info(4, 4, 6));
+        // An upsampling followed by a downsampling, the first chunk of 4 outputs is enough.
+        assert_eq!(info(1, 4, 1).then(&info(2, 1, 3)), info(1, 2, 1));
+        assert_eq!(info(1, 4, 1).then(&info(8, 1, 9)), info(2, 1, 3));
+    }
 }
@@ -2,9 +2,11 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::streaming::{StreamTensor, StreamingModule};
+use crate::kv_cache::RotatingKvCache;
+use crate::streaming::{self, state_key, StateDict, StreamInfo, StreamTensor, StreamingModule};
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
 use candle_nn::{linear_no_bias, Linear, VarBuilder};
+use candle_transformers::utils::repeat_kv;
 use std::sync::Arc;
 
 fn linear(in_d: usize, out_d: usize, bias: bool, vb: VarBuilder) -> Result<Linear> {
@@ -15,14 +17,15 @@ fn linear(in_d: usize, out_d: usize, bias: bool, vb: VarBuilder) -> Result<Linea
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PositionalEmbedding {
     Rope,
     Sin,
     None,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     pub d_model: usize,
     pub num_heads: usize,
@@ -37,6 +40,7 @@ pub struct Config {
     pub cross_attention: bool,
     pub conv_kernel_size: usize,
     pub use_conv_bias: bool,
+    #[serde(default, with = "crate::config::option_activation")]
     pub gating: Option<candle_nn::Activation>,
     pub norm: crate::NormType,
     pub context: usize,
@@ -48,10 +52,25 @@ pub struct Config {
     pub conv_layout: bool,
 }
 
+impl Config {
+    /// The number of key/value heads, each of them being shared by `kv_repeat` query heads.
+    pub fn num_kv_heads(&self) -> Result<usize> {
+        if self.num_heads.checked_rem(self.kv_repeat) != Some(0) {
+            candle::bail!(
+                "num_heads {} is not a multiple of kv_repeat {}",
+                self.num_heads,
+                self.kv_repeat
+            )
+        }
+        Ok(self.num_heads / self.kv_repeat)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RotaryEmbedding {
     sin: Tensor,
     cos: Tensor,
+    inv_freq: Vec<f32>,
     span: tracing::Span,
 }
 
@@ -60,24 +79,46 @@ impl RotaryEmbedding {
         let inv_freq: Vec<_> =
             (0..dim).step_by(2).map(|i| 1f32 / theta.powf(i as f32 / dim as f32)).collect();
         let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let inv_freq_t = Tensor::new(inv_freq.as_slice(), dev)?.reshape((1, inv_freq_len))?;
         let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
             .to_dtype(DType::F32)?
             .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
+        let freqs = t.matmul(&inv_freq_t)?;
         Ok(Self {
             sin: freqs.sin()?,
             cos: freqs.cos()?,
+            inv_freq,
             span: tracing::span!(tracing::Level::TRACE, "rot"),
         })
     }
 
+    fn cos_sin(&self, seqlen_offset: usize, seqlen: usize) -> Result<(Tensor, Tensor)> {
+        if seqlen_offset + seqlen <= self.cos.dim(0)? {
+            let c = self.cos.narrow(0, seqlen_offset, seqlen)?;
+            let s = self.sin.narrow(0, seqlen_offset, seqlen)?;
+            return Ok((c, s));
+        }
+        // Streaming sessions can go past the precomputed positions, the embeddings then get
+        // computed on the fly using f64 so as to remain accurate for large positions.
+        let n = self.inv_freq.len();
+        let mut c = Vec::with_capacity(seqlen * n);
+        let mut s = Vec::with_capacity(seqlen * n);
+        for pos in seqlen_offset..seqlen_offset + seqlen {
+            for &inv_freq in self.inv_freq.iter() {
+                let freq = pos as f64 * inv_freq as f64;
+                c.push(freq.cos() as f32);
+                s.push(freq.sin() as f32);
+            }
+        }
+        let dev = self.cos.device();
+        Ok((Tensor::from_vec(c, (seqlen, n), dev)?, Tensor::from_vec(s, (seqlen, n), dev)?))
+    }
+
     pub fn apply_rotary_emb(&self, qk: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
         let _enter = self.span.enter();
         let (_b_size, _nheads, seqlen, _headdim) = qk.dims4()?;
         let qk_dtype = qk.dtype();
-        let c = self.cos.narrow(0, seqlen_offset, seqlen)?;
-        let s = self.sin.narrow(0, seqlen_offset, seqlen)?;
+        let (c, s) = self.cos_sin(seqlen_offset, seqlen)?;
         candle_nn::rotary_emb::rope_i(&qk.to_dtype(DType::F32)?, &c, &s)?.to_dtype(qk_dtype)
     }
 }
@@ -121,10 +162,9 @@ pub struct StreamingMultiheadAttention {
     out_proj: Linear,
     kv_repeat: usize,
     num_heads: usize,
-    context: usize,
     neg_inf: Tensor,
     rope: Option<Arc<RotaryEmbedding>>,
-    kv_cache: candle_nn::kv_cache::KvCache,
+    kv_cache: RotatingKvCache,
     pos: usize,
     use_flash_attn: bool,
     span: tracing::Span,
@@ -133,7 +173,7 @@ pub struct StreamingMultiheadAttention {
 impl StreamingMultiheadAttention {
     pub fn new(rope: &Option<Arc<RotaryEmbedding>>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let embed_dim = cfg.d_model;
-        let num_kv = cfg.num_heads / cfg.kv_repeat;
+        let num_kv = cfg.num_kv_heads()?;
         let out_dim = embed_dim + 2 * num_kv * (embed_dim / cfg.num_heads);
         let in_proj_weight = vb.get((out_dim, embed_dim), "in_proj_weight")?;
         let in_proj_bias =
@@ -147,9 +187,8 @@ impl StreamingMultiheadAttention {
             rope: rope.clone(),
             kv_repeat: cfg.kv_repeat,
             num_heads: cfg.num_heads,
-            context: cfg.context,
             neg_inf,
-            kv_cache: candle_nn::kv_cache::KvCache::new(2, cfg.max_seq_len),
+            kv_cache: RotatingKvCache::new(cfg.context),
             pos: 0,
             use_flash_attn: false,
             span: tracing::span!(tracing::Level::TRACE, "mha"),
@@ -158,42 +197,30 @@ impl StreamingMultiheadAttention {
 
     pub fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        if self.kv_repeat != 1 {
-            candle::bail!("only kv-repeat = 1 is supported")
-        }
         let (b, t, hd) = xs.dims3()?;
         let head_dim = hd / self.num_heads;
+        let num_kv = self.num_heads / self.kv_repeat;
+        let kv_dim = num_kv * head_dim;
         // time_dim = 1, layout: b,t,h,d
-        let qkv = xs.apply(&self.in_proj)?.reshape((b, t, 3, self.num_heads, head_dim))?;
-        let q = qkv.i((.., .., 0))?;
-        let k = qkv.i((.., .., 1))?;
-        let v = qkv.i((.., .., 2))?;
+        let qkv = xs.apply(&self.in_proj)?;
+        let q = qkv.narrow(D::Minus1, 0, hd)?.reshape((b, t, self.num_heads, head_dim))?;
+        let k = qkv.narrow(D::Minus1, hd, kv_dim)?.reshape((b, t, num_kv, head_dim))?;
+        let v = qkv.narrow(D::Minus1, hd + kv_dim, kv_dim)?.reshape((b, t, num_kv, head_dim))?;
         // qk_layer_norm = None
-        // kv_repeat = 1, otherwise we would need repeat_kv
         let mut q = q.transpose(1, 2)?.contiguous()?; // b,h,t,d
-        let mut k = k.transpose(1, 2)?.contiguous()?; // b,h,k,d
-        let v = v.transpose(1, 2)?.contiguous()?; // b,h,k,d
+        let mut k = k.transpose(1, 2)?.contiguous()?; // b,h_kv,k,d
+        let v = v.transpose(1, 2)?.contiguous()?; // b,h_kv,k,d
         if let Some(rope) = &self.rope {
             q = rope.apply_rotary_emb(&q, self.pos)?;
             k = rope.apply_rotary_emb(&k, self.pos)?;
         }
 
+        // The kv-cache returns at most context past steps followed by the new ones, this is
+        // consistent with the mask shape we provide.
         let (k, v) = {
             self.pos += k.dim(2)?;
             self.kv_cache.append(&k.contiguous()?, &v.contiguous()?)?
         };
-        // The KV cache keeps all the data at the moment, we want to trim
-        // down the part that comes from the cache to at most context to
-        // be coherent with the mask shape we provide.
-        let k_len = k.dim(2)?;
-        let k_target_len = t + usize::min(self.context, k_len - t);
-        let (k, v) = if k_target_len < k_len {
-            let k = k.narrow(2, k_len - k_target_len, k_target_len)?;
-            let v = v.narrow(2, k_len - k_target_len, k_target_len)?;
-            (k, v)
-        } else {
-            (k.clone(), v.clone())
-        };
 
         let xs = if q.dtype() == DType::BF16 && self.use_flash_attn {
             let q = q.transpose(1, 2)?;
@@ -202,6 +229,10 @@ impl StreamingMultiheadAttention {
             let softmax_scale = 1f32 / (head_dim as f32).sqrt();
             flash_attn(&q, &k, &v, softmax_scale, t > 1)?.transpose(1, 2)?
         } else {
+            // The kv-cache only holds the num_kv heads, these get shared by groups of kv_repeat
+            // query heads.
+            let k = repeat_kv(k, self.kv_repeat)?; // b,h,k,d
+            let v = repeat_kv(v, self.kv_repeat)?; // b,h,k,d
             let pre_ws = q.matmul(&k.t()?)?; // b,h,t,k
             let pre_ws = (pre_ws * (head_dim as f64).powf(-0.5))?;
 
@@ -225,11 +256,37 @@ impl StreamingMultiheadAttention {
     }
 
     pub fn reset_kv_cache(&mut self) {
-        self.kv_cache.reset()
+        self.kv_cache.reset();
+        self.pos = 0;
+    }
+
+    pub fn copy_state(&mut self, from: &Self) -> Result<()> {
+        self.kv_cache = from.kv_cache.copy()?;
+        self.pos = from.pos;
+        Ok(())
+    }
+
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        if let (Some(k), Some(v)) = (self.kv_cache.k()?, self.kv_cache.v()?) {
+            state.insert(state_key(prefix, "k_cache"), k);
+            state.insert(state_key(prefix, "v_cache"), v);
+        }
+        streaming::save_usize(&state_key(prefix, "pos"), self.pos, state)
     }
 
-    pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
-        self.kv_cache = kv_cache
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.kv_cache.reset();
+        let k = state.get(&state_key(prefix, "k_cache"));
+        let v = state.get(&state_key(prefix, "v_cache"));
+        match (k, v) {
+            (Some(k), Some(v)) => {
+                self.kv_cache.append(k, v)?;
+            }
+            (None, None) => {}
+            _ => candle::bail!("inconsistent kv-cache state for {prefix}"),
+        }
+        self.pos = streaming::load_usize(&state_key(prefix, "pos"), state)?.unwrap_or(0);
+        Ok(())
     }
 }
 
@@ -248,7 +305,7 @@ pub struct StreamingMultiheadCrossAttention {
 impl StreamingMultiheadCrossAttention {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let embed_dim = cfg.d_model;
-        let num_kv = cfg.num_heads / cfg.kv_repeat;
+        let num_kv = cfg.num_kv_heads()?;
         let kv_dim = num_kv * (embed_dim / cfg.num_heads);
         let out_dim = embed_dim + 2 * kv_dim;
         let in_proj_weight = vb.get((out_dim, embed_dim), "in_proj_weight")?;
@@ -283,9 +340,6 @@ impl StreamingMultiheadCrossAttention {
 
     pub fn forward(&self, xs: &Tensor, ca_src: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        if self.kv_repeat != 1 {
-            candle::bail!("only kv-repeat = 1 is supported")
-        }
         let (b, t, hd) = xs.dims3()?;
         let head_dim = hd / self.num_heads;
         // time_dim = 1, layout: b,t,h,d
@@ -297,10 +351,9 @@ impl StreamingMultiheadCrossAttention {
         let k = k.reshape((ca_b, ca_t, ca_dim / head_dim, head_dim))?;
         let v = v.reshape((ca_b, ca_t, ca_dim / head_dim, head_dim))?;
         // qk_layer_norm = None
-        // kv_repeat = 1, otherwise we would need repeat_kv
         let q = q.transpose(1, 2)?.contiguous()?; // b,h,t,d
-        let k = k.transpose(1, 2)?.contiguous()?; // b,h,k,d
-        let v = v.transpose(1, 2)?.contiguous()?; // b,h,k,d
+        let k = repeat_kv(k.transpose(1, 2)?.contiguous()?, self.kv_repeat)?; // b,h,k,d
+        let v = repeat_kv(v.transpose(1, 2)?.contiguous()?, self.kv_repeat)?; // b,h,k,d
 
         let pre_ws = q.matmul(&k.t()?)?; // b,h,t,k
         let pre_ws = (pre_ws * (head_dim as f64).powf(-0.5))?;
@@ -546,8 +599,16 @@ impl StreamingTransformerLayer {
         self.self_attn.reset_kv_cache()
     }
 
-    pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
-        self.self_attn.set_kv_cache(kv_cache)
+    pub fn copy_state(&mut self, from: &Self) -> Result<()> {
+        self.self_attn.copy_state(&from.self_attn)
+    }
+
+    pub fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.self_attn.save_state(&state_key(prefix, "self_attn"), state)
+    }
+
+    pub fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.self_attn.load_state(&state_key(prefix, "self_attn"), state)
     }
 }
 
@@ -595,7 +656,7 @@ impl StreamingTransformer {
         let (_b, t, c) = xs.dims3()?;
         // We will extract at most "context" from the kv_cache.
         // Note that the mask will discard the values that are before context.
-        let pos = self.layers[0].self_attn.kv_cache.k_cache().current_seq_len().min(self.context);
+        let pos = self.layers[0].self_attn.kv_cache.current_seq_len().min(self.context);
         let mask =
             if t == 1 { None } else { Some(get_mask(t, pos + t, self.context, xs.device())?) };
         let mut xs = match self.positional_embedding {
@@ -628,10 +689,9 @@ impl StreamingTransformer {
         if self.layers.len() != from.layers.len() {
             candle::bail!("cannot copy kv-caches as the transformers have different depths")
         }
-        self.layers
-            .iter_mut()
-            .zip(from.layers.iter())
-            .for_each(|(v, w)| v.set_kv_cache(w.self_attn.kv_cache.clone()));
+        for (v, w) in self.layers.iter_mut().zip(from.layers.iter()) {
+            v.copy_state(w)?
+        }
         Ok(())
     }
 }
@@ -647,6 +707,25 @@ impl StreamingModule for StreamingTransformer {
             Some(xs) => Ok(StreamTensor::from_tensor(self.forward(xs)?)),
         }
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        self.reset_state();
+        Ok(StreamTensor::empty())
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer.save_state(&state_key(prefix, &format!("layers.{i}")), state)?
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer.load_state(&state_key(prefix, &format!("layers.{i}")), state)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -731,6 +810,22 @@ impl StreamingModule for ProjectedTransformer {
             }
         })
     }
+
+    fn flush(&mut self) -> Result<StreamTensor> {
+        self.transformer.flush()
+    }
+
+    fn save_state(&self, prefix: &str, state: &mut StateDict) -> Result<()> {
+        self.transformer.save_state(&state_key(prefix, "transformer"), state)
+    }
+
+    fn load_state(&mut self, prefix: &str, state: &StateDict) -> Result<()> {
+        self.transformer.load_state(&state_key(prefix, "transformer"), state)
+    }
+
+    fn stream_info(&self) -> StreamInfo {
+        self.transformer.stream_info()
+    }
 }
 
 #[cfg(feature = "flash-attn")]
@@ -748,3 +843,154 @@ fn flash_attn(
 fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
     unimplemented!("compile with '--features flash-attn'")
 }
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+    use std::collections::HashMap;
+
+    pub(crate) fn gqa_config(kv_repeat: usize) -> Config {
+        Config {
+            d_model: 32,
+            num_heads: 4,
+            num_layers: 2,
+            causal: true,
+            norm_first: true,
+            bias_ff: false,
+            bias_attn: false,
+            layer_scale: None,
+            positional_embedding: PositionalEmbedding::Rope,
+            use_conv_block: false,
+            cross_attention: false,
+            conv_kernel_size: 3,
+            use_conv_bias: true,
+            gating: Some(candle_nn::Activation::Silu),
+            norm: crate::NormType::RmsNorm,
+            context: 6,
+            max_period: 10000,
+            max_seq_len: 64,
+            kv_repeat,
+            dim_feedforward: 64,
+            conv_layout: false,
+        }
+    }
+
+    // Random weights for a transformer using `cfg`.
+    pub(crate) fn random_weights(cfg: &Config, dev: &Device) -> Result<HashMap<String, Tensor>> {
+        let vm = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&vm, DType::F32, dev);
+        StreamingTransformer::new(cfg, vb)?;
+        crate::streaming::test_utils::randomize(&vm, 0.2)?;
+        let ws = vm.data().lock().unwrap();
+        Ok(ws.iter().map(|(name, var)| (name.clone(), var.as_tensor().clone())).collect())
+    }
+
+    // The naive reference for grouped-query attention: the weights of an equivalent multi-head
+    // attention where each key/value head is explicitly duplicated for its kv_repeat query heads.
+    pub(crate) fn repeat_kv_weights(
+        ws: &HashMap<String, Tensor>,
+        cfg: &Config,
+    ) -> Result<HashMap<String, Tensor>> {
+        let head_dim = cfg.d_model / cfg.num_heads;
+        let kv_dim = cfg.num_kv_heads()? * head_dim;
+        let mut repeated = HashMap::new();
+        for (name, w) in ws.iter() {
+            let w = if name.ends_with("in_proj_weight") {
+                let mut rows = vec![w.narrow(0, 0, cfg.d_model)?];
+                for offset in [cfg.d_model, cfg.d_model + kv_dim] {
+                    for h in 0..cfg.num_heads {
+                        let kv_h = h / cfg.kv_repeat;
+                        rows.push(w.narrow(0, offset + kv_h * head_dim, head_dim)?)
+                    }
+                }
+                Tensor::cat(&rows, 0)?
+            } else {
+                w.clone()
+            };
+            repeated.insert(name.clone(), w);
+        }
+        Ok(repeated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::{gqa_config, random_weights, repeat_kv_weights};
+    use super::*;
+    use crate::streaming::test_utils::{max_diff, rand_tensor};
+    use std::collections::HashMap;
+
+    fn transformer(ws: &HashMap<String, Tensor>, cfg: &Config) -> Result<StreamingTransformer> {
+        let vb = VarBuilder::from_tensors(ws.clone(), DType::F32, &Device::Cpu);
+        StreamingTransformer::new(cfg, vb)
+    }
+
+    #[test]
+    fn gqa_matches_repeated_heads() -> Result<()> {
+        let dev = &Device::Cpu;
+        for kv_repeat in [2, 4] {
+            let cfg = gqa_config(kv_repeat);
+            let ws = random_weights(&cfg, dev)?;
+            let mut gqa = transformer(&ws, &cfg)?;
+            let mut mha = transformer(&repeat_kv_weights(&ws, &cfg)?, &gqa_config(1))?;
+            // Use more steps than the context so that the kv-cache gets trimmed.
+            let xs = rand_tensor(kv_repeat as u64, 1., (2, 10, cfg.d_model))?;
+            let ys_gqa = gqa.forward(&xs)?;
+            let ys_mha = mha.forward(&xs)?;
+            assert!(max_diff(&ys_gqa, &ys_mha)? < 1e-4);
+
+            // Stepping through the sequence gives the same result.
+            gqa.reset_state();
+            mha.reset_state();
+            for t in 0..xs.dim(1)? {
+                let xs = xs.narrow(1, t, 1)?.contiguous()?;
+                let ys_gqa = gqa.forward(&xs)?;
+                let ys_mha = mha.forward(&xs)?;
+                assert!(max_diff(&ys_gqa, &ys_mha)? < 1e-4);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_past_max_seq_len() -> Result<()> {
+        let dev = &Device::Cpu;
+        let cfg = gqa_config(2);
+        let ws = random_weights(&cfg, dev)?;
+        let mut batch = transformer(&ws, &Config { max_seq_len: 128, ..cfg.clone() })?;
+        let xs = rand_tensor(0, 1., (1, 100, cfg.d_model))?;
+        let ys = batch.forward(&xs)?;
+        let mut streaming = transformer(&ws, &Config { max_seq_len: 16, ..cfg.clone() })?;
+        // Mix single steps and chunks so that both ways of appending to the kv-cache get used.
+        let mut start = 0;
+        for len in [1, 3, 1, 1, 7, 2].iter().cycle() {
+            let len = usize::min(*len, xs.dim(1)? - start);
+            if len == 0 {
+                break;
+            }
+            let ys_s = streaming.forward(&xs.narrow(1, start, len)?)?;
+            assert!(max_diff(&ys_s, &ys.narrow(1, start, len)?)? < 1e-4);
+            let cache_len = streaming.layers[0].self_attn.kv_cache.current_seq_len();
+            assert!(cache_len <= cfg.context + 1);
+            start += len;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn gqa_copy_state() -> Result<()> {
+        let dev = &Device::Cpu;
+        let cfg = gqa_config(2);
+        let ws = random_weights(&cfg, dev)?;
+        let mut tr1 = transformer(&ws, &cfg)?;
+        let mut tr2 = transformer(&ws, &cfg)?;
+        let xs = rand_tensor(0, 1., (1, 4, cfg.d_model))?;
+        tr1.forward(&xs)?;
+        tr2.copy_state(&tr1)?;
+        let xs = rand_tensor(1, 1., (1, 1, cfg.d_model))?;
+        let ys1 = tr1.forward(&xs)?;
+        let ys2 = tr2.forward(&xs)?;
+        assert!(max_diff(&ys1, &ys2)? < 1e-6);
+        Ok(())
+    }
+}
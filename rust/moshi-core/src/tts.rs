@@ -2,6 +2,8 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::lm_generate_multistream::{StreamKind, StreamLayout};
+use crate::sampling::{Sampler, SamplingConfig};
 use candle::{DType, Result, Tensor, D};
 use candle_nn::{linear_no_bias, Linear, VarBuilder};
 use candle_transformers::models::t5;
@@ -10,6 +12,8 @@ pub struct Config {
     pub t5: t5::Config,
     pub lm: crate::lm::Config,
     pub encodec: crate::encodec::Config,
+    /// The generated audio stream, a single one with all the codebooks of the language model.
+    pub stream_layout: StreamLayout,
     pub max_duration_s: f64,
     pub speaker_cond_duration_s: f64,
     pub max_speakers: usize,
@@ -19,13 +23,31 @@ impl Config {
     pub fn v0_1(t5: t5::Config) -> Self {
         let lm = crate::lm::Config::tts_v0_1();
         let encodec = crate::encodec::Config::v0_1(None);
-        Self { t5, lm, encodec, max_duration_s: 60., speaker_cond_duration_s: 4., max_speakers: 5 }
+        let stream_layout = StreamLayout::new(vec![StreamKind::Generated], lm.audio_codebooks, 2);
+        Self {
+            t5,
+            lm,
+            encodec,
+            stream_layout,
+            max_duration_s: 60.,
+            speaker_cond_duration_s: 4.,
+            max_speakers: 5,
+        }
     }
 
     pub fn v0_2(t5: t5::Config) -> Self {
         let lm = crate::lm::Config::tts_v0_1();
         let encodec = crate::encodec::Config::v0_1(None);
-        Self { t5, lm, encodec, max_duration_s: 60., speaker_cond_duration_s: 10., max_speakers: 2 }
+        let stream_layout = StreamLayout::new(vec![StreamKind::Generated], lm.audio_codebooks, 2);
+        Self {
+            t5,
+            lm,
+            encodec,
+            stream_layout,
+            max_duration_s: 60.,
+            speaker_cond_duration_s: 10.,
+            max_speakers: 2,
+        }
     }
 }
 
@@ -39,6 +61,8 @@ pub struct Model {
     frame_rate: f64,
     audio_vocab_size: u32,
     audio_codebooks: usize,
+    // The delay in steps of each codebook.
+    delays: Vec<usize>,
     pub max_duration_s: f64,
     max_speakers: usize,
     end_of_gen: Option<usize>,
@@ -51,6 +75,13 @@ impl Model {
         vb_lm: VarBuilder,
         vb_speaker_cond: Option<VarBuilder>,
     ) -> Result<Self> {
+        let layout = &cfg.stream_layout;
+        layout.check()?;
+        if layout.streams != [StreamKind::Generated]
+            || layout.total_codebooks() != cfg.lm.audio_codebooks
+        {
+            candle::bail!("the tts model expects a single generated stream with all the codebooks")
+        }
         let t5 = t5::T5EncoderModel::load(vb_t5, &cfg.t5)?;
         let speaker_cond = match vb_speaker_cond {
             None => None,
@@ -82,6 +113,7 @@ impl Model {
             frame_rate: cfg.encodec.frame_rate,
             audio_vocab_size: cfg.lm.audio_vocab_size as u32,
             audio_codebooks: cfg.lm.audio_codebooks,
+            delays: layout.generated_delays(),
             max_duration_s: cfg.max_duration_s,
             max_speakers: cfg.max_speakers,
             end_of_gen: None,
@@ -141,37 +173,31 @@ impl Model {
     }
 
     pub fn sample(&mut self, conditions: &Tensor, cfg_alpha: f64) -> Result<Vec<Vec<u32>>> {
-        let lp = candle_transformers::generation::LogitsProcessor::from_sampling(
-            299792458,
-            candle_transformers::generation::Sampling::TopK { k: 100, temperature: 0.8 },
-        );
-        self.sample_lp(conditions, cfg_alpha, lp)
+        let lp = SamplingConfig::top_k(0.8, 100).sampler(299792458);
+        self.sample_lp(conditions, cfg_alpha, vec![lp])
     }
 
     pub fn sample_lp(
         &mut self,
         conditions: &Tensor,
         cfg_alpha: f64,
-        mut lp: candle_transformers::generation::LogitsProcessor,
+        mut lps: Vec<Sampler>,
     ) -> Result<Vec<Vec<u32>>> {
         let max_steps = (self.max_duration_s * self.frame_rate) as usize + 1;
         let audio_codebooks = self.audio_codebooks;
         let audio_vocab_size = self.audio_vocab_size;
-        let mut audio_tokens: Vec<Vec<u32>> = vec![vec![u32::MAX; audio_codebooks]; max_steps + 2];
+        let delays = self.delays.clone();
+        let max_delay = delays.iter().copied().max().unwrap_or(0);
+        let mut audio_tokens: Vec<Vec<u32>> =
+            vec![vec![u32::MAX; audio_codebooks]; max_steps + max_delay];
         let quantizer_bins = audio_vocab_size - 2; // 2048
-        for step_idx in 0..(max_steps + 2) {
+        for step_idx in 0..(max_steps + max_delay) {
             let mut codes = Vec::with_capacity(audio_codebooks);
-            for codebook in 0..audio_codebooks {
-                let t = if codebook == 0 {
-                    if step_idx == 0 {
-                        audio_vocab_size - 1
-                    } else {
-                        audio_tokens[step_idx - 1][0]
-                    }
-                } else if step_idx <= 2 {
+            for (codebook, &delay) in delays.iter().enumerate() {
+                let t = if step_idx <= delay {
                     audio_vocab_size - 1
                 } else {
-                    audio_tokens[step_idx - 3][codebook]
+                    audio_tokens[step_idx - delay - 1][codebook]
                 };
                 let t = Tensor::new(&[t], conditions.device())?.unsqueeze(0)?;
                 codes.push(Some(t))
@@ -182,17 +208,16 @@ impl Model {
                 Some(df) => df,
             };
             let last_audio_tokens = if self.speaker_cond.is_some() {
-                df.sample_cfg(step_idx, &ys, cfg_alpha, None, &mut lp)?
+                df.sample_cfg(step_idx, &ys, cfg_alpha, None, &delays, &mut lps)?
             } else {
-                df.sample(step_idx, &ys, None, &mut lp)?
+                df.sample(step_idx, &ys, None, &delays, &mut lps)?
             };
             for (c_idx, token) in last_audio_tokens.into_iter().enumerate() {
                 if step_idx > 0 && token >= quantizer_bins && self.end_of_gen.is_none() {
-                    // Continue generating for two steps to get the final acoustic tokens.
-                    self.end_of_gen = Some(step_idx + 2)
+                    // Continue generating until the final acoustic tokens have been sampled.
+                    self.end_of_gen = Some(step_idx + max_delay)
                 }
-                let delay = if c_idx == 0 { 0 } else { 2 };
-                audio_tokens[step_idx.saturating_sub(delay)][c_idx] = token
+                audio_tokens[step_idx.saturating_sub(delays[c_idx])][c_idx] = token
             }
             if Some(step_idx) == self.end_of_gen {
                 break;
@@ -26,15 +26,32 @@ impl Sample for i16 {
     }
 }
 
+/// Writes mono pcm data as 16 bits samples.
 pub fn write_pcm_as_wav<W: Write, S: Sample>(
     w: &mut W,
     samples: &[S],
     sample_rate: u32,
 ) -> std::io::Result<()> {
+    write_interleaved_pcm_as_wav(w, samples, 1, sample_rate)
+}
+
+/// Writes multi-channel pcm data, `samples` is expected to be interleaved, i.e. it starts with
+/// the first sample of each channel, then the second sample of each channel, etc.
+pub fn write_interleaved_pcm_as_wav<W: Write, S: Sample>(
+    w: &mut W,
+    samples: &[S],
+    n_channels: u16,
+    sample_rate: u32,
+) -> std::io::Result<()> {
+    if samples.len().checked_rem(n_channels as usize) != Some(0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} samples cannot be split in {n_channels} channels", samples.len()),
+        ));
+    }
     let len = 12u32; // header
     let len = len + 24u32; // fmt
     let len = len + samples.len() as u32 * 2 + 8; // data
-    let n_channels = 1u16;
     let bytes_per_second = sample_rate * 2 * n_channels as u32;
     w.write_all(b"RIFF")?;
     w.write_all(&(len - 8).to_le_bytes())?; // total length minus 8 bytes
@@ -44,10 +61,10 @@ pub fn write_pcm_as_wav<W: Write, S: Sample>(
     w.write_all(b"fmt ")?;
     w.write_all(&16u32.to_le_bytes())?; // block len minus 8 bytes
     w.write_all(&1u16.to_le_bytes())?; // PCM
-    w.write_all(&n_channels.to_le_bytes())?; // one channel
+    w.write_all(&n_channels.to_le_bytes())?;
     w.write_all(&sample_rate.to_le_bytes())?;
     w.write_all(&bytes_per_second.to_le_bytes())?;
-    w.write_all(&2u16.to_le_bytes())?; // 2 bytes of data per sample
+    w.write_all(&(2 * n_channels).to_le_bytes())?; // 2 bytes of data per sample and channel
     w.write_all(&16u16.to_le_bytes())?; // bits per sample
 
     // Data block
@@ -58,3 +75,63 @@ pub fn write_pcm_as_wav<W: Write, S: Sample>(
     }
     Ok(())
 }
+
+/// Writes one pcm buffer per channel, all the buffers must have the same length. For multi-channel
+/// audio decoded by `Encodec::decode`, these are the rows of the (channels, t) pcm data of a
+/// batch element.
+pub fn write_pcm_channels_as_wav<W: Write, S: Sample>(
+    w: &mut W,
+    channels: &[Vec<S>],
+    sample_rate: u32,
+) -> std::io::Result<()> {
+    let len = channels.first().map_or(0, |c| c.len());
+    if channels.iter().any(|c| c.len() != len) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "all the channels should have the same length",
+        ));
+    }
+    let samples: Vec<i16> =
+        (0..len).flat_map(|i| channels.iter().map(move |c| c[i].to_i16())).collect();
+    write_interleaved_pcm_as_wav(w, &samples, channels.len() as u16, sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn stereo_header() -> std::io::Result<()> {
+        let channels = vec![vec![1i16, 2, 3], vec![-1i16, -2, -3]];
+        let mut bytes = vec![];
+        write_pcm_channels_as_wav(&mut bytes, &channels, 24000)?;
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u16_at(&bytes, 20), 1); // PCM
+        assert_eq!(u16_at(&bytes, 22), 2); // channels
+        assert_eq!(u32_at(&bytes, 24), 24000); // sample rate
+        assert_eq!(u32_at(&bytes, 28), 24000 * 4); // bytes per second
+        assert_eq!(u16_at(&bytes, 32), 4); // block align
+        assert_eq!(u16_at(&bytes, 34), 16); // bits per sample
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+        // The samples are interleaved.
+        let samples: Vec<i16> = (0..6).map(|i| u16_at(&bytes, 44 + 2 * i) as i16).collect();
+        assert_eq!(samples, [1, -1, 2, -2, 3, -3]);
+
+        let mut bytes = vec![];
+        assert!(write_pcm_channels_as_wav(&mut bytes, &[vec![1i16], vec![]], 24000).is_err());
+        assert!(write_interleaved_pcm_as_wav(&mut bytes, &[1i16, 2, 3], 2, 24000).is_err());
+        Ok(())
+    }
+}